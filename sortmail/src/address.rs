@@ -0,0 +1,237 @@
+use unicode_normalization::UnicodeNormalization;
+
+//
+// Address normalization
+//
+// Addresses from the config file, the environment and message headers
+// are all put into the same canonical form before they're compared.
+//
+// Addresses are parsed as RFC 5322 allows them to be written: comments,
+// display names, angle brackets and source routes are dropped, and the
+// local part is unquoted. It's quoted again (as RFC 5321 asks) only if
+// it isn't a plain dot-atom, so `"john"@example.com` is
+// `john@example.com`, and `weird..user@example.com` and
+// `"weird..user"@example.com` are both `"weird..user"@example.com`.
+//
+
+/// Characters allowed in an unquoted local part besides letters,
+/// digits and dots.
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+/// Return the canonical form of an email address: the local part
+/// normalized by `normalize_local_part`, and the domain by
+/// `normalize_domain`.
+pub fn normalize_address(address: &str) -> String {
+    match split_address(address) {
+        Some((local_part, domain)) => join_address(&normalize_local_part(&local_part), &normalize_domain(&domain)),
+        None => quote_local_part(&normalize_local_part(&unquote_local_part(&bare_address(address))))
+    }
+}
+
+/// Like `normalize_address`, but leave the local part's case alone,
+/// for case-sensitive matching. It's still put in NFC, so the same
+/// characters compare equal however they were composed.
+pub fn normalize_address_domain(address: &str) -> String {
+    match split_address(address) {
+        Some((local_part, domain)) => join_address(&local_part.nfc().collect::<String>(), &normalize_domain(&domain)),
+        None => quote_local_part(&unquote_local_part(&bare_address(address)).nfc().collect::<String>())
+    }
+}
+
+/// Return the canonical form of a local part (or anything else that's
+/// matched without regard to case): Unicode case-folded and in NFC.
+///
+/// Case folding handles SMTPUTF8 addresses that lowercasing doesn't,
+/// like `STRASSE` and `straße`, and the normalization makes precomposed
+/// and decomposed accents (`é` and `e` + U+0301) the same.
+pub fn normalize_local_part(local_part: &str) -> String {
+    let decomposed: String = local_part.nfd().collect();
+    caseless::default_case_fold_str(&decomposed).nfc().collect()
+}
+
+/// Return the canonical form of a domain: lowercased, with any
+/// internationalized labels in their ASCII (punycode) form, so that
+/// `bücher.example` and `xn--bcher-kva.example` compare equal. A
+/// leading dot (as used for subdomain matching) is kept.
+///
+/// Domains that aren't valid IDNA are just lowercased.
+pub fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim();
+
+    let (dot, name) = match domain.strip_prefix('.') {
+        Some(name) => (".", name),
+        None => ("", domain)
+    };
+
+    match idna::domain_to_ascii(name) {
+        Ok(ascii) => format!("{dot}{ascii}"),
+        Err(_) => format!("{dot}{}", name.to_lowercase())
+    }
+}
+
+/// Split an address into its unquoted local part and its domain, or
+/// return None if it has no domain.
+pub fn split_address(address: &str) -> Option<(String, String)> {
+    let address = bare_address(address);
+    let at = position_outside_quotes(&address, '@', true)?;
+
+    Some((unquote_local_part(&address[..at]), address[at + 1..].trim().to_string()))
+}
+
+/// Put an unquoted local part and a domain back together, quoting the
+/// local part if it needs it.
+pub fn join_address(local_part: &str, domain: &str) -> String {
+    format!("{}@{domain}", quote_local_part(local_part))
+}
+
+/// Split a list of addresses separated by commas, ignoring commas
+/// inside quotes, comments and angle brackets, and return each in
+/// canonical form, but otherwise as it was given (the local part keeps
+/// its case and the domain isn't normalized).
+pub fn split_addresses(addresses: &str) -> Vec<String> {
+    let addresses = strip_comments(addresses);
+
+    let mut split = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut in_angle_brackets = false;
+    let mut chars = addresses.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if in_quotes => {
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angle_brackets = true,
+            '>' if !in_quotes => in_angle_brackets = false,
+            ',' if !(in_quotes || in_angle_brackets) => {
+                split.push(&addresses[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    split.push(&addresses[start..]);
+
+    split
+        .into_iter()
+        .map(|address| match split_address(address) {
+            Some((local_part, domain)) => join_address(&local_part, &domain),
+            None => bare_address(address)
+        })
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+/// Remove (possibly nested) parenthesized comments from a structured
+/// header value, leaving quoted strings and escaped characters intact.
+pub fn strip_comments(value: &str) -> String {
+    let mut stripped = String::with_capacity(value.len());
+    let mut comment_depth = 0;
+    let mut in_quotes = false;
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next();
+                if comment_depth == 0 {
+                    stripped.push(c);
+                    stripped.extend(escaped);
+                }
+            },
+            '"' if comment_depth == 0 => {
+                in_quotes = !in_quotes;
+                stripped.push(c);
+            },
+            '(' if !in_quotes => comment_depth += 1,
+            ')' if !in_quotes && comment_depth > 0 => comment_depth -= 1,
+            _ if comment_depth > 0 => {},
+            _ => stripped.push(c)
+        }
+    }
+
+    stripped
+}
+
+/// Return just the address from a single address as it may be written
+/// in a header: without comments, without a display name and angle
+/// brackets, and without a source route (`<@relay1,@relay2:user@host>`).
+fn bare_address(address: &str) -> String {
+    let address = strip_comments(address);
+
+    let address = match position_outside_quotes(&address, '<', false) {
+        Some(open) => {
+            let inside = &address[open + 1..];
+            inside.split_once('>').map_or(inside, |(inside, _)| inside)
+        },
+        None => &address
+    };
+
+    let address = address.trim();
+    let address = match address.starts_with('@') {
+        true => address.split_once(':').map_or(address, |(_, address)| address),
+        false => address
+    };
+
+    address.trim().to_string()
+}
+
+/// Find the first (or, if `last`, the last) `target` character that
+/// isn't inside a quoted string.
+fn position_outside_quotes(value: &str, target: char, last: bool) -> Option<usize> {
+    let mut found = None;
+    let mut in_quotes = false;
+    let mut chars = value.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if in_quotes => {
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            _ if c == target && !in_quotes => {
+                found = Some(i);
+                if !last {
+                    break;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    found
+}
+
+/// Remove the quoting from a local part, including from quoted words
+/// in the obsolete `john."q".doe` form, and any whitespace outside
+/// quotes.
+fn unquote_local_part(local_part: &str) -> String {
+    let mut unquoted = String::with_capacity(local_part.len());
+    let mut in_quotes = false;
+    let mut chars = local_part.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_quotes => unquoted.extend(chars.next()),
+            '"' => in_quotes = !in_quotes,
+            _ if c.is_whitespace() && !in_quotes => {},
+            _ => unquoted.push(c)
+        }
+    }
+
+    unquoted
+}
+
+/// Quote a local part, unless it's a valid dot-atom (or empty).
+fn quote_local_part(local_part: &str) -> String {
+    let is_dot_atom = local_part.is_empty() || local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(|c| c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c) || !c.is_ascii()));
+
+    match is_dot_atom {
+        true => local_part.to_string(),
+        false => format!("\"{}\"", local_part.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
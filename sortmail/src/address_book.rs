@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::address;
+
+//
+// Address books
+//
+// Contacts can be loaded from:
+//
+// - a directory of vCard (`.vcf`) files, like the ones kept by vdirsyncer
+// - a vCard file, which may hold many cards
+// - a mutt alias file (`alias nick Full Name <address>`)
+// - a plain list of addresses, one per line, with `#` comments
+//
+// Only the email addresses are kept.
+//
+
+/// Load the normalized email addresses from the address book at
+/// `path`, working out its format from what's there.
+pub fn load(path: &Path) -> Result<HashSet<String>> {
+    if path.is_dir() {
+        let mut addresses = HashSet::new();
+
+        let entries = std::fs::read_dir(path)
+            .with_context(|| format!("Error reading address book directory {}", path.display()))?;
+
+        for entry in entries {
+            let entry_path = entry
+                .with_context(|| format!("Error reading address book directory {}", path.display()))?
+                .path();
+
+            let is_vcard = entry_path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("vcf"));
+
+            if is_vcard {
+                addresses.extend(load(&entry_path)?);
+            }
+        }
+
+        return Ok(addresses);
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Error opening address book {}", path.display()))?;
+
+    let is_vcard = contents
+        .lines()
+        .any(|line| line.trim().eq_ignore_ascii_case("BEGIN:VCARD"));
+
+    let addresses = match is_vcard {
+        true => parse_vcard_addresses(&contents),
+        false => parse_address_list(&contents)
+    };
+
+    Ok(addresses)
+}
+
+/// Return the addresses in the `EMAIL` properties of vCards, like
+/// `EMAIL;TYPE=work:someone@example.com` or `item1.EMAIL:...`.
+fn parse_vcard_addresses(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            let property = name.split(';').next()?;
+            let property = property.rsplit('.').next()?;
+
+            match property.eq_ignore_ascii_case("EMAIL") {
+                true => Some(address::normalize_address(value)),
+                false => None
+            }
+        })
+        .filter(|address| address.contains('@'))
+        .collect()
+}
+
+/// Return the addresses in a mutt alias file or plain list: every
+/// word containing an `@`, with any angle brackets, quotes and commas
+/// removed.
+fn parse_address_list(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .map(|word| address::normalize_address(word.trim_matches(|c| matches!(c, '<' | '>' | '"' | '\''))))
+        .filter(|word| word.contains('@'))
+        .collect()
+}
@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+//
+// Aliases file
+//
+// A file in the style of /etc/aliases, mapping names to lists of
+// addresses:
+//
+//     # comment
+//     postmaster: root
+//     team: alice@example.com, bob,
+//         carol@example.org
+//
+// Lines starting with whitespace continue the previous entry. Names
+// can be bare local parts, which apply at any domain, or full
+// addresses. Targets without a domain stay at the recipient's domain.
+// Pipe (`|command`), file (`/path`) and `:include:` targets can't be
+// delivered to a Maildir, and are ignored with a warning when the alias
+// is used; an alias with no other targets is an error, rather than
+// mail that silently goes nowhere.
+//
+
+/// How deep aliases may refer to other aliases before expansion stops,
+/// in case of a loop.
+const MAX_ALIAS_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub struct Aliases {
+    /// Lowercased alias name to targets, including those that are
+    /// ignored
+    aliases: HashMap<String, Vec<String>>
+}
+
+impl Aliases {
+    pub fn from_file(aliases_file: &Path) -> Result<Aliases> {
+        let contents = std::fs::read_to_string(aliases_file)
+            .with_context(|| format!("Error opening aliases file {}", aliases_file.display()))?;
+
+        // Join continuation lines onto the entries they continue
+        let mut entries: Vec<String> = Vec::new();
+        for line in contents.lines() {
+            if line.trim_start().starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            match (line.starts_with(char::is_whitespace), entries.last_mut()) {
+                (true, Some(entry)) => entry.push_str(line),
+                _ => entries.push(line.to_string())
+            }
+        }
+
+        let aliases = entries
+            .iter()
+            .map(|entry| {
+                let (name, targets) = entry
+                    .split_once(':')
+                    .with_context(|| format!("Invalid alias {entry:?} in {}, expected name: address, ...", aliases_file.display()))?;
+
+                let targets = targets
+                    .split(',')
+                    .map(|target| target.trim().trim_matches('"'))
+                    .filter(|target| !target.is_empty())
+                    .map(str::to_string)
+                    .collect();
+
+                Ok((name.trim().to_lowercase(), targets))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Aliases {
+            aliases
+        })
+    }
+
+    pub fn is_alias(&self, address: &str) -> bool {
+        self.lookup(&address.to_lowercase()).is_some()
+    }
+
+    /// Return the addresses that `address` finally expands to,
+    /// following aliases of aliases. An address that isn't an alias
+    /// expands to itself, as does an alias that lists itself as a
+    /// target (`root: root, admin`). Fails if an alias has no targets
+    /// that can be delivered to.
+    pub fn expand(&self, address: &str) -> Result<Vec<String>> {
+        let mut expanded = Vec::new();
+        self.expand_into(address, 0, &mut HashSet::new(), &mut expanded)?;
+        Ok(expanded)
+    }
+
+    fn expand_into(&self, address: &str, depth: usize, seen: &mut HashSet<String>, expanded: &mut Vec<String>) -> Result<()> {
+        let lowercase_address = address.to_lowercase();
+
+        let targets = match depth < MAX_ALIAS_DEPTH && seen.insert(lowercase_address.clone()) {
+            true => self.lookup(&lowercase_address),
+            false => None
+        };
+
+        let Some(targets) = targets else {
+            if !expanded.iter().any(|done| done.eq_ignore_ascii_case(address)) {
+                expanded.push(address.to_string());
+            }
+            return Ok(());
+        };
+
+        let (ignored, targets): (Vec<_>, Vec<_>) = targets
+            .iter()
+            .partition(|target| target.starts_with(['|', '/', ':']));
+
+        for target in &ignored {
+            eprintln!("Warning: ignoring target {target:?} of alias {address}, which can't be delivered to a Maildir");
+        }
+
+        if targets.is_empty() {
+            bail!("Alias {address} has no targets that can be delivered to a Maildir");
+        }
+
+        let domain = address.rsplit_once('@').map(|(_, domain)| domain);
+
+        for target in targets {
+            let target = match (target.contains('@'), domain) {
+                (false, Some(domain)) => format!("{target}@{domain}"),
+                _ => target.clone()
+            };
+
+            self.expand_into(&target, depth + 1, seen, expanded)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the targets for a lowercased address, listed either under
+    /// the full address or under its local part.
+    fn lookup(&self, address: &str) -> Option<&Vec<String>> {
+        self.aliases.get(address).or_else(|| {
+            let (local_part, _) = address.rsplit_once('@')?;
+            self.aliases.get(local_part)
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::compress;
+use crate::dates::{self, FolderDate};
+use crate::folders::Namespace;
+use crate::message;
+use crate::store::{self, StoreOptions};
+
+//
+// Archiving
+//
+// `sortmail archive`, run from cron, moves the messages in the global
+// `archive_folders` that are older than `archive_after` into archive
+// folders named by the `archive_folder` template, `Archive.%Y.%m` unless
+// it says otherwise, with the date placeholders filled in from each
+// message's date as they would be on delivery (see the `dates` module
+// and `folder_date`). Archive folders are created as they're needed.
+//
+// A message is moved the way delivery stores a copy that's already on
+// disk: it's hardlinked into the archive folder under a new name, with
+// its flags, or copied through `tmp` if the folders are on different
+// filesystems, and only removed from its old folder once it's safely in
+// the new one. It stays as it is on disk, compressed or not, and keeps
+// the `,S=` and `,W=` sizes in its old name.
+//
+
+/// How `sortmail archive` archives messages, from the global options.
+#[derive(Debug)]
+pub struct Archive {
+    /// Folder template, with date placeholders, that messages go to
+    pub folder: String,
+    pub after: Duration,
+    pub folder_date: FolderDate
+}
+
+impl Archive {
+    /// Archive the messages in the Maildir folder at `folder_path` that
+    /// are old enough, into folders of the root Maildir created with
+    /// `options`, and return each message archived with the folder it
+    /// went to, without moving anything if `dry_run`.
+    pub fn archive_folder(&self, folder_path: &Path, root_maildir: &Path, namespace: &Namespace, options: &StoreOptions, dry_run: bool) -> Result<Vec<(PathBuf, String)>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        let cutoff = now.saturating_sub(self.after.as_secs() as i64);
+
+        let mut archived = Vec::new();
+
+        for subdir in ["cur", "new"] {
+            let subdir_path = folder_path.join(subdir);
+
+            let entries = match std::fs::read_dir(&subdir_path) {
+                Ok(entries) => entries,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| format!("Error reading {}", subdir_path.display()))
+            };
+
+            for entry in entries {
+                let entry = entry.with_context(|| format!("Error reading {}", subdir_path.display()))?;
+                let path = entry.path();
+
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if !metadata.is_file() {
+                    continue;
+                }
+
+                let delivery_time = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(now, |since_epoch| since_epoch.as_secs() as i64);
+
+                let time = match self.folder_date {
+                    FolderDate::Header => {
+                        // A message that's gone by the time it's read was
+                        // moved by a mail reader
+                        let Ok(bytes) = compress::read_message(&path) else {
+                            continue;
+                        };
+                        header_date(&bytes).unwrap_or(delivery_time)
+                    },
+                    FolderDate::Delivery => delivery_time
+                };
+
+                if time > cutoff {
+                    continue;
+                }
+
+                let mailbox_name = dates::expand(&self.folder, time);
+                let archive_path = namespace.folder_path(root_maildir, &mailbox_name);
+
+                if archive_path == folder_path {
+                    continue;
+                }
+
+                if !dry_run {
+                    let file_name = entry.file_name();
+                    let flags = match subdir {
+                        "cur" => Some(file_name.to_str().and_then(|name| name.split_once(":2,")).map_or("", |(_, flags)| flags)),
+                        _ => None
+                    };
+
+                    store::create_folder(&archive_path, options)?;
+                    store::store_file(&path, &archive_path, flags, options)
+                        .with_context(|| format!("Error archiving {} to {mailbox_name}", path.display()))?;
+
+                    match std::fs::remove_file(&path) {
+                        Ok(()) => {},
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {},
+                        Err(error) => return Err(error).with_context(|| format!("Error removing {} after archiving it", path.display()))
+                    }
+                }
+                archived.push((path, mailbox_name));
+            }
+        }
+
+        archived.sort();
+        Ok(archived)
+    }
+}
+
+/// Return the time in the `Date:` header of the message `bytes`, if it
+/// has a valid one.
+fn header_date(bytes: &[u8]) -> Option<i64> {
+    let (fields, _) = message::split_header_fields(bytes);
+
+    fields
+        .into_iter()
+        .find(|field| message::header_field_name(field).as_deref() == Some("date"))
+        .and_then(|field| mailparse::parse_header(field).ok())
+        .and_then(|(header, _)| mailparse::dateparse(&header.get_value()).ok())
+}
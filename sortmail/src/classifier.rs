@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::folders::{self, Namespace};
+use crate::message::IncomingMessage;
+
+//
+// Naive Bayes classifier
+//
+// A model of which folder a message belongs in, trained from the
+// messages already sorted into the Maildir's folders. Each message is
+// reduced to a set of tokens (words from the subject and body, plus
+// its senders, recipients and list), and a multinomial naive Bayes
+// model with add-one smoothing picks the most likely folder.
+//
+// The model is saved as a text file with a line per folder and a line
+// per (folder, token) pair:
+//
+//     messages <TAB> folder <TAB> count
+//     token <TAB> folder <TAB> token <TAB> count
+//
+
+/// Only this many messages are read from each folder when training,
+/// so that huge archive folders don't take forever.
+const MAX_TRAINING_MESSAGES_PER_FOLDER: usize = 5000;
+
+/// Words shorter or longer than these are ignored.
+const MIN_WORD_LENGTH: usize = 3;
+const MAX_WORD_LENGTH: usize = 30;
+
+/// A trained model.
+#[derive(Debug, Default)]
+pub struct Classifier {
+    folders: HashMap<String, FolderCounts>,
+
+    /// Every token seen in training, for smoothing
+    vocabulary: HashSet<String>
+}
+
+#[derive(Debug, Default)]
+struct FolderCounts {
+    /// The number of training messages in the folder
+    message_count: u64,
+
+    /// The number of training messages in the folder with each token
+    token_counts: HashMap<String, u64>,
+
+    /// The sum of `token_counts`
+    total_tokens: u64
+}
+
+/// The classifier's verdict for a message.
+#[derive(Debug)]
+pub struct Guess {
+    pub mailbox_name: String,
+
+    /// The model's probability that the message belongs in the folder
+    pub probability: f64
+}
+
+impl Classifier {
+    /// Train a new model from the messages in every folder of the
+    /// Maildir at `root_maildir`, except those named in
+    /// `ignore_folders` (and their subfolders).
+    pub fn train(root_maildir: &Path, namespace: &Namespace, ignore_folders: &[String]) -> Result<Classifier> {
+        let mut classifier = Classifier::default();
+
+        for (mailbox_name, folder_path) in folders::list(root_maildir, namespace, ignore_folders)? {
+            for message in folders::messages(&folder_path, MAX_TRAINING_MESSAGES_PER_FOLDER) {
+                classifier.add_message(&mailbox_name, &message);
+            }
+        }
+
+        Ok(classifier)
+    }
+
+    fn add_message(&mut self, mailbox_name: &str, message: &IncomingMessage) {
+        let folder = self.folders.entry(mailbox_name.to_string()).or_default();
+        folder.message_count += 1;
+
+        for token in tokenize(message) {
+            *folder.token_counts.entry(token.clone()).or_default() += 1;
+            folder.total_tokens += 1;
+            self.vocabulary.insert(token);
+        }
+    }
+
+    /// Return the number of training messages in each folder, largest
+    /// first.
+    pub fn folder_message_counts(&self) -> Vec<(&str, u64)> {
+        let mut counts: Vec<_> = self.folders
+            .iter()
+            .map(|(mailbox_name, folder)| (mailbox_name.as_str(), folder.message_count))
+            .collect();
+
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Return every folder's probability for the message, most likely
+    /// first, or an empty list if the model is empty.
+    pub fn classify(&self, message: &IncomingMessage) -> Vec<Guess> {
+        let total_messages: u64 = self.folders.values().map(|folder| folder.message_count).sum();
+        if total_messages == 0 {
+            return Vec::new();
+        }
+
+        let tokens = tokenize(message);
+        let vocabulary_size = self.vocabulary.len() as f64;
+
+        let log_likelihoods: Vec<(&String, f64)> = self.folders
+            .iter()
+            .map(|(mailbox_name, folder)| {
+                let prior = (folder.message_count as f64 / total_messages as f64).ln();
+                let denominator = folder.total_tokens as f64 + vocabulary_size;
+
+                let likelihood: f64 = tokens
+                    .iter()
+                    .filter(|token| self.vocabulary.contains(*token))
+                    .map(|token| {
+                        let count = folder.token_counts.get(token).copied().unwrap_or_default();
+                        ((count as f64 + 1.0) / denominator).ln()
+                    })
+                    .sum();
+
+                (mailbox_name, prior + likelihood)
+            })
+            .collect();
+
+        // Turn the log-likelihoods into probabilities without
+        // underflowing
+        let max_log_likelihood = log_likelihoods
+            .iter()
+            .map(|(_, log_likelihood)| *log_likelihood)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let total: f64 = log_likelihoods
+            .iter()
+            .map(|(_, log_likelihood)| (log_likelihood - max_log_likelihood).exp())
+            .sum();
+
+        let mut guesses: Vec<_> = log_likelihoods
+            .into_iter()
+            .map(|(mailbox_name, log_likelihood)| Guess {
+                mailbox_name: mailbox_name.clone(),
+                probability: (log_likelihood - max_log_likelihood).exp() / total
+            })
+            .collect();
+
+        guesses.sort_by(|a, b| b.probability.total_cmp(&a.probability).then(a.mailbox_name.cmp(&b.mailbox_name)));
+        guesses
+    }
+
+    /// Load a model saved by `save`.
+    pub fn load(path: &Path) -> Result<Classifier> {
+        let file = File::open(path)
+            .with_context(|| format!("Error opening classifier model {}", path.display()))?;
+
+        let mut classifier = Classifier::default();
+
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Error reading classifier model {}", path.display()))?;
+            let fields: Vec<_> = line.split('\t').collect();
+
+            let parse_count = |count: &str| count
+                .parse::<u64>()
+                .with_context(|| format!("Invalid count in classifier model {}: {line:?}", path.display()));
+
+            match fields.as_slice() {
+                ["messages", mailbox_name, count] => {
+                    classifier.folders.entry(mailbox_name.to_string()).or_default().message_count = parse_count(count)?;
+                },
+                ["token", mailbox_name, token, count] => {
+                    let count = parse_count(count)?;
+                    let folder = classifier.folders.entry(mailbox_name.to_string()).or_default();
+                    folder.token_counts.insert(token.to_string(), count);
+                    folder.total_tokens += count;
+                    classifier.vocabulary.insert(token.to_string());
+                },
+                _ => {}
+            }
+        }
+
+        Ok(classifier)
+    }
+
+    /// Save the model to `path`, replacing it atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("tmp");
+
+        let file = File::create(&temp_path)
+            .with_context(|| format!("Error creating classifier model {}", temp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+
+        let mut mailbox_names: Vec<_> = self.folders.keys().collect();
+        mailbox_names.sort();
+
+        for mailbox_name in mailbox_names {
+            let folder = &self.folders[mailbox_name];
+            writeln!(writer, "messages\t{mailbox_name}\t{}", folder.message_count)
+                .with_context(|| format!("Error writing classifier model {}", temp_path.display()))?;
+
+            let mut tokens: Vec<_> = folder.token_counts.iter().collect();
+            tokens.sort();
+
+            for (token, count) in tokens {
+                writeln!(writer, "token\t{mailbox_name}\t{token}\t{count}")
+                    .with_context(|| format!("Error writing classifier model {}", temp_path.display()))?;
+            }
+        }
+
+        writer.flush()
+            .and_then(|_| writer.get_ref().sync_all())
+            .with_context(|| format!("Error writing classifier model {}", temp_path.display()))?;
+
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("Error saving classifier model {}", path.display()))
+    }
+}
+
+/// Return the distinct tokens of a message: lowercased words from the
+/// subject (prefixed `subject:`) and body, and the addresses in the
+/// `From:`, `To:` and `Cc:` headers, the sender's domain and the
+/// mailing list identifier, each prefixed with where it came from.
+fn tokenize(message: &IncomingMessage) -> HashSet<String> {
+    let mut tokens = HashSet::new();
+
+    for subject in message.header_values("Subject") {
+        tokens.extend(words(subject).map(|word| format!("subject:{word}")));
+    }
+
+    tokens.extend(words(message.body_text()));
+
+    for address in message.header_addresses("From") {
+        if let Some((_, domain)) = address.rsplit_once('@') {
+            tokens.insert(format!("from-domain:{domain}"));
+        }
+        tokens.insert(format!("from:{address}"));
+    }
+
+    for header_name in ["To", "Cc"] {
+        tokens.extend(message.header_addresses(header_name).into_iter().map(|address| format!("to:{address}")));
+    }
+
+    if let Some(list_identifier) = message.mailing_list_identifier() {
+        tokens.insert(format!("list:{list_identifier}"));
+    }
+
+    // Tabs and newlines would break the model file's format
+    tokens.retain(|token| !token.contains(['\t', '\n', '\r']));
+    tokens
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (MIN_WORD_LENGTH..=MAX_WORD_LENGTH).contains(&word.chars().count()))
+        .filter(|word| !word.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_lowercase)
+}
@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+//
+// Stale tmp/ cleanup
+//
+// Each message is written into its folder's `tmp` directory and then
+// moved into `new` or `cur`, so a delivery that's interrupted (killed,
+// or out of disk space) leaves its file behind in `tmp`, where nothing
+// else will ever remove it. As the Maildir spec suggests, a file in
+// `tmp` that hasn't been touched for 36 hours is removed: no delivery
+// takes that long.
+//
+// A folder's `tmp` is cleaned whenever a message is stored in the
+// folder, and `sortmail clean` cleans every folder in the root Maildir,
+// for folders that rarely get mail.
+//
+
+/// Files in `tmp` untouched for this long are left over from
+/// interrupted deliveries.
+const STALE_TMP_AGE: Duration = Duration::from_secs(36 * 60 * 60);
+
+/// Return the files in the `tmp` directory of the Maildir folder at
+/// `folder_path` that are old enough to be left over from interrupted
+/// deliveries, removing them unless `dry_run`.
+pub fn clean_tmp(folder_path: &Path, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let tmp_path = folder_path.join("tmp");
+
+    let entries = match std::fs::read_dir(&tmp_path) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).with_context(|| format!("Error reading {}", tmp_path.display()))
+    };
+
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Error reading {}", tmp_path.display()))?;
+
+        // Deliveries only ever write plain files here
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let last_touched = [metadata.modified(), metadata.accessed()]
+            .into_iter()
+            .filter_map(Result::ok)
+            .max();
+
+        let is_stale = last_touched
+            .and_then(|last_touched| now.duration_since(last_touched).ok())
+            .is_some_and(|age| age >= STALE_TMP_AGE);
+
+        if !is_stale {
+            continue;
+        }
+
+        let path = entry.path();
+        if !dry_run {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {},
+                // Another cleanup got to it first
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| format!("Error removing {}", path.display()))
+            }
+        }
+        removed.push(path);
+    }
+
+    removed.sort();
+    Ok(removed)
+}
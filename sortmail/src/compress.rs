@@ -0,0 +1,132 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+
+//
+// Compression
+//
+// Messages in a mailbox with `compress` are stored compressed, in the
+// formats Dovecot's zlib plugin (`mail_compress` in newer versions)
+// reads: it spots compressed files by their magic bytes, so compressed
+// and uncompressed messages can share a folder. The `,S=` size in the
+// filename stays the uncompressed size, which is what Dovecot expects,
+// and what quotas count.
+//
+// Small messages barely shrink, so messages under the mailbox's
+// `compress_min_size` are stored as they are.
+//
+// Anything that reads stored messages back (training, suggestions,
+// archiving, spam learning) goes through `read_message`, or
+// `open_message` to read just the header, which spot compressed files
+// the same way Dovecot does.
+//
+
+/// Compression format, from a mailbox's `compress`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    Gzip,
+    Zstd
+}
+
+/// How a mailbox's messages are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    pub format: CompressionFormat,
+
+    /// Store messages smaller than this uncompressed
+    pub min_size: u64
+}
+
+/// zstd's default level, a good balance of speed and size.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The magic bytes that compressed message files start with.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Return `bytes` compressed as `compression` says, or as they are if
+/// they're too small to bother with.
+pub fn compress<'a>(bytes: &'a [u8], compression: Option<&Compression>) -> Result<Cow<'a, [u8]>> {
+    let Some(compression) = compression.filter(|compression| bytes.len() as u64 >= compression.min_size) else {
+        return Ok(Cow::Borrowed(bytes));
+    };
+
+    let compressed = match compression.format {
+        CompressionFormat::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(bytes).and_then(|_| encoder.finish())
+        },
+        CompressionFormat::Zstd => zstd::encode_all(bytes, ZSTD_LEVEL)
+    };
+
+    compressed
+        .map(Cow::Owned)
+        .with_context(|| format!("Error compressing message with {:?}", compression.format))
+}
+
+/// Return `bytes`, a stored message file's contents, decompressed if
+/// they start with the magic bytes of a format `compress` writes, or as
+/// they are otherwise.
+pub fn decompress(bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let format = match bytes {
+        _ if bytes.starts_with(GZIP_MAGIC) => CompressionFormat::Gzip,
+        _ if bytes.starts_with(ZSTD_MAGIC) => CompressionFormat::Zstd,
+        _ => return Ok(Cow::Borrowed(bytes))
+    };
+
+    let decompressed = match format {
+        CompressionFormat::Gzip => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes).read_to_end(&mut decompressed).map(|_| decompressed)
+        },
+        CompressionFormat::Zstd => zstd::decode_all(bytes)
+    };
+
+    decompressed
+        .map(Cow::Owned)
+        .with_context(|| format!("Error decompressing {format:?} message"))
+}
+
+/// Read the message stored at `path`, decompressing it if it was
+/// stored compressed.
+pub fn read_message(path: &Path) -> Result<Vec<u8>> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Error reading {}", path.display()))?;
+
+    let decompressed = match decompress(&bytes).with_context(|| format!("Error reading {}", path.display()))? {
+        Cow::Owned(decompressed) => Some(decompressed),
+        Cow::Borrowed(_) => None
+    };
+
+    Ok(decompressed.unwrap_or(bytes))
+}
+
+/// Open the message stored at `path` for reading, decompressing it as
+/// it's read if it was stored compressed, for when only the start of it
+/// is needed.
+pub fn open_message(path: &Path) -> Result<Box<dyn Read>> {
+    let mut file = File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Error opening {}", path.display()))?;
+
+    let start = file.fill_buf()
+        .with_context(|| format!("Error reading {}", path.display()))?;
+
+    let reader: Box<dyn Read> = match start {
+        _ if start.starts_with(GZIP_MAGIC) => Box::new(GzDecoder::new(file)),
+        _ if start.starts_with(ZSTD_MAGIC) => Box::new(
+            zstd::stream::read::Decoder::with_buffer(file)
+                .with_context(|| format!("Error reading {}", path.display()))?
+        ),
+        _ => Box::new(file)
+    };
+
+    Ok(reader)
+}
@@ -0,0 +1,108 @@
+use std::ffi::CString;
+
+use serde::Deserialize;
+
+use crate::message::IncomingMessage;
+
+//
+// Date placeholders in folder names
+//
+// Folder names can contain strftime(3) conversions, like
+// `Archive.%Y.%m`, which are filled in from the message's date in local
+// time, so that archives roll over to a new folder each month or year
+// by themselves. Each conversion's value is sanitized like any other
+// value put into a folder name, so `%F` gives `2024-05-01` but `%D`
+// can't add hierarchy levels with its slashes; `%%` is a literal `%`.
+//
+
+/// Which date fills in folder names' date placeholders.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FolderDate {
+    /// The message's `Date:` header, or the delivery time if it doesn't
+    /// have a valid one
+    #[default]
+    Header,
+
+    /// The delivery time
+    Delivery
+}
+
+/// Return the time, in seconds since the epoch, that `folder_date` says
+/// to use for `message`.
+pub fn message_time(message: &IncomingMessage, folder_date: FolderDate) -> i64 {
+    let now = || unsafe { libc::time(std::ptr::null_mut()) };
+
+    match folder_date {
+        FolderDate::Header => message
+            .header_values("Date")
+            .next()
+            .and_then(|date| mailparse::dateparse(date).ok())
+            .unwrap_or_else(now),
+        FolderDate::Delivery => now()
+    }
+}
+
+/// Return true if `mailbox_name` has date placeholders to fill in.
+pub fn has_placeholders(mailbox_name: &str) -> bool {
+    mailbox_name.contains('%')
+}
+
+/// Fill in the date placeholders in `mailbox_name` with the local time
+/// `time`.
+pub fn expand(mailbox_name: &str, time: i64) -> String {
+    let tm = local_tm(time);
+
+    let mut expanded = String::new();
+    let mut chars = mailbox_name.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') | None => expanded.push('%'),
+            Some(conversion) => expanded.push_str(&crate::sanitize_folder_name_component(&format_tm(&tm, &format!("%{conversion}"))))
+        }
+    }
+
+    expanded
+}
+
+/// Return the local time `time` as an RFC 5322 date, like `Tue, 01 Jul
+/// 2003 10:52:37 +0200`, for the `Date:` header of messages we send.
+pub fn rfc5322_date(time: i64) -> String {
+    format_tm(&local_tm(time), "%a, %d %b %Y %H:%M:%S %z")
+}
+
+/// Return `time` in UTC, formatted with the strftime `format`.
+pub fn format_utc(time: i64, format: &str) -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = time as libc::time_t;
+    unsafe { libc::gmtime_r(&time, &mut tm) };
+    format_tm(&tm, format)
+}
+
+/// Break down `time`, in seconds since the epoch, in local time.
+fn local_tm(time: i64) -> libc::tm {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = time as libc::time_t;
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    tm
+}
+
+/// Format `tm` with the strftime `format`.
+fn format_tm(tm: &libc::tm, format: &str) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+
+    let mut buffer = [0u8; 64];
+    let length = unsafe {
+        libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), format.as_ptr(), tm)
+    };
+
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
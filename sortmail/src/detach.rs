@@ -0,0 +1,207 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use mailparse::{DispositionType, ParsedMail};
+use sha2::{Digest, Sha256};
+
+use crate::store::{self, StoreOptions};
+
+//
+// Attachment detaching
+//
+// A mailbox with `detach_attachments` saves each attachment of at least
+// `detach_min_size` (1M unless it says otherwise) as a file in that
+// directory, and stores the message with the attachment replaced by a
+// short text part saying where the file went: its path, or a URL under
+// `detach_url` for a directory that a web server shares. Smaller
+// attachments stay in the message as they are, so the Maildir keeps
+// what's cheap to keep and loses the big files that make up most of
+// its size.
+//
+// Files are named after a hash of their contents (`<hash>-report.pdf`),
+// so an attachment that arrives again, or in several folders, is only
+// saved once. Signed and encrypted messages are stored as they are,
+// since changing them would break the signature or can't be done.
+//
+
+/// Attachments at least this big are detached, unless the mailbox sets
+/// `detach_min_size`.
+pub const DEFAULT_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Where a mailbox's attachments are detached to.
+#[derive(Clone, Debug)]
+pub struct Detach {
+    pub directory: PathBuf,
+    pub min_size: u64,
+
+    /// URL that `directory` is shared at, for the text left in the
+    /// message
+    pub url: Option<String>
+}
+
+impl Detach {
+    /// Save the big attachments of the message in `bytes` (whose
+    /// headers start at `header_start`) to the directory, and return
+    /// the message with each replaced by a text part, or None if there
+    /// was nothing to detach. Files and the directory are created with
+    /// the permissions `options` give.
+    pub fn detach(&self, bytes: &[u8], header_start: usize, options: &StoreOptions) -> Result<Option<Vec<u8>>> {
+        let message = &bytes[header_start..];
+        let Ok(parsed) = mailparse::parse_mail(message) else {
+            return Ok(None);
+        };
+
+        // A single-part message is never an attachment here, since
+        // replacing it would leave nothing of the message
+        let mut parts = Vec::new();
+        if !parsed.subparts.is_empty() {
+            self.collect_detachable(&parsed, &mut parts);
+        }
+
+        if parts.is_empty() {
+            return Ok(None);
+        }
+
+        store::create_dir_all(&self.directory, options.directory_mode)?;
+
+        let mut detached = bytes[..header_start].to_vec();
+        let mut copied_up_to = 0;
+
+        for part in parts {
+            let replacement = self.save(part, options)?;
+            let start = part.raw_bytes.as_ptr() as usize - message.as_ptr() as usize;
+
+            detached.extend_from_slice(&message[copied_up_to..start]);
+            detached.extend_from_slice(&replacement);
+            copied_up_to = start + part.raw_bytes.len();
+        }
+        detached.extend_from_slice(&message[copied_up_to..]);
+
+        Ok(Some(detached))
+    }
+
+    /// Add the leaf parts under `part` that are attachments big enough
+    /// to detach, in order.
+    fn collect_detachable<'a>(&self, part: &'a ParsedMail<'a>, parts: &mut Vec<&'a ParsedMail<'a>>) {
+        let mimetype = part.ctype.mimetype.to_lowercase();
+        if mimetype == "multipart/signed" || mimetype == "multipart/encrypted" {
+            return;
+        }
+
+        if !part.subparts.is_empty() {
+            for subpart in &part.subparts {
+                self.collect_detachable(subpart, parts);
+            }
+            return;
+        }
+
+        let disposition = part.get_content_disposition();
+        let is_attachment = disposition.disposition == DispositionType::Attachment
+            || disposition.params.contains_key("filename")
+            || part.ctype.params.contains_key("name");
+
+        let is_big_enough = part.get_body_raw().is_ok_and(|body| body.len() as u64 >= self.min_size);
+
+        if is_attachment && is_big_enough {
+            parts.push(part);
+        }
+    }
+
+    /// Save the attachment `part` and return the text part that takes
+    /// its place.
+    fn save(&self, part: &ParsedMail, options: &StoreOptions) -> Result<Vec<u8>> {
+        let body = part.get_body_raw().context("Error decoding attachment")?;
+
+        let filename = part.get_content_disposition().params
+            .get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .cloned()
+            .unwrap_or_default();
+
+        let hash: String = Sha256::digest(&body)
+            .iter()
+            .take(8)
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let file_name = format!("{hash}-{}", sanitize_file_name(&filename));
+        let path = self.directory.join(&file_name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => store::set_file_mode(&file, options.file_mode)
+                .and_then(|_| file.write_all(&body))
+                .and_then(|_| file.sync_all())
+                .with_context(|| format!("Error writing attachment {}", path.display()))?,
+            // The same attachment was saved before
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {},
+            Err(error) => return Err(error).with_context(|| format!("Error creating {}", path.display()))
+        }
+
+        let location = match self.url {
+            Some(ref url) => format!("{}/{}", url.trim_end_matches('/'), url_encode(&file_name)),
+            None => path.display().to_string()
+        };
+
+        let newline = match part.raw_bytes.windows(2).any(|pair| pair == b"\r\n") {
+            true => "\r\n",
+            false => "\n"
+        };
+
+        let description = match filename.is_empty() {
+            true => "An attachment".to_string(),
+            false => format!("The attachment \"{filename}\"")
+        };
+
+        let text = [
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Disposition: inline".to_string(),
+            "Content-Transfer-Encoding: 8bit".to_string(),
+            format!("X-Sortmail-Detached: {}", location.replace(['\r', '\n'], "")),
+            String::new(),
+            format!("{description} ({}, {}) was detached from this message, and saved as:", part.ctype.mimetype, format_size(body.len() as u64)),
+            location,
+            String::new()
+        ];
+
+        Ok(text.join(newline).into_bytes())
+    }
+}
+
+/// Return `filename` as it can be used in the attachment directory:
+/// without directories, control characters or a leading dot, and not
+/// too long.
+fn sanitize_file_name(filename: &str) -> String {
+    let base_name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let sanitized: String = base_name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .take(100)
+        .collect();
+    let sanitized = sanitized.trim().trim_start_matches('.');
+
+    match sanitized.is_empty() {
+        true => "attachment".to_string(),
+        false => sanitized.to_string()
+    }
+}
+
+/// Percent-encode `file_name` for a URL.
+fn url_encode(file_name: &str) -> String {
+    file_name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}")
+        })
+        .collect()
+}
+
+/// Return `size` in bytes as a short human-readable size, like `2.3M`.
+fn format_size(size: u64) -> String {
+    match size {
+        0..1024 => format!("{size} bytes"),
+        1024..1048576 => format!("{:.1}K", size as f64 / 1024.0),
+        _ => format!("{:.1}M", size as f64 / 1048576.0)
+    }
+}
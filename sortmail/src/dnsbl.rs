@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// DNS blocklists
+//
+// An address is listed in a DNSBL zone if the name made of its
+// reversed octets (or, for IPv6, nibbles) under the zone resolves,
+// e.g. 192.0.2.1 is looked up in zen.spamhaus.org as
+// 1.2.0.192.zen.spamhaus.org. Listed addresses resolve to return codes
+// in 127.0.0.0/8.
+//
+
+/// Return the DNSBL `zones` that list `ip`.
+///
+/// The zones are queried in parallel on background threads, using the
+/// system resolver. Zones that haven't answered within `timeout` are
+/// treated as not listing the address, so a slow or unreachable
+/// resolver can hold up delivery by at most `timeout`; the abandoned
+/// lookups are left to finish (or not) on their own.
+pub fn listing_zones(ip: IpAddr, zones: &[String], timeout: Duration) -> HashSet<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    for zone in zones {
+        let query = format!("{}.{}", reversed_labels(ip), zone.trim_end_matches('.'));
+        let zone = zone.clone();
+        let sender = sender.clone();
+
+        thread::spawn(move || {
+            // The receiver is gone if we already gave up waiting
+            let _ = sender.send((zone, query_is_listed(&query)));
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut listing_zones = HashSet::new();
+
+    for _ in zones {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((zone, true)) => {
+                listing_zones.insert(zone);
+            },
+            Ok((_, false)) => continue,
+            Err(_) => break
+        }
+    }
+
+    listing_zones
+}
+
+/// Resolve `query` and return true if it yields a listing return code.
+/// NXDOMAIN and lookup errors mean the address isn't listed.
+fn query_is_listed(query: &str) -> bool {
+    let addrs = match (query, 0).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return false
+    };
+
+    // 127.255.255.0/24 is used by some lists (e.g. Spamhaus) to report
+    // errors such as queries from a blocked public resolver, rather
+    // than a listing
+    addrs.into_iter().any(|addr| match addr.ip() {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            octets[0] == 127 && octets[..3] != [127, 255, 255]
+        },
+        IpAddr::V6(_) => false
+    })
+}
+
+/// Return the DNSBL query labels for `ip`, like `1.2.0.192` for
+/// `192.0.2.1`, or 32 dot-separated nibbles for an IPv6 address.
+fn reversed_labels(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4
+            .octets()
+            .iter()
+            .rev()
+            .map(|octet| octet.to_string())
+            .collect::<Vec<_>>()
+            .join("."),
+        IpAddr::V6(v6) => v6
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0xf, byte >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use crate::message::{header_field_name, split_header_fields};
+use crate::process::wait_with_timeout;
+
+//
+// Encryption at rest
+//
+// With the global `encrypt_to`, each message is encrypted to that public
+// key before it's stored, by running `age` (for age recipients, which
+// start with `age1`, or SSH keys) or `gpg` (for anything else, which
+// names an OpenPGP key in the keyring). The whole original message,
+// headers and all, is encrypted, and wrapped in a new message with
+// copies of the original's headers, so that mail clients can still list
+// and thread it:
+//
+// - OpenPGP messages are PGP/MIME (RFC 3156) `multipart/encrypted`
+//   messages, which mail clients that do OpenPGP decrypt and show as
+//   the original message.
+// - age messages have the encrypted message as an armored
+//   `message.eml.age` attachment, for `age --decrypt`.
+//
+// Messages that arrive already encrypted are stored as they are.
+//
+
+/// A public key that messages are encrypted to.
+#[derive(Clone, Debug)]
+pub enum EncryptionKey {
+    Age(String),
+    OpenPgp(String)
+}
+
+impl EncryptionKey {
+    /// Parse the global `encrypt_to`.
+    pub fn parse(value: &str) -> EncryptionKey {
+        match value.starts_with("age1") || value.starts_with("ssh-") {
+            true => EncryptionKey::Age(value.to_string()),
+            false => EncryptionKey::OpenPgp(value.to_string())
+        }
+    }
+
+    /// Return the command that encrypts its stdin to this key, as
+    /// ASCII armor on its stdout.
+    fn command(&self) -> Command {
+        match self {
+            EncryptionKey::Age(recipient) => {
+                let mut command = Command::new("age");
+                command.args(["--encrypt", "--armor", "--recipient", recipient]);
+                command
+            },
+            EncryptionKey::OpenPgp(recipient) => {
+                let mut command = Command::new("gpg");
+                command.args(["--batch", "--no-tty", "--quiet", "--trust-model", "always", "--armor", "--encrypt", "--recipient", recipient]);
+                command
+            }
+        }
+    }
+}
+
+/// Return `bytes` encrypted to `key` and wrapped in a new message, as
+/// described above. Anything before `header_start`, like an mbox-style
+/// `From ` line, is kept as it is.
+pub fn encrypt_message(bytes: &[u8], header_start: usize, key: &EncryptionKey, timeout: Duration) -> Result<Vec<u8>> {
+    let (prefix, message) = bytes.split_at(header_start);
+    let newline = match message.iter().position(|&b| b == b'\n') {
+        Some(pos) if pos > 0 && message[pos - 1] == b'\r' => "\r\n",
+        _ => "\n"
+    };
+
+    let armored = run_with_timeout(key.command(), message, timeout)?;
+    let armored = String::from_utf8_lossy(&armored).replace("\r\n", "\n").replace('\n', newline);
+
+    let mut encrypted = prefix.to_vec();
+
+    // The MIME headers are replaced by the wrapper's own
+    let (fields, _) = split_header_fields(message);
+    for field in fields {
+        let is_mime_header = header_field_name(field)
+            .is_some_and(|name| name.starts_with("content-") || name == "mime-version");
+
+        if !is_mime_header {
+            encrypted.extend_from_slice(field);
+        }
+    }
+
+    let wrapper = match key {
+        EncryptionKey::OpenPgp(_) => {
+            let boundary = boundary();
+
+            vec![
+                "MIME-Version: 1.0".to_string(),
+                format!("Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=\"{boundary}\""),
+                String::new(),
+                "This is an OpenPGP/MIME encrypted message (RFC 3156)".to_string(),
+                format!("--{boundary}"),
+                "Content-Type: application/pgp-encrypted".to_string(),
+                "Content-Description: PGP/MIME version identification".to_string(),
+                String::new(),
+                "Version: 1".to_string(),
+                String::new(),
+                format!("--{boundary}"),
+                "Content-Type: application/octet-stream; name=\"encrypted.asc\"".to_string(),
+                "Content-Description: OpenPGP encrypted message".to_string(),
+                "Content-Disposition: inline; filename=\"encrypted.asc\"".to_string(),
+                String::new(),
+                armored.trim_end().to_string(),
+                format!("--{boundary}--"),
+                String::new()
+            ]
+        },
+        EncryptionKey::Age(_) => vec![
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: application/octet-stream; name=\"message.eml.age\"".to_string(),
+            "Content-Disposition: attachment; filename=\"message.eml.age\"".to_string(),
+            "Content-Description: age encrypted message".to_string(),
+            String::new(),
+            armored.trim_end().to_string(),
+            String::new()
+        ]
+    };
+
+    encrypted.extend_from_slice(wrapper.join(newline).as_bytes());
+    Ok(encrypted)
+}
+
+/// Return a MIME boundary that can't appear in ASCII armor.
+fn boundary() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("=_sortmail_{}_{}_{}", now.as_secs(), now.subsec_nanos(), std::process::id())
+}
+
+/// Run `command` with `input` on its stdin, and return what it prints.
+/// The command is killed if it runs longer than `timeout`.
+fn run_with_timeout(mut command: Command, input: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let program = command.get_program().to_string_lossy().into_owned();
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Error running {program} to encrypt the message"))?;
+
+    // Write the message and read the output on other threads, so that
+    // neither pipe filling up can block us forever
+    let mut stdin = child.stdin.take().context("Error writing to encryption command")?;
+    let input = input.to_vec();
+    thread::spawn(move || {
+        let _ = stdin.write_all(&input);
+    });
+
+    let mut stdout = child.stdout.take().context("Error reading encryption command output")?;
+    let output_reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let status = wait_with_timeout(&mut child, timeout)
+        .with_context(|| format!("Encrypting with {program} didn't finish"))?;
+
+    if !status.success() {
+        bail!("Encrypting with {program} failed ({status})");
+    }
+
+    let output = output_reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Error reading encryption command output"))?
+        .context("Error reading encryption command output")?;
+
+    if output.is_empty() {
+        bail!("Encrypting with {program} produced no output");
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::store::{self, StoreOptions};
+
+//
+// Message expiry
+//
+// A mailbox with `expire_after = "30d"` keeps its messages for that
+// long: `sortmail expire`, run from cron, removes older ones from the
+// mailbox's folder, or with `expire_to`, moves them into that folder
+// instead, so that Junk and notification folders don't grow without
+// bound. Moved messages are linked or copied into the other folder as
+// archiving does (see the `archive` module), so it can be on another
+// filesystem. A message's age is taken from its file's modification time,
+// which is when it was delivered, since changing its flags only renames
+// it.
+//
+
+/// Expire the messages in the `cur` and `new` directories of the
+/// Maildir folder at `folder_path` that are at least `max_age` old,
+/// moving them into the folder at `archive_path` if there is one (created
+/// with `options` if it doesn't exist), and removing them otherwise.
+/// Return the messages expired, without touching them if `dry_run`.
+pub fn expire_folder(folder_path: &Path, max_age: Duration, archive_path: Option<&Path>, options: &StoreOptions, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let now = SystemTime::now();
+    let mut expired = Vec::new();
+
+    for subdir in ["cur", "new"] {
+        let subdir_path = folder_path.join(subdir);
+
+        let entries = match std::fs::read_dir(&subdir_path) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| format!("Error reading {}", subdir_path.display()))
+        };
+
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Error reading {}", subdir_path.display()))?;
+
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let is_expired = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age >= max_age);
+
+            if !is_expired {
+                continue;
+            }
+
+            let path = entry.path();
+            if !dry_run {
+                if let Some(archive_path) = archive_path {
+                    let file_name = entry.file_name();
+                    let flags = match subdir {
+                        "cur" => Some(file_name.to_str().and_then(|name| name.split_once(":2,")).map_or("", |(_, flags)| flags)),
+                        _ => None
+                    };
+
+                    store::create_folder(archive_path, options)?;
+                    match store::store_file(&path, archive_path, flags, options) {
+                        Ok(_) => {},
+                        // A mail reader moved or removed it meanwhile
+                        Err(_) if !path.exists() => continue,
+                        Err(error) => return Err(error).with_context(|| format!("Error expiring {}", path.display()))
+                    }
+                }
+
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {},
+                    // A mail reader moved or removed it meanwhile (after
+                    // it was copied, if it's being moved)
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound && archive_path.is_none() => continue,
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {},
+                    Err(error) => return Err(error).with_context(|| format!("Error expiring {}", path.display()))
+                }
+            }
+            expired.push(path);
+        }
+    }
+
+    expired.sort();
+    Ok(expired)
+}
@@ -0,0 +1,428 @@
+use anyhow::{anyhow, bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+
+use crate::Envelope;
+use crate::message::IncomingMessage;
+
+//
+// Rule expressions
+//
+// A `when` expression combines tests on the message with `and`, `or`
+// and `not`, e.g.:
+//
+//     to ~ "@lists\." and not header("X-Spam-Flag") == "YES"
+//
+// Each test is an operand, optionally followed by an operator and a
+// value:
+//
+//     to, sender, from, list_id, subject, body, header("Name"),
+//     importance (high, normal or low), language (ISO 639-1 code)
+//         == "text"   equal (case-insensitively)
+//         != "text"   not equal
+//         ~ "regex"   matches the regular expression (case-insensitively)
+//         !~ "regex"  doesn't match
+//         (nothing)   is present and non-empty
+//
+//     size, spam_score, recipients (the number of To: and Cc: addresses)
+//         <, <=, >, >=, ==, != number
+//
+//     encrypted, calendar, automated, dsn, suspicious_from
+//         (nothing)   the message is encrypted / is a calendar message /
+//                     is machine-generated / is a bounce / has a From:
+//                     header that looks forged
+//
+// Operands such as `from` and `header("...")` can have several values;
+// `==` and `~` are true if any value matches, and `!=` and `!~` are
+// true if none do.
+//
+
+#[derive(Debug)]
+pub enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    TextTest(TextOperand, TextComparison),
+    NumberTest(NumberOperand, NumberComparison, f64),
+    FlagTest(FlagOperand)
+}
+
+#[derive(Debug)]
+pub enum TextOperand {
+    To,
+    Sender,
+    From,
+    ListId,
+    Subject,
+    Body,
+    Importance,
+    Language,
+    Header(String)
+}
+
+#[derive(Debug)]
+pub enum TextComparison {
+    Equals(String),
+    NotEquals(String),
+    Matches(Regex),
+    NotMatches(Regex),
+    Exists
+}
+
+#[derive(Debug)]
+pub enum NumberOperand {
+    Size,
+    SpamScore,
+    Recipients
+}
+
+#[derive(Debug)]
+pub enum FlagOperand {
+    Encrypted,
+    Calendar,
+    Automated,
+    Dsn,
+    SuspiciousFrom
+}
+
+#[derive(Debug)]
+pub enum NumberComparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual
+}
+
+impl Expression {
+    /// Parse a `when` expression.
+    pub fn parse(source: &str) -> Result<Expression> {
+        let tokens = tokenize(source)
+            .with_context(|| format!("Error parsing expression {source:?}"))?;
+
+        let mut parser = Parser { tokens, position: 0 };
+
+        let expression = parser.parse_or()
+            .and_then(|expression| match parser.peek() {
+                None => Ok(expression),
+                Some(token) => Err(anyhow!("Unexpected {token:?} after end of expression"))
+            })
+            .with_context(|| format!("Error parsing expression {source:?}"))?;
+
+        Ok(expression)
+    }
+
+    pub fn evaluate(&self, envelope: &Envelope, message: &IncomingMessage) -> bool {
+        match self {
+            Expression::And(left, right) => left.evaluate(envelope, message) && right.evaluate(envelope, message),
+            Expression::Or(left, right) => left.evaluate(envelope, message) || right.evaluate(envelope, message),
+            Expression::Not(inner) => !inner.evaluate(envelope, message),
+            Expression::TextTest(operand, comparison) => {
+                let values = operand.values(envelope, message);
+
+                match comparison {
+                    TextComparison::Equals(expected) => values.iter().any(|value| value.to_lowercase() == *expected),
+                    TextComparison::NotEquals(expected) => !values.iter().any(|value| value.to_lowercase() == *expected),
+                    TextComparison::Matches(re) => values.iter().any(|value| re.is_match(value)),
+                    TextComparison::NotMatches(re) => !values.iter().any(|value| re.is_match(value)),
+                    TextComparison::Exists => values.iter().any(|value| !value.is_empty())
+                }
+            },
+            Expression::NumberTest(operand, comparison, expected) => {
+                let value = match operand {
+                    NumberOperand::Size => Some(message.size() as f64),
+                    NumberOperand::SpamScore => message.spam_score(),
+                    NumberOperand::Recipients => Some(message.recipient_count() as f64)
+                };
+
+                value.is_some_and(|value| match comparison {
+                    NumberComparison::Less => value < *expected,
+                    NumberComparison::LessOrEqual => value <= *expected,
+                    NumberComparison::Greater => value > *expected,
+                    NumberComparison::GreaterOrEqual => value >= *expected,
+                    NumberComparison::Equal => value == *expected,
+                    NumberComparison::NotEqual => value != *expected
+                })
+            },
+            Expression::FlagTest(operand) => match operand {
+                FlagOperand::Encrypted => message.is_encrypted(),
+                FlagOperand::Calendar => message.calendar_method().is_some(),
+                FlagOperand::Automated => message.is_automated(),
+                FlagOperand::Dsn => message.is_bounce(),
+                FlagOperand::SuspiciousFrom => message.has_suspicious_from(envelope.sender.as_deref())
+            }
+        }
+    }
+}
+
+impl TextOperand {
+    fn values(&self, envelope: &Envelope, message: &IncomingMessage) -> Vec<String> {
+        match self {
+            TextOperand::To => vec![envelope.recipient.clone()],
+            TextOperand::Sender => envelope.sender.iter().cloned().collect(),
+            TextOperand::From => message.header_addresses("From"),
+            TextOperand::ListId => message.list_id().into_iter().collect(),
+            TextOperand::Subject => message.header_values("Subject").map(str::to_string).collect(),
+            TextOperand::Body => vec![message.body_text().to_string()],
+            TextOperand::Importance => vec![message.importance().to_string()],
+            TextOperand::Language => message.language().map(str::to_string).into_iter().collect(),
+            TextOperand::Header(name) => message.header_values(name).map(str::to_string).collect()
+        }
+    }
+}
+
+//
+// Tokenizer
+//
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(f64),
+    Operator(&'static str),
+    OpenParen,
+    CloseParen
+}
+
+/// Two-character operators must come before their one-character
+/// prefixes.
+const OPERATORS: [&str; 8] = ["==", "!=", "!~", "<=", ">=", "~", "<", ">"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::OpenParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::CloseParen);
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    // Only quotes and backslashes are escaped, so that
+                    // regular expressions like "\.com$" survive intact
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped @ ('"' | '\'' | '\\'))) => value.push(escaped),
+                        Some((_, other)) => {
+                            value.push('\\');
+                            value.push(other);
+                        },
+                        None => bail!("Unterminated string starting at position {position}")
+                    },
+                    Some((_, quote)) if quote == c => break,
+                    Some((_, other)) => value.push(other),
+                    None => bail!("Unterminated string starting at position {position}")
+                }
+            }
+            tokens.push(Token::String(value));
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let mut number = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '-' || c == '.') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let value = number.parse()
+                .with_context(|| format!("Invalid number {number:?} at position {position}"))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut identifier = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                identifier.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Identifier(identifier.to_lowercase()));
+        } else {
+            let rest = &source[position..];
+            let operator = OPERATORS
+                .iter()
+                .find(|operator| rest.starts_with(*operator))
+                .with_context(|| format!("Unexpected character {c:?} at position {position}"))?;
+
+            for _ in 0..operator.len() {
+                chars.next();
+            }
+            tokens.push(Token::Operator(operator));
+        }
+    }
+
+    Ok(tokens)
+}
+
+//
+// Parser
+//
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(identifier)) if identifier == keyword)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if *token == expected => Ok(()),
+            Some(token) => bail!("Expected {expected:?}, found {token:?}"),
+            None => bail!("Expected {expected:?}, found end of expression")
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression> {
+        let mut expression = self.parse_and()?;
+
+        while self.next_is_keyword("or") {
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression> {
+        let mut expression = self.parse_not()?;
+
+        while self.next_is_keyword("and") {
+            self.next();
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_not()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_not(&mut self) -> Result<Expression> {
+        if self.next_is_keyword("not") {
+            self.next();
+            return Ok(Expression::Not(Box::new(self.parse_not()?)));
+        }
+
+        if self.peek() == Some(&Token::OpenParen) {
+            self.next();
+            let expression = self.parse_or()?;
+            self.expect(Token::CloseParen)?;
+            return Ok(expression);
+        }
+
+        self.parse_test()
+    }
+
+    fn parse_test(&mut self) -> Result<Expression> {
+        let identifier = match self.next() {
+            Some(Token::Identifier(identifier)) => identifier.clone(),
+            Some(token) => bail!("Expected a test, found {token:?}"),
+            None => bail!("Expected a test, found end of expression")
+        };
+
+        let number_operand = match identifier.as_str() {
+            "size" => Some(NumberOperand::Size),
+            "spam_score" => Some(NumberOperand::SpamScore),
+            "recipients" => Some(NumberOperand::Recipients),
+            _ => None
+        };
+
+        if let Some(operand) = number_operand {
+            return self.parse_number_test(operand);
+        }
+
+        match identifier.as_str() {
+            "encrypted" => return Ok(Expression::FlagTest(FlagOperand::Encrypted)),
+            "calendar" => return Ok(Expression::FlagTest(FlagOperand::Calendar)),
+            "automated" => return Ok(Expression::FlagTest(FlagOperand::Automated)),
+            "dsn" => return Ok(Expression::FlagTest(FlagOperand::Dsn)),
+            "suspicious_from" => return Ok(Expression::FlagTest(FlagOperand::SuspiciousFrom)),
+            _ => {}
+        }
+
+        let operand = match identifier.as_str() {
+            "to" => TextOperand::To,
+            "sender" => TextOperand::Sender,
+            "from" => TextOperand::From,
+            "list_id" => TextOperand::ListId,
+            "subject" => TextOperand::Subject,
+            "body" => TextOperand::Body,
+            "importance" => TextOperand::Importance,
+            "language" => TextOperand::Language,
+            "header" => {
+                self.expect(Token::OpenParen)?;
+                let name = match self.next() {
+                    Some(Token::String(name)) => name.clone(),
+                    _ => bail!("Expected a quoted header name after header(")
+                };
+                self.expect(Token::CloseParen)?;
+                TextOperand::Header(name)
+            },
+            _ => bail!("Unknown test {identifier:?}")
+        };
+
+        let operator = match self.peek() {
+            Some(Token::Operator(operator)) => *operator,
+            _ => return Ok(Expression::TextTest(operand, TextComparison::Exists))
+        };
+        self.next();
+
+        let value = match self.next() {
+            Some(Token::String(value)) => value.clone(),
+            _ => bail!("Expected a quoted string after {operator}")
+        };
+
+        let build_regex = |pattern: &str| RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Error parsing regular expression {pattern:?}"));
+
+        let comparison = match operator {
+            "==" => TextComparison::Equals(value.to_lowercase()),
+            "!=" => TextComparison::NotEquals(value.to_lowercase()),
+            "~" => TextComparison::Matches(build_regex(&value)?),
+            "!~" => TextComparison::NotMatches(build_regex(&value)?),
+            _ => bail!("Operator {operator} can't be used with {identifier}")
+        };
+
+        Ok(Expression::TextTest(operand, comparison))
+    }
+
+    fn parse_number_test(&mut self, operand: NumberOperand) -> Result<Expression> {
+        let comparison = match self.next() {
+            Some(Token::Operator("<")) => NumberComparison::Less,
+            Some(Token::Operator("<=")) => NumberComparison::LessOrEqual,
+            Some(Token::Operator(">")) => NumberComparison::Greater,
+            Some(Token::Operator(">=")) => NumberComparison::GreaterOrEqual,
+            Some(Token::Operator("==")) => NumberComparison::Equal,
+            Some(Token::Operator("!=")) => NumberComparison::NotEqual,
+            _ => bail!("Expected a numeric comparison after {operand:?}")
+        };
+
+        let value = match self.next() {
+            Some(Token::Number(value)) => *value,
+            _ => bail!("Expected a number after {operand:?} comparison")
+        };
+
+        Ok(Expression::NumberTest(operand, comparison, value))
+    }
+}
@@ -0,0 +1,321 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::compress;
+use crate::message::IncomingMessage;
+
+//
+// Maildir folders
+//
+// Where each folder lives in the root Maildir, and reading back the
+// mail that's already been sorted, for the commands that learn from it.
+// The root Maildir itself (the inbox) isn't a folder.
+//
+// Folder names in the config file use either `.` or `/` to separate
+// hierarchy levels (`Work.Clients` or `Work/Clients`), whichever the
+// layout; names read back from the Maildir always use `.`. They don't
+// include the namespace prefix, if there is one.
+//
+// With the `fs` layout, a folder named `cur`, `new` or `tmp` would
+// land inside its parent's Maildir directories, so on disk its first
+// character is escaped as `%` and two hex digits (`Lists/%6eew` for
+// `Lists.new`), as Dovecot does with an escape character set; so is the
+// `%` of a name that starts with one.
+//
+// Folders that sortmail creates can be added to the IMAP server's list
+// of subscribed folders, since many IMAP clients only show those.
+//
+
+/// How folders are laid out on disk, named as in Dovecot's `LAYOUT`
+/// setting.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Every folder is a `.Work.Clients` directory in the root Maildir
+    #[default]
+    #[serde(rename = "maildir++")]
+    MaildirPlusPlus,
+
+    /// Folders are nested Maildirs, like `Work/Clients` under the root
+    /// Maildir
+    #[serde(rename = "fs")]
+    Fs
+}
+
+/// Which IMAP server's subscription list new folders are added to.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Subscriptions {
+    /// Dovecot's `subscriptions` file
+    Dovecot,
+
+    /// Courier's `courierimapsubscribed` file
+    Courier
+}
+
+/// How folder names in the config file map onto the root Maildir.
+#[derive(Debug)]
+pub struct Namespace {
+    pub layout: Layout,
+
+    /// Hierarchy levels (like `INBOX`, for an `INBOX.` prefix) that
+    /// every folder lives under
+    pub prefix: String
+}
+
+impl Namespace {
+    /// Return the path of the Maildir for the folder `mailbox_name`.
+    /// Empty hierarchy levels are dropped, so a name can't climb out of
+    /// the root Maildir with `..`.
+    pub fn folder_path(&self, root_maildir: &Path, mailbox_name: &str) -> PathBuf {
+        let components = self.components(mailbox_name);
+
+        match self.layout {
+            Layout::MaildirPlusPlus => root_maildir.join(format!(".{}", components.join("."))),
+            Layout::Fs => components.iter().fold(root_maildir.to_path_buf(), |path, component| path.join(escape_fs_component(component)))
+        }
+    }
+
+    /// Return the folder `mailbox_name` as Dovecot names it, with the
+    /// prefix and with Dovecot's default hierarchy separator for the
+    /// layout: `.` for Maildir++ and `/` for `fs`.
+    pub fn dovecot_name(&self, mailbox_name: &str) -> String {
+        let separator = match self.layout {
+            Layout::MaildirPlusPlus => ".",
+            Layout::Fs => "/"
+        };
+
+        self.components(mailbox_name).join(separator)
+    }
+
+    /// Return the hierarchy levels of the folder `mailbox_name` on disk,
+    /// starting with the prefix's.
+    fn components<'a>(&'a self, mailbox_name: &'a str) -> Vec<&'a str> {
+        [self.prefix.as_str(), mailbox_name]
+            .into_iter()
+            .flat_map(|name| name.split(['.', '/']))
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+
+    /// Return `mailbox_name`, as read back from the Maildir, without the
+    /// prefix, or None if it isn't under the prefix.
+    fn strip_prefix(&self, mailbox_name: String) -> Option<String> {
+        let prefix = self.prefix
+            .split(['.', '/'])
+            .filter(|component| !component.is_empty())
+            .collect::<Vec<_>>()
+            .join(".");
+
+        match prefix.is_empty() {
+            true => Some(mailbox_name),
+            false => mailbox_name
+                .strip_prefix(&format!("{prefix}."))
+                .map(str::to_string)
+        }
+    }
+}
+
+/// Add the folder `mailbox_name` to the root Maildir's list of
+/// subscribed folders, in `subscriptions`' format, unless it's already
+/// there. The list is locked while it's updated.
+///
+/// Dovecot's `subscriptions` file has a line per folder, with `.` (or
+/// `/`, with the `fs` layout) between hierarchy levels, or with a tab
+/// between them if the file starts with a `V\t2` version header.
+/// Courier's `courierimapsubscribed` file has a line per folder too,
+/// always starting `INBOX.`.
+pub fn subscribe(root_maildir: &Path, namespace: &Namespace, mailbox_name: &str, subscriptions: Subscriptions) -> Result<()> {
+    let file_name = match subscriptions {
+        Subscriptions::Dovecot => "subscriptions",
+        Subscriptions::Courier => "courierimapsubscribed"
+    };
+
+    let path = root_maildir.join(file_name);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Error opening subscriptions {}", path.display()))?;
+
+    file.lock()
+        .with_context(|| format!("Error locking subscriptions {}", path.display()))?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Error reading subscriptions {}", path.display()))?;
+
+    let components = namespace.components(mailbox_name);
+
+    let name = match subscriptions {
+        Subscriptions::Courier => format!("INBOX.{}", components.join(".")),
+        Subscriptions::Dovecot if contents.starts_with("V\t2\n") => components.join("\t"),
+        Subscriptions::Dovecot => namespace.dovecot_name(mailbox_name)
+    };
+
+    if contents.lines().any(|line| line == name) {
+        return Ok(());
+    }
+
+    let mut added = format!("{name}\n");
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        added.insert(0, '\n');
+    }
+
+    file.seek(SeekFrom::End(0))
+        .and_then(|_| file.write_all(added.as_bytes()))
+        .with_context(|| format!("Error writing subscriptions {}", path.display()))
+}
+
+/// Return the name and path of every folder in the Maildir at
+/// `root_maildir`, sorted by name, except those named in
+/// `ignore_folders` and their subfolders, and those outside the
+/// namespace's prefix.
+pub fn list(root_maildir: &Path, namespace: &Namespace, ignore_folders: &[String]) -> Result<Vec<(String, PathBuf)>> {
+    list_matching(root_maildir, namespace, ignore_folders, |folder_path| folder_path.join("cur").is_dir())
+}
+
+/// Like `list`, but also returning folders that are missing some of
+/// their `cur`, `new` and `tmp` directories, for repairing them.
+pub fn list_partial(root_maildir: &Path, namespace: &Namespace) -> Result<Vec<(String, PathBuf)>> {
+    list_matching(root_maildir, namespace, &[], |folder_path| ["cur", "new", "tmp"]
+        .iter()
+        .any(|subdir| folder_path.join(subdir).is_dir()))
+}
+
+/// List the folders as `list` does, taking a directory for a folder if
+/// `is_folder`.
+fn list_matching(root_maildir: &Path, namespace: &Namespace, ignore_folders: &[String], is_folder: fn(&Path) -> bool) -> Result<Vec<(String, PathBuf)>> {
+    let folders = match namespace.layout {
+        Layout::MaildirPlusPlus => list_maildir_plus_plus(root_maildir, is_folder)?,
+        Layout::Fs => list_fs(root_maildir, None, is_folder)?
+    };
+
+    let mut folders: Vec<_> = folders
+        .into_iter()
+        .filter_map(|(mailbox_name, folder_path)| namespace
+            .strip_prefix(mailbox_name)
+            .map(|mailbox_name| (mailbox_name, folder_path)))
+        .collect();
+
+    let ignore_folders: Vec<_> = ignore_folders
+        .iter()
+        .map(|ignored| ignored.replace('/', "."))
+        .collect();
+
+    folders.retain(|(mailbox_name, _)| !ignore_folders
+        .iter()
+        .any(|ignored| mailbox_name == ignored || mailbox_name.starts_with(&format!("{ignored}."))));
+
+    folders.sort();
+    Ok(folders)
+}
+
+fn list_maildir_plus_plus(root_maildir: &Path, is_folder: fn(&Path) -> bool) -> Result<Vec<(String, PathBuf)>> {
+    let entries = std::fs::read_dir(root_maildir)
+        .with_context(|| format!("Error reading Maildir {}", root_maildir.display()))?;
+
+    let mut folders = Vec::new();
+
+    for entry in entries {
+        let folder_path = entry
+            .with_context(|| format!("Error reading Maildir {}", root_maildir.display()))?
+            .path();
+
+        let Some(mailbox_name) = folder_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix('.'))
+            .map(str::to_string) else {
+            continue;
+        };
+
+        if is_folder(&folder_path) {
+            folders.push((mailbox_name, folder_path));
+        }
+    }
+
+    Ok(folders)
+}
+
+/// Return the folders nested in `directory`, whose own folder name is
+/// `parent_name` (None for the root Maildir), and their subfolders.
+fn list_fs(directory: &Path, parent_name: Option<&str>, is_folder: fn(&Path) -> bool) -> Result<Vec<(String, PathBuf)>> {
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Error reading Maildir {}", directory.display()))?;
+
+    let mut folders = Vec::new();
+
+    for entry in entries {
+        let folder_path = entry
+            .with_context(|| format!("Error reading Maildir {}", directory.display()))?
+            .path();
+
+        let Some(name) = folder_path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if ["cur", "new", "tmp"].contains(&name) || name.starts_with('.') || !folder_path.is_dir() {
+            continue;
+        }
+
+        let name = unescape_fs_component(name);
+        let mailbox_name = match parent_name {
+            Some(parent_name) => format!("{parent_name}.{name}"),
+            None => name
+        };
+
+        // A directory may just hold subfolders, without being a
+        // Maildir itself
+        if is_folder(&folder_path) {
+            folders.push((mailbox_name.clone(), folder_path.clone()));
+        }
+
+        folders.extend(list_fs(&folder_path, Some(&mailbox_name), is_folder)?);
+    }
+
+    Ok(folders)
+}
+
+/// Return the directory name for the `fs` layout folder hierarchy
+/// level `component`, escaping it if it's one of a Maildir's own
+/// directories or starts with the escape character.
+fn escape_fs_component(component: &str) -> String {
+    match ["cur", "new", "tmp"].contains(&component) || component.starts_with('%') {
+        true => format!("%{:02x}{}", component.as_bytes()[0], &component[1..]),
+        false => component.to_string()
+    }
+}
+
+/// Return the folder hierarchy level that the `fs` layout directory
+/// `name` is for, undoing `escape_fs_component`.
+fn unescape_fs_component(name: &str) -> String {
+    let unescaped = name
+        .strip_prefix('%')
+        .and_then(|rest| Some((u8::from_str_radix(rest.get(..2)?, 16).ok()?, &rest[2..])))
+        .filter(|(first, _)| first.is_ascii());
+
+    match unescaped {
+        Some((first, rest)) => format!("{}{rest}", first as char),
+        None => name.to_string()
+    }
+}
+
+/// Return up to `limit` of the messages in the Maildir folder at
+/// `folder_path`. Messages that can't be read or parsed are skipped.
+pub fn messages(folder_path: &Path, limit: usize) -> impl Iterator<Item = IncomingMessage> + '_ {
+    ["cur", "new"]
+        .into_iter()
+        .filter_map(|subdir| std::fs::read_dir(folder_path.join(subdir)).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|message_path| compress::read_message(&message_path).ok())
+        .filter_map(|bytes| IncomingMessage::from_bytes(bytes.into_boxed_slice()).ok())
+        .take(limit)
+}
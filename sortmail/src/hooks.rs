@@ -0,0 +1,100 @@
+use std::env;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::Envelope;
+use crate::process::wait_with_timeout;
+
+//
+// Post-delivery hooks
+//
+// After a message is stored, the global `post_deliver` command and the
+// `post_deliver` command of the mailbox that chose the delivery are run
+// by `/bin/sh -c`, for things like kicking an indexer, sending a
+// notification or starting a sync. They get no input, and these
+// environment variables:
+//
+//     SORTMAIL_DESTINATION    the Maildir folder, mbox file or MH folder
+//                             the message was stored in
+//     SORTMAIL_MAILBOX        the folder's name, if it isn't the root
+//                             Maildir
+//     SORTMAIL_RULE           the mailbox (or global option, like
+//                             `list_folder`) that chose the delivery
+//     SORTMAIL_RECIPIENT      the recipient address
+//     SORTMAIL_EXTENSION      the recipient's address extension, if any
+//     SORTMAIL_SENDER         the envelope sender, if any
+//
+// With the global `doveadm_index`, Dovecot is also asked to index each
+// Maildir folder a message is stored in, with `doveadm index -q`, which
+// queues the folder for Dovecot's indexer service rather than waiting
+// for it; otherwise Dovecot only catches up when an IMAP client next
+// opens the folder.
+//
+// The message is already delivered by then, so a hook that fails is
+// only reported.
+//
+
+/// What a post-delivery hook is told about the delivery.
+pub struct Delivery<'a> {
+    pub envelope: &'a Envelope,
+    pub destination: &'a Path,
+    pub mailbox_name: Option<&'a str>,
+    pub chosen_by: &'a str
+}
+
+/// Run the hook `command` for `delivery`, and fail unless it exits
+/// successfully. The command is killed if it runs longer than `timeout`.
+pub fn run_post_deliver(command: &str, delivery: &Delivery, timeout: Duration) -> Result<()> {
+    let envelope = delivery.envelope;
+
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("SORTMAIL_DESTINATION", delivery.destination)
+        .env("SORTMAIL_MAILBOX", delivery.mailbox_name.unwrap_or_default())
+        .env("SORTMAIL_RULE", delivery.chosen_by)
+        .env("SORTMAIL_RECIPIENT", &envelope.recipient)
+        .env("SORTMAIL_EXTENSION", envelope.recipient_extension.as_deref().unwrap_or_default())
+        .env("SORTMAIL_SENDER", envelope.sender.as_deref().unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Error running post_deliver command {command}"))?;
+
+    let status = wait_with_timeout(&mut child, timeout)
+        .with_context(|| format!("post_deliver command {command} didn't finish"))?;
+
+    if !status.success() {
+        bail!("post_deliver command {command} failed ({status})");
+    }
+
+    Ok(())
+}
+
+/// Queue `dovecot_name`, a folder of the current user's (or `INBOX`),
+/// for indexing by Dovecot's indexer service. The command is killed if
+/// it runs longer than `timeout`.
+pub fn run_doveadm_index(dovecot_name: &str, timeout: Duration) -> Result<()> {
+    let user = env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .context("Error finding the Dovecot user: neither USER nor LOGNAME is set")?;
+
+    let mut child = Command::new("doveadm")
+        .args(["index", "-q", "-u", &user, dovecot_name])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Error running doveadm index")?;
+
+    let status = wait_with_timeout(&mut child, timeout)
+        .context("doveadm index didn't finish")?;
+
+    if !status.success() {
+        bail!("doveadm index failed ({status})");
+    }
+
+    Ok(())
+}
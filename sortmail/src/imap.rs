@@ -0,0 +1,291 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::smtp::base64_encode;
+use crate::tls::{self, Security, Stream};
+
+//
+// IMAP client
+//
+// Just enough IMAP (RFC 9051, and the IMAP4rev1 that most servers still
+// speak) to APPEND a message to a folder on a server we have no
+// filesystem access to, like a hosted mailbox: LOGIN, an optional
+// CREATE, APPEND and LOGOUT, over TLS (on port 993, or with STARTTLS)
+// unless the config says otherwise.
+//
+// Folder names in the config separate hierarchy levels with `.` or `/`,
+// as for Maildir folders; they're sent with the server's own separator,
+// in IMAP's modified UTF-7. Maildir flags (`S`, `F` and so on) become
+// the matching IMAP system flags, and keywords are passed on as they
+// are.
+//
+
+/// The IMAP server that `imap_folder` mail is appended to, from the
+/// global `imap_` options.
+#[derive(Debug)]
+pub struct ImapServer {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+    pub username: String,
+    pub password: String,
+    pub timeout: Duration
+}
+
+/// The IMAP system flag for each Maildir flag.
+const SYSTEM_FLAGS: [(char, &str); 5] = [
+    ('D', "\\Draft"),
+    ('F', "\\Flagged"),
+    ('R', "\\Answered"),
+    ('S', "\\Seen"),
+    ('T', "\\Deleted")
+];
+
+/// An open, logged-in connection to the server.
+struct Session {
+    stream: BufReader<Stream>,
+    next_tag: usize
+}
+
+impl ImapServer {
+    /// Append `message` to `folder`, creating the folder first if
+    /// `create_folder`, with the IMAP flags for the Maildir `flags` and
+    /// the `keywords`.
+    pub fn append(&self, folder: &str, create_folder: bool, flags: Option<&str>, keywords: &[String], message: &[u8]) -> Result<()> {
+        let mut session = self.connect()
+            .with_context(|| format!("Error connecting to IMAP server {}:{}", self.host, self.port))?;
+
+        session.command(&format!("LOGIN {} {}", quoted(&self.username), quoted(&self.password)))
+            .context("IMAP server refused the login")?;
+
+        let delimiter = session.hierarchy_delimiter()?;
+        let mailbox = quoted(&encode_mailbox_name(&folder.replace(['.', '/'], &delimiter)));
+
+        if create_folder {
+            // Fails if the folder already exists, which is fine
+            let _ = session.command(&format!("CREATE {mailbox}"));
+        }
+
+        let flag_list: Vec<_> = SYSTEM_FLAGS
+            .iter()
+            .filter(|(flag, _)| flags.unwrap_or_default().contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .chain(keywords.iter().cloned())
+            .collect();
+
+        let message = crlf(message);
+        let tag = session.tag();
+        session.send(&format!("{tag} APPEND {mailbox} ({}) {{{}}}", flag_list.join(" "), message.len()))?;
+        session.expect_continuation(&tag).context("IMAP server refused APPEND")?;
+
+        session.stream.get_mut().write_all(&message)
+            .and_then(|_| session.stream.get_mut().write_all(b"\r\n"))
+            .and_then(|_| session.stream.get_mut().flush())
+            .context("Error sending message to IMAP server")?;
+        session.expect_tagged(&tag).context("IMAP server refused the message")?;
+
+        // The message is appended at this point, so a failed LOGOUT
+        // doesn't matter
+        let _ = session.command("LOGOUT");
+
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<Session> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?;
+
+        let mut last_error = None;
+        let mut tcp_stream = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    tcp_stream = Some(stream);
+                    break;
+                },
+                Err(error) => last_error = Some(error)
+            }
+        }
+
+        let tcp_stream = match (tcp_stream, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(error)) => return Err(error.into()),
+            (None, None) => bail!("No addresses found for {}", self.host)
+        };
+
+        tcp_stream.set_read_timeout(Some(self.timeout))?;
+        tcp_stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut session = match self.security {
+            Security::Tls => Session::new(tls::start_tls(&self.host, tcp_stream)?),
+            Security::StartTls | Security::None => Session::new(Stream::Plain(tcp_stream))
+        };
+
+        session.expect_greeting()?;
+
+        if self.security == Security::StartTls {
+            session.command("STARTTLS").context("IMAP server refused STARTTLS")?;
+
+            let Stream::Plain(tcp_stream) = session.stream.into_inner() else {
+                bail!("Connection is already using TLS");
+            };
+            session = Session::new(tls::start_tls(&self.host, tcp_stream)?);
+        }
+
+        Ok(session)
+    }
+}
+
+impl Session {
+    fn new(stream: Stream) -> Session {
+        Session {
+            stream: BufReader::new(stream),
+            next_tag: 1
+        }
+    }
+
+    fn tag(&mut self) -> String {
+        let tag = format!("s{}", self.next_tag);
+        self.next_tag += 1;
+        tag
+    }
+
+    fn send(&mut self, line: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{line}\r\n").as_bytes())
+            .and_then(|_| stream.flush())
+            .context("Error writing to IMAP server")
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).context("Error reading from IMAP server")? == 0 {
+            bail!("Connection closed by IMAP server");
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Send a tagged command and wait for its tagged OK.
+    fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        let tag = self.tag();
+        self.send(&format!("{tag} {command}"))?;
+        self.expect_tagged(&tag)
+    }
+
+    fn expect_greeting(&mut self) -> Result<()> {
+        let greeting = self.read_line()?;
+        match greeting.starts_with("* OK") || greeting.starts_with("* PREAUTH") {
+            true => Ok(()),
+            false => bail!("IMAP server refused the connection: {greeting}")
+        }
+    }
+
+    /// Read responses up to the one tagged `tag`, and return the
+    /// untagged ones if it's OK.
+    fn expect_tagged(&mut self, tag: &str) -> Result<Vec<String>> {
+        let mut untagged = Vec::new();
+
+        loop {
+            let line = self.read_line()?;
+            let Some(status) = line.strip_prefix(tag).and_then(|rest| rest.strip_prefix(' ')) else {
+                untagged.push(line);
+                continue;
+            };
+
+            match status.get(..2).is_some_and(|ok| ok.eq_ignore_ascii_case("OK")) {
+                true => return Ok(untagged),
+                false => bail!("{status}")
+            }
+        }
+    }
+
+    /// Wait for the server to ask for a literal, failing if it answers
+    /// the command tagged `tag` instead.
+    fn expect_continuation(&mut self, tag: &str) -> Result<()> {
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with('+') {
+                return Ok(());
+            }
+            if let Some(status) = line.strip_prefix(tag) {
+                bail!("{}", status.trim());
+            }
+        }
+    }
+
+    /// Return the server's hierarchy delimiter, or `/` if it has none.
+    fn hierarchy_delimiter(&mut self) -> Result<String> {
+        let responses = self.command("LIST \"\" \"\"").context("IMAP server refused LIST")?;
+
+        let delimiter = responses
+            .iter()
+            .filter_map(|response| response.strip_prefix("* LIST "))
+            .find_map(|rest| {
+                let (_, after_attributes) = rest.split_once(") ")?;
+                let delimiter = after_attributes.strip_prefix('"')?;
+                let delimiter = delimiter.strip_prefix('\\').unwrap_or(delimiter);
+                delimiter.chars().next()
+            })
+            .unwrap_or('/');
+
+        Ok(delimiter.to_string())
+    }
+}
+
+/// Return `value` as an IMAP quoted string.
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Return `bytes` with every line ending as CRLF, as IMAP wants.
+fn crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(bytes.len());
+
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'\n' && (i == 0 || bytes[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(b);
+    }
+
+    converted
+}
+
+/// Encode a mailbox name in IMAP's modified UTF-7 (RFC 3501 section
+/// 5.1.3): printable ASCII as it is, except `&` as `&-`, and anything
+/// else as UTF-16 in base64 with `,` for `/`, between `&` and `-`.
+fn encode_mailbox_name(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+
+    let flush = |pending: &mut Vec<u16>, encoded: &mut String| {
+        if pending.is_empty() {
+            return;
+        }
+
+        let bytes: Vec<u8> = pending.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+        encoded.push('&');
+        encoded.push_str(&base64_encode(&bytes).trim_end_matches('=').replace('/', ","));
+        encoded.push('-');
+        pending.clear();
+    };
+
+    for c in name.chars() {
+        match c {
+            '&' => {
+                flush(&mut pending, &mut encoded);
+                encoded.push_str("&-");
+            },
+            ' '..='~' => {
+                flush(&mut pending, &mut encoded);
+                encoded.push(c);
+            },
+            _ => pending.extend(c.encode_utf16(&mut [0; 2]).iter())
+        }
+    }
+    flush(&mut pending, &mut encoded);
+
+    encoded
+}
@@ -0,0 +1,95 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+//
+// Dovecot keywords
+//
+// Dovecot stores IMAP keywords in a Maildir folder as lowercase letters
+// in a message's info flags, `a` for keyword 0 up to `z` for keyword
+// 25. The folder's `dovecot-keywords` file maps the numbers to names,
+// with one `<number> <keyword>` line each.
+//
+
+/// Name of the keyword list file, in each Maildir folder.
+const KEYWORDS_FILE_NAME: &str = "dovecot-keywords";
+
+/// Dovecot has a letter for each of this many keywords per folder.
+const MAX_KEYWORDS: usize = 26;
+
+/// Return true if `keyword` can be used as an IMAP keyword: an atom
+/// that isn't a system flag like `\Seen`.
+pub fn is_valid(keyword: &str) -> bool {
+    !keyword.is_empty() && !keyword.chars().any(|c| {
+        c.is_control() || c.is_whitespace() || "(){%*\"\\]".contains(c)
+    })
+}
+
+/// Return the info flag letters for `keywords` in the Maildir folder at
+/// `folder_path`, adding any that the folder's `dovecot-keywords` file
+/// doesn't list yet. The file is locked while it's updated, so
+/// concurrent deliveries agree on the letters.
+pub fn letters(folder_path: &Path, keywords: &[String]) -> Result<String> {
+    let path = folder_path.join(KEYWORDS_FILE_NAME);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Error opening keyword list {}", path.display()))?;
+
+    file.lock()
+        .with_context(|| format!("Error locking keyword list {}", path.display()))?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Error reading keyword list {}", path.display()))?;
+
+    let mut slots: Vec<Option<String>> = vec![None; MAX_KEYWORDS];
+    for line in contents.lines() {
+        let Some((number, keyword)) = line.split_once(' ') else {
+            continue;
+        };
+
+        if let Some(slot) = number.parse::<usize>().ok().and_then(|number| slots.get_mut(number)) {
+            *slot = Some(keyword.to_string());
+        }
+    }
+
+    let mut letters = String::new();
+    let mut added = String::new();
+
+    for keyword in keywords {
+        // IMAP keywords are case-insensitive
+        let number = match slots.iter().position(|slot| slot.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(keyword))) {
+            Some(number) => number,
+            None => {
+                let Some(number) = slots.iter().position(Option::is_none) else {
+                    bail!("Keyword list {} is full, can't add {keyword}", path.display());
+                };
+
+                slots[number] = Some(keyword.clone());
+                added.push_str(&format!("{number} {keyword}\n"));
+                number
+            }
+        };
+
+        letters.push((b'a' + number as u8) as char);
+    }
+
+    if !added.is_empty() {
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            added.insert(0, '\n');
+        }
+
+        file.seek(SeekFrom::End(0))
+            .and_then(|_| file.write_all(added.as_bytes()))
+            .with_context(|| format!("Error writing keyword list {}", path.display()))?;
+    }
+
+    Ok(letters)
+}
@@ -0,0 +1,68 @@
+use whatlang::Lang;
+
+//
+// Language detection
+//
+// Languages are named by their two-letter ISO 639-1 codes (`de`, `en`),
+// though the config file may also use the three-letter ISO 639-3 codes
+// (`deu`) or English names (`German`).
+//
+
+/// Only this much text is looked at; more doesn't make the guess any
+/// better, only slower.
+const MAX_DETECTION_CHARS: usize = 10_000;
+
+/// ISO 639-3 codes of the languages that can be detected, and their
+/// ISO 639-1 codes.
+const LANGUAGE_CODES: [(&str, &str); 70] = [
+    ("afr", "af"), ("aka", "ak"), ("amh", "am"), ("ara", "ar"), ("aze", "az"),
+    ("bel", "be"), ("ben", "bn"), ("bul", "bg"), ("cat", "ca"), ("ces", "cs"),
+    ("cmn", "zh"), ("cym", "cy"), ("dan", "da"), ("deu", "de"), ("ell", "el"),
+    ("eng", "en"), ("epo", "eo"), ("est", "et"), ("fin", "fi"), ("fra", "fr"),
+    ("guj", "gu"), ("heb", "he"), ("hin", "hi"), ("hrv", "hr"), ("hun", "hu"),
+    ("hye", "hy"), ("ind", "id"), ("ita", "it"), ("jav", "jv"), ("jpn", "ja"),
+    ("kan", "kn"), ("kat", "ka"), ("khm", "km"), ("kor", "ko"), ("lat", "la"),
+    ("lav", "lv"), ("lit", "lt"), ("mal", "ml"), ("mar", "mr"), ("mkd", "mk"),
+    ("mya", "my"), ("nep", "ne"), ("nld", "nl"), ("nob", "nb"), ("ori", "or"),
+    ("pan", "pa"), ("pes", "fa"), ("pol", "pl"), ("por", "pt"), ("ron", "ro"),
+    ("rus", "ru"), ("sin", "si"), ("slk", "sk"), ("slv", "sl"), ("sna", "sn"),
+    ("spa", "es"), ("srp", "sr"), ("swe", "sv"), ("tam", "ta"), ("tel", "te"),
+    ("tgl", "tl"), ("tha", "th"), ("tuk", "tk"), ("tur", "tr"), ("ukr", "uk"),
+    ("urd", "ur"), ("uzb", "uz"), ("vie", "vi"), ("yid", "yi"), ("zul", "zu")
+];
+
+/// Return the ISO 639-1 code of the language `text` is written in, or
+/// None if it can't be told reliably.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let text = match text.char_indices().nth(MAX_DETECTION_CHARS) {
+        Some((end, _)) => &text[..end],
+        None => text
+    };
+
+    let info = whatlang::detect(text).filter(|info| info.is_reliable())?;
+    iso_639_1_code(info.lang())
+}
+
+/// Return the ISO 639-1 code for a language named in the config file,
+/// or None if it isn't one that can be detected.
+pub fn normalize_code(name: &str) -> Option<&'static str> {
+    let name = name.trim().to_lowercase();
+
+    let by_two_letter_code = LANGUAGE_CODES
+        .iter()
+        .find(|(_, code)| *code == name)
+        .map(|(_, code)| *code);
+
+    let by_name = || Lang::from_code(name.as_str())
+        .or_else(|| Lang::all().iter().copied().find(|lang| lang.eng_name().eq_ignore_ascii_case(&name)))
+        .and_then(iso_639_1_code);
+
+    by_two_letter_code.or_else(by_name)
+}
+
+fn iso_639_1_code(lang: Lang) -> Option<&'static str> {
+    LANGUAGE_CODES
+        .iter()
+        .find(|(code, _)| *code == lang.code())
+        .map(|(_, code)| *code)
+}
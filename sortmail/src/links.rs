@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::store::{self, StoreOptions};
+
+//
+// Linked folders
+//
+// With the global `canonical_folder`, every message is stored once, in
+// that folder, and each folder a rule delivers it to gets a link to
+// that copy instead of a copy of its own, so that a message can be in
+// any number of folders, like a message with several labels in Gmail,
+// without taking up space for each. `folder_links` says which kind of
+// link: symbolic links (the default), which work across filesystems
+// and make the canonical copy the only real one, or hardlinks, which
+// every mail reader handles but which can't cross filesystems (a full
+// copy is stored instead then) and which keep a message in the other
+// folders after it's deleted from the canonical one.
+//
+// A symbolic link has the same unique name as the canonical copy, with
+// its own flags, which is how its copy is found again: a mail reader that
+// changes the canonical copy's flags renames it, leaving the symbolic
+// links to it dangling, and `sortmail relink`, run from cron, points
+// them at the copy's new name, or removes them if the copy was
+// deleted, so that deleting a message from the canonical folder deletes
+// it from every folder.
+//
+
+/// How the other folders link to a message's canonical copy.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    #[default]
+    Symlink,
+    Hardlink
+}
+
+/// Link the message stored at `canonical`, whose contents are `bytes`,
+/// into the Maildir folder at `folder_path`: in `new`, or in `cur` with
+/// info flags `flags` if given. Returns the path of the link.
+pub fn link(kind: LinkKind, canonical: &Path, folder_path: &Path, flags: Option<&str>, bytes: &[u8], options: &StoreOptions) -> Result<PathBuf> {
+    match kind {
+        LinkKind::Hardlink => store::store_linked(canonical, folder_path, flags, bytes, options),
+        LinkKind::Symlink => symlink(canonical, folder_path, flags, options)
+    }
+}
+
+/// Make a symbolic link to `canonical` in the folder at `folder_path`,
+/// through its `tmp` directory, as a message is stored.
+fn symlink(canonical: &Path, folder_path: &Path, flags: Option<&str>, options: &StoreOptions) -> Result<PathBuf> {
+    let unique = unique_name(canonical)
+        .with_context(|| format!("Invalid message file name {}", canonical.display()))?;
+
+    let path = match flags {
+        Some(flags) => folder_path.join("cur").join(format!("{unique}:2,{flags}")),
+        None => folder_path.join("new").join(unique)
+    };
+
+    if path.symlink_metadata().is_ok() {
+        bail!("{} is already linked into {}", canonical.display(), folder_path.display());
+    }
+
+    let temp_path = folder_path.join("tmp").join(unique);
+    replace_symlink(canonical, &temp_path, &path)?;
+
+    if options.durable {
+        store::sync_parent_dir(&path)?;
+    }
+
+    Ok(path)
+}
+
+/// Make `path` a symbolic link to `canonical`, relative to its own
+/// directory so the Maildir can be moved, by making it at `temp_path`
+/// and renaming it into place.
+fn replace_symlink(canonical: &Path, temp_path: &Path, path: &Path) -> Result<()> {
+    let link_dir = path.parent().context("Link has no directory")?;
+    let target = relative_path(link_dir, canonical)?;
+
+    let _ = std::fs::remove_file(temp_path);
+    std::os::unix::fs::symlink(&target, temp_path)
+        .with_context(|| format!("Error creating {}", temp_path.display()))?;
+
+    std::fs::rename(temp_path, path)
+        .with_context(|| format!("Error moving {} to {}", temp_path.display(), path.display()))
+}
+
+/// Return the path of `target` relative to the directory `from`.
+fn relative_path(from: &Path, target: &Path) -> Result<PathBuf> {
+    let from = std::fs::canonicalize(from)
+        .with_context(|| format!("Error resolving {}", from.display()))?;
+    let target = std::fs::canonicalize(target)
+        .with_context(|| format!("Error resolving {}", target.display()))?;
+
+    let from: Vec<Component> = from.components().collect();
+    let target: Vec<Component> = target.components().collect();
+
+    let common = from
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &target[common..] {
+        relative.push(component);
+    }
+
+    Ok(relative)
+}
+
+/// Return the unique part of the name of the message file at `path`,
+/// without its info flags.
+fn unique_name(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    Some(name.split_once(':').map_or(name, |(unique, _)| unique))
+}
+
+/// Return the messages in the canonical folder at `canonical_folder_path`
+/// by the unique part of their names.
+pub fn canonical_copies(canonical_folder_path: &Path) -> Result<HashMap<String, PathBuf>> {
+    let mut copies = HashMap::new();
+
+    for subdir in ["cur", "new"] {
+        let subdir_path = canonical_folder_path.join(subdir);
+
+        let entries = match std::fs::read_dir(&subdir_path) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| format!("Error reading {}", subdir_path.display()))
+        };
+
+        for entry in entries {
+            let path = entry.with_context(|| format!("Error reading {}", subdir_path.display()))?.path();
+
+            if let Some(unique) = unique_name(&path) {
+                copies.insert(unique.to_string(), path.clone());
+            }
+        }
+    }
+
+    Ok(copies)
+}
+
+/// Point the dangling symbolic links in the Maildir folder at
+/// `folder_path` at their messages' new names in `canonical_copies`,
+/// or remove them if their messages are gone, and return a description
+/// of each fix, without making them if `dry_run`.
+pub fn relink_folder(folder_path: &Path, canonical_copies: &HashMap<String, PathBuf>, dry_run: bool) -> Result<Vec<String>> {
+    let mut fixes = Vec::new();
+
+    for subdir in ["cur", "new"] {
+        let subdir_path = folder_path.join(subdir);
+
+        let entries = match std::fs::read_dir(&subdir_path) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| format!("Error reading {}", subdir_path.display()))
+        };
+
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Error reading {}", subdir_path.display()))?;
+            let path = entry.path();
+
+            let is_dangling = entry.file_type().is_ok_and(|file_type| file_type.is_symlink()) && !path.exists();
+            if !is_dangling {
+                continue;
+            }
+
+            let Some(unique) = unique_name(&path) else {
+                continue;
+            };
+
+            match canonical_copies.get(unique) {
+                Some(canonical) => {
+                    if !dry_run {
+                        replace_symlink(canonical, &folder_path.join("tmp").join(unique), &path)?;
+                    }
+                    fixes.push(format!("Relinked {} to {}", path.display(), canonical.display()));
+                },
+                None => {
+                    if !dry_run {
+                        std::fs::remove_file(&path)
+                            .with_context(|| format!("Error removing {}", path.display()))?;
+                    }
+                    fixes.push(format!("Removed {}, whose message was deleted", path.display()));
+                }
+            }
+        }
+    }
+
+    fixes.sort();
+    Ok(fixes)
+}
@@ -0,0 +1,108 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::Envelope;
+use crate::message::IncomingMessage;
+use crate::process::wait_with_timeout;
+
+//
+// External lookup command
+//
+// The command is run (without a shell) with the recipient address as
+// its last argument, and with these environment variables set:
+//
+//     SORTMAIL_RECIPIENT      the recipient address
+//     SORTMAIL_EXTENSION      the recipient's address extension, if any
+//     SORTMAIL_SENDER         the envelope sender, if any
+//     SORTMAIL_HEADER_<NAME>  the value of each of the `lookup_headers`,
+//                             with the name uppercased and `-` as `_`
+//                             (e.g. SORTMAIL_HEADER_LIST_ID)
+//
+// The first line it prints is the folder to deliver to, which counts as
+// a mailbox with priority 0 (see `AddressMap::actions_for_message`), so
+// mailboxes with a higher priority, such as ones that reject or discard,
+// still come first; printing nothing leaves the decision to the mailbox
+// rules.
+//
+
+#[derive(Debug)]
+pub struct LookupCommand {
+    pub command: Vec<String>,
+    pub headers: Vec<String>,
+    pub timeout: Duration
+}
+
+impl LookupCommand {
+    /// Run the command for a message, and return the folder name it
+    /// printed, if any. The command is killed if it runs longer than
+    /// `timeout`.
+    pub fn run(&self, envelope: &Envelope, message: &IncomingMessage) -> Result<Option<String>> {
+        let (program, args) = self.command
+            .split_first()
+            .context("lookup_command is empty")?;
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .arg(&envelope.recipient)
+            .env("SORTMAIL_RECIPIENT", &envelope.recipient)
+            .env("SORTMAIL_EXTENSION", envelope.recipient_extension.as_deref().unwrap_or_default())
+            .env("SORTMAIL_SENDER", envelope.sender.as_deref().unwrap_or_default())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+
+        for header_name in &self.headers {
+            let variable = format!("SORTMAIL_HEADER_{}", header_name.to_uppercase().replace('-', "_"));
+            command.env(variable, message.header_values(header_name).next().unwrap_or_default());
+        }
+
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Error running lookup command {program}"))?;
+
+        // Read the output on another thread, so that a command that
+        // prints a lot can't block forever on a full pipe
+        let mut stdout = child.stdout.take().context("Error reading lookup command output")?;
+        let output_reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+
+        let status = wait_with_timeout(&mut child, self.timeout)
+            .with_context(|| format!("Lookup command {program} didn't finish"))?;
+
+        if !status.success() {
+            bail!("Lookup command {program} failed ({status})");
+        }
+
+        let output = output_reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Error reading lookup command output"))?
+            .context("Error reading lookup command output")?;
+
+        let mailbox_name = String::from_utf8_lossy(&output)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+
+        if mailbox_name.is_empty() {
+            return Ok(None);
+        }
+
+        // The name becomes part of a path, so it mustn't be able to
+        // escape the Maildir
+        let is_safe = !mailbox_name.contains(['/', '\0'])
+            && mailbox_name.split('.').all(|component| !component.is_empty());
+
+        match is_safe {
+            true => Ok(Some(mailbox_name)),
+            false => bail!("Lookup command {program} printed an invalid folder name {mailbox_name:?}")
+        }
+    }
+}
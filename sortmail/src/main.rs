@@ -1,15 +1,89 @@
+mod address;
+mod address_book;
+mod aliases;
+mod archive;
+mod classifier;
+mod clean;
+mod compress;
+mod dates;
+mod detach;
+mod dnsbl;
+mod encrypt;
+mod expire;
+mod expr;
+mod folders;
+mod hooks;
+mod imap;
+mod keywords;
+mod language;
+mod links;
+mod lookup;
+mod mbox;
+mod message;
+mod message_ids;
+mod message_store;
+mod mh;
+mod notmuch;
+mod pipe;
+mod process;
+mod quota;
+mod remote;
+mod repair;
+mod s3;
+mod sieve;
+mod smtp;
+mod spam_learner;
+mod store;
+mod suggest;
+mod tls;
+mod users;
+mod vacation;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::env;
 use std::io::{Read, stdin};
-use std::collections::HashMap;
+use std::net::IpAddr;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
 use maildir::Maildir;
-use anyhow::{Context, Result};
-use clap::Parser;
-use regex::RegexSet;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use indexmap::{IndexMap, IndexSet};
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Deserializer};
 
+use aliases::Aliases;
+use archive::Archive;
+use classifier::Classifier;
+use compress::{Compression, CompressionFormat};
+use dates::FolderDate;
+use detach::Detach;
+use encrypt::EncryptionKey;
+use expr::Expression;
+use folders::{Layout, Namespace, Subscriptions};
+use imap::ImapServer;
+use links::LinkKind;
+use lookup::LookupCommand;
+use message::{HeaderEdit, IncomingMessage};
+use message_ids::MessageIdIndex;
+use message_store::{store_message, ImapFolder, MaildirFolder, MboxFile, MessageStore, MhFolder, S3Folder, StoreRequest, StoredCopies};
+use pipe::{PipeCommand, PipeFailure};
+use quota::{Quota, QuotaFull};
+use remote::RemoteMaildir;
+use s3::{Endpoint, S3Bucket};
+use sieve::{SieveMode, SieveScript};
+use smtp::Smarthost;
+use spam_learner::{LearnedLog, SpamLearner, Verdict};
+use store::StoreOptions;
+use tls::Security;
+use users::{Forked, LocalUser};
+use vacation::{ReplyLog, Vacation};
+
 //
 // Command-line args
 //
@@ -35,197 +109,4427 @@ struct Args {
     #[arg(short = 'M', long = "maildir", value_name = "/path/to/Maildir")]
     override_root_maildir: Option<PathBuf>,
 
-    /// Environment variable that contains the original recipient's email address (default: ORIGINAL_RECIPIENT)
+    /// Run as root as the system's delivery agent, delivering each recipient's mail to the local user named by its local part, in their $HOME/Maildir
+    #[arg(short = 'U', long = "multi-user", conflicts_with = "override_root_maildir")]
+    multi_user: bool,
+
+    /// Environment variable that contains the original recipient's email address, or several separated by commas (default: ORIGINAL_RECIPIENT)
     #[arg(short = 'R', long = "recipient-env", value_name = "ENV")]
-    original_recipient_environment_variable: Option<String>
+    original_recipient_environment_variable: Option<String>,
+
+    /// Build the recipient address from the LOCAL, DOMAIN and EXTENSION environment variables set by Postfix, instead of ORIGINAL_RECIPIENT
+    #[arg(short = 'L', long = "local-domain-env", conflicts_with = "original_recipient_environment_variable")]
+    local_domain_environment: bool,
+
+    /// Environment variable that contains the envelope sender's email address (default: SENDER)
+    #[arg(short = 'S', long = "sender-env", value_name = "ENV")]
+    sender_environment_variable: Option<String>,
+
+    /// Recipient email address, instead of one from the environment; can be given more than once
+    #[arg(short = 'r', long = "recipient", value_name = "ADDRESS", conflicts_with_all = ["original_recipient_environment_variable", "local_domain_environment"])]
+    recipient_options: Vec<String>,
+
+    /// Recipient email addresses, like --recipient
+    #[arg(value_name = "RECIPIENT", conflicts_with_all = ["original_recipient_environment_variable", "local_domain_environment"])]
+    recipient_arguments: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+/// Things to do other than delivering a message.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Train the classifier from the messages already sorted into the Maildir's folders
+    Train,
+
+    /// Print the classifier's guesses for a message read from stdin
+    Classify,
+
+    /// Print suggested mailbox tables for the config file, based on how the mail in the Maildir's folders is sorted
+    Suggest {
+        /// Only suggest senders, lists and recipients seen in at least this many of a folder's messages
+        #[arg(long, value_name = "COUNT", default_value_t = 3)]
+        min_messages: usize
+    },
+
+    /// Remove files left in the Maildir's tmp directories by interrupted deliveries, once they're 36 hours old
+    Clean,
+
+    /// Recreate missing cur, new and tmp directories in the Maildir's folders, and give their owner back access to them
+    Repair,
+
+    /// Remove or archive the messages in mailboxes with expire_after that are older than it allows
+    Expire,
+
+    /// Move messages older than archive_after from the archive_folders into dated archive folders
+    Archive,
+
+    /// Train the spam filter on the messages moved into or out of the junk_folder since it last learned
+    Learn,
+
+    /// Repoint links to messages in the canonical_folder whose flags have changed, and remove links to deleted ones
+    Relink
 }
 
 //
 // Config file
 //
 
-type ConfigToml = HashMap<String, ConfigMailbox>;
+/// The top-level `[sortmail]` table holds global options, the
+/// `[catchall]` table holds per-domain catch-all folders and the
+/// `[maildirs]` table holds per-domain root Maildirs; every other table
+/// configures a mailbox. Mailboxes are kept in the order they
+/// appear in the file, since that's the order their rules are tried.
+#[derive(Deserialize, Debug)]
+struct ConfigToml {
+    #[serde(default)]
+    sortmail: ConfigGlobal,
+
+    #[serde(default)]
+    catchall: HashMap<String, ConfigCatchall>,
+
+    #[serde(default)]
+    maildirs: HashMap<String, String>,
+
+    #[serde(flatten)]
+    mailboxes: IndexMap<String, ConfigMailbox>
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ConfigGlobal {
+    /// Folder template for plus-addressed mail that no mailbox claims
+    plus_folder: Option<String>,
+
+    /// Folder template for mailing list mail that no mailbox claims
+    list_folder: Option<String>,
+
+    /// Characters that separate a recipient's local part from its
+    /// extension (default: `+`)
+    recipient_delimiter: Option<String>,
+
+    /// Minimum total score for a mailbox to win by scoring (default: 1)
+    score_threshold: Option<f64>,
+
+    /// Default for mailboxes that don't set `case_sensitive`
+    #[serde(default)]
+    case_sensitive: bool,
+
+    /// Ignore dots in local parts when looking up exact addresses, as
+    /// Gmail does
+    #[serde(default)]
+    ignore_local_part_dots: bool,
+
+    /// Ignore address extensions in exact `addresses` entries, so an
+    /// entry with a `+tag` covers every tag
+    #[serde(default)]
+    ignore_extensions: bool,
+
+    /// Networks of our own relays, whose `Received:` headers are
+    /// looked past when finding the message's source IP
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    trusted_relays: Vec<String>,
+
+    /// Seconds to wait for DNSBL answers before giving up (default: 2)
+    dnsbl_timeout: Option<f64>,
+
+    /// Keep track of delivered Message-IDs and don't deliver a message
+    /// with the same Message-ID again
+    #[serde(default)]
+    dedupe: bool,
+
+    /// Keep track of the content hashes of delivered messages and don't
+    /// deliver a byte-identical copy again
+    #[serde(default)]
+    dedupe_content: bool,
+
+    /// Folder for duplicate messages when `dedupe` or `dedupe_content`
+    /// is on; they're discarded if this isn't set
+    duplicate_folder: Option<String>,
+
+    /// Aliases file to expand recipients through
+    aliases_file: Option<PathBuf>,
+
+    /// File replies that no mailbox claims into the folder their parent
+    /// message was delivered to
+    #[serde(default)]
+    follow_threads: bool,
+
+    /// Folder for machine-generated messages that no mailbox claims
+    automated_folder: Option<String>,
+
+    /// Folder that every stored message is also linked into
+    all_mail_folder: Option<String>,
+
+    /// Folder that holds the only copy of each message, which other
+    /// folders link to
+    canonical_folder: Option<String>,
+
+    /// How other folders link to `canonical_folder` (default: `symlink`)
+    #[serde(default)]
+    folder_links: LinkKind,
+
+    /// Folders that `sortmail archive` moves old messages out of
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    archive_folders: Vec<String>,
+
+    /// Age at which `sortmail archive` moves messages (default: a year)
+    #[serde(default, deserialize_with = "deserialize_age")]
+    archive_after: Option<Duration>,
+
+    /// Folder template that `sortmail archive` moves messages to
+    /// (default: `Archive.%Y.%m`)
+    archive_folder: Option<String>,
+
+    /// Folder that spam is sorted into, for `spam_learner`
+    junk_folder: Option<String>,
+
+    /// Spam filter that learns from the mail sorted into (or rescued
+    /// from) `junk_folder`
+    spam_learner: Option<SpamLearner>,
+
+    /// Folders that messages moved out of `junk_folder` into aren't
+    /// learned as ham from
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    spam_learner_ignore_folders: Vec<String>,
+
+    /// Command (and arguments) that picks the folder for a message
+    lookup_command: Option<Vec<String>>,
+
+    /// Headers whose values are passed to `lookup_command`
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    lookup_headers: Vec<String>,
+
+    /// Sieve script that sorts mail instead of or after the mailboxes
+    sieve_script: Option<PathBuf>,
+
+    /// When `sieve_script` runs (default: `instead`)
+    #[serde(default)]
+    sieve_mode: SieveMode,
+
+    /// Seconds to let `lookup_command` run before giving up (default: 10)
+    lookup_timeout: Option<f64>,
+
+    /// Add an `X-Sortmail-Guess:` header with the classifier's guess to
+    /// messages that no mailbox claims
+    #[serde(default)]
+    classifier: bool,
+
+    /// Folders the classifier isn't trained on, and that no mailboxes
+    /// are suggested for
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    classifier_ignore_folders: Vec<String>,
+
+    /// SMTP server that `forward_to` mail is relayed through
+    smarthost: Option<String>,
+
+    /// Port of the smarthost (default: 25, or 465 with TLS and 587 with
+    /// STARTTLS)
+    smarthost_port: Option<u16>,
+
+    /// How the connection to the smarthost is secured (default: `none`)
+    smarthost_security: Option<Security>,
+
+    /// Credentials for AUTH PLAIN, if the smarthost wants them
+    smarthost_username: Option<String>,
+    smarthost_password: Option<String>,
+
+    /// Seconds to wait for the smarthost before giving up (default: 30)
+    smarthost_timeout: Option<f64>,
+
+    /// IMAP server that `imap_folder` mail is appended to
+    imap_host: Option<String>,
+
+    /// Port of the IMAP server (default: 993, or 143 without TLS)
+    imap_port: Option<u16>,
+
+    /// How the connection to the IMAP server is secured (default: `tls`)
+    #[serde(default)]
+    imap_security: Security,
+
+    /// Credentials for the IMAP server
+    imap_username: Option<String>,
+    imap_password: Option<String>,
+
+    /// Seconds to wait for the IMAP server before giving up (default:
+    /// 30)
+    imap_timeout: Option<f64>,
+
+    /// Bucket that `s3_folder` mail is stored in
+    s3_bucket: Option<String>,
+
+    /// The bucket's region (default: `us-east-1`)
+    s3_region: Option<String>,
+
+    /// URL of the S3 API (default: AWS's own, for `s3_region`)
+    s3_endpoint: Option<String>,
+
+    /// Address the bucket as `endpoint/bucket` instead of
+    /// `bucket.endpoint`
+    #[serde(default)]
+    s3_path_style: bool,
+
+    /// Credentials for the bucket (default: from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`)
+    s3_access_key: Option<String>,
+    s3_secret_key: Option<String>,
+
+    /// Seconds to wait for S3 before giving up (default: 30)
+    s3_timeout: Option<f64>,
+
+    /// Seconds to let a `pipe` command run before giving up (default: 60)
+    pipe_timeout: Option<f64>,
+
+    /// File whose contents are sent as an automatic reply to senders
+    vacation_file: Option<PathBuf>,
+
+    /// Subject of automatic replies (default: `Auto: {subject}`)
+    vacation_subject: Option<String>,
+
+    /// From: address of automatic replies (default: the recipient)
+    vacation_from: Option<String>,
+
+    /// Days before the same sender is replied to again (default: 7)
+    vacation_days: Option<f64>,
+
+    /// The recipient's other addresses, which mail can name instead of
+    /// the one it was delivered to
+    #[serde(default, deserialize_with = "deserialize_normalized_addresses_separated_by_newlines")]
+    vacation_addresses: Vec<String>,
+
+    /// Folders whose messages are stored in `cur` rather than `new`, so
+    /// that they never show up as new mail
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    cur_folders: Vec<String>,
+
+    /// Header that mailboxes' `labels` are added in (default: `X-Label`)
+    label_header: Option<String>,
+
+    /// Add `X-Sortmail-Rule:` and `X-Sortmail-Version:` headers to
+    /// stored messages
+    #[serde(default)]
+    trace_headers: bool,
+
+    /// Add `Return-Path:` and `Delivered-To:` headers to stored messages,
+    /// as a local delivery agent does
+    #[serde(default)]
+    envelope_headers: bool,
+
+    /// How folders are laid out in the root Maildir (default:
+    /// `maildir++`)
+    #[serde(default)]
+    folder_layout: Layout,
+
+    /// Prefix for every folder's name on disk, like `INBOX.`
+    #[serde(default)]
+    folder_prefix: String,
+
+    /// Config file, relative to each user's home directory, that's used
+    /// instead of this one for users who have it, in multi-user delivery
+    user_config: Option<PathBuf>,
+
+    /// Root Maildir, with `%n`, `%d` and `%u` filled in from the
+    /// recipient (default: $HOME/Maildir)
+    root_maildir: Option<String>,
+
+    /// Add `,W=` with the message's size with CRLF line endings to
+    /// stored messages' filenames
+    #[serde(default)]
+    rfc822_size: bool,
+
+    /// Sync stored messages' directories to disk before exiting
+    #[serde(default)]
+    durable: bool,
+
+    /// Permissions for stored messages, like `"0600"`, instead of the
+    /// umask's
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    file_mode: Option<u32>,
+
+    /// Permissions for directories created for folders, like `"0700"`
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    directory_mode: Option<u32>,
+
+    /// Maildir++ quota on the root Maildir's size, recorded in its
+    /// `maildirsize` file
+    #[serde(default, deserialize_with = "deserialize_size")]
+    quota: Option<u64>,
+
+    /// Maildir++ quota on the number of messages in the root Maildir
+    quota_messages: Option<u64>,
+
+    /// What to do with a message that would go over quota (default:
+    /// `tempfail`)
+    #[serde(default)]
+    quota_full: QuotaFull,
+
+    /// Folder for messages that would go over quota, with `quota_full =
+    /// "overflow"`
+    quota_overflow_folder: Option<String>,
+
+    /// Folder that the raw message is stored in if it can't be sorted
+    /// or delivered
+    quarantine_folder: Option<String>,
+
+    /// Exit code once a message has been quarantined (default: 0)
+    quarantine_exit_code: Option<i32>,
+
+    /// IMAP server whose subscription list folders that sortmail
+    /// creates are added to
+    subscribe_new_folders: Option<Subscriptions>,
+
+    /// Which date fills in date placeholders in folder names (default:
+    /// `header`)
+    #[serde(default)]
+    folder_date: FolderDate,
+
+    /// age recipient or OpenPGP key that stored messages are encrypted
+    /// to
+    encrypt_to: Option<String>,
+
+    /// Seconds to let `age` or `gpg` encrypt a message (default: 60)
+    encrypt_timeout: Option<f64>,
+
+    /// Deliver to Maildir folders with `notmuch insert`
+    #[serde(default)]
+    notmuch: bool,
+
+    /// Command run after each message is stored
+    post_deliver: Option<String>,
+
+    /// Have Dovecot index Maildir folders as messages are stored in them
+    #[serde(default)]
+    doveadm_index: bool,
+
+    /// Repair Maildir folders before storing messages in them
+    #[serde(default)]
+    repair_folders: bool
+}
 
 #[derive(Deserialize, Debug)]
 struct ConfigMailbox {
-    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    #[serde(default)]
+    priority: i64,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
     addresses: Vec<String>,
 
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_addresses: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    glob_addresses: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_normalized_domains_separated_by_newlines")]
+    domains: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    local_parts: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    exclude_addresses: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    exclude_re: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_normalized_addresses_separated_by_newlines")]
+    senders: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_senders: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_normalized_addresses_separated_by_newlines")]
+    from_addresses: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_from: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_normalized_addresses_separated_by_newlines")]
+    reply_to_addresses: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_reply_to: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    address_book: Vec<String>,
+
     #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
-    re_addresses: Vec<String>
-}
+    list_ids: Vec<String>,
 
-fn deserialize_email_addresses_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
-    let s = String::deserialize(d)?;
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_list_ids: Vec<String>,
 
-    Ok(s.split("\n")
-       .map(|addr| addr.trim().to_lowercase())
-       .filter(|addr| !addr.is_empty())
-       .collect())
-}
+    #[serde(default, deserialize_with = "deserialize_normalized_domains_separated_by_newlines")]
+    received_origins: Vec<String>,
 
-//
-// Address map
-//
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_received_origin: Vec<String>,
 
-#[derive(Debug)]
-struct AddressMap {
-    exact_address_to_mailbox_name: HashMap<String, Rc<String>>,
-    address_regexset_to_mailbox_name: Vec<(RegexSet, Rc<String>)>
-}
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    source_cidrs: Vec<String>,
 
-impl AddressMap {
-    /// Load config_file as a TOML file containing a mapping of email
-    /// addresses to Maildir mailboxes.
-    ///
-    /// Input file should contain tables with a single `addresses` key
-    /// containing newline-separated email addresses, like:
-    ///
-    /// [MailboxName]
-    /// addresses = """
-    /// address1@example.com
-    /// address2@example.com
-    /// """
-    /// re_addresses = """
-    /// ^local_part@
-    /// @things.example.com$
-    /// """
-    ///
-    /// Return the mapping of each email address to the Maildir mailbox
-    /// name it should be sorted into.
-    fn from_file(config_file: &Path) -> Result<AddressMap> {
-        let contents = std::fs::read_to_string(config_file)
-            .with_context(|| format!("Error opening config file {}", config_file.display()))?;
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    dnsbl: Vec<String>,
 
-        let config: ConfigToml = toml::from_str(&contents)
-            .with_context(|| format!("Error parsing config file {}", config_file.display()))?;
+    #[serde(default)]
+    spam_score_at_least: Option<f64>,
 
-        let zipped_addresses_result: Result<Vec<_>> = config
-            .into_iter()
-            .map(|(mailbox_name_string, mailbox_config)| {
-                let mailbox_name = Rc::new(mailbox_name_string);
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_body: Vec<String>,
 
-                let exact_address_to_mailbox_name: Vec<(_, _)> = mailbox_config
-                    .addresses
-                    .into_iter()
-                    .map(|address| (address, Rc::clone(&mailbox_name)))
-                    .collect();
+    #[serde(default)]
+    has_attachment: Option<bool>,
 
-                let regexset_to_mailbox_name_result = match mailbox_config.re_addresses.is_empty() {
-                    true => Ok(None),
-                    false => RegexSet::new(mailbox_config.re_addresses)
-                        .context("Error parsing regular expressions")
-                        .map(|set| Some((set, Rc::clone(&mailbox_name))))
-                };
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    attachment_types: Vec<String>,
 
-                regexset_to_mailbox_name_result.map(|rstmn| (exact_address_to_mailbox_name, rstmn))
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    re_attachment_names: Vec<String>,
 
-            }).collect();
+    #[serde(default)]
+    calendar: Option<bool>,
 
-        let (exact_address_mailbox_name_lists, address_regexset_maybe_mailbox_name): (Vec<_>, Vec<Option<(_, _)>>) = zipped_addresses_result?.into_iter().unzip();
+    #[serde(default)]
+    encrypted: Option<bool>,
 
-        let exact_address_to_mailbox_name: HashMap<_, _> = exact_address_mailbox_name_lists.into_iter().flatten().collect();
-        let address_regexset_to_mailbox_name: Vec<(_, _)> = address_regexset_maybe_mailbox_name.into_iter().flatten().collect();
+    #[serde(default)]
+    automated: Option<bool>,
 
-        Ok(AddressMap {
-            exact_address_to_mailbox_name: exact_address_to_mailbox_name,
-            address_regexset_to_mailbox_name: address_regexset_to_mailbox_name
-        })
-    }
+    #[serde(default)]
+    dsn: Option<bool>,
 
-    fn mailbox_name_for_address(&self, address: &str) -> Option<&str> {
-        if let Some(mailbox_name) = self.exact_address_to_mailbox_name.get(address) {
-            return Some(mailbox_name);
-        }
+    #[serde(default)]
+    suspicious_from: Option<bool>,
 
-        let matching_regex = self.address_regexset_to_mailbox_name.iter().find(
-            |(ref re, _)| re.is_match(address)
-        );
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    importance: Vec<String>,
 
-        matching_regex.map(|item| item.1.as_str())
-    }
-}
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    language: Vec<String>,
 
-//
-// Mailbox delivery
-//
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    calendar_methods: Vec<String>,
 
-fn get_normalized_original_recipient_email_address(args: &Args) -> Result<String> {
-    let env_variable: &str = match args.original_recipient_environment_variable {
-        Some(ref name) => name,
-        None => "ORIGINAL_RECIPIENT"
-    };
+    #[serde(default, deserialize_with = "deserialize_size")]
+    min_size: Option<u64>,
 
-    Ok(env::var(env_variable)
-       .with_context(|| format!("Missing {} environment variable for recipient email address", env_variable))?
-       .to_lowercase()
-    )
-}
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_size: Option<u64>,
 
+    #[serde(default)]
+    min_recipients: Option<usize>,
 
-/// Load an email message from stdin and the environment, and deliver
-/// it to the right Maildir mailbox based on the mappings detailed in
-/// the file at `args.config`.
-fn sort_message_from_stdin(args: &Args) -> Result<()> {
-    let mut maildir = match args.override_root_maildir {
-        Some(ref path) => PathBuf::from(path),
-        None => {
-            let homedir = env::var("HOME")
-                .context("Unable to find HOME environment variable")?;
-            let mut path = PathBuf::from(homedir);
-            path.push("Maildir");
-            path
-        }
-    };
+    #[serde(default)]
+    max_recipients: Option<usize>,
 
-    let mappings = AddressMap::from_file(&args.config)
-        .with_context(|| format!("Error loading config file {}", args.config.display()))?;
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    auth_results: Vec<String>,
 
-    if args.print_address_map {
-        dbg!(&mappings);
-    }
+    #[serde(default, deserialize_with = "deserialize_email_addresses_separated_by_newlines")]
+    trusted_authserv_ids: Vec<String>,
 
+    #[serde(default, deserialize_with = "deserialize_header_patterns_separated_by_newlines")]
+    headers: HashMap<String, Vec<String>>,
 
-    // Save to maildir
+    #[serde(default)]
+    when: Option<String>,
 
-    let original_recipient_email_address = get_normalized_original_recipient_email_address(args)?;
+    #[serde(default)]
+    score: Vec<ConfigScore>,
 
-    if let Some(mailbox_name) = mappings.mailbox_name_for_address(&original_recipient_email_address) {
-        maildir.push(format!(".{mailbox_name}"));
-    }
+    #[serde(default)]
+    score_threshold: Option<f64>,
 
-    println!(
-        "Recipient {original_recipient_email_address}: Deliver to {}{}",
-        maildir.display(),
-        match args.dry_run {
-            true => " (dry run, no actual delivery will be performed)",
-            false => ""
-        }
-    );
+    #[serde(default)]
+    folder: Option<String>,
 
-    let mailbox = Maildir::from(maildir);
+    #[serde(default)]
+    plus_folder: Option<String>,
 
-    let incoming_message_bytes: Box<[u8]> = stdin()
-        .bytes()
-        .collect::<Result<_, _>>()
-        .context("Error loading message data from stdin")?;
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    copy_to: Vec<String>,
 
-    if incoming_message_bytes.is_empty() {
-        return Err(Box::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
-            .context("Empty incoming message data");
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    flags: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    keywords: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    labels: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    remove_headers: Vec<String>,
+
+    #[serde(default)]
+    rewrite_headers: Vec<ConfigRewriteHeader>,
+
+    #[serde(default)]
+    mbox: Option<PathBuf>,
+
+    #[serde(default)]
+    mh: Option<PathBuf>,
+
+    #[serde(default)]
+    remote_maildir: Option<String>,
+
+    #[serde(default)]
+    imap_folder: Option<String>,
+
+    #[serde(default)]
+    s3_folder: Option<String>,
+
+    #[serde(default)]
+    compress: Option<CompressionFormat>,
+
+    #[serde(default, deserialize_with = "deserialize_size")]
+    compress_min_size: Option<u64>,
+
+    #[serde(default)]
+    detach_attachments: Option<PathBuf>,
+
+    #[serde(default, deserialize_with = "deserialize_size")]
+    detach_min_size: Option<u64>,
+
+    #[serde(default)]
+    detach_url: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_age")]
+    expire_after: Option<Duration>,
+
+    #[serde(default)]
+    expire_to: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_patterns_separated_by_newlines")]
+    forward_to: Vec<String>,
+
+    #[serde(default)]
+    keep: Option<bool>,
+
+    #[serde(default)]
+    pipe: Option<String>,
+
+    #[serde(default)]
+    pipe_failure: PipeFailure,
+
+    #[serde(default)]
+    post_deliver: Option<String>,
+
+    #[serde(default)]
+    discard: bool,
+
+    #[serde(default)]
+    reject: Option<String>,
+
+    #[serde(default)]
+    reject_code: Option<i32>,
+
+    #[serde(default)]
+    case_sensitive: Option<bool>,
+
+    #[serde(default, rename = "continue")]
+    continue_matching: bool
+}
+
+/// A `[catchall."example.net"]` table.
+#[derive(Deserialize, Debug)]
+struct ConfigCatchall {
+    folder: String
+}
+
+/// A weighted pattern in a mailbox's `[[MailboxName.score]]` list.
+#[derive(Deserialize, Debug)]
+struct ConfigScore {
+    when: String,
+    weight: f64
+}
+
+/// A header rewrite in a mailbox's `[[MailboxName.rewrite_headers]]`
+/// list.
+#[derive(Deserialize, Debug)]
+struct ConfigRewriteHeader {
+    header: String,
+    pattern: String,
+
+    #[serde(default)]
+    replacement: String
+}
+
+/// List-valued keys can be written either as a newline-separated
+/// string (handy for long lists in a multi-line string) or as a TOML
+/// array of strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringList {
+    SeparatedByNewlines(String),
+    Array(Vec<String>)
+}
+
+impl StringList {
+    /// Return the trimmed, non-empty entries in the list.
+    fn into_entries(self) -> Vec<String> {
+        let entries = match self {
+            StringList::SeparatedByNewlines(s) => s.split('\n').map(str::to_string).collect(),
+            StringList::Array(entries) => entries
+        };
+
+        entries
+            .into_iter()
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect()
     }
+}
 
-    if !args.dry_run {
-        mailbox
-            .store_new(&incoming_message_bytes)
-            .context("Error saving message to Maildir")?;
+fn deserialize_email_addresses_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(StringList::deserialize(d)?
+       .into_entries()
+       .into_iter()
+       .map(|addr| addr.to_lowercase())
+       .collect())
+}
+
+fn deserialize_normalized_addresses_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(StringList::deserialize(d)?
+       .into_entries()
+       .iter()
+       .map(|addr| address::normalize_address(addr))
+       .collect())
+}
+
+fn deserialize_normalized_domains_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(StringList::deserialize(d)?
+       .into_entries()
+       .iter()
+       .map(|domain| address::normalize_domain(domain))
+       .collect())
+}
+
+fn deserialize_patterns_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(StringList::deserialize(d)?.into_entries())
+}
+
+/// Sizes can be written as a number of bytes, or as a string with a
+/// `K`, `M` or `G` suffix (powers of 1024), like `"10M"`.
+fn deserialize_size<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        WithSuffix(String)
     }
 
-    Ok(())
+    let size_string = match Size::deserialize(d)? {
+        Size::Bytes(bytes) => return Ok(Some(bytes)),
+        Size::WithSuffix(s) => s
+    };
+
+    let trimmed = size_string.trim();
+    let (number, multiplier) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1024),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1024 * 1024),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024),
+        _ => (trimmed, 1)
+    };
+
+    let n = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| serde::de::Error::custom(format!("invalid size {size_string:?}")))?;
+
+    n.checked_mul(multiplier)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("size {size_string:?} is too big")))
 }
 
+/// Ages are written as a number with a unit, like `"30d"`: `m` for
+/// minutes, `h` for hours, `d` for days or `w` for weeks. A bare number
+/// is a number of days.
+fn deserialize_age<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Age {
+        Days(u64),
+        WithUnit(String)
+    }
 
-fn main() -> Result<()> {
+    let age_string = match Age::deserialize(d)? {
+        Age::Days(days) => {
+            return days
+                .checked_mul(24 * 60 * 60)
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .ok_or_else(|| serde::de::Error::custom(format!("age of {days} days is too long")));
+        },
+        Age::WithUnit(s) => s
+    };
+
+    let trimmed = age_string.trim();
+    let (number, seconds) = match trimmed.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('m') => (&trimmed[..trimmed.len() - 1], 60),
+        Some('h') => (&trimmed[..trimmed.len() - 1], 60 * 60),
+        Some('d') => (&trimmed[..trimmed.len() - 1], 24 * 60 * 60),
+        Some('w') => (&trimmed[..trimmed.len() - 1], 7 * 24 * 60 * 60),
+        _ => (trimmed, 24 * 60 * 60)
+    };
+
+    let n = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| serde::de::Error::custom(format!("invalid age {age_string:?}, expected a number of minutes, hours, days or weeks, like \"30d\"")))?;
+
+    n.checked_mul(seconds)
+        .map(|secs| Some(Duration::from_secs(secs)))
+        .ok_or_else(|| serde::de::Error::custom(format!("age {age_string:?} is too long")))
+}
+
+/// Permissions can be written as a string of octal digits, like
+/// `"0600"`, or as a TOML octal integer, like `0o600`.
+fn deserialize_mode<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        Octal(String)
+    }
+
+    let mode = match Mode::deserialize(d)? {
+        Mode::Number(mode) => mode,
+        Mode::Octal(s) => u32::from_str_radix(s.trim(), 8)
+            .map_err(|_| serde::de::Error::custom(format!("invalid mode {s:?}, expected octal digits like \"0600\"")))?
+    };
+
+    match mode <= 0o7777 {
+        true => Ok(Some(mode)),
+        false => Err(serde::de::Error::custom(format!("invalid mode {mode:o}, expected at most 7777")))
+    }
+}
+
+fn deserialize_header_patterns_separated_by_newlines<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<String, Vec<String>>, D::Error> {
+    let header_table = HashMap::<String, StringList>::deserialize(d)?;
+
+    Ok(header_table
+       .into_iter()
+       .map(|(header_name, patterns)| (header_name.trim().to_lowercase(), patterns.into_entries()))
+       .collect())
+}
+
+//
+// Address map
+//
+
+/// Patterns that route a message into a single mailbox.
+#[derive(Debug)]
+struct MailboxRule {
+    mailbox_name: String,
+
+    /// Where the rule comes in the order that rules are tried, and
+    /// relative to the `lookup_command`, which comes in at 0
+    priority: i64,
+
+    /// The rule has exact `addresses`, which are looked up in the
+    /// address map rather than matched here
+    has_addresses: bool,
+    address_regexset: Option<RegexSet>,
+
+    /// The `re_addresses` patterns compiled individually, for their
+    /// capture groups; only needed when `folder` refers to them
+    address_regexes: Vec<Regex>,
+    address_glob_regexset: Option<RegexSet>,
+    domains: HashSet<String>,
+    local_parts: HashSet<String>,
+    exclude_addresses: HashSet<String>,
+    exclude_regexset: Option<RegexSet>,
+    senders: HashSet<String>,
+    sender_regexset: Option<RegexSet>,
+    from_addresses: HashSet<String>,
+    from_regexset: Option<RegexSet>,
+    reply_to_addresses: HashSet<String>,
+    reply_to_regexset: Option<RegexSet>,
+
+    /// Addresses loaded from the `address_book` files
+    address_book: HashSet<String>,
+    list_ids: HashSet<String>,
+    list_id_regexset: Option<RegexSet>,
+    received_origins: HashSet<String>,
+    received_origin_regexset: Option<RegexSet>,
+    source_cidrs: Vec<IpNet>,
+
+    /// The global `trusted_relays`, for finding the source IP
+    trusted_relays: Rc<[IpNet]>,
+
+    dnsbl_zones: Vec<String>,
+
+    /// Every mailbox's `dnsbl` zones, which are all queried together
+    /// the first time any of them is needed
+    all_dnsbl_zones: Rc<[String]>,
+    dnsbl_timeout: Duration,
+    spam_score_at_least: Option<f64>,
+    body_regexset: Option<RegexSet>,
+    has_attachment: Option<bool>,
+    attachment_types: Vec<String>,
+    attachment_name_regexset: Option<RegexSet>,
+    calendar: Option<bool>,
+    calendar_methods: HashSet<String>,
+    encrypted: Option<bool>,
+    automated: Option<bool>,
+    dsn: Option<bool>,
+    suspicious_from: Option<bool>,
+    importance: HashSet<String>,
+    languages: HashSet<&'static str>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_recipients: Option<usize>,
+    max_recipients: Option<usize>,
+    auth_results: Vec<(String, String)>,
+    trusted_authserv_ids: HashSet<String>,
+    header_regexsets: Vec<(String, RegexSet)>,
+    when: Option<Expression>,
+    scores: Vec<(Expression, f64)>,
+    score_threshold: f64,
+    folder: Option<String>,
+    plus_folder: Option<String>,
+
+    /// Further folders that get a copy of the message
+    copy_to: Vec<String>,
+
+    /// Maildir info flags (like `FS`) that the message is stored in the
+    /// mailbox's folder with
+    flags: String,
+
+    /// IMAP keywords that the message is stored in the mailbox's folder
+    /// with
+    keywords: Vec<String>,
+
+    /// Labels added to the copy in the mailbox's folder, in the global
+    /// `label_header`
+    labels: Vec<String>,
+
+    /// Headers removed or rewritten in the copy in the mailbox's folder
+    header_edits: Vec<HeaderEdit>,
+
+    /// Where the message is stored instead of the mailbox's folder, from
+    /// `mbox`, `mh`, `remote_maildir`, `imap_folder` or `s3_folder`
+    store: Option<Rc<dyn MessageStore>>,
+
+    /// Compression for the copy in the mailbox's folder
+    compress: Option<Compression>,
+
+    /// Where big attachments are saved instead of in the stored message
+    detach: Option<Detach>,
+
+    /// Addresses the message is relayed to via the smarthost
+    forward_to: Vec<String>,
+
+    /// Store the message in the mailbox's folder, as well as
+    /// forwarding it
+    keep: bool,
+
+    /// Command the message is fed to, instead of storing it in the
+    /// mailbox's folder
+    pipe: Option<PipeCommand>,
+
+    /// Command run after the mailbox's messages are stored
+    post_deliver: Option<String>,
+
+    /// Age at which `sortmail expire` removes the messages in the
+    /// mailbox's folder, or moves them to `expire_to`
+    expire_after: Option<Duration>,
+    expire_to: Option<String>,
+
+    /// Drop the message instead of delivering it
+    discard: bool,
+
+    /// Refuse the message instead of delivering it
+    reject: Option<Rejection>,
+
+    /// Match the recipient keys against the original-case recipient
+    /// instead of ignoring case
+    case_sensitive: bool,
+
+    /// Keep looking for more matching rules after this one, delivering
+    /// a copy of the message for each
+    continue_matching: bool
+}
+
+impl MailboxRule {
+    /// Return true if the message passes the rule's gates and any of
+    /// its other conditions match it (see config-example.toml for
+    /// what each config key checks). A rule with nothing but gates
+    /// matches every message that passes them, and a rule with neither
+    /// matches nothing.
+    fn matches(&self, envelope: &Envelope, message: &IncomingMessage) -> bool {
+        match self.has_conditions() {
+            true => self.passes_gates(envelope, message) && self.any_condition_matches(envelope, message),
+            false => self.has_gates() && self.passes_gates(envelope, message)
+        }
+    }
+
+    /// Return true if the rule has any gates (see `passes_gates`).
+    fn has_gates(&self) -> bool {
+        self.calendar.is_some()
+            || !self.calendar_methods.is_empty()
+            || self.encrypted.is_some()
+            || self.automated.is_some()
+            || self.dsn.is_some()
+            || self.suspicious_from.is_some()
+    }
+
+    /// Return true if the message passes all of the rule's `calendar`,
+    /// `encrypted`, `automated`, `dsn` and `suspicious_from` gates,
+    /// which narrow down what its other conditions match rather than
+    /// matching by themselves.
+    fn passes_gates(&self, envelope: &Envelope, message: &IncomingMessage) -> bool {
+        if self.calendar.is_some() || !self.calendar_methods.is_empty() {
+            let calendar_matches = match message.calendar_method() {
+                Some(method) => self.calendar != Some(false)
+                    && (self.calendar_methods.is_empty() || self.calendar_methods.contains(method)),
+                None => self.calendar == Some(false)
+            };
+
+            if !calendar_matches {
+                return false;
+            }
+        }
+
+        self.encrypted.is_none_or(|encrypted| message.is_encrypted() == encrypted)
+            && self.automated.is_none_or(|automated| message.is_automated() == automated)
+            && self.dsn.is_none_or(|dsn| message.is_bounce() == dsn)
+            && self.suspicious_from.is_none_or(|suspicious_from| message.has_suspicious_from(envelope.sender.as_deref()) == suspicious_from)
+    }
+
+    /// Return true if the rule has conditions other than gates,
+    /// including `addresses` and `score` patterns, which are matched
+    /// elsewhere.
+    fn has_conditions(&self) -> bool {
+        self.has_addresses
+            || self.address_regexset.is_some()
+            || self.address_glob_regexset.is_some()
+            || !self.domains.is_empty()
+            || !self.local_parts.is_empty()
+            || !self.senders.is_empty()
+            || self.sender_regexset.is_some()
+            || !self.from_addresses.is_empty()
+            || self.from_regexset.is_some()
+            || !self.reply_to_addresses.is_empty()
+            || self.reply_to_regexset.is_some()
+            || !self.address_book.is_empty()
+            || !self.list_ids.is_empty()
+            || self.list_id_regexset.is_some()
+            || !self.received_origins.is_empty()
+            || self.received_origin_regexset.is_some()
+            || !self.source_cidrs.is_empty()
+            || !self.dnsbl_zones.is_empty()
+            || self.spam_score_at_least.is_some()
+            || self.body_regexset.is_some()
+            || self.has_attachment.is_some()
+            || !self.attachment_types.is_empty()
+            || self.attachment_name_regexset.is_some()
+            || !self.importance.is_empty()
+            || !self.languages.is_empty()
+            || self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.min_recipients.is_some()
+            || self.max_recipients.is_some()
+            || !self.auth_results.is_empty()
+            || !self.header_regexsets.is_empty()
+            || self.when.is_some()
+            || !self.scores.is_empty()
+    }
+
+    /// Return true if any of the rule's conditions other than gates
+    /// match the message.
+    fn any_condition_matches(&self, envelope: &Envelope, message: &IncomingMessage) -> bool {
+        let [recipient, _] = self.recipient_addresses(envelope);
+
+        if let Some(ref re) = self.address_regexset {
+            if re.is_match(recipient) {
+                return true;
+            }
+        }
+
+        if let Some(ref re) = self.address_glob_regexset {
+            if re.is_match(recipient) {
+                return true;
+            }
+        }
+
+        if !self.domains.is_empty() && self.domain_matches(&envelope.recipient) {
+            return true;
+        }
+
+        if !self.local_parts.is_empty() {
+            if let Some((local_part, _)) = recipient.rsplit_once('@') {
+                if self.local_parts.contains(local_part) {
+                    return true;
+                }
+            }
+        }
+
+        if let Some(ref sender) = envelope.sender {
+            if self.senders.contains(sender) {
+                return true;
+            }
+
+            if let Some(ref re) = self.sender_regexset {
+                if re.is_match(sender) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.from_addresses.is_empty() || self.from_regexset.is_some() {
+            let from_matches = message.header_addresses("From").iter().any(|from| {
+                self.from_addresses.contains(from)
+                    || self.from_regexset.as_ref().is_some_and(|re| re.is_match(from))
+            });
+
+            if from_matches {
+                return true;
+            }
+        }
+
+        if !self.reply_to_addresses.is_empty() || self.reply_to_regexset.is_some() {
+            let reply_to_matches = message.header_addresses("Reply-To").iter().any(|reply_to| {
+                self.reply_to_addresses.contains(reply_to)
+                    || self.reply_to_regexset.as_ref().is_some_and(|re| re.is_match(reply_to))
+            });
+
+            if reply_to_matches {
+                return true;
+            }
+        }
+
+        if !self.address_book.is_empty() {
+            let in_address_book = envelope.sender.iter().any(|sender| self.address_book.contains(sender))
+                || message.header_addresses("From").iter().any(|from| self.address_book.contains(from));
+
+            if in_address_book {
+                return true;
+            }
+        }
+
+        if !self.list_ids.is_empty() || self.list_id_regexset.is_some() {
+            let list_id_matches = message.list_id().is_some_and(|list_id| {
+                self.list_ids.contains(&list_id)
+                    || self.list_id_regexset.as_ref().is_some_and(|re| re.is_match(&list_id))
+            });
+
+            if list_id_matches {
+                return true;
+            }
+        }
+
+        if !self.received_origins.is_empty() || self.received_origin_regexset.is_some() {
+            let origin_matches = message.received_origin_hosts().iter().any(|host| {
+                hostname_in_domains(host, &self.received_origins)
+                    || self.received_origin_regexset.as_ref().is_some_and(|re| re.is_match(host))
+            });
+
+            if origin_matches {
+                return true;
+            }
+        }
+
+        if !self.source_cidrs.is_empty() {
+            if let Some(ip) = message.source_ip(&self.trusted_relays) {
+                if self.source_cidrs.iter().any(|cidr| cidr.contains(&ip)) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.dnsbl_zones.is_empty() {
+            let listings = message.dnsbl_listings(&self.trusted_relays, &self.all_dnsbl_zones, self.dnsbl_timeout);
+
+            if self.dnsbl_zones.iter().any(|zone| listings.contains(zone)) {
+                return true;
+            }
+        }
+
+        if let Some(threshold) = self.spam_score_at_least {
+            if message.spam_score().is_some_and(|score| score >= threshold) {
+                return true;
+            }
+        }
+
+        if let Some(ref re) = self.body_regexset {
+            if re.is_match(message.body_text()) {
+                return true;
+            }
+        }
+
+        if let Some(has_attachment) = self.has_attachment {
+            if message.attachments().is_empty() != has_attachment {
+                return true;
+            }
+        }
+
+        if !self.attachment_types.is_empty() {
+            let attachment_type_matches = message.attachments().iter().any(|attachment| {
+                self.attachment_types.iter().any(|mimetype| mimetype_matches(mimetype, &attachment.content_type))
+            });
+
+            if attachment_type_matches {
+                return true;
+            }
+        }
+
+        if let Some(ref re) = self.attachment_name_regexset {
+            let attachment_name_matches = message.attachments()
+                .iter()
+                .filter_map(|attachment| attachment.filename.as_deref())
+                .any(|filename| re.is_match(filename));
+
+            if attachment_name_matches {
+                return true;
+            }
+        }
+
+        if !self.importance.is_empty() && self.importance.contains(message.importance()) {
+            return true;
+        }
+
+        if !self.languages.is_empty() && message.language().is_some_and(|language| self.languages.contains(language)) {
+            return true;
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let size = message.size();
+
+            if self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max) {
+                return true;
+            }
+        }
+
+        if self.min_recipients.is_some() || self.max_recipients.is_some() {
+            let recipient_count = message.recipient_count();
+
+            if self.min_recipients.is_none_or(|min| recipient_count >= min) && self.max_recipients.is_none_or(|max| recipient_count <= max) {
+                return true;
+            }
+        }
+
+        if !self.auth_results.is_empty() && self.auth_results_match(message) {
+            return true;
+        }
+
+        let header_matches = self.header_regexsets.iter().any(
+            |(header_name, re)| message.header_values(header_name).any(|value| re.is_match(value))
+        );
+
+        if header_matches {
+            return true;
+        }
+
+        self.when.as_ref().is_some_and(|expression| expression.evaluate(envelope, message))
+    }
+
+    /// Return true if the message carries on to later mailboxes when
+    /// this rule's `pipe` command fails.
+    fn falls_through(&self) -> bool {
+        self.pipe.as_ref().is_some_and(|pipe| pipe.on_failure == PipeFailure::Continue)
+    }
+
+    /// Return the sum of the weights of the rule's `score` patterns
+    /// that match the message.
+    fn score(&self, envelope: &Envelope, message: &IncomingMessage) -> f64 {
+        self.scores
+            .iter()
+            .filter(|(expression, _)| expression.evaluate(envelope, message))
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    /// Return true if the recipient is vetoed by the `exclude_addresses`
+    /// or `exclude_re` patterns, in which case the rule doesn't apply
+    /// even if `matches` returns true.
+    fn excludes(&self, envelope: &Envelope) -> bool {
+        self.recipient_addresses(envelope)
+            .into_iter()
+            .any(|address| {
+                self.exclude_addresses.contains(address)
+                    || self.exclude_regexset.as_ref().is_some_and(|re| re.is_match(address))
+            })
+    }
+
+    /// Return the name of the folder this rule delivers to: the
+    /// mailbox name, or the `folder` template with `{1}`, `{2}`, ...
+    /// replaced by the (sanitized) capture groups of the `re_addresses`
+    /// pattern that matched the recipient. If no pattern with capture
+    /// groups matched (say, because the rule matched on another key),
+    /// the mailbox name is used, unless the template doesn't use any
+    /// capture groups.
+    fn folder_name(&self, envelope: &Envelope) -> String {
+        let [recipient, _] = self.recipient_addresses(envelope);
+
+        let captures = self.address_regexes
+            .iter()
+            .find_map(|re| re.captures(recipient).filter(|captures| captures.len() > 1));
+
+        match (&self.folder, captures) {
+            (Some(template), Some(captures)) => captures
+                .iter()
+                .enumerate()
+                .skip(1)
+                .fold(template.clone(), |folder, (i, capture)| folder.replace(
+                    &format!("{{{i}}}"),
+                    &sanitize_folder_name_component(capture.map_or("", |capture| capture.as_str()))
+                )),
+            (Some(template), None) if !has_capture_placeholders(template) => template.clone(),
+            _ => self.mailbox_name.clone()
+        }
+    }
+
+    /// Return the recipient and the recipient without its extension,
+    /// lowercased unless the rule is `case_sensitive`.
+    fn recipient_addresses<'a>(&self, envelope: &'a Envelope) -> [&'a String; 2] {
+        match self.case_sensitive {
+            true => [&envelope.original_case_recipient, &envelope.original_case_recipient_without_extension],
+            false => [&envelope.recipient, &envelope.recipient_without_extension]
+        }
+    }
+
+    /// Return true if the domain of `address` is listed in `domains`
+    /// (see `hostname_in_domains`).
+    fn domain_matches(&self, address: &str) -> bool {
+        match address.rsplit_once('@') {
+            Some((_, domain)) => hostname_in_domains(domain, &self.domains),
+            None => false
+        }
+    }
+
+    /// Return true if a trusted `Authentication-Results:` header
+    /// reports one of the `auth_results` method/result pairs.
+    ///
+    /// When no `trusted_authserv_ids` are configured, only the topmost
+    /// header is trusted, since it's the one added by our own MX;
+    /// headers further down could have been forged by the sender.
+    fn auth_results_match(&self, message: &IncomingMessage) -> bool {
+        let all_results = message.authentication_results();
+
+        let trusted_results: Vec<_> = match self.trusted_authserv_ids.is_empty() {
+            true => all_results.iter().take(1).collect(),
+            false => all_results
+                .iter()
+                .filter(|results| self.trusted_authserv_ids.contains(&results.authserv_id))
+                .collect()
+        };
+
+        trusted_results
+            .iter()
+            .flat_map(|results| results.results.iter())
+            .any(|result| self.auth_results.contains(result))
+    }
+}
+
+/// Return true if `hostname` is listed in `domains`, or one of its
+/// parent domains is listed with a leading dot (`.example.org` covers
+/// `lists.example.org`, but not `example.org` itself).
+fn hostname_in_domains(hostname: &str, domains: &HashSet<String>) -> bool {
+    if domains.contains(hostname) {
+        return true;
+    }
+
+    hostname
+        .match_indices('.')
+        .any(|(dot, _)| domains.contains(&hostname[dot..]))
+}
+
+/// Make a relative root Maildir template relative to the config file's
+/// directory. A leading `~/` is left for `expand_root_maildir_template`,
+/// since in multi-user delivery it's the recipient's home directory
+/// rather than ours.
+fn resolve_root_maildir_template(config_file: &Path, template: String) -> String {
+    match template.starts_with('~') || Path::new(&template).is_absolute() {
+        true => template,
+        false => resolve_config_path(config_file, Path::new(&template)).to_string_lossy().into_owned()
+    }
+}
+
+/// Look up the domain of `address` in a map keyed by domain, trying
+/// the domain itself and then each parent domain with a leading dot.
+fn get_for_domain<'a, T>(map: &'a HashMap<String, T>, address: &str) -> Option<&'a T> {
+    let (_, domain) = address.rsplit_once('@')?;
+
+    map.get(domain).or_else(|| domain
+        .match_indices('.')
+        .find_map(|(dot, _)| map.get(&domain[dot..])))
+}
+
+/// Resolve a path named in the config file: `~/` is the home
+/// directory, and relative paths are relative to the config file's
+/// directory.
+fn resolve_config_path(config_file: &Path, path: &Path) -> PathBuf {
+    if let Ok(relative_to_home) = path.strip_prefix("~") {
+        if let Ok(home) = env::var("HOME") {
+            return Path::new(&home).join(relative_to_home);
+        }
+    }
+
+    config_file.parent().unwrap_or(Path::new("")).join(path)
+}
+
+/// Parse networks written like `10.0.0.0/8` or `2001:db8::/32`; a bare
+/// address is taken as a network of just that address.
+fn parse_cidrs(cidrs: &[String]) -> Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| cidr
+            .parse::<IpNet>()
+            .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+            .with_context(|| format!("Invalid network {cidr:?}, expected an address or address/prefix-length")))
+        .collect()
+}
+
+/// Translate a glob pattern, where `*` matches any run of characters
+/// and `?` matches any single character, into an anchored regular
+/// expression.
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut literal = String::new();
+
+    for c in glob.chars() {
+        match c {
+            '*' | '?' => {
+                re.push_str(&regex::escape(&literal));
+                literal.clear();
+                re.push_str(match c {
+                    '*' => ".*",
+                    _ => "."
+                });
+            },
+            _ => literal.push(c)
+        }
+    }
+
+    re.push_str(&regex::escape(&literal));
+    re.push('$');
+    re
+}
+
+/// Compile `patterns` into a RegexSet, or return None if there are no
+/// patterns.
+fn build_regexset(patterns: &[String], case_insensitive: bool) -> Result<Option<RegexSet>> {
+    match patterns.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(RegexSetBuilder::new(patterns)
+            .case_insensitive(case_insensitive)
+            .build()?))
+    }
+}
+
+/// Compare a MIME type against a configured one, which may use a
+/// `type/*` wildcard for the subtype.
+fn mimetype_matches(pattern: &str, mimetype: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(toplevel) => mimetype.split('/').next() == Some(toplevel),
+        None => pattern == mimetype
+    }
+}
+
+/// Translate flag names like `Seen` and `Flagged` (or their letters,
+/// `S` and `F`) into a Maildir info flags string like `FS`, with the
+/// letters in ASCII order as the Maildir spec asks.
+fn parse_maildir_flags(names: &[String]) -> Result<String> {
+    const FLAGS: [(&str, char); 6] = [
+        ("draft", 'D'),
+        ("flagged", 'F'),
+        ("passed", 'P'),
+        ("replied", 'R'),
+        ("seen", 'S'),
+        ("trashed", 'T')
+    ];
+
+    let mut letters = names
+        .iter()
+        .map(|name| FLAGS
+            .iter()
+            .find(|(flag_name, letter)| name.eq_ignore_ascii_case(flag_name) || name.as_str() == letter.to_string())
+            .map(|(_, letter)| *letter)
+            .with_context(|| format!("Unknown Maildir flag {name:?}, expected Seen, Flagged, Replied, Passed, Draft or Trashed")))
+        .collect::<Result<Vec<_>>>()?;
+
+    letters.sort_unstable();
+    letters.dedup();
+
+    Ok(letters.into_iter().collect())
+}
+
+/// Make a value safe to use as part of a Maildir folder name: anything
+/// other than ASCII letters, digits, `-` and `_` becomes `_`, so that
+/// it can't add hierarchy levels (`.`) or escape the Maildir (`/`).
+fn sanitize_folder_name_component(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            true => c,
+            false => '_'
+        })
+        .collect()
+}
+
+/// Return true if a `folder` template uses capture groups, like `{1}`.
+fn has_capture_placeholders(template: &str) -> bool {
+    template
+        .split('{')
+        .skip(1)
+        .any(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Placeholders for the message's sender in folder names.
+const SENDER_PLACEHOLDERS: [&str; 2] = ["{from_domain}", "{from_localpart}"];
+
+/// Return true if `mailbox_name` has sender placeholders to fill in.
+fn has_sender_placeholders(mailbox_name: &str) -> bool {
+    SENDER_PLACEHOLDERS.iter().any(|placeholder| mailbox_name.contains(placeholder))
+}
+
+/// Fill in `{from_domain}` and `{from_localpart}` in `mailbox_name`
+/// with the (sanitized) parts of the message's `From:` address, or of
+/// the envelope sender if it has none, or with `unknown` if neither
+/// is there.
+fn fill_in_sender_placeholders(mailbox_name: &str, message: &IncomingMessage, sender: Option<&str>) -> String {
+    let from = message.header_addresses("From").into_iter().next()
+        .or_else(|| sender.map(str::to_lowercase))
+        .and_then(|address| address::split_address(&address))
+        .filter(|(local_part, domain)| !local_part.is_empty() && !domain.is_empty());
+
+    let (local_part, domain) = match from {
+        Some((local_part, domain)) => (sanitize_folder_name_component(&local_part), sanitize_folder_name_component(&domain)),
+        None => ("unknown".to_string(), "unknown".to_string())
+    };
+
+    mailbox_name
+        .replace("{from_domain}", &domain)
+        .replace("{from_localpart}", &local_part)
+}
+
+/// One delivery of a message, as decided by the address map.
+#[derive(Debug, Default)]
+struct DeliveryAction {
+    /// The folder to deliver to, or None for the root Maildir; for
+    /// deliveries that don't store the message, the mailbox that asked
+    /// for them
+    mailbox_name: Option<String>,
+
+    /// Create the folder if it doesn't exist yet
+    create_folder: bool,
+
+    /// No mailbox (or lookup command) chose this delivery
+    unclaimed: bool,
+
+    kind: DeliveryKind,
+
+    /// Command run after the message is stored, besides the global
+    /// `post_deliver`
+    post_deliver: Option<String>,
+
+    /// Only carry out this delivery if this many of the recipient's
+    /// earlier `pipe_failure = "continue"` pipes failed, since it's
+    /// where the message falls through to
+    after_failed_pipes: usize,
+
+    /// Maildir info flags to store the message with, in `cur` rather
+    /// than `new`, or empty to store it as a new message
+    flags: String,
+
+    /// IMAP keywords to store the message with, as Dovecot does
+    keywords: Vec<String>,
+
+    /// Labels to add to the stored message in a `label_header` header
+    labels: Vec<String>,
+
+    /// Changes to make to the stored message's headers
+    header_edits: Vec<HeaderEdit>,
+
+    /// Compress the message stored in the folder `mailbox_name`
+    compress: Option<Compression>,
+
+    /// Save the message's big attachments here, and store it without them
+    detach: Option<Detach>,
+
+    /// The mailbox whose rule chose this delivery, or the global option
+    /// that did (like `list_folder`, or `default` for the root Maildir
+    /// or catch-all) if no mailbox claimed the message
+    chosen_by: String
+}
+
+/// What a delivery does with the message.
+#[derive(Clone, Debug, Default)]
+enum DeliveryKind {
+    /// Store it in the folder `mailbox_name`
+    #[default]
+    Folder,
+
+    /// Store it here instead of in the folder `mailbox_name`
+    Store(Rc<dyn MessageStore>),
+
+    /// Relay it to this address via the smarthost
+    Forward(String),
+
+    /// Feed it to this command
+    Pipe(PipeCommand),
+
+    /// Drop it
+    Discard,
+
+    /// Refuse it, so that the MTA bounces it
+    Reject(Rejection)
+}
+
+/// Where a delivery puts the message, for spotting deliveries that
+/// would duplicate each other.
+#[derive(PartialEq, Eq, Hash)]
+enum Destination {
+    Folder(Option<String>),
+    Store(String),
+    Forward(String),
+    Pipe(String),
+    Discard,
+    Reject
+}
+
+impl DeliveryAction {
+    fn destination(&self) -> Destination {
+        match self.kind {
+            DeliveryKind::Folder => Destination::Folder(self.mailbox_name.clone()),
+            DeliveryKind::Store(ref store) => Destination::Store(store.location()),
+            DeliveryKind::Forward(ref address) => Destination::Forward(address.clone()),
+            DeliveryKind::Pipe(ref pipe) => Destination::Pipe(pipe.command.clone()),
+            DeliveryKind::Discard => Destination::Discard,
+            DeliveryKind::Reject(_) => Destination::Reject
+        }
+    }
+
+    /// Return true if this delivery stores the message, in a folder or
+    /// another store.
+    fn stores_message(&self) -> bool {
+        matches!(self.kind, DeliveryKind::Folder | DeliveryKind::Store(_))
+    }
+}
+
+/// Why, and with what exit code, a message is refused.
+#[derive(Clone, Debug)]
+struct Rejection {
+    reason: String,
+
+    /// A sysexits.h code, which the MTA turns into a bounce
+    exit_code: i32
+}
+
+#[derive(Debug)]
+struct AddressMap {
+    exact_address_to_mailbox_rule: HashMap<String, Rc<MailboxRule>>,
+
+    /// Exact addresses from `case_sensitive` mailboxes, in their
+    /// original case
+    case_sensitive_exact_address_to_mailbox_rule: HashMap<String, Rc<MailboxRule>>,
+    mailbox_rules: Vec<Rc<MailboxRule>>,
+    plus_folder: Option<String>,
+    list_folder: Option<String>,
+
+    /// Lowercased domain (with a leading dot for subdomains) to
+    /// catch-all folder name
+    catchall_folders: HashMap<String, String>,
+
+    /// Root Maildir template, and lowercased domain (with a leading dot
+    /// for subdomains) to root Maildir template
+    root_maildir: Option<String>,
+    domain_root_maildirs: HashMap<String, String>,
+
+    /// Characters that separate a recipient's local part from its
+    /// extension
+    recipient_delimiter: String,
+
+    /// Normalizations applied to both sides of exact address lookups
+    /// (see `exact_address_key`)
+    ignore_local_part_dots: bool,
+    ignore_extensions: bool,
+
+    dedupe: bool,
+    dedupe_content: bool,
+    duplicate_folder: Option<String>,
+    follow_threads: bool,
+    automated_folder: Option<String>,
+    all_mail_folder: Option<String>,
+    canonical_folder: Option<String>,
+    folder_links: LinkKind,
+    archive_folders: Vec<String>,
+    archive: Archive,
+    junk_folder: Option<String>,
+    spam_learner: Option<SpamLearner>,
+    spam_learner_ignore_folders: Vec<String>,
+    aliases: Option<Aliases>,
+    lookup_command: Option<LookupCommand>,
+    sieve_script: Option<SieveScript>,
+    sieve_mode: SieveMode,
+    classifier: bool,
+    classifier_ignore_folders: Vec<String>,
+    smarthost: Option<Smarthost>,
+    vacation: Option<Vacation>,
+    cur_folders: Vec<String>,
+    label_header: String,
+    trace_headers: bool,
+    envelope_headers: bool,
+
+    /// Where folders are found in the root Maildir
+    namespace: Namespace,
+
+    /// Each user's own config file, relative to their home directory,
+    /// for multi-user delivery
+    user_config: Option<PathBuf>,
+
+    /// How messages are written to Maildir folders
+    store_options: StoreOptions,
+
+    /// The root Maildir's quota, if one is set
+    quota: Option<Quota>,
+    quota_full: QuotaFull,
+    quota_overflow_folder: Option<String>,
+
+    /// Folder that the raw message is stored in if it can't be sorted
+    /// or delivered, and the exit code after that
+    quarantine_folder: Option<String>,
+    quarantine_exit_code: Option<i32>,
+
+    subscribe_new_folders: Option<Subscriptions>,
+    folder_date: FolderDate,
+
+    /// Key that stored messages are encrypted to, if any
+    encryption_key: Option<EncryptionKey>,
+    encrypt_timeout: Duration,
+
+    /// Deliver to Maildir folders with `notmuch insert`
+    notmuch: bool,
+
+    /// Command run after each message is stored, and how long it can
+    /// take (`pipe_timeout`)
+    post_deliver: Option<String>,
+    post_deliver_timeout: Duration,
+
+    /// Have Dovecot index Maildir folders as messages are stored in them
+    doveadm_index: bool,
+
+    /// Repair Maildir folders before storing messages in them
+    repair_folders: bool
+}
+
+impl AddressMap {
+    /// Load config_file as a TOML file containing a mapping of email
+    /// addresses to Maildir mailboxes: a `[sortmail]` table of global
+    /// options, and a table per mailbox with the keys that route mail
+    /// into it. config-example.toml shows every key.
+    ///
+    /// Return the mapping of each email address to the Maildir mailbox
+    /// name it should be sorted into.
+    fn from_file(config_file: &Path) -> Result<AddressMap> {
+        let contents = std::fs::read_to_string(config_file)
+            .with_context(|| format!("Error opening config file {}", config_file.display()))?;
+
+        let config: ConfigToml = toml::from_str(&contents)
+            .with_context(|| format!("Error parsing config file {}", config_file.display()))?;
+
+        let global_score_threshold = config.sortmail.score_threshold.unwrap_or(1.0);
+
+        let trusted_relays: Rc<[IpNet]> = parse_cidrs(&config.sortmail.trusted_relays)
+            .context("Error parsing trusted_relays")?
+            .into();
+
+        let aliases = config.sortmail.aliases_file
+            .map(|aliases_file| Aliases::from_file(&resolve_config_path(config_file, &aliases_file)))
+            .transpose()?;
+
+        let lookup_timeout = Duration::try_from_secs_f64(config.sortmail.lookup_timeout.unwrap_or(10.0))
+            .context("Invalid lookup_timeout")?;
+
+        let lookup_command = config.sortmail.lookup_command.map(|command| LookupCommand {
+            command,
+            headers: config.sortmail.lookup_headers,
+            timeout: lookup_timeout
+        });
+
+        let sieve_script = config.sortmail.sieve_script
+            .map(|sieve_script| SieveScript::from_file(&resolve_config_path(config_file, &sieve_script)))
+            .transpose()?;
+
+        let dnsbl_timeout = Duration::try_from_secs_f64(config.sortmail.dnsbl_timeout.unwrap_or(2.0))
+            .context("Invalid dnsbl_timeout")?;
+
+        let mut all_dnsbl_zones: Vec<String> = config.mailboxes
+            .values()
+            .flat_map(|mailbox_config| mailbox_config.dnsbl.iter().cloned())
+            .collect();
+        all_dnsbl_zones.sort();
+        all_dnsbl_zones.dedup();
+        let all_dnsbl_zones: Rc<[String]> = all_dnsbl_zones.into();
+
+        let smarthost_timeout = Duration::try_from_secs_f64(config.sortmail.smarthost_timeout.unwrap_or(30.0))
+            .context("Invalid smarthost_timeout")?;
+
+        let smarthost_security = config.sortmail.smarthost_security.unwrap_or(Security::None);
+
+        if smarthost_security == Security::None && config.sortmail.smarthost_username.is_some() {
+            bail!("smarthost_username is set, but credentials are only sent with smarthost_security = \"tls\" or \"starttls\"");
+        }
+
+        let smarthost = config.sortmail.smarthost.map(|host| Smarthost {
+            host,
+            port: config.sortmail.smarthost_port.unwrap_or(match smarthost_security {
+                Security::Tls => 465,
+                Security::StartTls => 587,
+                Security::None => 25
+            }),
+            security: smarthost_security,
+            username: config.sortmail.smarthost_username,
+            password: config.sortmail.smarthost_password,
+            timeout: smarthost_timeout
+        });
+
+        if smarthost.is_none() {
+            if let Some((mailbox_name, _)) = config.mailboxes.iter().find(|(_, mailbox_config)| !mailbox_config.forward_to.is_empty()) {
+                bail!("Mailbox {mailbox_name} sets forward_to, but there's no smarthost to forward through");
+            }
+        }
+
+        let imap_timeout = Duration::try_from_secs_f64(config.sortmail.imap_timeout.unwrap_or(30.0))
+            .context("Invalid imap_timeout")?;
+
+        let imap_server = match config.sortmail.imap_host {
+            Some(host) => Some(Rc::new(ImapServer {
+                port: config.sortmail.imap_port.unwrap_or(match config.sortmail.imap_security {
+                    Security::Tls => 993,
+                    Security::StartTls | Security::None => 143
+                }),
+                security: config.sortmail.imap_security,
+                username: config.sortmail.imap_username.context("imap_host is set, but imap_username isn't")?,
+                password: config.sortmail.imap_password.context("imap_host is set, but imap_password isn't")?,
+                timeout: imap_timeout,
+                host
+            })),
+            None => {
+                if let Some((mailbox_name, _)) = config.mailboxes.iter().find(|(_, mailbox_config)| mailbox_config.imap_folder.is_some()) {
+                    bail!("Mailbox {mailbox_name} sets imap_folder, but there's no imap_host to append to");
+                }
+                None
+            }
+        };
+
+        let s3_timeout = Duration::try_from_secs_f64(config.sortmail.s3_timeout.unwrap_or(30.0))
+            .context("Invalid s3_timeout")?;
+
+        let s3_bucket = match config.sortmail.s3_bucket {
+            Some(bucket) => {
+                let region = config.sortmail.s3_region.unwrap_or_else(|| "us-east-1".to_string());
+
+                Some(Rc::new(S3Bucket {
+                    endpoint: match config.sortmail.s3_endpoint {
+                        Some(ref url) => Endpoint::parse(url)?,
+                        None => Endpoint::aws(&region)
+                    },
+                    path_style: config.sortmail.s3_path_style,
+                    access_key: config.sortmail.s3_access_key
+                        .or_else(|| env::var("AWS_ACCESS_KEY_ID").ok())
+                        .context("s3_bucket is set, but s3_access_key isn't")?,
+                    secret_key: config.sortmail.s3_secret_key
+                        .or_else(|| env::var("AWS_SECRET_ACCESS_KEY").ok())
+                        .context("s3_bucket is set, but s3_secret_key isn't")?,
+                    timeout: s3_timeout,
+                    region,
+                    bucket
+                }))
+            },
+            None => {
+                if let Some((mailbox_name, _)) = config.mailboxes.iter().find(|(_, mailbox_config)| mailbox_config.s3_folder.is_some()) {
+                    bail!("Mailbox {mailbox_name} sets s3_folder, but there's no s3_bucket to store in");
+                }
+                None
+            }
+        };
+
+        let vacation_interval = Duration::try_from_secs_f64(config.sortmail.vacation_days.unwrap_or(7.0) * 24.0 * 60.0 * 60.0)
+            .context("Invalid vacation_days")?;
+
+        let vacation = config.sortmail.vacation_file
+            .map(|vacation_file| {
+                let vacation_file = resolve_config_path(config_file, &vacation_file);
+
+                if smarthost.is_none() {
+                    bail!("vacation_file is set, but there's no smarthost to send replies through");
+                }
+
+                Ok(Vacation {
+                    subject: config.sortmail.vacation_subject.unwrap_or_else(|| "Auto: {subject}".to_string()),
+                    body: std::fs::read_to_string(&vacation_file)
+                        .with_context(|| format!("Error opening vacation_file {}", vacation_file.display()))?,
+                    from: config.sortmail.vacation_from,
+                    interval: vacation_interval,
+                    addresses: config.sortmail.vacation_addresses
+                })
+            })
+            .transpose()?;
+
+        if config.sortmail.quota_full == QuotaFull::Overflow && config.sortmail.quota_overflow_folder.is_none() {
+            bail!("quota_full is overflow, but there's no quota_overflow_folder");
+        }
+
+        let pipe_timeout = Duration::try_from_secs_f64(config.sortmail.pipe_timeout.unwrap_or(60.0))
+            .context("Invalid pipe_timeout")?;
+
+        let encrypt_timeout = Duration::try_from_secs_f64(config.sortmail.encrypt_timeout.unwrap_or(60.0))
+            .context("Invalid encrypt_timeout")?;
+
+        if config.sortmail.spam_learner.is_some() && config.sortmail.junk_folder.is_none() {
+            bail!("spam_learner is set without a junk_folder to learn from");
+        }
+
+        let mut mailbox_configs: Vec<_> = config.mailboxes.into_iter().collect();
+        mailbox_configs.sort_by_key(|(_, mailbox_config)| std::cmp::Reverse(mailbox_config.priority));
+
+        let zipped_addresses_result: Result<Vec<_>> = mailbox_configs
+            .into_iter()
+            .map(|(mailbox_name, mut mailbox_config)| {
+                let case_sensitive = mailbox_config.case_sensitive.unwrap_or(config.sortmail.case_sensitive);
+
+                let recipient_keys = [
+                    &mut mailbox_config.addresses,
+                    &mut mailbox_config.local_parts,
+                    &mut mailbox_config.exclude_addresses
+                ];
+
+                for address in recipient_keys.into_iter().flatten() {
+                    *address = match case_sensitive {
+                        true => address::normalize_address_domain(address),
+                        false => address::normalize_address(address)
+                    };
+                }
+
+                let address_regexset = build_regexset(&mailbox_config.re_addresses, !case_sensitive)
+                    .context("Error parsing regular expressions")?;
+
+                let folder = mailbox_config.folder.map(|folder| folder.trim_start_matches('.').to_string());
+
+                let address_regexes = match folder {
+                    Some(_) => mailbox_config.re_addresses
+                        .iter()
+                        .map(|pattern| RegexBuilder::new(pattern).case_insensitive(!case_sensitive).build())
+                        .collect::<Result<Vec<_>, _>>()
+                        .context("Error parsing regular expressions")?,
+                    None => Vec::new()
+                };
+
+                let glob_regexes: Vec<_> = mailbox_config.glob_addresses.iter().map(|glob| glob_to_regex(glob)).collect();
+                let address_glob_regexset = build_regexset(&glob_regexes, !case_sensitive)
+                    .context("Error parsing glob patterns")?;
+
+                let exclude_regexset = build_regexset(&mailbox_config.exclude_re, !case_sensitive)
+                    .context("Error parsing exclusion regular expressions")?;
+
+                let sender_regexset = build_regexset(&mailbox_config.re_senders, true)
+                    .context("Error parsing sender regular expressions")?;
+
+                let from_regexset = build_regexset(&mailbox_config.re_from, true)
+                    .context("Error parsing From regular expressions")?;
+
+                let reply_to_regexset = build_regexset(&mailbox_config.re_reply_to, true)
+                    .context("Error parsing Reply-To regular expressions")?;
+
+                let list_id_regexset = build_regexset(&mailbox_config.re_list_ids, true)
+                    .context("Error parsing List-Id regular expressions")?;
+
+                let received_origin_regexset = build_regexset(&mailbox_config.re_received_origin, true)
+                    .context("Error parsing Received origin regular expressions")?;
+
+                let mut address_book = HashSet::new();
+                for address_book_path in &mailbox_config.address_book {
+                    address_book.extend(address_book::load(&resolve_config_path(config_file, Path::new(address_book_path)))?);
+                }
+
+                let attachment_name_regexset = build_regexset(&mailbox_config.re_attachment_names, true)
+                    .context("Error parsing attachment name regular expressions")?;
+
+                let source_cidrs = parse_cidrs(&mailbox_config.source_cidrs)
+                    .context("Error parsing source_cidrs")?;
+
+                let body_regexset = match mailbox_config.re_body.is_empty() {
+                    true => None,
+                    false => Some(RegexSetBuilder::new(mailbox_config.re_body)
+                        .case_insensitive(true)
+                        .multi_line(true)
+                        .build()
+                        .context("Error parsing body regular expressions")?)
+                };
+
+                let auth_results = mailbox_config
+                    .auth_results
+                    .iter()
+                    .map(|auth_result| auth_result
+                        .split_once('=')
+                        .map(|(method, result)| (method.trim().to_string(), result.trim().to_string()))
+                        .with_context(|| format!("Invalid auth_results entry {auth_result:?}, expected method=result")))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let flags = parse_maildir_flags(&mailbox_config.flags)?;
+
+                let header_removals = mailbox_config
+                    .remove_headers
+                    .iter()
+                    .map(|header_name| HeaderEdit::Remove(header_name.to_lowercase()));
+
+                let header_rewrites = mailbox_config
+                    .rewrite_headers
+                    .iter()
+                    .map(|rewrite| RegexBuilder::new(&rewrite.pattern)
+                        .case_insensitive(true)
+                        .build()
+                        .with_context(|| format!("Error parsing rewrite_headers pattern for header {}", rewrite.header))
+                        .map(|pattern| HeaderEdit::Rewrite {
+                            name: rewrite.header.trim().to_lowercase(),
+                            pattern,
+                            replacement: rewrite.replacement.clone()
+                        }))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let header_edits: Vec<_> = header_removals.chain(header_rewrites).collect();
+
+                if let Some(keyword) = mailbox_config.keywords.iter().find(|keyword| !keywords::is_valid(keyword)) {
+                    bail!("Invalid keyword {keyword:?}, expected a word without spaces or special characters");
+                }
+
+                let remote_maildir = mailbox_config.remote_maildir
+                    .as_deref()
+                    .map(RemoteMaildir::parse)
+                    .transpose()
+                    .with_context(|| format!("Error parsing remote_maildir for mailbox {mailbox_name}"))?;
+
+                let mut stores: Vec<Rc<dyn MessageStore>> = Vec::new();
+                if let Some(ref mbox) = mailbox_config.mbox {
+                    stores.push(Rc::new(MboxFile(resolve_config_path(config_file, mbox))));
+                }
+                if let Some(ref mh) = mailbox_config.mh {
+                    stores.push(Rc::new(MhFolder(resolve_config_path(config_file, mh))));
+                }
+                if let Some(remote_maildir) = remote_maildir {
+                    stores.push(Rc::new(remote_maildir));
+                }
+                if let (Some(folder), Some(server)) = (&mailbox_config.imap_folder, &imap_server) {
+                    stores.push(Rc::new(ImapFolder {
+                        server: server.clone(),
+                        folder: folder.clone()
+                    }));
+                }
+                if let (Some(folder), Some(bucket)) = (&mailbox_config.s3_folder, &s3_bucket) {
+                    stores.push(Rc::new(S3Folder {
+                        bucket: bucket.clone(),
+                        folder: folder.trim_matches('/').to_string()
+                    }));
+                }
+
+                if stores.len() > 1 {
+                    bail!("Mailbox {mailbox_name} sets more than one of mbox, mh, remote_maildir, imap_folder and s3_folder, expected at most one");
+                }
+                let store = stores.pop();
+
+                if let (Some(_), Some(store)) = (&mailbox_config.expire_after, &store) {
+                    bail!("Mailbox {mailbox_name} sets expire_after, but its messages go to {}, which sortmail expire can't reach", store.location());
+                }
+
+                if mailbox_config.expire_to.is_some() && mailbox_config.expire_after.is_none() {
+                    bail!("Mailbox {mailbox_name} sets expire_to without expire_after");
+                }
+
+                if let (Some(_), Some(store)) = (&mailbox_config.compress, &store) {
+                    if !store.can_compress() {
+                        bail!("Mailbox {mailbox_name} sets compress, but its messages go to {}, which can't be compressed", store.location());
+                    }
+                }
+
+                let compress = mailbox_config.compress.map(|format| Compression {
+                    format,
+                    min_size: mailbox_config.compress_min_size.unwrap_or(0)
+                });
+
+                let detach = mailbox_config.detach_attachments.map(|directory| Detach {
+                    directory: resolve_config_path(config_file, &directory),
+                    min_size: mailbox_config.detach_min_size.unwrap_or(detach::DEFAULT_MIN_SIZE),
+                    url: mailbox_config.detach_url
+                });
+
+                let pipe = mailbox_config.pipe.map(|command| PipeCommand {
+                    command,
+                    on_failure: mailbox_config.pipe_failure,
+                    timeout: pipe_timeout
+                });
+
+                let reject = mailbox_config.reject.map(|reason| Rejection {
+                    reason,
+                    exit_code: mailbox_config.reject_code.unwrap_or(EX_NOUSER)
+                });
+
+                if let Some(exit_code) = mailbox_config.reject_code.filter(|code| !(64..=78).contains(code)) {
+                    bail!("Invalid reject_code {exit_code}, expected a sysexits.h code from 64 to 78");
+                }
+
+                // A mailbox's messages go to one place, or nowhere
+                let kinds = [
+                    (mailbox_config.discard, "discard"),
+                    (reject.is_some(), "reject"),
+                    (pipe.is_some(), "pipe"),
+                    (store.is_some(), "mbox, mh, remote_maildir, imap_folder or s3_folder")
+                ];
+                let set_kinds: Vec<_> = kinds.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect();
+                if set_kinds.len() > 1 {
+                    bail!("Mailbox {mailbox_name} sets {}, expected at most one", set_kinds.join(" and "));
+                }
+                if (mailbox_config.discard || reject.is_some()) && !mailbox_config.forward_to.is_empty() {
+                    bail!("Mailbox {mailbox_name} sets forward_to, but also discards or rejects its messages");
+                }
+
+                if let Some(importance) = mailbox_config.importance.iter().find(|importance| !["high", "normal", "low"].contains(&importance.as_str())) {
+                    bail!("Invalid importance {importance:?}, expected high, normal or low");
+                }
+
+                let languages = mailbox_config
+                    .language
+                    .iter()
+                    .map(|name| language::normalize_code(name)
+                        .with_context(|| format!("Unknown language {name:?}, expected a code like \"de\" or a name like \"German\"")))
+                    .collect::<Result<HashSet<_>>>()?;
+
+                let header_regexsets = mailbox_config
+                    .headers
+                    .into_iter()
+                    .filter(|(_, patterns)| !patterns.is_empty())
+                    .map(|(header_name, patterns)| {
+                        RegexSetBuilder::new(patterns)
+                            .case_insensitive(true)
+                            .build()
+                            .with_context(|| format!("Error parsing regular expressions for header {header_name}"))
+                            .map(|set| (header_name, set))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let when = mailbox_config
+                    .when
+                    .as_deref()
+                    .map(Expression::parse)
+                    .transpose()?;
+
+                let scores = mailbox_config
+                    .score
+                    .iter()
+                    .map(|score| Expression::parse(&score.when).map(|expression| (expression, score.weight)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let mailbox_rule = Rc::new(MailboxRule {
+                    mailbox_name,
+                    priority: mailbox_config.priority,
+                    has_addresses: !mailbox_config.addresses.is_empty(),
+                    address_regexset,
+                    address_regexes,
+                    address_glob_regexset,
+                    domains: mailbox_config.domains.into_iter().collect(),
+                    local_parts: mailbox_config.local_parts.into_iter().collect(),
+                    exclude_addresses: mailbox_config.exclude_addresses.into_iter().collect(),
+                    exclude_regexset,
+                    senders: mailbox_config.senders.into_iter().collect(),
+                    sender_regexset,
+                    from_addresses: mailbox_config.from_addresses.into_iter().collect(),
+                    from_regexset,
+                    reply_to_addresses: mailbox_config.reply_to_addresses.into_iter().collect(),
+                    reply_to_regexset,
+                    address_book,
+                    list_ids: mailbox_config.list_ids.into_iter().collect(),
+                    list_id_regexset,
+                    received_origins: mailbox_config.received_origins.into_iter().collect(),
+                    received_origin_regexset,
+                    source_cidrs,
+                    trusted_relays: Rc::clone(&trusted_relays),
+                    dnsbl_zones: mailbox_config.dnsbl,
+                    all_dnsbl_zones: Rc::clone(&all_dnsbl_zones),
+                    dnsbl_timeout,
+                    spam_score_at_least: mailbox_config.spam_score_at_least,
+                    body_regexset,
+                    has_attachment: mailbox_config.has_attachment,
+                    attachment_types: mailbox_config.attachment_types,
+                    attachment_name_regexset,
+                    calendar: mailbox_config.calendar,
+                    calendar_methods: mailbox_config.calendar_methods.into_iter().collect(),
+                    encrypted: mailbox_config.encrypted,
+                    automated: mailbox_config.automated,
+                    dsn: mailbox_config.dsn,
+                    suspicious_from: mailbox_config.suspicious_from,
+                    importance: mailbox_config.importance.into_iter().collect(),
+                    languages,
+                    min_size: mailbox_config.min_size,
+                    max_size: mailbox_config.max_size,
+                    min_recipients: mailbox_config.min_recipients,
+                    max_recipients: mailbox_config.max_recipients,
+                    auth_results,
+                    trusted_authserv_ids: mailbox_config.trusted_authserv_ids.into_iter().collect(),
+                    header_regexsets,
+                    when,
+                    scores,
+                    score_threshold: mailbox_config.score_threshold.unwrap_or(global_score_threshold),
+                    folder,
+                    plus_folder: mailbox_config.plus_folder,
+                    copy_to: mailbox_config.copy_to,
+                    flags,
+                    keywords: mailbox_config.keywords,
+                    labels: mailbox_config.labels,
+                    header_edits,
+                    store,
+                    compress,
+                    detach,
+                    forward_to: mailbox_config.forward_to,
+                    keep: mailbox_config.keep.unwrap_or(true),
+                    pipe,
+                    post_deliver: mailbox_config.post_deliver,
+                    expire_after: mailbox_config.expire_after,
+                    expire_to: mailbox_config.expire_to,
+                    discard: mailbox_config.discard,
+                    reject,
+                    case_sensitive,
+                    continue_matching: mailbox_config.continue_matching
+                });
+
+                let exact_address_to_mailbox_rule: Vec<(_, _)> = mailbox_config
+                    .addresses
+                    .into_iter()
+                    .map(|address| (address, Rc::clone(&mailbox_rule)))
+                    .collect();
+
+                Ok((exact_address_to_mailbox_rule, mailbox_rule))
+
+            }).collect();
+
+        let (exact_address_mailbox_rule_lists, mailbox_rules): (Vec<_>, Vec<_>) = zipped_addresses_result?.into_iter().unzip();
+
+        let mut address_map = AddressMap {
+            exact_address_to_mailbox_rule: HashMap::new(),
+            case_sensitive_exact_address_to_mailbox_rule: HashMap::new(),
+            mailbox_rules,
+            plus_folder: config.sortmail.plus_folder,
+            list_folder: config.sortmail.list_folder,
+            catchall_folders: config.catchall
+                .into_iter()
+                .map(|(domain, catchall)| (address::normalize_domain(&domain), catchall.folder))
+                .collect(),
+            root_maildir: config.sortmail.root_maildir.map(|template| resolve_root_maildir_template(config_file, template)),
+            domain_root_maildirs: config.maildirs
+                .into_iter()
+                .map(|(domain, template)| (address::normalize_domain(&domain), resolve_root_maildir_template(config_file, template)))
+                .collect(),
+            recipient_delimiter: config.sortmail.recipient_delimiter.unwrap_or_else(|| "+".to_string()),
+            ignore_local_part_dots: config.sortmail.ignore_local_part_dots,
+            ignore_extensions: config.sortmail.ignore_extensions,
+            dedupe: config.sortmail.dedupe,
+            dedupe_content: config.sortmail.dedupe_content,
+            duplicate_folder: config.sortmail.duplicate_folder,
+            follow_threads: config.sortmail.follow_threads,
+            automated_folder: config.sortmail.automated_folder,
+            all_mail_folder: config.sortmail.all_mail_folder,
+            canonical_folder: config.sortmail.canonical_folder,
+            folder_links: config.sortmail.folder_links,
+            archive_folders: config.sortmail.archive_folders,
+            archive: Archive {
+                folder: config.sortmail.archive_folder.unwrap_or_else(|| "Archive.%Y.%m".to_string()),
+                after: config.sortmail.archive_after.unwrap_or(Duration::from_secs(365 * 24 * 60 * 60)),
+                folder_date: config.sortmail.folder_date
+            },
+            junk_folder: config.sortmail.junk_folder,
+            spam_learner: config.sortmail.spam_learner,
+            spam_learner_ignore_folders: config.sortmail.spam_learner_ignore_folders,
+            aliases,
+            lookup_command,
+            sieve_script,
+            sieve_mode: config.sortmail.sieve_mode,
+            classifier: config.sortmail.classifier,
+            classifier_ignore_folders: config.sortmail.classifier_ignore_folders,
+            smarthost,
+            vacation,
+            cur_folders: config.sortmail.cur_folders,
+            label_header: config.sortmail.label_header.unwrap_or_else(|| "X-Label".to_string()),
+            trace_headers: config.sortmail.trace_headers,
+            envelope_headers: config.sortmail.envelope_headers,
+            namespace: Namespace {
+                layout: config.sortmail.folder_layout,
+                prefix: config.sortmail.folder_prefix
+            },
+            user_config: config.sortmail.user_config,
+            store_options: StoreOptions {
+                rfc822_size: config.sortmail.rfc822_size,
+                durable: config.sortmail.durable,
+                compression: None,
+                file_mode: config.sortmail.file_mode,
+                directory_mode: config.sortmail.directory_mode
+            },
+            quota: match (config.sortmail.quota, config.sortmail.quota_messages) {
+                (None, None) => None,
+                (bytes, messages) => Some(Quota { bytes, messages })
+            },
+            quota_full: config.sortmail.quota_full,
+            quota_overflow_folder: config.sortmail.quota_overflow_folder,
+            quarantine_folder: config.sortmail.quarantine_folder,
+            quarantine_exit_code: config.sortmail.quarantine_exit_code,
+            subscribe_new_folders: config.sortmail.subscribe_new_folders,
+            folder_date: config.sortmail.folder_date,
+            encryption_key: config.sortmail.encrypt_to.as_deref().map(EncryptionKey::parse),
+            encrypt_timeout,
+            notmuch: config.sortmail.notmuch,
+            post_deliver: config.sortmail.post_deliver,
+            post_deliver_timeout: pipe_timeout,
+            doveadm_index: config.sortmail.doveadm_index,
+            repair_folders: config.sortmail.repair_folders
+        };
+
+        for (address, mailbox_rule) in exact_address_mailbox_rule_lists.into_iter().flatten() {
+            let key = address_map.exact_address_key(&address);
+            let exact_addresses = match mailbox_rule.case_sensitive {
+                true => &mut address_map.case_sensitive_exact_address_to_mailbox_rule,
+                false => &mut address_map.exact_address_to_mailbox_rule
+            };
+            exact_addresses.entry(key).or_insert(mailbox_rule);
+        }
+
+        Ok(address_map)
+    }
+
+    /// Return the key that `address` is stored under (or looked up by)
+    /// in the exact address maps: the address itself, but without dots
+    /// in the local part if `ignore_local_part_dots` is set, and without
+    /// an extension if `ignore_extensions` is set. So with both set,
+    /// `first.last+shop@gmail.com` and `firstlast@gmail.com` are the
+    /// same.
+    fn exact_address_key(&self, address: &str) -> String {
+        if !(self.ignore_local_part_dots || self.ignore_extensions) {
+            return address.to_string();
+        }
+
+        let Some((mut local_part, domain)) = address::split_address(address) else {
+            return address.to_string();
+        };
+
+        if self.ignore_extensions {
+            if let Some((base, _)) = local_part.split_once(|c| self.recipient_delimiter.contains(c)) {
+                local_part = base.to_string();
+            }
+        }
+
+        if self.ignore_local_part_dots {
+            local_part.retain(|c| c != '.');
+        }
+
+        address::join_address(&local_part, &domain)
+    }
+
+    /// Work out where a message should be delivered.
+    ///
+    /// There's one delivery for each matching rule (see
+    /// `matching_rules`), plus the default delivery to the root Maildir
+    /// if no rule matched or every matching rule had `continue = true`.
+    /// A folder is only delivered to once, however many rules chose it.
+    ///
+    /// If there's a `lookup_command` and it names a folder, that's
+    /// matched like a rule with priority 0 and no `continue`, coming
+    /// before the rules of priority 0 and below: the message goes there
+    /// (creating it if needed) instead of to them, or to the default
+    /// delivery, but rules with a higher priority still apply first.
+    ///
+    /// A `sieve_script` takes the place of the rules, or with
+    /// `sieve_mode = "after"` runs where the default delivery would
+    /// have happened; its `fileinto` folders are delivered to, and the
+    /// default delivery only happens if the script keeps the message.
+    ///
+    /// A message that's stored anywhere (not just piped or forwarded)
+    /// is also stored in the global `all_mail_folder`, if there is one.
+    ///
+    /// If the recipient has an extension (`user+tag@example.com`) and
+    /// the matching mailbox has a `plus_folder` template, the message
+    /// goes to the folder named by the template instead, with `{tag}`
+    /// replaced by the (sanitized) extension and `{mailbox}` by the
+    /// mailbox name.
+    ///
+    /// A message that no mailbox claims goes to `parent_mailbox_name`
+    /// if that's given: the folder of an earlier message in the same
+    /// thread, when `follow_threads` is on. Otherwise, machine-generated
+    /// mail (see `IncomingMessage::is_automated`) goes to the global
+    /// `automated_folder`, if there is one, and plus-addressed mail
+    /// that no mailbox claims uses the global `plus_folder` template,
+    /// if there is one.
+    ///
+    /// Otherwise, mailing list mail that no mailbox claims goes to the
+    /// folder named by the global `list_folder` template, if there is
+    /// one, with `{listname}` replaced by the first part of the list's
+    /// identifier (`announce` for `announce.lists.example.com` or
+    /// `announce@lists.example.com`) and `{listid}` by the whole
+    /// identifier, both sanitized. That folder is created if needed, so
+    /// each new list gets its own folder without any configuration.
+    ///
+    /// Failing that, mail for a domain with a `[catchall]` folder goes
+    /// to that folder, and anything else to the root Maildir.
+    fn actions_for_message(&self, envelope: &Envelope, message: &IncomingMessage, parent_mailbox_name: Option<&str>) -> Vec<DeliveryAction> {
+        let tag = envelope.recipient_extension
+            .as_deref()
+            .map(sanitize_folder_name_component);
+
+        let folder_name = |mailbox_name: Option<&str>, plus_folder: Option<&String>| {
+            match (plus_folder, &tag) {
+                (Some(template), Some(tag)) => Some(template
+                    .replace("{tag}", tag)
+                    .replace("{mailbox}", mailbox_name.unwrap_or_default())),
+                _ => mailbox_name.map(str::to_string)
+            }
+        };
+
+        let lookup_mailbox_name = match self.lookup_command {
+            Some(ref lookup_command) => match lookup_command.run(envelope, message) {
+                Ok(mailbox_name) => mailbox_name,
+                Err(error) => {
+                    eprintln!("Warning: {error:#}; using mailbox rules instead");
+                    None
+                }
+            },
+            None => None
+        };
+
+        let mut rules = match (&self.sieve_script, self.sieve_mode) {
+            (Some(_), SieveMode::Instead) => Vec::new(),
+            _ => self.matching_rules(envelope, message)
+        };
+
+        // The lookup command's folder takes the place of the rules it
+        // comes before, if the search for rules gets that far
+        let lookup_mailbox_name = lookup_mailbox_name.filter(|_| {
+            let lookup_position = rules.iter().position(|rule| rule.priority <= 0).unwrap_or(rules.len());
+            rules.truncate(lookup_position);
+            rules.last().is_none_or(|rule| rule.continue_matching || rule.falls_through())
+        });
+
+        // Only the first matching rule gets to discard or reject the
+        // message outright; one matched after a rule with `continue` (or
+        // a pipe that the message may fall through from) would throw
+        // away what that rule asked for, so it only goes without a copy
+        // of its own
+        if let Some(rule) = rules.first().filter(|rule| rule.discard || rule.reject.is_some()) {
+            return vec![DeliveryAction {
+                mailbox_name: Some(rule.mailbox_name.clone()),
+                kind: match rule.reject {
+                    Some(ref rejection) => DeliveryKind::Reject(rejection.clone()),
+                    None => DeliveryKind::Discard
+                },
+                chosen_by: rule.mailbox_name.clone(),
+                ..Default::default()
+            }];
+        }
+
+        let mut deliver_to_default = lookup_mailbox_name.is_none() && rules.last().is_none_or(|rule| rule.continue_matching || rule.falls_through());
+        let mut unclaimed = rules.is_empty() && lookup_mailbox_name.is_none();
+        let mut failed_pipes = 0;
+
+        let mut actions: Vec<_> = rules
+            .into_iter()
+            .flat_map(|rule| {
+                let after_failed_pipes = failed_pipes;
+                if rule.falls_through() {
+                    failed_pipes += 1;
+                }
+
+                let discards = rule.discard || rule.reject.is_some();
+                let action = match rule.pipe {
+                    _ if discards => DeliveryAction {
+                        mailbox_name: Some(rule.mailbox_name.clone()),
+                        kind: DeliveryKind::Discard,
+                        after_failed_pipes,
+                        chosen_by: rule.mailbox_name.clone(),
+                        ..Default::default()
+                    },
+                    Some(ref pipe) => DeliveryAction {
+                        mailbox_name: Some(rule.mailbox_name.clone()),
+                        kind: DeliveryKind::Pipe(pipe.clone()),
+                        after_failed_pipes,
+                        chosen_by: rule.mailbox_name.clone(),
+                        ..Default::default()
+                    },
+                    None => DeliveryAction {
+                        mailbox_name: folder_name(Some(&rule.folder_name(envelope)), rule.plus_folder.as_ref()),
+                        create_folder: rule.folder.is_some(),
+                        kind: rule.store.clone().map_or(DeliveryKind::Folder, DeliveryKind::Store),
+                        after_failed_pipes,
+                        flags: rule.flags.clone(),
+                        keywords: rule.keywords.clone(),
+                        labels: rule.labels.clone(),
+                        header_edits: rule.header_edits.clone(),
+                        compress: rule.compress,
+                        detach: rule.detach.clone(),
+                        post_deliver: rule.post_deliver.clone(),
+                        chosen_by: rule.mailbox_name.clone(),
+                        ..Default::default()
+                    }
+                };
+
+                let forwards = rule.forward_to.iter().map(move |address| DeliveryAction {
+                    mailbox_name: Some(rule.mailbox_name.clone()),
+                    kind: DeliveryKind::Forward(address.clone()),
+                    after_failed_pipes,
+                    chosen_by: rule.mailbox_name.clone(),
+                    ..Default::default()
+                });
+
+                let copies = rule.copy_to.iter().map(move |mailbox_name| DeliveryAction {
+                    mailbox_name: Some(mailbox_name.clone()),
+                    create_folder: true,
+                    after_failed_pipes,
+                    post_deliver: rule.post_deliver.clone(),
+                    chosen_by: rule.mailbox_name.clone(),
+                    ..Default::default()
+                });
+
+                std::iter::once(action)
+                    .filter(|action| rule.keep || matches!(action.kind, DeliveryKind::Pipe(_)))
+                    .chain(copies.filter(move |_| !discards))
+                    .chain(forwards.filter(move |_| !discards))
+            })
+            .collect();
+
+        if let Some(mailbox_name) = lookup_mailbox_name {
+            actions.push(DeliveryAction {
+                mailbox_name: Some(mailbox_name),
+                create_folder: true,
+                after_failed_pipes: failed_pipes,
+                chosen_by: "lookup_command".to_string(),
+                ..Default::default()
+            });
+        }
+
+        if let Some(sieve_script) = self.sieve_script.as_ref().filter(|_| deliver_to_default) {
+            let outcome = sieve_script.evaluate(envelope, message);
+
+            // Sieve names the root Maildir INBOX, and Dovecot users'
+            // scripts may give folders their namespace prefix
+            let sieve_mailbox_name = |folder: String| {
+                let prefix = &self.namespace.prefix;
+                let unprefixed = match folder.strip_prefix(prefix.as_str()) {
+                    Some(rest) if !prefix.is_empty() && rest.starts_with(['.', '/']) => rest[1..].to_string(),
+                    _ => folder
+                };
+
+                match unprefixed.eq_ignore_ascii_case("INBOX") || unprefixed.eq_ignore_ascii_case(prefix) {
+                    true => None,
+                    false => Some(unprefixed)
+                }
+            };
+
+            unclaimed &= outcome.folders.is_empty();
+
+            for (folder, create_folder) in outcome.folders {
+                actions.push(DeliveryAction {
+                    mailbox_name: sieve_mailbox_name(folder),
+                    create_folder,
+                    after_failed_pipes: failed_pipes,
+                    chosen_by: "sieve".to_string(),
+                    ..Default::default()
+                });
+            }
+
+            if !outcome.keep {
+                deliver_to_default = false;
+
+                if actions.is_empty() {
+                    actions.push(DeliveryAction {
+                        mailbox_name: Some("sieve".to_string()),
+                        kind: DeliveryKind::Discard,
+                        after_failed_pipes: failed_pipes,
+                        chosen_by: "sieve".to_string(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        if deliver_to_default {
+            let list_folder_name = || {
+                let template = self.list_folder.as_ref()?;
+                let list_identifier = message.mailing_list_identifier()?;
+                let list_name = list_identifier.split(['.', '@']).next().unwrap_or_default();
+
+                Some(template
+                    .replace("{listname}", &sanitize_folder_name_component(list_name))
+                    .replace("{listid}", &sanitize_folder_name_component(&list_identifier)))
+            };
+
+            let automated_folder_name = || match message.is_automated() {
+                true => self.automated_folder.clone(),
+                false => None
+            };
+
+            let default_mailbox_name = parent_mailbox_name
+                .map(|mailbox_name| (mailbox_name.to_string(), "follow_threads"))
+                .or_else(|| automated_folder_name().map(|mailbox_name| (mailbox_name, "automated_folder")))
+                .or_else(|| folder_name(None, self.plus_folder.as_ref()).map(|mailbox_name| (mailbox_name, "plus_folder")));
+
+            actions.push(match default_mailbox_name {
+                Some((mailbox_name, chosen_by)) => DeliveryAction {
+                    mailbox_name: Some(mailbox_name),
+                    unclaimed,
+                    after_failed_pipes: failed_pipes,
+                    chosen_by: chosen_by.to_string(),
+                    ..Default::default()
+                },
+                None => match list_folder_name() {
+                    Some(mailbox_name) => DeliveryAction {
+                        mailbox_name: Some(mailbox_name),
+                        create_folder: true,
+                        unclaimed,
+                        after_failed_pipes: failed_pipes,
+                        chosen_by: "list_folder".to_string(),
+                        ..Default::default()
+                    },
+                    None => {
+                        let catchall_folder = self.catchall_folder(&envelope.recipient).cloned();
+
+                        DeliveryAction {
+                            chosen_by: match catchall_folder {
+                                Some(_) => "catchall".to_string(),
+                                None => "default".to_string(),
+                            },
+                            mailbox_name: catchall_folder,
+                            unclaimed,
+                            after_failed_pipes: failed_pipes,
+                            ..Default::default()
+                        }
+                    }
+                }
+            });
+        }
+
+        let mut actions = self.with_all_mail_copy(actions);
+
+        let mut seen_destinations = HashSet::new();
+        actions.retain(|action| seen_destinations.insert(action.destination()));
+
+        actions
+    }
+
+    /// Add a delivery to the `all_mail_folder`, if there is one, to
+    /// `actions` that store the message somewhere. It goes last, so it
+    /// can be linked to an earlier copy, and happens whenever the
+    /// earliest of those does.
+    fn with_all_mail_copy(&self, mut actions: Vec<DeliveryAction>) -> Vec<DeliveryAction> {
+        let Some(ref all_mail_folder) = self.all_mail_folder else {
+            return actions;
+        };
+
+        let first_stored_after_failed_pipes = actions
+            .iter()
+            .filter(|action| action.stores_message())
+            .map(|action| action.after_failed_pipes)
+            .min();
+
+        if let Some(after_failed_pipes) = first_stored_after_failed_pipes {
+            actions.push(DeliveryAction {
+                mailbox_name: Some(all_mail_folder.clone()),
+                create_folder: true,
+                after_failed_pipes,
+                chosen_by: "all_mail_folder".to_string(),
+                ..Default::default()
+            });
+        }
+
+        actions
+    }
+
+    /// Return true if messages for `mailbox_name` (None for the root
+    /// Maildir) are stored in `cur`, because it's one of the
+    /// `cur_folders` or a subfolder of one.
+    fn is_cur_folder(&self, mailbox_name: Option<&str>) -> bool {
+        let Some(mailbox_name) = mailbox_name else {
+            return false;
+        };
+
+        let mailbox_name = mailbox_name.replace('/', ".");
+
+        self.cur_folders
+            .iter()
+            .map(|folder| folder.replace('/', "."))
+            .any(|folder| mailbox_name == folder || mailbox_name.starts_with(&format!("{folder}.")))
+    }
+
+    /// Return the catch-all folder for the domain of `address`, trying
+    /// the domain itself and then each parent domain with a leading
+    /// dot.
+    fn catchall_folder(&self, address: &str) -> Option<&String> {
+        get_for_domain(&self.catchall_folders, address)
+    }
+
+    /// Return the root Maildir template for `address`: its domain's
+    /// entry in `[maildirs]`, or the global `root_maildir`.
+    fn root_maildir_template(&self, address: &str) -> Option<&String> {
+        get_for_domain(&self.domain_root_maildirs, address).or(self.root_maildir.as_ref())
+    }
+
+    /// Find the mailbox rules matching a message. Exact address matches
+    /// take precedence over any pattern (trying the recipient with any
+    /// extension removed if the full address isn't listed); otherwise
+    /// the first mailbox whose patterns match is used.
+    ///
+    /// Candidates are then checked against their exclusion patterns,
+    /// and an excluded mailbox is passed over in favour of the next
+    /// one that matches.
+    ///
+    /// If no mailbox matches outright, each mailbox's `score` patterns
+    /// are totalled, and the highest-scoring mailbox wins if its total
+    /// reaches its `score_threshold`.
+    ///
+    /// A matching rule with `continue = true` doesn't end the search:
+    /// later rules (and then scoring) are still tried, and every rule
+    /// that matches is returned, in order.
+    fn matching_rules(&self, envelope: &Envelope, message: &IncomingMessage) -> Vec<&MailboxRule> {
+        let mut rules: Vec<&MailboxRule> = Vec::new();
+
+        let keep_searching = |rules: &Vec<&MailboxRule>| rules.last().is_none_or(|rule| rule.continue_matching || rule.falls_through());
+        let already_matched = |rules: &Vec<&MailboxRule>, rule: &MailboxRule| rules.iter().any(|matched| std::ptr::eq(*matched, rule));
+
+        let exact_match = [
+            self.case_sensitive_exact_address_to_mailbox_rule.get(&self.exact_address_key(&envelope.original_case_recipient)),
+            self.exact_address_to_mailbox_rule.get(&self.exact_address_key(&envelope.recipient)),
+            self.case_sensitive_exact_address_to_mailbox_rule.get(&self.exact_address_key(&envelope.original_case_recipient_without_extension)),
+            self.exact_address_to_mailbox_rule.get(&self.exact_address_key(&envelope.recipient_without_extension))
+        ]
+            .into_iter()
+            .flatten()
+            .find(|rule| !rule.excludes(envelope) && rule.passes_gates(envelope, message));
+
+        if let Some(rule) = exact_match {
+            rules.push(rule);
+        }
+
+        for rule in &self.mailbox_rules {
+            if !keep_searching(&rules) {
+                return rules;
+            }
+
+            if !already_matched(&rules, rule) && rule.matches(envelope, message) && !rule.excludes(envelope) {
+                rules.push(rule);
+            }
+        }
+
+        if !keep_searching(&rules) {
+            return rules;
+        }
+
+        let best_scoring_rule = self.mailbox_rules
+            .iter()
+            .filter(|rule| !rule.scores.is_empty() && !rule.excludes(envelope) && !already_matched(&rules, rule) && rule.passes_gates(envelope, message))
+            .map(|rule| (rule, rule.score(envelope, message)))
+            .filter(|(rule, score)| *score >= rule.score_threshold)
+            .fold(None, |best: Option<(&Rc<MailboxRule>, f64)>, (rule, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((rule, score))
+            });
+
+        if let Some((rule, _)) = best_scoring_rule {
+            rules.push(rule);
+        }
+
+        rules
+    }
+}
+
+//
+// Mailbox delivery
+//
+
+/// Envelope addresses for the message being delivered, as handed to
+/// us by the MTA.
+#[derive(Debug)]
+struct Envelope {
+    recipient: String,
+
+    /// The recipient address with its extension (if any) removed, e.g.
+    /// `user@example.com` for `user+tag@example.com`
+    recipient_without_extension: String,
+
+    /// The recipient address extension, e.g. `tag` for
+    /// `user+tag@example.com`
+    recipient_extension: Option<String>,
+
+    /// `recipient` and `recipient_without_extension` as the MTA gave
+    /// them, before lowercasing (but with the domain normalized), for
+    /// `case_sensitive` mailboxes
+    original_case_recipient: String,
+    original_case_recipient_without_extension: String,
+
+    /// The envelope sender (MAIL FROM), or None if it wasn't supplied
+    /// or is the null sender used by bounces.
+    sender: Option<String>
+}
+
+/// Split `address` into the address with its extension removed and
+/// the extension itself, e.g. `user+tag@example.com` into
+/// (`user@example.com`, `tag`). The extension starts after the first
+/// character of the local part that appears in `delimiters`.
+fn split_recipient_extension(address: &str, delimiters: &str) -> Option<(String, String)> {
+    let (local_part, domain) = address::split_address(address)?;
+    let (base, extension) = local_part.split_once(|c| delimiters.contains(c))?;
+
+    match extension.is_empty() {
+        true => None,
+        false => Some((address::join_address(base, &domain), extension.to_string()))
+    }
+}
+
+/// Split `recipient` into (recipient, recipient without extension,
+/// extension).
+fn split_recipient(recipient: String, recipient_delimiter: &str) -> (String, String, Option<String>) {
+    match split_recipient_extension(&recipient, recipient_delimiter) {
+        Some((recipient_without_extension, extension)) => (recipient, recipient_without_extension, Some(extension)),
+        None => (recipient.clone(), recipient, None)
+    }
+}
+
+/// Replace each recipient that is an alias with the addresses it
+/// expands to. An alias is looked up by the full recipient address,
+/// then by the address without its extension. Fails if an alias
+/// expands to nothing that can be delivered to.
+fn expand_recipient_aliases(
+    aliases: &Aliases,
+    recipients: Vec<(String, String, Option<String>)>,
+    recipient_delimiter: &str
+) -> Result<Vec<(String, String, Option<String>)>> {
+    let mut expanded = Vec::new();
+
+    for recipient in recipients {
+        let alias = [&recipient.0, &recipient.1]
+            .into_iter()
+            .find(|address| aliases.is_alias(address));
+
+        match alias {
+            Some(alias) => expanded.extend(aliases
+                .expand(alias)?
+                .into_iter()
+                .map(|address| split_recipient(address, recipient_delimiter))),
+            None => expanded.push(recipient)
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Read the recipient addresses from the `--recipient` options and
+/// positional arguments, or if there are none, from ORIGINAL_RECIPIENT
+/// (or the variable named by `--recipient-env`). Either may list
+/// several addresses separated by commas. Each is parsed as an RFC 5322
+/// address, so quoted local parts, comments and angle brackets are
+/// allowed.
+fn get_original_recipient_email_addresses(args: &Args) -> Result<Vec<String>> {
+    let arguments: Vec<_> = args.recipient_options.iter().chain(&args.recipient_arguments).collect();
+
+    if !arguments.is_empty() {
+        let recipients: Vec<_> = arguments
+            .into_iter()
+            .flat_map(|argument| address::split_addresses(argument))
+            .collect();
+
+        return match recipients.is_empty() {
+            true => bail!("No recipient email address in the command-line arguments"),
+            false => Ok(recipients)
+        };
+    }
+
+    let env_variable: &str = match args.original_recipient_environment_variable {
+        Some(ref name) => name,
+        None => "ORIGINAL_RECIPIENT"
+    };
+
+    let recipients = address::split_addresses(&env::var(env_variable)
+        .with_context(|| format!("Missing {} environment variable for recipient email address", env_variable))?);
+
+    match recipients.is_empty() {
+        true => bail!("No recipient email address in {env_variable} environment variable"),
+        false => Ok(recipients)
+    }
+}
+
+/// Read the recipient addresses from the environment and split off
+/// their extensions.
+///
+/// Normally the addresses come from the command line (`--recipient` or
+/// positional arguments), or failing that from ORIGINAL_RECIPIENT (or
+/// the variable named by `--recipient-env`), and extensions are found using
+/// `recipient_delimiter`. With `--local-domain-env`, a single address
+/// is instead put together from Postfix's LOCAL, DOMAIN and EXTENSION
+/// variables, and EXTENSION is taken as the extension as-is.
+///
+/// The parts are returned in their original case.
+fn get_recipients(args: &Args, recipient_delimiter: &str) -> Result<Vec<(String, String, Option<String>)>> {
+    if !args.local_domain_environment {
+        let recipients = get_original_recipient_email_addresses(args)?;
+
+        return Ok(recipients
+            .into_iter()
+            .map(|recipient| split_recipient(recipient, recipient_delimiter))
+            .collect());
+    }
+
+    let get_env_variable = |name: &str| env::var(name)
+        .map(|value| value.trim().to_string())
+        .with_context(|| format!("Missing {name} environment variable for recipient email address"));
+
+    let local_part = get_env_variable("LOCAL")?;
+    let domain = get_env_variable("DOMAIN")?;
+    let extension = get_env_variable("EXTENSION").ok().filter(|extension| !extension.is_empty());
+
+    let Some(extension) = extension else {
+        let recipient = address::join_address(&local_part, &domain);
+        return Ok(vec![(recipient.clone(), recipient, None)]);
+    };
+
+    // local(8) includes the extension in LOCAL, but other delivery
+    // agents may not
+    let base_local_part = local_part
+        .strip_suffix(extension.as_str())
+        .and_then(|base| base.strip_suffix(|c| recipient_delimiter.contains(c)))
+        .unwrap_or(&local_part);
+
+    let delimiter = recipient_delimiter.chars().next().unwrap_or('+');
+
+    Ok(vec![(
+        address::join_address(&format!("{base_local_part}{delimiter}{extension}"), &domain),
+        address::join_address(base_local_part, &domain),
+        Some(extension)
+    )])
+}
+
+fn get_normalized_sender_email_address(args: &Args) -> Option<String> {
+    let env_variable: &str = match args.sender_environment_variable {
+        Some(ref name) => name,
+        None => "SENDER"
+    };
+
+    env::var(env_variable)
+        .ok()
+        .map(|sender| address::normalize_address(&sender))
+        .filter(|sender| !sender.is_empty())
+}
+
+
+/// The sysexits.h exit code for an unknown user, which MTAs turn into
+/// a permanent bounce.
+const EX_NOUSER: i32 = 67;
+
+/// The sysexits.h exit code for an output file that can't be created,
+/// which MTAs turn into a permanent bounce.
+const EX_CANTCREAT: i32 = 73;
+
+/// The sysexits.h exit code for a temporary failure, which MTAs retry
+/// later.
+const EX_TEMPFAIL: i32 = 75;
+
+/// Header added to forwarded messages, naming the address they were
+/// forwarded to, so that forwarding loops can be spotted.
+const FORWARDED_TO_HEADER: &str = "X-Sortmail-Forwarded-To";
+
+/// Name of the delivered Message-ID index file, in the root Maildir.
+const MESSAGE_ID_INDEX_FILE_NAME: &str = ".sortmail-message-ids";
+
+/// Name of the classifier model file, in the root Maildir.
+const CLASSIFIER_MODEL_FILE_NAME: &str = ".sortmail-classifier";
+
+/// Name of the file of senders sent vacation replies, in the root
+/// Maildir.
+const VACATION_LOG_FILE_NAME: &str = ".sortmail-vacation";
+
+/// Name of the file of messages the spam filter has learned from, in
+/// the root Maildir.
+const SPAM_LEARNED_FILE_NAME: &str = ".sortmail-learned";
+
+/// Return the root Maildir: `args.override_root_maildir`, or
+/// $HOME/Maildir.
+fn get_root_maildir(args: &Args) -> Result<PathBuf> {
+    match args.override_root_maildir {
+        Some(ref path) => Ok(PathBuf::from(path)),
+        None => {
+            let homedir = env::var("HOME")
+                .context("Unable to find HOME environment variable")?;
+            let mut path = PathBuf::from(homedir);
+            path.push("Maildir");
+            Ok(path)
+        }
+    }
+}
+
+/// Return the root Maildir for `recipient`: `args.override_root_maildir`,
+/// or the recipient's `root_maildir` template filled in, or
+/// $HOME/Maildir.
+fn get_recipient_root_maildir(args: &Args, mappings: &AddressMap, recipient: &str) -> Result<PathBuf> {
+    if args.override_root_maildir.is_some() {
+        return get_root_maildir(args);
+    }
+
+    match mappings.root_maildir_template(recipient) {
+        Some(template) => expand_root_maildir_template(template, recipient),
+        None => get_root_maildir(args)
+    }
+}
+
+/// Fill in `%n` (local part), `%d` (domain), `%u` (address) and `%%`
+/// in a root Maildir template, and expand a leading `~/`. A recipient
+/// whose parts would escape the template's directory, like one with a
+/// `/` in its local part, is refused.
+fn expand_root_maildir_template(template: &str, recipient: &str) -> Result<PathBuf> {
+    let (local_part, domain) = address::split_address(recipient)
+        .with_context(|| format!("Recipient {recipient} has no domain for root_maildir"))?;
+
+    let address = address::join_address(&local_part, &domain);
+
+    for value in [&local_part, &domain, &address] {
+        if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\0']) {
+            bail!("Recipient {recipient} can't be used in a root_maildir path");
+        }
+    }
+
+    let mut expanded = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => expanded.push_str(&local_part),
+            Some('d') => expanded.push_str(&domain),
+            Some('u') => expanded.push_str(&address),
+            Some('%') => expanded.push('%'),
+            Some(other) => bail!("Unknown %{other} in root_maildir {template:?}, expected %n, %d, %u or %%"),
+            None => bail!("Unfinished % at the end of root_maildir {template:?}")
+        }
+    }
+
+    match expanded.strip_prefix("~/") {
+        Some(relative_to_home) => {
+            let homedir = env::var("HOME")
+                .context("Unable to find HOME environment variable")?;
+            Ok(Path::new(&homedir).join(relative_to_home))
+        },
+        None => Ok(PathBuf::from(expanded))
+    }
+}
+
+fn load_address_map(args: &Args) -> Result<AddressMap> {
+    let mappings = AddressMap::from_file(&args.config)
+        .with_context(|| format!("Error loading config file {}", args.config.display()))?;
+
+    if args.print_address_map {
+        dbg!(&mappings);
+    }
+
+    Ok(mappings)
+}
+
+fn read_message_from_stdin() -> Result<IncomingMessage> {
+    IncomingMessage::from_bytes(read_message_bytes_from_stdin()?.into_boxed_slice())
+}
+
+/// Read the raw message from stdin, which mustn't be empty.
+fn read_message_bytes_from_stdin() -> Result<Vec<u8>> {
+    let mut incoming_message_bytes = Vec::new();
+    stdin()
+        .read_to_end(&mut incoming_message_bytes)
+        .context("Error loading message data from stdin")?;
+
+    if incoming_message_bytes.is_empty() {
+        return Err(Box::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+            .context("Empty incoming message data");
+    }
+
+    Ok(incoming_message_bytes)
+}
+
+/// Return the classifier's best guess for a message, formatted for the
+/// `X-Sortmail-Guess:` header, like `Receipts (0.93)`. Problems with
+/// the model are reported but don't stop delivery.
+fn classifier_guess(root_maildir: &Path, message: &IncomingMessage) -> Option<String> {
+    let model_path = root_maildir.join(CLASSIFIER_MODEL_FILE_NAME);
+
+    let classifier = match Classifier::load(&model_path) {
+        Ok(classifier) => classifier,
+        Err(error) => {
+            eprintln!("Warning: {error:#}; run sortmail train to create it");
+            return None;
+        }
+    };
+
+    classifier
+        .classify(message)
+        .first()
+        .map(|guess| format!("{} ({:.2})", guess.mailbox_name, guess.probability))
+}
+
+/// Train the classifier from the folders of the root Maildir, and save
+/// the model there.
+fn train_classifier(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let classifier = Classifier::train(&root_maildir, &mappings.namespace, &mappings.classifier_ignore_folders)?;
+
+    for (mailbox_name, message_count) in classifier.folder_message_counts() {
+        println!("{mailbox_name}: {message_count} messages");
+    }
+
+    if !args.dry_run {
+        classifier.save(&root_maildir.join(CLASSIFIER_MODEL_FILE_NAME))?;
+    }
+
+    Ok(())
+}
+
+/// Print the classifier's guesses for the message on stdin, most
+/// likely first.
+fn classify_message_from_stdin(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let incoming_message = read_message_from_stdin()?;
+
+    let classifier = Classifier::load(&root_maildir.join(CLASSIFIER_MODEL_FILE_NAME))?;
+
+    for guess in classifier.classify(&incoming_message) {
+        println!("{:.4} {}", guess.probability, guess.mailbox_name);
+    }
+
+    Ok(())
+}
+
+/// Print TOML mailbox tables suggested by the mail already sorted into
+/// the root Maildir's folders.
+fn suggest_mailboxes(args: &Args, min_messages: usize) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    print!("{}", suggest::suggest(&root_maildir, &mappings.namespace, &mappings.classifier_ignore_folders, min_messages)?);
+
+    Ok(())
+}
+
+/// Remove the files left behind in the `tmp` directories of the root
+/// Maildir and its folders by interrupted deliveries.
+fn clean_maildir(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let folder_paths = folders::list(&root_maildir, &mappings.namespace, &[])?
+        .into_iter()
+        .map(|(_, folder_path)| folder_path);
+
+    for folder_path in std::iter::once(root_maildir.clone()).chain(folder_paths) {
+        for path in clean::clean_tmp(&folder_path, args.dry_run)? {
+            println!(
+                "Removed {}{}",
+                path.display(),
+                match args.dry_run {
+                    true => " (dry run, nothing was actually removed)",
+                    false => ""
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Repair the root Maildir and each of its folders, including those
+/// too broken to show up as folders otherwise.
+fn repair_maildir(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let folder_paths = folders::list_partial(&root_maildir, &mappings.namespace)?
+        .into_iter()
+        .map(|(_, folder_path)| folder_path);
+
+    let mut fix_count = 0;
+    for folder_path in std::iter::once(root_maildir.clone()).chain(folder_paths) {
+        for fix in repair::repair_folder(&folder_path, mappings.store_options.directory_mode, args.dry_run)? {
+            println!("{fix}");
+            fix_count += 1;
+        }
+    }
+
+    println!(
+        "{fix_count} problems found{}",
+        match args.dry_run {
+            true => " (dry run, nothing was actually changed)",
+            false => ""
+        }
+    );
+
+    Ok(())
+}
+
+/// Expire the old messages in the folders of each mailbox with
+/// `expire_after`: its own folder, or every folder its `folder`
+/// template can produce.
+fn expire_mailboxes(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let folders = folders::list(&root_maildir, &mappings.namespace, &[])?;
+
+    for rule in &mappings.mailbox_rules {
+        let Some(max_age) = rule.expire_after else {
+            continue;
+        };
+
+        let folder_name_regex = folder_template_regex(rule.folder.as_deref().unwrap_or(&rule.mailbox_name));
+        let archive_path = rule.expire_to
+            .as_ref()
+            .map(|expire_to| mappings.namespace.folder_path(&root_maildir, expire_to));
+
+        for (mailbox_name, folder_path) in &folders {
+            if !folder_name_regex.is_match(mailbox_name) || archive_path.as_ref() == Some(folder_path) {
+                continue;
+            }
+
+            let expired = expire::expire_folder(folder_path, max_age, archive_path.as_deref(), &mappings.store_options, args.dry_run)?;
+
+            if !expired.is_empty() {
+                println!(
+                    "{mailbox_name}: {} {} messages{}",
+                    match archive_path {
+                        Some(_) => "moved",
+                        None => "removed"
+                    },
+                    expired.len(),
+                    match (&rule.expire_to, args.dry_run) {
+                        (_, true) => " (dry run, nothing was actually changed)".to_string(),
+                        (Some(expire_to), false) => format!(" to {expire_to}"),
+                        (None, false) => String::new()
+                    }
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the old messages in the `archive_folders` into their archive
+/// folders.
+fn archive_messages(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    if mappings.archive_folders.is_empty() {
+        bail!("No archive_folders to archive messages from");
+    }
+
+    for mailbox_name in &mappings.archive_folders {
+        let folder_path = match mailbox_name.eq_ignore_ascii_case("INBOX") {
+            true => root_maildir.clone(),
+            false => mappings.namespace.folder_path(&root_maildir, mailbox_name)
+        };
+
+        let archived = mappings.archive.archive_folder(&folder_path, &root_maildir, &mappings.namespace, &mappings.store_options, args.dry_run)?;
+
+        let mut counts: IndexMap<&str, usize> = IndexMap::new();
+        for (_, archive_mailbox_name) in &archived {
+            *counts.entry(archive_mailbox_name).or_default() += 1;
+        }
+        counts.sort_keys();
+
+        for (archive_mailbox_name, count) in counts {
+            println!(
+                "{mailbox_name}: moved {count} messages to {archive_mailbox_name}{}",
+                match args.dry_run {
+                    true => " (dry run, nothing was actually moved)",
+                    false => ""
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Train the spam filter on the messages in the `junk_folder` that it
+/// hasn't learned as spam, and on those it learned as spam that have
+/// since been moved to another folder as ham.
+fn learn_sorted_spam(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let (Some(learner), Some(junk_folder)) = (mappings.spam_learner, &mappings.junk_folder) else {
+        bail!("No spam_learner and junk_folder to train");
+    };
+
+    let mut learned = LearnedLog::open(&root_maildir.join(SPAM_LEARNED_FILE_NAME))?;
+    let junk_path = mappings.namespace.folder_path(&root_maildir, junk_folder);
+
+    let read_message = |path: &Path| compress::read_message(path)
+        .and_then(|bytes| IncomingMessage::from_bytes(bytes.into_boxed_slice()));
+
+    let mut to_learn = Vec::new();
+
+    for path in spam_learner::message_paths(&junk_path) {
+        let Ok(message) = read_message(&path) else {
+            continue;
+        };
+
+        if learned.verdict(&spam_learner::message_key(&message)) != Some(Verdict::Spam) {
+            to_learn.push((message, Verdict::Spam, junk_folder.clone()));
+        }
+    }
+
+    let mut ignore_folders = mappings.spam_learner_ignore_folders.clone();
+    ignore_folders.push(junk_folder.clone());
+
+    let folders = std::iter::once(("INBOX".to_string(), root_maildir.clone()))
+        .chain(folders::list(&root_maildir, &mappings.namespace, &ignore_folders)?);
+
+    for (mailbox_name, folder_path) in folders {
+        for path in spam_learner::message_paths(&folder_path) {
+            let was_spam = spam_learner::read_message_id(&path)
+                .is_some_and(|message_id| learned.verdict(&message_id) == Some(Verdict::Spam));
+
+            if let (true, Ok(message)) = (was_spam, read_message(&path)) {
+                to_learn.push((message, Verdict::Ham, mailbox_name.clone()));
+            }
+        }
+    }
+
+    for (message, verdict, mailbox_name) in to_learn {
+        if !args.dry_run {
+            learner.learn(verdict, &message.bytes[message.header_start()..], mappings.post_deliver_timeout)?;
+            learned.record(&spam_learner::message_key(&message), verdict)?;
+        }
+
+        println!(
+            "Learned {} as {} from {mailbox_name}{}",
+            message.message_id().unwrap_or_else(|| "a message without a Message-ID".to_string()),
+            match verdict {
+                Verdict::Spam => "spam",
+                Verdict::Ham => "ham"
+            },
+            match args.dry_run {
+                true => " (dry run, nothing was actually learned)",
+                false => ""
+            }
+        );
+    }
+
+    Ok(())
+}
+
+/// Fix the links to messages in the `canonical_folder` in the root
+/// Maildir and each of its other folders.
+fn relink_folders(args: &Args) -> Result<()> {
+    let root_maildir = get_root_maildir(args)?;
+    let mappings = load_address_map(args)?;
+
+    let Some(ref canonical_folder) = mappings.canonical_folder else {
+        bail!("No canonical_folder to relink to");
+    };
+
+    let canonical_copies = links::canonical_copies(&mappings.namespace.folder_path(&root_maildir, canonical_folder))?;
+
+    let folder_paths = folders::list(&root_maildir, &mappings.namespace, std::slice::from_ref(canonical_folder))?
+        .into_iter()
+        .map(|(_, folder_path)| folder_path);
+
+    let mut fix_count = 0;
+    for folder_path in std::iter::once(root_maildir.clone()).chain(folder_paths) {
+        for fix in links::relink_folder(&folder_path, &canonical_copies, args.dry_run)? {
+            println!("{fix}");
+            fix_count += 1;
+        }
+    }
+
+    println!(
+        "{fix_count} links fixed{}",
+        match args.dry_run {
+            true => " (dry run, nothing was actually changed)",
+            false => ""
+        }
+    );
+
+    Ok(())
+}
+
+/// Return a regex matching the folder names that the folder template
+/// `template` can produce, with each placeholder matching anything.
+fn folder_template_regex(template: &str) -> Regex {
+    let mut pattern = String::from("^");
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+                pattern.push_str(".+");
+            },
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                pattern.push('%');
+            },
+            '%' => {
+                chars.next();
+                pattern.push_str(".+");
+            },
+            '/' => pattern.push_str(r"\."),
+            c => pattern.push_str(&regex::escape(&c.to_string()))
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).expect("escaped folder template is a valid regex")
+}
+
+/// Load an email message from stdin and the environment, and deliver
+/// it to the right Maildir mailbox based on the mappings detailed in
+/// the file at `args.config`.
+fn sort_message_from_stdin(args: &Args) -> Result<()> {
+    let mappings = load_address_map(args)?;
+
+    let bytes = read_message_bytes_from_stdin()?;
+
+    let mut recipients = get_recipients(args, &mappings.recipient_delimiter)?;
+
+    if let Some(ref aliases) = mappings.aliases {
+        recipients = expand_recipient_aliases(aliases, recipients, &mappings.recipient_delimiter)?;
+    }
+
+    // A message whose headers can't be parsed can't be sorted, but it
+    // can still be quarantined, in every recipient's root Maildir
+    let incoming_message = match mappings.quarantine_folder.is_some() {
+        false => IncomingMessage::from_bytes(bytes.into_boxed_slice())?,
+        true => match IncomingMessage::from_bytes(bytes.clone().into_boxed_slice()) {
+            Ok(incoming_message) => incoming_message,
+            Err(error) => {
+                let reason = format!("{error:#}");
+
+                return match args.multi_user {
+                    true => deliver_to_local_users(recipients, |user, recipients| {
+                        quarantine_as_local_user(args, &mappings, &bytes, &reason, user, recipients)
+                    }),
+                    false => quarantine_for_recipients(args, &mappings, &bytes, &reason, recipients)
+                };
+            }
+        }
+    };
+
+    if args.multi_user {
+        return deliver_to_local_users(recipients, |user, recipients| {
+            deliver_as_user(args, &mappings, &incoming_message, user, recipients)
+        });
+    }
+
+    sort_message_for_recipients(args, &mappings, &incoming_message, recipients)
+}
+
+/// Sort a message separately into each root Maildir that `recipients`
+/// have (see `get_recipient_root_maildir`). A failure for one root
+/// Maildir doesn't stop delivery to the others, but is reported once
+/// they're done.
+fn sort_message_for_recipients(args: &Args, mappings: &AddressMap, incoming_message: &IncomingMessage, recipients: Vec<(String, String, Option<String>)>) -> Result<()> {
+    let mut recipients_by_root_maildir: IndexMap<PathBuf, Vec<_>> = IndexMap::new();
+
+    for recipient in recipients {
+        let root_maildir = get_recipient_root_maildir(args, mappings, &address::normalize_address(&recipient.1))?;
+        recipients_by_root_maildir.entry(root_maildir).or_default().push(recipient);
+    }
+
+    if recipients_by_root_maildir.len() == 1 {
+        let (root_maildir, recipients) = recipients_by_root_maildir.pop().unwrap_or_default();
+
+        return match sort_message(args, &root_maildir, mappings, incoming_message, recipients) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                quarantine_message(args, mappings, &root_maildir, &incoming_message.bytes, error)?;
+                exit_after_quarantine(mappings)
+            }
+        };
+    }
+
+    let mut failed_root_maildirs = Vec::new();
+    let mut quarantined = false;
+
+    for (root_maildir, recipients) in recipients_by_root_maildir {
+        let sorted = sort_message(args, &root_maildir, mappings, incoming_message, recipients)
+            .or_else(|error| quarantine_message(args, mappings, &root_maildir, &incoming_message.bytes, error).map(|_| quarantined = true));
+
+        if let Err(error) = sorted {
+            eprintln!("Error: {error:#}");
+            failed_root_maildirs.push(root_maildir.display().to_string());
+        }
+    }
+
+    if !failed_root_maildirs.is_empty() {
+        bail!("Error delivering message to {}", failed_root_maildirs.join(", "));
+    }
+
+    match quarantined {
+        true => exit_after_quarantine(mappings),
+        false => Ok(())
+    }
+}
+
+/// Store the raw message `bytes` in the global `quarantine_folder` of
+/// `root_maildir`, since `error` kept it from being sorted or delivered.
+/// Without a `quarantine_folder` (or on a dry run), `error` is returned
+/// as it is.
+fn quarantine_message(args: &Args, mappings: &AddressMap, root_maildir: &Path, bytes: &[u8], error: anyhow::Error) -> Result<()> {
+    let Some(ref quarantine_folder) = mappings.quarantine_folder else {
+        return Err(error);
+    };
+
+    if args.dry_run {
+        return Err(error);
+    }
+
+    eprintln!("Error: {error:#}");
+
+    let maildir = mappings.namespace.folder_path(root_maildir, quarantine_folder);
+
+    store_message(&Maildir::from(maildir.clone()), true, None, &[], bytes, None, &mappings.store_options)
+        .with_context(|| format!("Error quarantining message in {}", maildir.display()))?;
+
+    println!("Quarantined message in {}", maildir.display());
+    Ok(())
+}
+
+/// Quarantine the raw message `bytes`, which couldn't be parsed for
+/// `reason`, once in each root Maildir that `recipients` have (see
+/// `get_recipient_root_maildir`). A failure for one root Maildir doesn't
+/// stop the others being tried, but is reported once they're done.
+fn quarantine_for_recipients(args: &Args, mappings: &AddressMap, bytes: &[u8], reason: &str, recipients: Vec<(String, String, Option<String>)>) -> Result<()> {
+    let mut root_maildirs = IndexSet::new();
+
+    for recipient in &recipients {
+        root_maildirs.insert(get_recipient_root_maildir(args, mappings, &address::normalize_address(&recipient.1))?);
+    }
+
+    let mut failed_root_maildirs = Vec::new();
+
+    for root_maildir in root_maildirs {
+        if let Err(error) = quarantine_message(args, mappings, &root_maildir, bytes, anyhow!("{reason}")) {
+            eprintln!("Error: {error:#}");
+            failed_root_maildirs.push(root_maildir.display().to_string());
+        }
+    }
+
+    if !failed_root_maildirs.is_empty() {
+        bail!("Error quarantining message in {}", failed_root_maildirs.join(", "));
+    }
+
+    exit_after_quarantine(mappings)
+}
+
+/// Exit with the global `quarantine_exit_code` now that the message
+/// has been quarantined, or return success if there isn't one.
+fn exit_after_quarantine(mappings: &AddressMap) -> Result<()> {
+    if let Some(exit_code) = mappings.quarantine_exit_code {
+        std::process::exit(exit_code);
+    }
+
+    Ok(())
+}
+
+/// Deliver a message for the whole system, as root: the local part of
+/// each recipient (without its extension) names a local user, and each
+/// user's recipients are handed to `deliver` (which sorts them into
+/// their $HOME/Maildir, say, with `deliver_as_user`) by a child process
+/// (see the `users` module). The
+/// user's own `user_config` file is used if they have one, and this
+/// config otherwise; this config's `recipient_delimiter` and
+/// `aliases_file` decide who the recipients are either way.
+///
+/// If any recipient isn't a local user, the message is bounced before
+/// anything is delivered. Otherwise each user's delivery succeeds or
+/// fails on its own, and a failure for any of them is reported to the
+/// MTA, with a temporary failure taking precedence.
+fn deliver_to_local_users(recipients: Vec<(String, String, Option<String>)>, deliver: impl Fn(&LocalUser, Vec<(String, String, Option<String>)>) -> Result<()>) -> Result<()> {
+    if !users::is_root() {
+        bail!("Multi-user delivery has to run as root");
+    }
+
+    let mut recipients_by_user: IndexMap<String, (LocalUser, Vec<_>)> = IndexMap::new();
+
+    for recipient in recipients {
+        let local_part = address::split_address(&recipient.1)
+            .map(|(local_part, _)| local_part.to_lowercase())
+            .unwrap_or_default();
+
+        if !recipients_by_user.contains_key(&local_part) {
+            let user = lookup_local_user(&local_part, &recipient.0)?;
+            recipients_by_user.insert(local_part.clone(), (user, Vec::new()));
+        }
+
+        recipients_by_user[&local_part].1.push(recipient);
+    }
+
+    let mut children = Vec::new();
+
+    for (user, recipients) in recipients_by_user.into_values() {
+        match users::fork()? {
+            Forked::Child => {
+                let result = deliver(&user, recipients);
+
+                std::process::exit(match result {
+                    Ok(()) => 0,
+                    Err(error) => {
+                        eprintln!("Error: {error:?}");
+                        1
+                    }
+                });
+            },
+            Forked::Parent(pid) => children.push((user, pid))
+        }
+    }
+
+    let mut failed_users = Vec::new();
+    let mut exit_code = None;
+
+    for (user, pid) in children {
+        match users::wait(pid)? {
+            Some(0) => {},
+            Some(1) => failed_users.push(user.name),
+            // A rejection, or a temporary failure
+            Some(code) => {
+                failed_users.push(user.name);
+
+                if exit_code != Some(EX_TEMPFAIL) {
+                    exit_code = Some(code);
+                }
+            },
+            None => {
+                failed_users.push(user.name);
+                exit_code = Some(EX_TEMPFAIL);
+            }
+        }
+    }
+
+    if let Some(code) = exit_code {
+        eprintln!("Error delivering message to {}", failed_users.join(", "));
+        std::process::exit(code);
+    }
+
+    if !failed_users.is_empty() {
+        bail!("Error delivering message to {}", failed_users.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Return the local user named `local_part`, for the recipient
+/// `recipient`. If there's no such user, or it's root, the message is
+/// bounced.
+fn lookup_local_user(local_part: &str, recipient: &str) -> Result<LocalUser> {
+    match users::lookup(local_part)? {
+        Some(user) if user.uid == 0 => {
+            eprintln!("Not delivering to {recipient} as root; alias root to another user");
+            std::process::exit(EX_NOUSER);
+        },
+        Some(user) => Ok(user),
+        None => {
+            eprintln!("No local user {local_part:?} for recipient {recipient}");
+            std::process::exit(EX_NOUSER);
+        }
+    }
+}
+
+/// Switch to `user` and quarantine the raw message `bytes`, which
+/// couldn't be parsed for `reason`, for their `recipients`, with their
+/// own config file if they have one (see `quarantine_for_recipients`).
+fn quarantine_as_local_user(args: &Args, mappings: &AddressMap, bytes: &[u8], reason: &str, user: &LocalUser, recipients: Vec<(String, String, Option<String>)>) -> Result<()> {
+    let user_mappings = become_local_user(args, mappings, user, &recipients)?;
+
+    quarantine_for_recipients(args, user_mappings.as_ref().unwrap_or(mappings), bytes, reason, recipients)
+}
+
+/// Switch to `user` and sort a message for their `recipients` into
+/// their Maildir, using their own config file if they have one.
+fn deliver_as_user(args: &Args, mappings: &AddressMap, incoming_message: &IncomingMessage, user: &LocalUser, recipients: Vec<(String, String, Option<String>)>) -> Result<()> {
+    let user_mappings = become_local_user(args, mappings, user, &recipients)?;
+
+    sort_message_for_recipients(args, user_mappings.as_ref().unwrap_or(mappings), incoming_message, recipients)
+}
+
+/// Switch to `user`, and return their own config, if they have one. A
+/// root Maildir for their `recipients` that the user couldn't create
+/// is created for them first.
+fn become_local_user(args: &Args, mappings: &AddressMap, user: &LocalUser, recipients: &[(String, String, Option<String>)]) -> Result<Option<AddressMap>> {
+    // A root_maildir outside the user's home, like /var/mail/%n, may be
+    // somewhere they can't create it themselves
+    if !args.dry_run {
+        for recipient in recipients {
+            let recipient_address = address::normalize_address(&recipient.1);
+
+            let Some(template) = mappings.root_maildir_template(&recipient_address).filter(|template| !template.starts_with("~/")) else {
+                continue;
+            };
+
+            let root_maildir = expand_root_maildir_template(template, &recipient_address)?;
+            if user.create_root_maildir(&root_maildir, mappings.store_options.directory_mode)? {
+                println!("Recipient {}: Created root Maildir {} for user {}", recipient.0, root_maildir.display(), user.name);
+            }
+        }
+    }
+
+    user.become_user()?;
+
+    let user_config_path = mappings
+        .user_config
+        .as_ref()
+        .map(|path| user.home.join(path))
+        .filter(|path| path.exists());
+
+    match user_config_path {
+        Some(ref path) => AddressMap::from_file(path)
+            .map(Some)
+            .with_context(|| format!("Error loading config file {}", path.display())),
+        None => Ok(None)
+    }
+}
+
+/// Sort a message for `recipients` (as returned by `get_recipients`)
+/// into the Maildir at `root_maildir` and carry out the deliveries.
+fn sort_message(args: &Args, root_maildir: &Path, mappings: &AddressMap, incoming_message: &IncomingMessage, recipients: Vec<(String, String, Option<String>)>) -> Result<()> {
+    let sender = get_normalized_sender_email_address(args);
+
+    let envelopes: Vec<_> = recipients
+        .into_iter()
+        .map(|(original_case_recipient, original_case_recipient_without_extension, recipient_extension)| Envelope {
+            recipient: address::normalize_address(&original_case_recipient),
+            recipient_without_extension: address::normalize_address(&original_case_recipient_without_extension),
+            recipient_extension: recipient_extension.map(|extension| address::normalize_local_part(&extension)),
+            original_case_recipient: address::normalize_address_domain(&original_case_recipient),
+            original_case_recipient_without_extension: address::normalize_address_domain(&original_case_recipient_without_extension),
+            sender: sender.clone()
+        })
+        .collect();
+
+    let message_id = incoming_message.message_id();
+    let message_id_index_path = root_maildir.join(MESSAGE_ID_INDEX_FILE_NAME);
+    let content_key = mappings.dedupe_content.then(|| format!("sha256:{}", incoming_message.content_hash()));
+    let use_message_id_index = mappings.dedupe || mappings.dedupe_content || mappings.follow_threads;
+
+    // A dry run only reads an existing index, rather than creating one
+    let mut message_id_index = match use_message_id_index && (!args.dry_run || message_id_index_path.exists()) {
+        true => Some(MessageIdIndex::open(&message_id_index_path)?),
+        false => None
+    };
+
+    let is_duplicate = message_id_index.as_ref().is_some_and(|index| {
+        let message_id_seen = mappings.dedupe && message_id.as_deref().is_some_and(|message_id| index.contains(message_id));
+        let content_seen = content_key.as_deref().is_some_and(|content_key| index.contains(content_key));
+
+        message_id_seen || content_seen
+    });
+
+    // The folder of the nearest ancestor of this message that we
+    // delivered, if it wasn't the root Maildir
+    let parent_mailbox_name = match (&message_id_index, mappings.follow_threads) {
+        (Some(index), true) => incoming_message
+            .thread_parent_ids()
+            .iter()
+            .find_map(|parent_id| index.mailbox_name(parent_id))
+            .flatten()
+            .map(str::to_string),
+        _ => None
+    };
+
+    // Each recipient is sorted separately, but a folder only gets one
+    // copy of the message however many recipients are sorted into it
+    let mut deliveries: Vec<_> = envelopes
+        .iter()
+        .flat_map(|envelope| mappings
+            .actions_for_message(envelope, incoming_message, parent_mailbox_name.as_deref())
+            .into_iter()
+            .map(move |action| (envelope, action)))
+        .collect();
+
+    // Sender and date placeholders are filled in before anything else
+    // looks at the folder names, and a folder for a new sender or a new
+    // month (say) is created
+    let has_placeholders = |mailbox_name: &str| has_sender_placeholders(mailbox_name) || dates::has_placeholders(mailbox_name);
+
+    if deliveries.iter().any(|(_, action)| action.mailbox_name.as_deref().is_some_and(has_placeholders)) {
+        let time = dates::message_time(incoming_message, mappings.folder_date);
+
+        for (envelope, action) in &mut deliveries {
+            if let Some(mailbox_name) = action.mailbox_name.as_mut().filter(|mailbox_name| has_placeholders(mailbox_name)) {
+                let with_sender = fill_in_sender_placeholders(mailbox_name, incoming_message, envelope.sender.as_deref());
+                *mailbox_name = dates::expand(&with_sender, time);
+                action.create_folder = true;
+            }
+        }
+    }
+
+    // Rejecting any recipient rejects the message, before anything is
+    // delivered
+    let rejection = deliveries.iter().find_map(|(envelope, action)| match action.kind {
+        DeliveryKind::Reject(ref rejection) => Some((envelope, action.mailbox_name.as_deref().unwrap_or_default(), rejection)),
+        _ => None
+    });
+
+    if let Some((envelope, mailbox_name, rejection)) = rejection {
+        println!(
+            "Recipient {}: Reject with exit code {}, as mailbox {mailbox_name} asks{}",
+            envelope.recipient,
+            rejection.exit_code,
+            match args.dry_run {
+                true => " (dry run, nothing will be rejected)",
+                false => ""
+            }
+        );
+
+        if !args.dry_run {
+            eprintln!("{}", rejection.reason);
+            std::process::exit(rejection.exit_code);
+        }
+
+        deliveries.retain(|(_, action)| !matches!(action.kind, DeliveryKind::Reject(_)));
+    }
+
+    if is_duplicate {
+        match mappings.duplicate_folder {
+            Some(ref duplicate_folder) => {
+                deliveries = vec![(&envelopes[0], DeliveryAction {
+                    mailbox_name: Some(duplicate_folder.clone()),
+                    chosen_by: "duplicate_folder".to_string(),
+                    ..Default::default()
+                })];
+            },
+            None => {
+                println!(
+                    "Message {}: Already delivered, discarding duplicate",
+                    message_id.as_deref().unwrap_or_default()
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    // A message that would take the root Maildir over its quota is put
+    // off, refused or sent to the overflow folder, before anything is
+    // delivered
+    let stores_in_maildir = deliveries
+        .iter()
+        .any(|(_, action)| matches!(action.kind, DeliveryKind::Folder));
+
+    if let (Some(quota), true) = (&mappings.quota, stores_in_maildir) {
+        let usage = quota::usage(root_maildir)?;
+
+        if quota.is_exceeded(&usage, incoming_message.bytes.len() as u64) {
+            let dry_run_note = match args.dry_run {
+                true => " (dry run, nothing will be refused)",
+                false => ""
+            };
+
+            let exit_code = match (mappings.quota_full, &mappings.quota_overflow_folder) {
+                (QuotaFull::Tempfail, _) => Some(EX_TEMPFAIL),
+                (QuotaFull::Reject, _) => Some(EX_CANTCREAT),
+                (QuotaFull::Overflow, overflow_folder) => {
+                    println!("Maildir {}: Over quota, delivering to {}", root_maildir.display(), overflow_folder.as_deref().unwrap_or_default());
+
+                    for (_, action) in deliveries.iter_mut().filter(|(_, action)| matches!(action.kind, DeliveryKind::Folder)) {
+                        action.mailbox_name = overflow_folder.clone();
+                        action.create_folder = true;
+                        action.chosen_by = "quota_overflow_folder".to_string();
+                    }
+
+                    None
+                }
+            };
+
+            if let Some(exit_code) = exit_code {
+                println!("Maildir {}: Over quota, exiting with exit code {exit_code}{dry_run_note}", root_maildir.display());
+
+                if !args.dry_run {
+                    eprintln!("Mailbox is full");
+                    std::process::exit(exit_code);
+                }
+            }
+        }
+    }
+
+    // Replies are filed with the first folder the message went to
+    let primary_mailbox_name = deliveries
+        .iter()
+        .find(|(_, action)| action.stores_message() && action.after_failed_pipes == 0)
+        .and_then(|(_, action)| action.mailbox_name.clone());
+
+    // Unclaimed messages are marked with where the classifier thinks
+    // they belong
+    let guess = match mappings.classifier && deliveries.iter().any(|(_, action)| action.unclaimed) {
+        true => classifier_guess(root_maildir, incoming_message),
+        false => None
+    };
+
+    if let Some(ref guess) = guess {
+        println!("Classifier guess: {guess}");
+    }
+
+    // A failed delivery doesn't stop the message going to the other
+    // folders, but is still reported as an error once they're done, so
+    // that the MTA doesn't consider the message delivered
+    let mut delivered_destinations = HashSet::new();
+    let mut failed_mailbox_names = Vec::new();
+    let mut forwarded_addresses = HashSet::new();
+    let mut delivered_anywhere = false;
+
+    // Copies of the message stored in Maildir folders so far, which
+    // identical copies in other folders are linked to
+    let stored_copies = RefCell::new(StoredCopies::default());
+
+    // How many fall-through pipes have failed for each recipient, and
+    // whether a pipe failed in a way that should make the MTA retry
+    let mut failed_pipe_counts: HashMap<&str, usize> = HashMap::new();
+    let mut pipe_tempfailed = false;
+
+    for (envelope, action) in deliveries {
+        if action.after_failed_pipes > failed_pipe_counts.get(envelope.recipient.as_str()).copied().unwrap_or(0) {
+            continue;
+        }
+
+        if let DeliveryKind::Discard = action.kind {
+            println!(
+                "Recipient {}: Discard, as mailbox {} asks{}",
+                envelope.recipient,
+                action.mailbox_name.as_deref().unwrap_or_default(),
+                match args.dry_run {
+                    true => " (dry run, nothing will be discarded)",
+                    false => ""
+                }
+            );
+            continue;
+        }
+
+        if let DeliveryKind::Forward(ref address) = action.kind {
+            if !forwarded_addresses.insert(address.to_lowercase()) {
+                continue;
+            }
+
+            match forward_message(mappings, envelope, incoming_message, address, args.dry_run) {
+                Ok(()) => delivered_anywhere = true,
+                Err(error) => {
+                    eprintln!("Error forwarding to {address}: {error:#}");
+                    failed_mailbox_names.push(format!("forward to {address}"));
+                }
+            }
+            continue;
+        }
+
+        if let DeliveryKind::Pipe(ref pipe) = action.kind {
+            println!(
+                "Recipient {}: Pipe to {}{}",
+                envelope.recipient,
+                pipe.command,
+                match args.dry_run {
+                    true => " (dry run, the command won't be run)",
+                    false => ""
+                }
+            );
+
+            if args.dry_run {
+                continue;
+            }
+
+            let mailbox_name = action.mailbox_name.as_deref().unwrap_or_default();
+
+            match (pipe.run(envelope, mailbox_name, &incoming_message.bytes), pipe.on_failure) {
+                (Ok(()), _) => delivered_anywhere = true,
+                (Err(error), PipeFailure::Delivered) => {
+                    eprintln!("Warning: {error:#}; treating the message as delivered");
+                    delivered_anywhere = true;
+                },
+                (Err(error), PipeFailure::Continue) => {
+                    eprintln!("Warning: {error:#}; trying the next mailboxes");
+                    *failed_pipe_counts.entry(envelope.recipient.as_str()).or_default() += 1;
+                },
+                (Err(error), PipeFailure::Tempfail) => {
+                    eprintln!("Error: {error:#}");
+                    failed_mailbox_names.push(format!("pipe to {}", pipe.command));
+                    pipe_tempfailed = true;
+                }
+            }
+            continue;
+        }
+
+        if !delivered_destinations.insert(action.destination()) {
+            continue;
+        }
+
+        let maildir = match action.mailbox_name {
+            Some(ref mailbox_name) => mappings.namespace.folder_path(root_maildir, mailbox_name),
+            None => root_maildir.to_path_buf()
+        };
+
+        let mailbox_store = match action.kind {
+            DeliveryKind::Store(ref store) => Some(store),
+            _ => None
+        };
+
+        let store_location = mailbox_store.map(|store| PathBuf::from(store.location()));
+        let destination_path = store_location.as_ref().unwrap_or(&maildir);
+
+        println!(
+            "Recipient {}: Deliver to {}{}",
+            envelope.recipient,
+            destination_path.display(),
+            match args.dry_run {
+                true => " (dry run, no actual delivery will be performed)",
+                false => ""
+            }
+        );
+
+        if args.dry_run {
+            continue;
+        }
+
+        let flags = match action.flags.is_empty() && !mappings.is_cur_folder(action.mailbox_name.as_deref()) {
+            true => None,
+            false => Some(action.flags.as_str())
+        };
+
+        let mut added_headers = Vec::new();
+
+        if mappings.envelope_headers {
+            added_headers.push(("Return-Path", format!("<{}>", envelope.sender.as_deref().unwrap_or_default())));
+            added_headers.push(("Delivered-To", envelope.original_case_recipient.clone()));
+        }
+
+        if mappings.trace_headers {
+            added_headers.push(("X-Sortmail-Rule", action.chosen_by.clone()));
+            added_headers.push(("X-Sortmail-Version", env!("CARGO_PKG_VERSION").to_string()));
+        }
+
+        if let (Some(guess), true) = (&guess, action.unclaimed) {
+            added_headers.push(("X-Sortmail-Guess", guess.clone()));
+        }
+
+        if !action.labels.is_empty() {
+            added_headers.push((mappings.label_header.as_str(), action.labels.join(", ")));
+        }
+
+        let bytes = match added_headers.is_empty() && action.header_edits.is_empty() {
+            true => Cow::Borrowed(&incoming_message.bytes[..]),
+            false => Cow::Owned(incoming_message.bytes_with_headers(&added_headers, &action.header_edits))
+        };
+
+        // A message whose attachments can't be saved is stored whole
+        let bytes = match &action.detach {
+            Some(detach) => match detach.detach(&bytes, incoming_message.header_start(), &mappings.store_options) {
+                Ok(Some(detached)) => Cow::Owned(detached),
+                Ok(None) => bytes,
+                Err(error) => {
+                    eprintln!("Error detaching attachments for {}: {error:#}", destination_path.display());
+                    bytes
+                }
+            },
+            None => bytes
+        };
+
+        let encrypted = match &mappings.encryption_key {
+            Some(key) if !incoming_message.is_encrypted() => {
+                match encrypt::encrypt_message(&bytes, incoming_message.header_start(), key, mappings.encrypt_timeout) {
+                    Ok(encrypted) => Some(encrypted),
+                    Err(error) => {
+                        eprintln!("Error delivering to {}: {error:#}", destination_path.display());
+                        failed_mailbox_names.push(destination_path.display().to_string());
+                        continue;
+                    }
+                }
+            },
+            _ => None
+        };
+
+        // Copies are still matched up by their plaintext, so identical
+        // encrypted copies are linked like any others
+        let stored_bytes = encrypted.as_deref().unwrap_or(&bytes);
+
+        let maildir_folder = MaildirFolder {
+            mappings,
+            root_maildir,
+            mailbox_name: action.mailbox_name.as_deref(),
+            path: maildir.clone(),
+            labels: &action.labels,
+            plaintext: &bytes,
+            copies: &stored_copies
+        };
+
+        let store: &dyn MessageStore = match mailbox_store {
+            Some(store) => store.as_ref(),
+            None => &maildir_folder
+        };
+
+        let stored = store.store(&StoreRequest {
+            bytes: stored_bytes,
+            header_start: incoming_message.header_start(),
+            sender: envelope.sender.as_deref(),
+            flags,
+            keywords: &action.keywords,
+            create_folder: action.create_folder,
+            options: StoreOptions { compression: action.compress, ..mappings.store_options },
+            timeout: mappings.post_deliver_timeout
+        });
+
+        if stored.is_ok() {
+            let delivery = hooks::Delivery {
+                envelope,
+                destination: destination_path,
+                mailbox_name: action.mailbox_name.as_deref(),
+                chosen_by: &action.chosen_by
+            };
+
+            for command in [&mappings.post_deliver, &action.post_deliver].into_iter().flatten() {
+                if let Err(error) = hooks::run_post_deliver(command, &delivery, mappings.post_deliver_timeout) {
+                    eprintln!("Error running post_deliver for {}: {error:#}", destination_path.display());
+                }
+            }
+
+            if mappings.doveadm_index && mailbox_store.is_none() {
+                let dovecot_name = match action.mailbox_name {
+                    Some(ref mailbox_name) => mappings.namespace.dovecot_name(mailbox_name),
+                    None => "INBOX".to_string()
+                };
+
+                if let Err(error) = hooks::run_doveadm_index(&dovecot_name, mappings.post_deliver_timeout) {
+                    eprintln!("Error indexing {}: {error:#}", destination_path.display());
+                }
+            }
+
+            if let (Some(learner), Some(junk_folder), None) = (mappings.spam_learner, &mappings.junk_folder, mailbox_store) {
+                if action.mailbox_name.as_ref() == Some(junk_folder) {
+                    let learned = learner
+                        .learn(Verdict::Spam, &incoming_message.bytes[incoming_message.header_start()..], mappings.post_deliver_timeout)
+                        .and_then(|_| spam_learner::record(&root_maildir.join(SPAM_LEARNED_FILE_NAME), &spam_learner::message_key(incoming_message), Verdict::Spam));
+
+                    if let Err(error) = learned {
+                        eprintln!("Error training the spam filter on {}: {error:#}", destination_path.display());
+                    }
+                }
+            }
+        }
+
+        match stored {
+            Ok(()) => delivered_anywhere = true,
+            Err(error) => {
+                eprintln!("Error delivering to {}: {error:#}", destination_path.display());
+                failed_mailbox_names.push(destination_path.display().to_string());
+            }
+        }
+    }
+
+    if let Some(ref mut index) = message_id_index {
+        if !is_duplicate && !args.dry_run && delivered_anywhere {
+            for key in [&message_id, &content_key].into_iter().flatten() {
+                index.record(key, primary_mailbox_name.as_deref())?;
+            }
+        }
+    }
+
+    if let Some(ref vacation) = mappings.vacation {
+        if !is_duplicate && delivered_anywhere {
+            send_vacation_reply(args, root_maildir, mappings, vacation, &envelopes, incoming_message);
+        }
+    }
+
+    if pipe_tempfailed {
+        eprintln!("Error delivering message to {}; try again later", failed_mailbox_names.join(", "));
+        std::process::exit(EX_TEMPFAIL);
+    }
+
+    if !failed_mailbox_names.is_empty() {
+        bail!("Error delivering message to {}", failed_mailbox_names.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Send the vacation reply to a delivered message's sender, for the
+/// first recipient that should send one, unless that sender had one
+/// within `vacation_days`. The message is already delivered, so errors
+/// are only reported.
+fn send_vacation_reply(args: &Args, root_maildir: &Path, mappings: &AddressMap, vacation: &Vacation, envelopes: &[Envelope], message: &IncomingMessage) {
+    let Some(envelope) = envelopes.iter().find(|envelope| vacation.reason_not_to_reply(envelope, message).is_none()) else {
+        if let Some(reason) = envelopes.first().and_then(|envelope| vacation.reason_not_to_reply(envelope, message)) {
+            println!("Recipient {}: No vacation reply, since {reason}", envelopes[0].recipient);
+        }
+        return;
+    };
+
+    let sender = envelope.sender.as_deref().unwrap_or_default();
+    let reply_log_path = root_maildir.join(VACATION_LOG_FILE_NAME);
+
+    // A dry run only reads an existing log, rather than creating one
+    let reply_log = match !args.dry_run || reply_log_path.exists() {
+        true => ReplyLog::open(&reply_log_path).map(Some),
+        false => Ok(None)
+    };
+
+    let mut reply_log = match reply_log {
+        Ok(reply_log) => reply_log,
+        Err(error) => {
+            eprintln!("Error sending vacation reply to {sender}: {error:#}");
+            return;
+        }
+    };
+
+    if reply_log.as_ref().is_some_and(|reply_log| reply_log.replied_within(sender, vacation.interval)) {
+        println!("Recipient {}: No vacation reply, since {sender} had one recently", envelope.recipient);
+        return;
+    }
+
+    println!(
+        "Recipient {}: Send vacation reply to {sender}{}",
+        envelope.recipient,
+        match args.dry_run {
+            true => " (dry run, nothing will be sent)",
+            false => ""
+        }
+    );
+
+    if args.dry_run {
+        return;
+    }
+
+    let Some(ref smarthost) = mappings.smarthost else {
+        return;
+    };
+
+    let sent = smarthost
+        .send(None, sender, &vacation.reply(envelope, message))
+        .and_then(|_| match reply_log {
+            Some(ref mut reply_log) => reply_log.record(sender, vacation.interval),
+            None => Ok(())
+        });
+
+    if let Err(error) = sent {
+        eprintln!("Error sending vacation reply to {sender}: {error:#}");
+    }
+}
+
+/// Relay a message to `address` via the smarthost, with an
+/// `X-Sortmail-Forwarded-To:` header added. The envelope sender is the
+/// recipient the message was forwarded for, so that bounces come back
+/// here, except that bounces are forwarded with the null sender.
+///
+/// A message that already has an `X-Sortmail-Forwarded-To:` header for
+/// `address` has been around a forwarding loop, and isn't forwarded
+/// again.
+fn forward_message(mappings: &AddressMap, envelope: &Envelope, message: &IncomingMessage, address: &str, dry_run: bool) -> Result<()> {
+    if message.header_values(FORWARDED_TO_HEADER).any(|value| value.eq_ignore_ascii_case(address)) {
+        println!("Recipient {}: Not forwarding to {address}, since the message has been forwarded there before", envelope.recipient);
+        return Ok(());
+    }
+
+    println!(
+        "Recipient {}: Forward to {address}{}",
+        envelope.recipient,
+        match dry_run {
+            true => " (dry run, nothing will be forwarded)",
+            false => ""
+        }
+    );
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let smarthost = mappings.smarthost.as_ref().context("No smarthost to forward through")?;
+    let bytes = message.bytes_with_header(FORWARDED_TO_HEADER, address);
+    let sender = envelope.sender.as_ref().map(|_| envelope.recipient.as_str());
+
+    smarthost.send(sender, address, &bytes[message.header_start()..])
+}
+
+
+fn main() -> Result<()> {
     let args = Args::parse();
-    sort_message_from_stdin(&args)
+
+    match args.command {
+        None => sort_message_from_stdin(&args),
+        Some(Command::Train) => train_classifier(&args),
+        Some(Command::Classify) => classify_message_from_stdin(&args),
+        Some(Command::Suggest { min_messages }) => suggest_mailboxes(&args, min_messages),
+        Some(Command::Clean) => clean_maildir(&args),
+        Some(Command::Repair) => repair_maildir(&args),
+        Some(Command::Expire) => expire_mailboxes(&args),
+        Some(Command::Archive) => archive_messages(&args),
+        Some(Command::Learn) => learn_sorted_spam(&args),
+        Some(Command::Relink) => relink_folders(&args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use message_store::MemoryStore;
+
+    use super::*;
+
+    /// A scratch directory with a config file and a root Maildir,
+    /// removed when the test is done.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str, config: &str) -> TestDir {
+            let path = env::temp_dir().join(format!("sortmail-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            store::create_folder(&path.join("Maildir"), &StoreOptions::default()).unwrap();
+            std::fs::write(path.join("sortmail.toml"), config).unwrap();
+            TestDir(path)
+        }
+
+        fn root_maildir(&self) -> PathBuf {
+            self.0.join("Maildir")
+        }
+
+        fn args(&self) -> Args {
+            Args::parse_from(["sortmail", "-c", &self.0.join("sortmail.toml").display().to_string()])
+        }
+
+        fn address_map(&self) -> AddressMap {
+            AddressMap::from_file(&self.0.join("sortmail.toml")).unwrap()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Make the mailbox `mailbox_name` store its messages in `store`.
+    fn use_store(mappings: &mut AddressMap, mailbox_name: &str, store: Rc<dyn MessageStore>) {
+        let index = mappings.mailbox_rules.iter().position(|rule| rule.mailbox_name == mailbox_name).unwrap();
+        let old_rule = mappings.mailbox_rules.remove(index);
+
+        // The rule can only be changed once nothing else shares it
+        let maps = [&mut mappings.exact_address_to_mailbox_rule, &mut mappings.case_sensitive_exact_address_to_mailbox_rule];
+        let addresses: Vec<Vec<String>> = maps
+            .into_iter()
+            .map(|map| {
+                let addresses: Vec<_> = map.keys().filter(|address| Rc::ptr_eq(&map[*address], &old_rule)).cloned().collect();
+                map.retain(|_, rule| !Rc::ptr_eq(rule, &old_rule));
+                addresses
+            })
+            .collect();
+
+        let mut rule = Rc::try_unwrap(old_rule).unwrap();
+        rule.store = Some(store);
+        let rule = Rc::new(rule);
+
+        mappings.mailbox_rules.insert(index, Rc::clone(&rule));
+        let maps = [&mut mappings.exact_address_to_mailbox_rule, &mut mappings.case_sensitive_exact_address_to_mailbox_rule];
+        for (map, addresses) in maps.into_iter().zip(addresses) {
+            map.extend(addresses.into_iter().map(|address| (address, Rc::clone(&rule))));
+        }
+    }
+
+    fn recipients(addresses: &[&str]) -> Vec<(String, String, Option<String>)> {
+        addresses.iter().map(|address| (address.to_string(), address.to_string(), None)).collect()
+    }
+
+    fn message() -> IncomingMessage {
+        let bytes = b"From: someone@example.com\nSubject: Hello\nMessage-ID: <1@example.com>\n\nHi\n";
+        IncomingMessage::from_bytes(bytes.to_vec().into_boxed_slice()).unwrap()
+    }
+
+    #[test]
+    fn stores_in_the_mailbox_store() {
+        let dir = TestDir::new("mailbox-store", "[Lists]\naddresses = [\"lists@example.org\"]\nflags = [\"seen\"]\nkeywords = [\"$Label1\"]\n");
+        let mut mappings = dir.address_map();
+        let store = Rc::new(MemoryStore::default());
+        use_store(&mut mappings, "Lists", store.clone());
+
+        sort_message(&dir.args(), &dir.root_maildir(), &mappings, &message(), recipients(&["lists@example.org"])).unwrap();
+
+        let messages = store.messages.borrow();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].flags.as_deref(), Some("S"));
+        assert_eq!(messages[0].keywords, ["$Label1"]);
+        assert!(messages[0].bytes.ends_with(b"Subject: Hello\nMessage-ID: <1@example.com>\n\nHi\n"));
+    }
+
+    #[test]
+    fn stores_once_for_several_recipients() {
+        let dir = TestDir::new("several-recipients", "[Lists]\naddresses = [\"a@example.org\", \"b@example.org\"]\n");
+        let mut mappings = dir.address_map();
+        let store = Rc::new(MemoryStore::default());
+        use_store(&mut mappings, "Lists", store.clone());
+
+        sort_message(&dir.args(), &dir.root_maildir(), &mappings, &message(), recipients(&["a@example.org", "b@example.org"])).unwrap();
+
+        assert_eq!(store.messages.borrow().len(), 1);
+    }
+
+    #[test]
+    fn keeps_delivering_after_a_failed_store() {
+        let dir = TestDir::new("failed-store", "[Broken]\naddresses = [\"a@example.org\"]\n\n[Working]\naddresses = [\"b@example.org\"]\n");
+        let mut mappings = dir.address_map();
+        let broken = Rc::new(MemoryStore { name: "broken".to_string(), fail: true, ..Default::default() });
+        let working = Rc::new(MemoryStore { name: "working".to_string(), ..Default::default() });
+        use_store(&mut mappings, "Broken", broken);
+        use_store(&mut mappings, "Working", working.clone());
+
+        let sorted = sort_message(&dir.args(), &dir.root_maildir(), &mappings, &message(), recipients(&["a@example.org", "b@example.org"]));
+
+        assert!(sorted.is_err());
+        assert_eq!(working.messages.borrow().len(), 1);
+    }
+
+    #[test]
+    fn links_identical_copies_in_maildir_folders() {
+        let dir = TestDir::new("maildir-copies", "[One]\naddresses = [\"a@example.org\"]\n\n[Two]\naddresses = [\"b@example.org\"]\n");
+        let mappings = dir.address_map();
+        for folder in ["One", "Two"] {
+            store::create_folder(&mappings.namespace.folder_path(&dir.root_maildir(), folder), &StoreOptions::default()).unwrap();
+        }
+
+        sort_message(&dir.args(), &dir.root_maildir(), &mappings, &message(), recipients(&["a@example.org", "b@example.org"])).unwrap();
+
+        let inodes: Vec<u64> = ["One", "Two"]
+            .iter()
+            .map(|folder| {
+                let new = mappings.namespace.folder_path(&dir.root_maildir(), folder).join("new");
+                let entry = std::fs::read_dir(new).unwrap().next().unwrap().unwrap();
+                entry.metadata().unwrap().ino()
+            })
+            .collect();
+
+        assert_eq!(inodes[0], inodes[1]);
+    }
+
+    #[test]
+    fn stores_one_copy_in_the_canonical_folder() {
+        let config = "[sortmail]\ncanonical_folder = \"All\"\n\n[All]\naddresses = [\"a@example.org\"]\n\n[Two]\naddresses = [\"b@example.org\"]\n";
+
+        for (name, order) in [("canonical-first", ["a@example.org", "b@example.org"]), ("canonical-last", ["b@example.org", "a@example.org"])] {
+            let dir = TestDir::new(name, config);
+            let mappings = dir.address_map();
+            for folder in ["All", "Two"] {
+                store::create_folder(&mappings.namespace.folder_path(&dir.root_maildir(), folder), &StoreOptions::default()).unwrap();
+            }
+
+            sort_message(&dir.args(), &dir.root_maildir(), &mappings, &message(), recipients(&order)).unwrap();
+
+            let canonical_folder = mappings.namespace.folder_path(&dir.root_maildir(), "All");
+            let canonical_entries: Vec<_> = ["new", "cur"]
+                .iter()
+                .flat_map(|subdir| std::fs::read_dir(canonical_folder.join(subdir)).unwrap())
+                .map(|entry| entry.unwrap().path())
+                .collect();
+
+            assert_eq!(canonical_entries.len(), 1, "{name}");
+            assert!(!canonical_entries[0].is_symlink(), "{name}");
+        }
+    }
 }
@@ -0,0 +1,184 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use crate::store::{self, StoreOptions};
+
+//
+// mbox delivery
+//
+// Messages are appended to an mbox file in the mboxrd format: each
+// starts with a `From sender date` line, lines in the message that
+// start with any number of `>` followed by `From ` get one more `>`,
+// and each message ends with a blank line.
+//
+// The file is locked both with a `.lock` dotlock file next to it and
+// with flock(2), so that mail readers using either kind of locking see
+// whole messages.
+//
+
+/// How long to keep trying to take the dotlock.
+const DOTLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A dotlock older than this is assumed to have been left behind by a
+/// crashed process, and is removed.
+const STALE_DOTLOCK_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// How often to retry the dotlock.
+const DOTLOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A held dotlock, removed when dropped.
+struct Dotlock {
+    path: PathBuf
+}
+
+impl Drop for Dotlock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Append `message` to the mbox file at `path`, creating it if needed.
+/// If the message starts with an mbox-style `From ` line, as some MTAs
+/// add, it's used as is; otherwise one is made up from `sender` (or
+/// `MAILER-DAEMON` for bounces) and the current time. A failed write
+/// is rolled back, so the file isn't left with half a message. With
+/// `durable`, the directory is synced too, in case the file is new.
+pub fn append(path: &Path, sender: Option<&str>, message: &[u8], options: &StoreOptions) -> Result<()> {
+    let _dotlock = take_dotlock(path)?;
+
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Error opening mbox {}", path.display()))?;
+
+    if is_new {
+        store::set_file_mode(&file, options.file_mode)
+            .with_context(|| format!("Error changing permissions of mbox {}", path.display()))?;
+    }
+
+    file.lock()
+        .with_context(|| format!("Error locking mbox {}", path.display()))?;
+
+    let original_length = file.metadata()
+        .with_context(|| format!("Error reading mbox {}", path.display()))?
+        .len();
+
+    if let Err(error) = file.write_all(&mbox_entry(sender, message)).and_then(|_| file.sync_data()) {
+        let _ = file.set_len(original_length);
+        return Err(error).with_context(|| format!("Error writing to mbox {}", path.display()));
+    }
+
+    if options.durable {
+        store::sync_parent_dir(path)?;
+    }
+
+    Ok(())
+}
+
+/// Create `<path>.lock`, waiting for another process's lock to go
+/// away. A lock can't be taken if the directory isn't writable, as with
+/// some system spool directories; then flock alone has to do.
+fn take_dotlock(path: &Path) -> Result<Option<Dotlock>> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    let deadline = SystemTime::now() + DOTLOCK_TIMEOUT;
+
+    loop {
+        match File::create_new(&lock_path) {
+            Ok(_) => return Ok(Some(Dotlock { path: lock_path })),
+            Err(error) if error.kind() == ErrorKind::PermissionDenied => return Ok(None),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {},
+            Err(error) => return Err(error).with_context(|| format!("Error creating lock file {}", lock_path.display()))
+        }
+
+        let is_stale = std::fs::metadata(&lock_path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_DOTLOCK_AGE));
+
+        if is_stale {
+            let _ = std::fs::remove_file(&lock_path);
+            continue;
+        }
+
+        if SystemTime::now() >= deadline {
+            bail!("Timed out waiting for lock file {}", lock_path.display());
+        }
+
+        thread::sleep(DOTLOCK_RETRY_INTERVAL);
+    }
+}
+
+/// Return `message` as an mboxrd entry, with a `From ` line, escaped
+/// `From ` lines and a blank line after it.
+fn mbox_entry(sender: Option<&str>, message: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(message.len() + 100);
+
+    let message = match message.starts_with(b"From ") {
+        true => {
+            let line_end = message.iter().position(|&b| b == b'\n').map_or(message.len(), |pos| pos + 1);
+            entry.extend_from_slice(&message[..line_end]);
+            &message[line_end..]
+        },
+        false => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            entry.extend_from_slice(format!("From {} {}\n", sender.unwrap_or("MAILER-DAEMON"), asctime(now)).as_bytes());
+            message
+        }
+    };
+
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        if line.iter().skip_while(|&&b| b == b'>').take(5).eq(b"From ") {
+            entry.push(b'>');
+        }
+
+        entry.extend_from_slice(line);
+    }
+
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+
+    entry.push(b'\n');
+    entry
+}
+
+/// Format a Unix time in UTC the way asctime(3) does, like `Thu Oct 15
+/// 18:30:55 2026`.
+fn asctime(unix_time: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = unix_time / 86400;
+    let seconds = unix_time % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's
+    // days_from_civil algorithm, run backwards)
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = (month_index + 2) % 12;
+    let year = year_of_era + era * 400 + u64::from(month < 2);
+
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {year}",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize],
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
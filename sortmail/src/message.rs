@@ -0,0 +1,1000 @@
+use std::cell::OnceCell;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use mailparse::{DispositionType, MailAddr, ParsedMail};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::address::{self, strip_comments};
+use crate::dnsbl;
+use crate::language;
+use crate::smtp::base64_encode;
+
+//
+// Incoming message
+//
+
+/// Only this much decoded body text is searched by body patterns, so
+/// that a huge message can't make matching arbitrarily slow.
+const MAX_BODY_TEXT_BYTES: usize = 1024 * 1024;
+
+/// Lowercased pieces of the subjects that common MTAs give bounces
+/// (Postfix, Exim, Sendmail, qmail, Exchange, Gmail).
+const BOUNCE_SUBJECTS: [&str; 7] = [
+    "undelivered mail returned to sender",
+    "mail delivery failed",
+    "returned mail:",
+    "failure notice",
+    "undeliverable:",
+    "delivery status notification (failure)",
+    "delivery failure"
+];
+
+/// An email message read from stdin, along with the headers parsed
+/// out of it for use by the mailbox rules.
+pub struct IncomingMessage {
+    pub bytes: Box<[u8]>,
+
+    /// Offset of the first header in `bytes`, past any mbox-style
+    /// `From ` line.
+    header_start: usize,
+
+    /// The message headers, in the order they appear in the message.
+    headers: Vec<Header>,
+
+    /// What the body's MIME structure holds, collected the first time
+    /// any of it is needed.
+    mime_parts: OnceCell<MimeParts>,
+
+    language: OnceCell<Option<&'static str>>,
+    dnsbl_listings: OnceCell<HashSet<String>>
+}
+
+/// One header of the message.
+struct Header {
+    /// Lowercased header name
+    name: String,
+
+    /// Unfolded header value, exactly as it appears in the message
+    raw_value: String,
+
+    /// Unfolded header value with RFC 2047 encoded-words (like
+    /// `=?UTF-8?B?...?=`) decoded
+    value: String
+}
+
+/// What was collected in the one walk over the message's MIME structure.
+struct MimeParts {
+    /// Decoded text of the inline `text/*` parts
+    text: String,
+
+    attachments: Vec<Attachment>,
+
+    /// Lowercased iTIP method of the first `text/calendar` part, or an
+    /// empty string if it doesn't say
+    calendar_method: Option<String>
+}
+
+/// The results recorded in one `Authentication-Results:` header
+/// (RFC 8601).
+pub struct AuthenticationResults {
+    /// Lowercased identifier of the server that performed the checks
+    pub authserv_id: String,
+
+    /// Lowercased (method, result) pairs, e.g. ("dkim", "fail")
+    pub results: Vec<(String, String)>
+}
+
+/// What a `Received:` header says about the system the message was
+/// received from.
+struct ReceivedFrom {
+    /// Lowercased HELO/EHLO name and reverse DNS name
+    hosts: Vec<String>,
+
+    /// The connecting IP address, if recorded
+    ip: Option<IpAddr>
+}
+
+/// A MIME part of the message that is presented as an attachment,
+/// rather than as part of the message text.
+pub struct Attachment {
+    /// Lowercased MIME type, e.g. `application/pdf`
+    pub content_type: String,
+
+    /// Decoded filename (including RFC 2231 and RFC 2047 encoded
+    /// names), if the attachment has one
+    pub filename: Option<String>
+}
+
+/// A change made to the headers of a stored copy of the message.
+#[derive(Clone, Debug)]
+pub enum HeaderEdit {
+    /// Drop every header with this (lowercased) name
+    Remove(String),
+
+    /// Replace matches of `pattern` in the decoded value of every header
+    /// with this (lowercased) name, as `Regex::replace_all` does
+    Rewrite {
+        name: String,
+        pattern: Regex,
+        replacement: String
+    }
+}
+
+/// Encoded-words are kept under the 75 character limit by encoding at
+/// most this many bytes of text in each.
+const MAX_ENCODED_WORD_TEXT_BYTES: usize = 45;
+
+impl IncomingMessage {
+    /// Parse the headers out of the raw message bytes.
+    ///
+    /// When the MTA is configured to prepend an mbox-style `From `
+    /// envelope line (e.g. Postfix pipe(8) with the `F` flag), that
+    /// line is skipped before parsing the headers.
+    pub fn from_bytes(bytes: Box<[u8]>) -> Result<IncomingMessage> {
+        let header_start = match bytes.starts_with(b"From ") {
+            true => bytes.iter().position(|&b| b == b'\n').map_or(bytes.len(), |pos| pos + 1),
+            false => 0
+        };
+
+        let (parsed_headers, _) = mailparse::parse_headers(&bytes[header_start..])
+            .context("Error parsing message headers")?;
+
+        let headers = parsed_headers
+            .iter()
+            .map(|header| Header {
+                name: header.get_key().trim().to_lowercase(),
+                raw_value: unfold_header_value(header.get_value_raw()),
+                value: header.get_value().trim().to_string()
+            })
+            .collect();
+
+        Ok(IncomingMessage {
+            bytes,
+            header_start,
+            headers,
+            mime_parts: OnceCell::new(),
+            language: OnceCell::new(),
+            dnsbl_listings: OnceCell::new()
+        })
+    }
+
+    /// Return the values of every header named `name` (matched
+    /// case-insensitively), in the order they appear in the message,
+    /// with any RFC 2047 encoded-words decoded so that they can be
+    /// matched as readable text.
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers_named(name).map(|header| header.value.as_str())
+    }
+
+    /// Return the values of every header named `name` as they appear
+    /// in the message, without decoding encoded-words. Structured
+    /// headers (addresses, identifiers) must be parsed from these,
+    /// since a decoded word could contain syntax such as `,` or `<`.
+    fn raw_header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers_named(name).map(|header| header.raw_value.as_str())
+    }
+
+    fn headers_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Header> + 'a {
+        self.headers
+            .iter()
+            .filter(move |header| header.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Return the size of the message in bytes, not counting any
+    /// mbox-style `From ` line.
+    pub fn size(&self) -> u64 {
+        (self.bytes.len() - self.header_start) as u64
+    }
+
+    /// Return the offset of the first header in the message bytes, past
+    /// any mbox-style `From ` line.
+    pub fn header_start(&self) -> usize {
+        self.header_start
+    }
+
+    /// Return the message bytes with a header added at the top (after
+    /// any mbox-style `From ` line), using the same line ending as the
+    /// message's first header.
+    pub fn bytes_with_header(&self, name: &str, value: &str) -> Vec<u8> {
+        self.bytes_with_headers(&[(name, value.to_string())], &[])
+    }
+
+    /// Return the message bytes with `headers` added at the top, in
+    /// order, as `bytes_with_header` does, and with the message's own
+    /// headers changed by `edits`. Headers that no edit changes are
+    /// kept byte for byte.
+    pub fn bytes_with_headers(&self, headers: &[(&str, String)], edits: &[HeaderEdit]) -> Vec<u8> {
+        let message = &self.bytes[self.header_start..];
+        let newline = match message.iter().position(|&b| b == b'\n') {
+            Some(pos) if pos > 0 && message[pos - 1] == b'\r' => "\r\n",
+            _ => "\n"
+        };
+
+        let added: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}{newline}"))
+            .collect();
+
+        let mut bytes = Vec::with_capacity(self.bytes.len() + added.len());
+        bytes.extend_from_slice(&self.bytes[..self.header_start]);
+        bytes.extend_from_slice(added.as_bytes());
+
+        if edits.is_empty() {
+            bytes.extend_from_slice(message);
+            return bytes;
+        }
+
+        let (fields, body) = split_header_fields(message);
+
+        for field in fields {
+            match edit_header_field(field, edits, newline) {
+                Some(edited) => bytes.extend_from_slice(edited.as_bytes()),
+                None if is_removed(field, edits) => {},
+                None => bytes.extend_from_slice(field)
+            }
+        }
+
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// Return the lowercased address part of every mailbox listed in
+    /// the headers named `name` (e.g. `From`), with display names and
+    /// comments stripped. Group syntax is flattened into its members.
+    pub fn header_addresses(&self, name: &str) -> Vec<String> {
+        self.raw_header_values(name)
+            .flat_map(parse_address_list)
+            .collect()
+    }
+
+    /// Return the number of different addresses in the `To:` and
+    /// `Cc:` headers.
+    pub fn recipient_count(&self) -> usize {
+        let mut recipients: Vec<_> = ["To", "Cc"]
+            .into_iter()
+            .flat_map(|header_name| self.header_addresses(header_name))
+            .collect();
+
+        recipients.sort();
+        recipients.dedup();
+        recipients.len()
+    }
+
+    /// Return the message's `Message-ID:`, normalized (see
+    /// `normalize_message_id`), or None if it doesn't have one.
+    pub fn message_id(&self) -> Option<String> {
+        let value = strip_comments(self.raw_header_values("Message-ID").next()?);
+        let message_id = match value.split_once('<') {
+            Some((_, rest)) => rest.split_once('>').map_or(rest, |(id, _)| id),
+            None => &value
+        };
+
+        normalize_message_id(message_id)
+    }
+
+    /// Return the SHA-256 of the message, past any mbox-style `From `
+    /// line (whose date changes when the MTA retries), in hex.
+    pub fn content_hash(&self) -> String {
+        Sha256::digest(&self.bytes[self.header_start..])
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Return the Message-IDs of the messages this one replies to,
+    /// nearest first: those in `In-Reply-To:`, then those in
+    /// `References:` from last (the parent) to first (the thread's
+    /// root).
+    pub fn thread_parent_ids(&self) -> Vec<String> {
+        let message_ids = |name| self.raw_header_values(name)
+            .flat_map(|value| {
+                let value = strip_comments(value);
+                value
+                    .split('<')
+                    .skip(1)
+                    .filter_map(|rest| normalize_message_id(rest.split_once('>')?.0))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut parent_ids = message_ids("In-Reply-To");
+        parent_ids.extend(message_ids("References").into_iter().rev());
+        parent_ids
+    }
+
+    /// Return the lowercased list identifier from the `List-Id:`
+    /// header (RFC 2919), i.e. the part in angle brackets with any
+    /// description dropped, or None if the message has no List-Id.
+    pub fn list_id(&self) -> Option<String> {
+        let value = strip_comments(self.raw_header_values("List-Id").next()?);
+
+        let list_id = match (value.rfind('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value.as_str()
+        };
+
+        Some(list_id.trim().to_lowercase()).filter(|list_id| !list_id.is_empty())
+    }
+
+    /// Return the lowercased hostnames that the earliest `Received:`
+    /// header (the one added by the first server to handle the
+    /// message) says it came from: the name given in HELO/EHLO, and
+    /// the reverse DNS name the server looked up, if any. For example,
+    /// `from scanner (printer.corp.example.com [10.1.2.3]) by ...`
+    /// yields `scanner` and `printer.corp.example.com`.
+    pub fn received_origin_hosts(&self) -> Vec<String> {
+        self.raw_header_values("Received")
+            .last()
+            .and_then(parse_received_from)
+            .map(|received_from| received_from.hosts)
+            .unwrap_or_default()
+    }
+
+    /// Return the IP address the message was sent from: the connecting
+    /// IP recorded by the topmost `Received:` header, which was added
+    /// by our own server. If that IP belongs to one of
+    /// `trusted_relays` (say, an internal relay that handed the message
+    /// on), the header below it is consulted instead, and so on.
+    ///
+    /// Headers further down than the first untrusted hop are never
+    /// believed, since the sender could have written them. Nor are
+    /// headers below one that doesn't record a connecting IP: there's
+    /// no telling who wrote those either, so there's no source IP.
+    pub fn source_ip(&self, trusted_relays: &[IpNet]) -> Option<IpAddr> {
+        for value in self.raw_header_values("Received") {
+            let ip = parse_received_from(value)?.ip?;
+
+            if !trusted_relays.iter().any(|relay| relay.contains(&ip)) {
+                return Some(ip);
+            }
+        }
+
+        None
+    }
+
+    /// Return the DNSBL `zones` that list the message's source IP (see
+    /// `source_ip`), if it has one. They're only looked up the first
+    /// time this is called, so every caller should ask about the same
+    /// zones.
+    pub fn dnsbl_listings(&self, trusted_relays: &[IpNet], zones: &[String], timeout: Duration) -> &HashSet<String> {
+        self.dnsbl_listings.get_or_init(|| match self.source_ip(trusted_relays) {
+            Some(ip) => dnsbl::listing_zones(ip, zones, timeout),
+            None => HashSet::new()
+        })
+    }
+
+    /// If the message looks like mailing list traffic, return an
+    /// identifier for the list: the `List-Id:` identifier, or else the
+    /// list address from `List-Post:`, or else (for messages marked
+    /// `Precedence: list`) the `Sender:` address. Returns None for
+    /// messages that don't look like list mail, or that don't say
+    /// which list they came from.
+    pub fn mailing_list_identifier(&self) -> Option<String> {
+        if let Some(list_id) = self.list_id() {
+            return Some(list_id);
+        }
+
+        let list_post = self.raw_header_values("List-Post")
+            .find_map(|value| {
+                let value = strip_comments(value);
+                let (_, after_mailto) = value.split_once("mailto:")?;
+                let address = address::normalize_address(after_mailto.split(['>', '?', ',']).next()?);
+                Some(address).filter(|address| !address.is_empty())
+            });
+
+        if list_post.is_some() {
+            return list_post;
+        }
+
+        let is_list_precedence = self.raw_header_values("Precedence")
+            .any(|value| value.trim().eq_ignore_ascii_case("list"));
+
+        match is_list_precedence {
+            true => self.header_addresses("Sender").into_iter().next(),
+            false => None
+        }
+    }
+
+    /// Return true if the message says it was generated by a machine
+    /// rather than written by a person: it has an `Auto-Submitted:`
+    /// header other than `no` (RFC 3834's `auto-generated`,
+    /// `auto-replied` or `auto-notified`), or `Precedence: bulk` or
+    /// `junk`.
+    pub fn is_automated(&self) -> bool {
+        let is_auto_submitted = self.raw_header_values("Auto-Submitted")
+            .any(|value| {
+                let value = strip_comments(value);
+                let keyword = value.split(';').next().unwrap_or_default().trim();
+                !keyword.is_empty() && !keyword.eq_ignore_ascii_case("no")
+            });
+
+        let is_bulk_precedence = self.raw_header_values("Precedence")
+            .any(|value| matches!(value.trim().to_lowercase().as_str(), "bulk" | "junk"));
+
+        is_auto_submitted || is_bulk_precedence
+    }
+
+    /// Return true if the message is a bounce: a delivery status
+    /// notification (`multipart/report; report-type=delivery-status`),
+    /// or a message from `MAILER-DAEMON`, or from `postmaster` with a
+    /// subject like the ones that MTAs give their bounces.
+    pub fn is_bounce(&self) -> bool {
+        let is_delivery_status_report = self.raw_header_values("Content-Type")
+            .next()
+            .map(mailparse::parse_content_type)
+            .is_some_and(|content_type| {
+                content_type.mimetype.eq_ignore_ascii_case("multipart/report")
+                    && content_type.params
+                        .get("report-type")
+                        .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"))
+            });
+
+        if is_delivery_status_report {
+            return true;
+        }
+
+        let from_local_parts: Vec<_> = self.header_addresses("From")
+            .into_iter()
+            .filter_map(|address| address.rsplit_once('@').map(|(local_part, _)| local_part.to_string()))
+            .collect();
+
+        if from_local_parts.iter().any(|local_part| local_part == "mailer-daemon") {
+            return true;
+        }
+
+        let has_bounce_subject = self.header_values("Subject")
+            .any(|subject| {
+                let subject = subject.to_lowercase();
+                BOUNCE_SUBJECTS.iter().any(|bounce_subject| subject.contains(bounce_subject))
+            });
+
+        has_bounce_subject && from_local_parts.iter().any(|local_part| local_part == "postmaster")
+    }
+
+    /// Return the message's importance, `high`, `normal` or `low`, from
+    /// the first of the `X-Priority:` (1 and 2 are high, 4 and 5 low),
+    /// `Importance:`, `Priority:` (`urgent`, `normal`, `non-urgent`) and
+    /// `X-MSMail-Priority:` headers that has a recognizable value.
+    /// Messages without one are `normal`.
+    pub fn importance(&self) -> &'static str {
+        let x_priority = self.raw_header_values("X-Priority")
+            .find_map(|value| match value.trim().chars().next()? {
+                '1' | '2' => Some("high"),
+                '3' => Some("normal"),
+                '4' | '5' => Some("low"),
+                _ => None
+            });
+
+        let importance = || ["Importance", "Priority", "X-MSMail-Priority"]
+            .into_iter()
+            .flat_map(|header_name| self.raw_header_values(header_name))
+            .find_map(|value| match strip_comments(value).trim().to_lowercase().as_str() {
+                "high" | "urgent" => Some("high"),
+                "normal" => Some("normal"),
+                "low" | "non-urgent" => Some("low"),
+                _ => None
+            });
+
+        x_priority.or_else(importance).unwrap_or("normal")
+    }
+
+    /// Return the ISO 639-1 code of the language the message's subject
+    /// and body text are written in, or None if it can't be told (see
+    /// the `language` module). It's only worked out the first time this
+    /// is called.
+    pub fn language(&self) -> Option<&'static str> {
+        *self.language.get_or_init(|| {
+            let subjects: Vec<_> = self.header_values("Subject").collect();
+            language::detect(&format!("{}\n{}", subjects.join("\n"), self.body_text()))
+        })
+    }
+
+    /// Return the display name and the normalized address of every
+    /// mailbox listed in the headers named `name`, with any
+    /// encoded-words in the display names decoded.
+    pub fn header_mailboxes(&self, name: &str) -> Vec<(Option<String>, String)> {
+        let Ok((headers, _)) = mailparse::parse_headers(&self.bytes[self.header_start..]) else {
+            return Vec::new();
+        };
+
+        headers
+            .iter()
+            .filter(|header| header.get_key_ref().trim().eq_ignore_ascii_case(name))
+            .filter_map(|header| mailparse::addrparse_header(header).ok())
+            .flat_map(|address_list| address_list.iter().flat_map(|addr| match addr {
+                MailAddr::Single(info) => vec![info.clone()],
+                MailAddr::Group(group) => group.addrs.clone()
+            }).collect::<Vec<_>>())
+            .map(|info| (info.display_name, address::normalize_address(&info.addr)))
+            .collect()
+    }
+
+    /// Return true if the `From:` header looks forged: a display name
+    /// mentions a domain (like `PayPal.com Support <x@example.net>`)
+    /// that the address isn't at, or the address's domain is unrelated
+    /// to the domain of the envelope `sender`. Domains are related if
+    /// they're the same or one is a subdomain of the other.
+    pub fn has_suspicious_from(&self, sender: Option<&str>) -> bool {
+        let sender_domain = sender.and_then(|sender| sender.rsplit_once('@')).map(|(_, domain)| domain);
+
+        self.header_mailboxes("From")
+            .iter()
+            .any(|(display_name, address)| {
+                let Some((_, domain)) = address.rsplit_once('@') else {
+                    return false;
+                };
+
+                let display_name_mismatch = display_name
+                    .as_deref()
+                    .map(domains_mentioned)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|mentioned| !are_related_domains(mentioned, domain));
+
+                let sender_mismatch = sender_domain.is_some_and(|sender_domain| !are_related_domains(sender_domain, domain));
+
+                display_name_mismatch || sender_mismatch
+            })
+    }
+
+    /// Return the parsed `Authentication-Results:` headers, topmost
+    /// (i.e. most recently added) first.
+    pub fn authentication_results(&self) -> Vec<AuthenticationResults> {
+        self.raw_header_values("Authentication-Results")
+            .filter_map(parse_authentication_results)
+            .collect()
+    }
+
+    /// Return the decoded text of the message's inline `text/*` MIME
+    /// parts (with transfer encoding and charset decoding applied),
+    /// joined by newlines and truncated to `MAX_BODY_TEXT_BYTES`.
+    ///
+    /// Encrypted messages have no body text, since matching against
+    /// ciphertext is meaningless.
+    pub fn body_text(&self) -> &str {
+        match self.is_encrypted() {
+            true => "",
+            false => self.decoded_text()
+        }
+    }
+
+    /// Return true if the message is encrypted: a PGP/MIME
+    /// (`multipart/encrypted`) or S/MIME (`application/pkcs7-mime`,
+    /// other than signed-only) message, or one with an inline PGP
+    /// message in its text.
+    pub fn is_encrypted(&self) -> bool {
+        let content_type = self.raw_header_values("Content-Type")
+            .next()
+            .map(mailparse::parse_content_type);
+
+        let is_mime_encrypted = content_type.is_some_and(|content_type| {
+            match content_type.mimetype.to_lowercase().as_str() {
+                "multipart/encrypted" => true,
+                "application/pkcs7-mime" | "application/x-pkcs7-mime" => content_type.params
+                    .get("smime-type")
+                    .is_none_or(|smime_type| !smime_type.eq_ignore_ascii_case("signed-data")),
+                _ => false
+            }
+        });
+
+        is_mime_encrypted || self.decoded_text().contains("-----BEGIN PGP MESSAGE-----")
+    }
+
+    /// The body text, encrypted or not.
+    fn decoded_text(&self) -> &str {
+        &self.mime_parts().text
+    }
+
+    /// Return the message's attachments: leaf MIME parts that either
+    /// have a `Content-Disposition: attachment` or carry a filename.
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.mime_parts().attachments
+    }
+
+    /// If the message is a calendar message (it has a `text/calendar`
+    /// part, or Exchange's `Content-Class:
+    /// urn:content-classes:calendarmessage` header), return its
+    /// lowercased iTIP method, like `request` for an invitation or
+    /// update, `reply` or `cancel`, or an empty string if it doesn't
+    /// say. Returns None for other messages.
+    pub fn calendar_method(&self) -> Option<&str> {
+        let is_calendar_class = || self.raw_header_values("Content-Class")
+            .any(|value| value.trim().eq_ignore_ascii_case("urn:content-classes:calendarmessage"));
+
+        match self.mime_parts().calendar_method.as_deref() {
+            None if is_calendar_class() => Some(""),
+            method => method
+        }
+    }
+
+    /// The body text, attachments and calendar method, all collected
+    /// from a single parse of the body the first time any of them is
+    /// needed. A body that can't be parsed has none of them.
+    fn mime_parts(&self) -> &MimeParts {
+        self.mime_parts.get_or_init(|| {
+            let mut mime_parts = MimeParts {
+                text: String::new(),
+                attachments: Vec::new(),
+                calendar_method: None
+            };
+
+            if let Ok(parsed) = mailparse::parse_mail(&self.bytes[self.header_start..]) {
+                collect_body_text(&parsed, &mut mime_parts.text);
+                collect_attachments(&parsed, &mut mime_parts.attachments);
+                mime_parts.calendar_method = find_calendar_method(&parsed);
+            }
+
+            mime_parts
+        })
+    }
+
+    /// Return the spam score assigned by an upstream filter, taken
+    /// from the first of these headers that yields one:
+    ///
+    /// - `X-Spam-Score: 7.2`
+    /// - `X-Spam-Status: Yes, score=7.2 required=5.0 ...` (or `hits=`)
+    /// - `X-Spam-Level: *******` (one star per point)
+    pub fn spam_score(&self) -> Option<f64> {
+        let from_score = self.header_values("X-Spam-Score")
+            .find_map(parse_leading_float);
+
+        let from_status = || self.header_values("X-Spam-Status")
+            .flat_map(|value| value.split(|c: char| c.is_whitespace() || c == ','))
+            .find_map(|field| field
+                .strip_prefix("score=")
+                .or_else(|| field.strip_prefix("hits="))
+                .and_then(parse_leading_float));
+
+        let from_level = || self.header_values("X-Spam-Level")
+            .next()
+            .map(|value| value.chars().filter(|&c| c == '*').count() as f64);
+
+        from_score.or_else(from_status).or_else(from_level)
+    }
+}
+
+/// Parse an `Authentication-Results:` header value like
+/// `mx.example.com; dkim=pass header.d=example.com; spf=fail (reason) smtp.mailfrom=...`
+fn parse_authentication_results(value: &str) -> Option<AuthenticationResults> {
+    let value = strip_comments(value).to_lowercase();
+    let mut statements = value.split(';');
+
+    // The authserv-id may be followed by a version number
+    let authserv_id = statements.next()?.split_whitespace().next()?.to_string();
+
+    let results = statements
+        .filter_map(|statement| {
+            let (method, rest) = statement.trim().split_once('=')?;
+            let result = rest.split_whitespace().next()?;
+
+            // Strip any method version, e.g. "dkim/1"
+            let method = method.split('/').next()?.trim();
+
+            Some((method.to_string(), result.to_string()))
+        })
+        .collect();
+
+    Some(AuthenticationResults {
+        authserv_id,
+        results
+    })
+}
+
+/// Return the Message-ID `id` (without its angle brackets) in angle
+/// brackets, with any whitespace taken out, so that it's a single token
+/// that compares equal however a mailer folded it, or None if it's
+/// empty.
+fn normalize_message_id(id: &str) -> Option<String> {
+    let id: String = id.split_whitespace().collect();
+    Some(format!("<{id}>")).filter(|_| !id.is_empty())
+}
+
+/// Parse the `from` clause of a `Received:` header value, e.g.
+/// `from helo.example.com (rdns.example.com [192.0.2.1]) by ...`
+/// (RFC 5321 section 4.4), into its HELO and reverse DNS hostnames and
+/// IP address. Returns None if the header has no `from` clause.
+fn parse_received_from(value: &str) -> Option<ReceivedFrom> {
+    let value = value.trim_start();
+    let rest = match value.get(..5) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("from ") => &value[5..],
+        _ => return None
+    };
+
+    let mut hosts = Vec::new();
+    let mut ip = None;
+
+    let rest = rest.trim_start();
+    let helo_end = rest.find(|c: char| c.is_whitespace() || c == '(' || c == ';').unwrap_or(rest.len());
+    hosts.push(&rest[..helo_end]);
+
+    // The parenthesized TCP-info, like `(rdns.example.com [192.0.2.1])`
+    // or `(helo=name)`
+    let after_helo = rest[helo_end..].trim_start();
+    if let Some(tcp_info) = after_helo.strip_prefix('(').and_then(|info| info.split(')').next()) {
+        hosts.extend(tcp_info
+            .split_whitespace()
+            .map(|word| word.strip_prefix("helo=").unwrap_or(word))
+            .filter(|word| word.contains('.')));
+
+        ip = tcp_info
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('[')?.strip_suffix(']'))
+            .find_map(parse_address_literal);
+    }
+
+    // IP address literals like `[192.0.2.1]` aren't hostnames
+    let hosts = hosts
+        .into_iter()
+        .filter(|host| !host.is_empty() && !host.starts_with('['))
+        .map(str::to_lowercase)
+        .collect();
+
+    Some(ReceivedFrom {
+        hosts,
+        ip
+    })
+}
+
+/// Parse the inside of an address literal, like `192.0.2.1` or
+/// `IPv6:2001:db8::1`.
+fn parse_address_literal(literal: &str) -> Option<IpAddr> {
+    let literal = match literal.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("ipv6:") => &literal[5..],
+        _ => literal
+    };
+
+    literal.parse().ok()
+}
+
+/// Append the decoded text of `part` and its inline `text/*`
+/// subparts to `text`, stopping once `MAX_BODY_TEXT_BYTES` is reached.
+fn collect_body_text(part: &ParsedMail, text: &mut String) {
+    if text.len() >= MAX_BODY_TEXT_BYTES {
+        return;
+    }
+
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            collect_body_text(subpart, text);
+        }
+        return;
+    }
+
+    if !part.ctype.mimetype.starts_with("text/")
+        || part.get_content_disposition().disposition == DispositionType::Attachment {
+        return;
+    }
+
+    if let Ok(body) = part.get_body() {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&body);
+    }
+
+    if text.len() > MAX_BODY_TEXT_BYTES {
+        let mut end = MAX_BODY_TEXT_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
+fn collect_attachments(part: &ParsedMail, attachments: &mut Vec<Attachment>) {
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            collect_attachments(subpart, attachments);
+        }
+        return;
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition.params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned();
+
+    if disposition.disposition == DispositionType::Attachment || filename.is_some() {
+        attachments.push(Attachment {
+            content_type: part.ctype.mimetype.to_lowercase(),
+            filename
+        });
+    }
+}
+
+/// Find the first `text/calendar` part in `part` or its subparts, and
+/// return its lowercased method: the `method` Content-Type parameter,
+/// or else the `METHOD:` property in the iCalendar data.
+fn find_calendar_method(part: &ParsedMail) -> Option<String> {
+    if !part.subparts.is_empty() {
+        return part.subparts.iter().find_map(find_calendar_method);
+    }
+
+    if !part.ctype.mimetype.eq_ignore_ascii_case("text/calendar") {
+        return None;
+    }
+
+    let method = part.ctype.params
+        .get("method")
+        .cloned()
+        .or_else(|| part.get_body().ok()?
+            .lines()
+            .find_map(|line| line
+                .split_once(':')
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case("METHOD"))
+                .map(|(_, value)| value.to_string())))
+        .unwrap_or_default();
+
+    Some(method.trim().to_lowercase())
+}
+
+/// Parse the floating-point number at the start of `value`, ignoring
+/// anything after it (e.g. the `/ 15.0` in rspamd's `5.3 / 15.0`).
+fn parse_leading_float(value: &str) -> Option<f64> {
+    let value = value.trim_start();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(value.len());
+
+    value[..end].parse().ok()
+}
+
+/// Parse an RFC 5322 address list into bare, lowercased addresses.
+/// Unparseable values yield no addresses.
+fn parse_address_list(value: &str) -> Vec<String> {
+    let address_list = match mailparse::addrparse(&strip_comments(value)) {
+        Ok(address_list) => address_list,
+        Err(_) => return Vec::new()
+    };
+
+    address_list
+        .iter()
+        .flat_map(|addr| match addr {
+            MailAddr::Single(info) => vec![info.addr.clone()],
+            MailAddr::Group(group) => group.addrs.iter().map(|info| info.addr.clone()).collect()
+        })
+        .map(|addr| address::normalize_address(&addr))
+        .filter(|addr| !addr.is_empty())
+        .collect()
+}
+
+/// Return the (normalized) things in `text` that look like domain
+/// names or email addresses' domains, like `paypal.com` in
+/// `PayPal.com Support` or `support@paypal.com`.
+fn domains_mentioned(text: &str) -> Vec<String> {
+    text
+        .split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '-'))
+        .map(|word| word.trim_matches(['.', '-']))
+        .filter(|word| {
+            let labels: Vec<_> = word.split('.').collect();
+            let top_level_domain = labels.last().copied().unwrap_or_default();
+
+            labels.len() >= 2
+                && labels.iter().all(|label| !label.is_empty())
+                && top_level_domain.chars().count() >= 2
+                && top_level_domain.chars().all(char::is_alphabetic)
+        })
+        .map(address::normalize_domain)
+        .collect()
+}
+
+fn are_related_domains(a: &str, b: &str) -> bool {
+    a == b || a.ends_with(&format!(".{b}")) || b.ends_with(&format!(".{a}"))
+}
+
+/// Split the message (without any mbox-style `From ` line) into its
+/// header fields, each with its continuation lines and line endings,
+/// and the rest of the message from the blank line on.
+pub fn split_header_fields(message: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut field_start = 0;
+    let mut offset = 0;
+
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        if line == b"\n" || line == b"\r\n" {
+            break;
+        }
+
+        let is_continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !is_continuation && offset > field_start {
+            fields.push(&message[field_start..offset]);
+            field_start = offset;
+        }
+
+        offset += line.len();
+    }
+
+    if offset > field_start {
+        fields.push(&message[field_start..offset]);
+    }
+
+    (fields, &message[offset..])
+}
+
+/// Return the lowercased name of a raw header field.
+pub fn header_field_name(field: &[u8]) -> Option<String> {
+    let colon = field.iter().position(|&b| b == b':')?;
+    Some(String::from_utf8_lossy(&field[..colon]).trim().to_lowercase())
+}
+
+fn is_removed(field: &[u8], edits: &[HeaderEdit]) -> bool {
+    let name = header_field_name(field);
+
+    edits.iter().any(|edit| match edit {
+        HeaderEdit::Remove(removed) => name.as_ref() == Some(removed),
+        HeaderEdit::Rewrite { .. } => false
+    })
+}
+
+/// Apply the `Rewrite` edits for this field's header to its decoded
+/// value, and return the rewritten field if that changed anything.
+/// Values that are no longer plain ASCII are written as UTF-8
+/// encoded-words.
+fn edit_header_field(field: &[u8], edits: &[HeaderEdit], newline: &str) -> Option<String> {
+    let name = header_field_name(field)?;
+
+    let rewrites: Vec<_> = edits
+        .iter()
+        .filter_map(|edit| match edit {
+            HeaderEdit::Rewrite { name: rewritten, pattern, replacement } if *rewritten == name => Some((pattern, replacement)),
+            _ => None
+        })
+        .collect();
+
+    if rewrites.is_empty() {
+        return None;
+    }
+
+    let (header, _) = mailparse::parse_header(field).ok()?;
+    let value = header.get_value().trim().to_string();
+
+    let rewritten = rewrites
+        .into_iter()
+        .fold(value.clone(), |value, (pattern, replacement)| pattern.replace_all(&value, replacement.as_str()).into_owned());
+
+    if rewritten == value {
+        return None;
+    }
+
+    let encoded = match rewritten.is_ascii() {
+        true => rewritten,
+        false => encode_words(&rewritten).join(&format!("{newline} "))
+    };
+
+    Some(format!("{}: {encoded}{newline}", header.get_key()))
+}
+
+/// Encode `text` as a series of RFC 2047 `=?UTF-8?B?...?=` words,
+/// splitting it only between characters.
+fn encode_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chunk_start = 0;
+
+    for (offset, c) in text.char_indices() {
+        if offset + c.len_utf8() - chunk_start > MAX_ENCODED_WORD_TEXT_BYTES {
+            words.push(&text[chunk_start..offset]);
+            chunk_start = offset;
+        }
+    }
+
+    words.push(&text[chunk_start..]);
+
+    words
+        .into_iter()
+        .map(|chunk| format!("=?UTF-8?B?{}?=", base64_encode(chunk.as_bytes())))
+        .collect()
+}
+
+fn unfold_header_value(raw_value: &[u8]) -> String {
+    String::from_utf8_lossy(raw_value)
+        .replace("\r\n", "")
+        .replace('\n', "")
+        .trim()
+        .to_string()
+}
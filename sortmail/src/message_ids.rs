@@ -0,0 +1,134 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+//
+// Delivered Message-ID index
+//
+// A text file with one `<unix time> <message-id> <folder>` line per
+// delivered message, where the folder is empty for the root Maildir.
+// Message-IDs are normalized without whitespace (see
+// `IncomingMessage::message_id`), so the folder is everything after the
+// second space.
+// It's used to recognize copies of a message that has already been
+// delivered (e.g. when it arrives both directly and via a mailing
+// list), and to find the folder a reply's parent message went to.
+//
+// With `dedupe_content`, each delivered message's content hash is
+// recorded here too, as a `sha256:<hex>` line in place of a Message-ID
+// (which always has angle brackets, so the two can't be confused), to
+// recognize a byte-identical redelivery, as when the MTA retries a
+// delivery that it didn't hear the end of.
+//
+
+/// Message-IDs are forgotten after this long.
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The Message-ID index, held open and exclusively locked so that
+/// concurrent deliveries can't both decide a message is new.
+pub struct MessageIdIndex {
+    file: File,
+    entries: Vec<IndexEntry>
+}
+
+struct IndexEntry {
+    time: u64,
+    message_id: String,
+
+    /// The folder the message was delivered to, or None for the root
+    /// Maildir
+    mailbox_name: Option<String>
+}
+
+impl MessageIdIndex {
+    /// Open (creating it if needed) and lock the index file at `path`.
+    /// Blocks until any other delivery holding the lock is finished.
+    pub fn open(path: &Path) -> Result<MessageIdIndex> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Error opening Message-ID index {}", path.display()))?;
+
+        file.lock()
+            .with_context(|| format!("Error locking Message-ID index {}", path.display()))?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .with_context(|| format!("Error reading Message-ID index {}", path.display()))?;
+
+        // Unparseable lines are dropped rather than treated as errors,
+        // so a damaged index only costs some duplicate detection
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, ' ');
+                let time = fields.next()?.parse().ok()?;
+                let message_id = fields.next()?.to_string();
+                let mailbox_name = fields.next().filter(|name| !name.is_empty()).map(str::to_string);
+
+                Some(IndexEntry {
+                    time,
+                    message_id,
+                    mailbox_name
+                })
+            })
+            .collect();
+
+        Ok(MessageIdIndex {
+            file,
+            entries
+        })
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.entries.iter().any(|entry| entry.message_id == message_id)
+    }
+
+    /// Return the folder that the message with `message_id` was
+    /// delivered to: Some(None) for the root Maildir, or None if the
+    /// message isn't in the index.
+    pub fn mailbox_name(&self, message_id: &str) -> Option<Option<&str>> {
+        self.entries
+            .iter()
+            .find(|entry| entry.message_id == message_id)
+            .map(|entry| entry.mailbox_name.as_deref())
+    }
+
+    /// Add `message_id` to the index, along with the folder it was
+    /// delivered to, dropping expired entries, and write it back out.
+    /// A Message-ID that's already in the index keeps the folder it
+    /// was first delivered to.
+    pub fn record(&mut self, message_id: &str, mailbox_name: Option<&str>) -> Result<()> {
+        if self.contains(message_id) {
+            return Ok(());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+
+        self.entries.retain(|entry| now.saturating_sub(entry.time) < RETENTION.as_secs());
+        self.entries.push(IndexEntry {
+            time: now,
+            message_id: message_id.to_string(),
+            mailbox_name: mailbox_name.map(str::to_string)
+        });
+
+        let contents: String = self.entries
+            .iter()
+            .map(|entry| format!("{} {} {}\n", entry.time, entry.message_id, entry.mailbox_name.as_deref().unwrap_or_default()))
+            .collect();
+
+        self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.set_len(0))
+            .and_then(|_| self.file.write_all(contents.as_bytes()))
+            .and_then(|_| self.file.sync_all())
+            .context("Error writing Message-ID index")
+    }
+}
@@ -0,0 +1,407 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use maildir::Maildir;
+use sha2::{Digest, Sha256};
+
+use crate::AddressMap;
+use crate::clean;
+use crate::compress::{self, Compression};
+use crate::folders;
+use crate::imap::ImapServer;
+use crate::keywords;
+use crate::links::{self, LinkKind};
+use crate::mbox;
+use crate::mh;
+use crate::notmuch;
+use crate::quota;
+use crate::remote::RemoteMaildir;
+use crate::repair;
+use crate::s3::S3Bucket;
+use crate::store::{self, StoreOptions};
+
+//
+// Message stores
+//
+// A mailbox normally stores its messages in its folder of the root
+// Maildir, but one of the mailbox options `mbox`, `mh`,
+// `remote_maildir`, `imap_folder` or `s3_folder` sends them somewhere
+// else instead. Each of those is a `MessageStore`, chosen when the
+// config is loaded; delivery only hands it the message, so a new kind of
+// store needs an implementation of the trait and an option to choose
+// it, and nothing in the rules.
+//
+// Folders of the root Maildir, where mail goes otherwise, are a
+// `MaildirFolder` store, made for each delivery rather than when the
+// config is loaded: storing in them also links identical copies of the
+// message in other folders, counts towards the quota, subscribes new
+// folders and can go through notmuch, all of which depend on the root
+// Maildir and on the message's other deliveries.
+//
+
+/// A message for a `MessageStore`, and how to store it.
+pub struct StoreRequest<'a> {
+    /// The message as delivered, including the mbox-style `From ` line,
+    /// if it had one, before `header_start`
+    pub bytes: &'a [u8],
+    pub header_start: usize,
+
+    /// The envelope sender, for stores that record it
+    pub sender: Option<&'a str>,
+
+    /// Maildir info flags, or None to store the message as new
+    pub flags: Option<&'a str>,
+    pub keywords: &'a [String],
+
+    /// Create the folder if it doesn't exist
+    pub create_folder: bool,
+    pub options: StoreOptions,
+
+    /// How long a store that runs a command or talks to a server may
+    /// take
+    pub timeout: Duration
+}
+
+/// Somewhere that a mailbox's messages are stored.
+pub trait MessageStore: fmt::Debug {
+    /// Return where the store puts messages, as a path or URL, for
+    /// messages and `post_deliver` hooks. Two stores with the same
+    /// location are the same store.
+    fn location(&self) -> String;
+
+    /// Return true if the store can compress messages (with the
+    /// mailbox's `compress`).
+    fn can_compress(&self) -> bool {
+        false
+    }
+
+    /// Store the message.
+    fn store(&self, request: &StoreRequest) -> Result<()>;
+}
+
+/// A folder of the root Maildir, for one delivery of a message.
+#[derive(Debug)]
+pub struct MaildirFolder<'a> {
+    pub mappings: &'a AddressMap,
+    pub root_maildir: &'a Path,
+    pub mailbox_name: Option<&'a str>,
+    pub path: PathBuf,
+
+    /// notmuch tags for the message
+    pub labels: &'a [String],
+
+    /// The message as it was before encryption, which identical copies
+    /// are matched by
+    pub plaintext: &'a [u8],
+
+    /// Where the message's other deliveries stored it
+    pub copies: &'a RefCell<StoredCopies>
+}
+
+/// The copies of a message stored in Maildir folders so far, by the
+/// hash of their plaintext and how they're compressed, since copies are
+/// only linked to copies compressed the same way.
+#[derive(Debug, Default)]
+pub struct StoredCopies {
+    /// Copies in any folder, which identical copies in other folders
+    /// are hardlinked to
+    folders: Vec<([u8; 32], Option<Compression>, PathBuf)>,
+
+    /// Copies in the canonical folder, which other folders link to
+    canonical: Vec<([u8; 32], Option<Compression>, PathBuf)>
+}
+
+/// Return the stored copy in `copies` of the message with `hash`,
+/// compressed with `compression`.
+fn find_copy(copies: &[([u8; 32], Option<Compression>, PathBuf)], hash: &[u8; 32], compression: Option<Compression>) -> Option<PathBuf> {
+    copies
+        .iter()
+        .find(|(copy_hash, copy_compression, _)| copy_hash == hash && *copy_compression == compression)
+        .map(|(_, _, path)| path.clone())
+}
+
+impl MessageStore for MaildirFolder<'_> {
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn can_compress(&self) -> bool {
+        true
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        let mappings = self.mappings;
+
+        if mappings.repair_folders && self.path.is_dir() {
+            match repair::repair_folder(&self.path, request.options.directory_mode, false) {
+                Ok(fixes) => {
+                    for fix in fixes {
+                        println!("Maildir {}: {fix}", self.path.display());
+                    }
+                },
+                Err(error) => eprintln!("Error repairing {}: {error:#}", self.path.display())
+            }
+        }
+
+        if mappings.notmuch {
+            return notmuch::insert(self.root_maildir, &self.path, request.create_folder, request.flags, self.labels, request.bytes, request.timeout);
+        }
+
+        let hash: [u8; 32] = Sha256::digest(self.plaintext).into();
+        let compression = request.options.compression;
+        let mut copies = self.copies.borrow_mut();
+
+        let existing_copy = find_copy(&copies.folders, &hash, compression);
+        let is_first_copy = existing_copy.is_none();
+        let is_new_folder = request.create_folder && !self.path.exists();
+
+        let canonical_folder_path = mappings.canonical_folder
+            .as_ref()
+            .map(|canonical_folder| mappings.namespace.folder_path(self.root_maildir, canonical_folder));
+        let canonical_copy = find_copy(&copies.canonical, &hash, compression);
+
+        let folder = Maildir::from(self.path.clone());
+
+        let path = match canonical_folder_path {
+            // The canonical folder holds one real copy of each message,
+            // which another folder may already have stored there
+            Some(ref canonical_folder_path) if *canonical_folder_path == self.path => {
+                if canonical_copy.is_some() {
+                    return Ok(());
+                }
+
+                let path = store_message(&folder, request.create_folder, request.flags, request.keywords, request.bytes, None, &request.options)?;
+                copies.canonical.push((hash, compression, path.clone()));
+                path
+            },
+            // Other folders only get a link to the message's copy in the
+            // canonical folder, stored the first time
+            Some(canonical_folder_path) => {
+                let canonical_copy = match canonical_copy {
+                    Some(path) => path,
+                    None => {
+                        let path = store_message(&Maildir::from(canonical_folder_path), true, None, &[], request.bytes, None, &request.options)?;
+                        copies.canonical.push((hash, compression, path.clone()));
+                        path
+                    }
+                };
+
+                let link_to = Some((canonical_copy.as_path(), mappings.folder_links));
+                store_message(&folder, request.create_folder, request.flags, request.keywords, request.bytes, link_to, &request.options)?
+            },
+            None => {
+                let link_to = existing_copy.as_deref().map(|existing_copy| (existing_copy, LinkKind::Hardlink));
+                store_message(&folder, request.create_folder, request.flags, request.keywords, request.bytes, link_to, &request.options)?
+            }
+        };
+
+        // The message is stored at this point, so problems with the rest
+        // are only reported
+        if let Err(error) = quota::record_delivery(self.root_maildir, mappings.quota.as_ref(), request.bytes.len() as u64) {
+            eprintln!("Error updating quota for {}: {error:#}", self.root_maildir.display());
+        }
+
+        if let (Some(subscriptions), Some(mailbox_name), true) = (mappings.subscribe_new_folders, self.mailbox_name, is_new_folder) {
+            if let Err(error) = folders::subscribe(self.root_maildir, &mappings.namespace, mailbox_name, subscriptions) {
+                eprintln!("Error subscribing to {mailbox_name}: {error:#}");
+            }
+        }
+
+        if let Err(error) = clean::clean_tmp(&self.path, false) {
+            eprintln!("Error cleaning up {}: {error:#}", self.path.display());
+        }
+
+        if is_first_copy {
+            copies.folders.push((hash, compression, path));
+        }
+
+        Ok(())
+    }
+}
+
+/// Save a message to a Maildir, creating the Maildir first if
+/// `create_folder` is set. A message with `flags` (which may be empty)
+/// goes straight into `cur` with those flags set, as a message that has
+/// already been seen by a mail client; otherwise it's stored in `new`.
+///
+/// A message with `keywords` is also stored in `cur`, with the letters
+/// that the folder's `dovecot-keywords` file gives the keywords added
+/// to its flags.
+///
+/// If the same message was already stored at `existing_copy`, it's
+/// hardlinked to that rather than written again, where the filesystem
+/// allows. Returns the path of the stored message.
+pub fn store_message(mailbox: &Maildir, create_folder: bool, flags: Option<&str>, keywords: &[String], bytes: &[u8], link_to: Option<(&Path, LinkKind)>, options: &StoreOptions) -> Result<PathBuf> {
+    if create_folder {
+        store::create_folder(mailbox.path(), options)
+            .context("Error creating Maildir folder")?;
+    }
+
+    let flags = flags_with_keywords(mailbox, flags, keywords)?;
+
+    let stored = match link_to {
+        Some((existing_copy, kind)) => links::link(kind, existing_copy, mailbox.path(), flags.as_deref(), bytes, options),
+        None => store::store(mailbox.path(), flags.as_deref(), bytes, options)
+    };
+
+    stored.context("Error saving message to Maildir")
+}
+
+/// Return the info flags for a message stored in `mailbox` with
+/// `flags` and the IMAP `keywords`, whose letters are added.
+fn flags_with_keywords(mailbox: &Maildir, flags: Option<&str>, keywords: &[String]) -> Result<Option<String>> {
+    if keywords.is_empty() {
+        return Ok(flags.map(str::to_string));
+    }
+
+    // Maildir wants the flags in ASCII order, which puts the keyword
+    // letters after the uppercase system flags
+    let mut letters: Vec<char> = flags
+        .unwrap_or_default()
+        .chars()
+        .chain(keywords::letters(mailbox.path(), keywords)?.chars())
+        .collect();
+
+    letters.sort_unstable();
+    letters.dedup();
+    Ok(Some(letters.into_iter().collect()))
+}
+
+/// An mbox file that messages are appended to (see the `mbox` module).
+#[derive(Debug)]
+pub struct MboxFile(pub PathBuf);
+
+impl MessageStore for MboxFile {
+    fn location(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        mbox::append(&self.0, request.sender, request.bytes, &request.options)
+    }
+}
+
+/// An MH folder (see the `mh` module).
+#[derive(Debug)]
+pub struct MhFolder(pub PathBuf);
+
+impl MessageStore for MhFolder {
+    fn location(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        mh::store(&self.0, request.bytes, &request.options).map(|_| ())
+    }
+}
+
+impl MessageStore for RemoteMaildir {
+    fn location(&self) -> String {
+        self.to_string()
+    }
+
+    fn can_compress(&self) -> bool {
+        true
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        self.upload(request.create_folder, request.flags, request.bytes, &request.options, request.timeout)
+    }
+}
+
+/// A folder on the IMAP server (see the `imap` module).
+#[derive(Debug)]
+pub struct ImapFolder {
+    pub server: Rc<ImapServer>,
+    pub folder: String
+}
+
+impl MessageStore for ImapFolder {
+    fn location(&self) -> String {
+        format!("imap://{}@{}/{}", self.server.username, self.server.host, self.folder)
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        // IMAP has no place for a `From ` line
+        self.server.append(&self.folder, request.create_folder, request.flags, request.keywords, &request.bytes[request.header_start..])
+    }
+}
+
+/// A folder in the S3 bucket (see the `s3` module).
+#[derive(Debug)]
+pub struct S3Folder {
+    pub bucket: Rc<S3Bucket>,
+    pub folder: String
+}
+
+impl MessageStore for S3Folder {
+    fn location(&self) -> String {
+        format!("s3://{}/{}", self.bucket.bucket, self.folder)
+    }
+
+    fn can_compress(&self) -> bool {
+        true
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        let contents = compress::compress(request.bytes, request.options.compression.as_ref())?;
+        let name = store::remote_message_name(request.flags, request.bytes, &request.options);
+
+        let key = match self.folder.is_empty() {
+            true => name.display().to_string(),
+            false => format!("{}/{}", self.folder, name.display())
+        };
+
+        let metadata = [
+            ("sender", request.sender.unwrap_or_default().to_string()),
+            ("keywords", request.keywords.join(" "))
+        ];
+
+        self.bucket.put_object(&key, &contents, &metadata)
+            .with_context(|| format!("Error storing message in {}", self.location()))
+    }
+}
+
+/// A store that keeps messages in memory, for tests, or fails to store
+/// anything if `fail`.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    pub name: String,
+    pub fail: bool,
+
+    pub messages: RefCell<Vec<StoredMessage>>
+}
+
+/// A message in a `MemoryStore`.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct StoredMessage {
+    pub bytes: Vec<u8>,
+    pub flags: Option<String>,
+    pub keywords: Vec<String>
+}
+
+#[cfg(test)]
+impl MessageStore for MemoryStore {
+    fn location(&self) -> String {
+        format!("memory:{}", self.name)
+    }
+
+    fn store(&self, request: &StoreRequest) -> Result<()> {
+        if self.fail {
+            anyhow::bail!("{} is broken", self.location());
+        }
+
+        self.messages.borrow_mut().push(StoredMessage {
+            bytes: request.bytes.to_vec(),
+            flags: request.flags.map(str::to_string),
+            keywords: request.keywords.to_vec()
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::store::{self, StoreOptions};
+
+//
+// MH delivery
+//
+// An MH folder is a directory of messages named by number, `1`, `2`,
+// and so on. New messages are added to the folder's `unseen` sequence
+// in its `.mh_sequences` file, as nmh's inc does, so that they show up
+// as new in nmh and mh-e.
+//
+
+/// Name of the sequence file, in each MH folder.
+const SEQUENCES_FILE_NAME: &str = ".mh_sequences";
+
+/// The sequence that new messages are added to (nmh's default
+/// `Unseen-Sequence`).
+const UNSEEN_SEQUENCE: &str = "unseen";
+
+/// Give up after this many attempts at claiming a message number that
+/// other deliveries keep taking first.
+const MAX_NUMBER_ATTEMPTS: usize = 100;
+
+/// Store `message` in the MH folder at `folder_path` (creating it if
+/// needed) under the next free message number, and add it to the
+/// `unseen` sequence. Returns the message number. With `durable`, the
+/// message is flushed to disk before it's added to the folder, and the
+/// folder afterwards.
+pub fn store(folder_path: &Path, message: &[u8], options: &StoreOptions) -> Result<u64> {
+    store::create_dir_all(folder_path, options.directory_mode)
+        .with_context(|| format!("Error creating MH folder {}", folder_path.display()))?;
+
+    // The message is written under a temporary name and then linked
+    // to its number, so it never appears half-written and two
+    // deliveries can't claim the same number
+    let temp_path = folder_path.join(format!(",sortmail-{}", std::process::id()));
+    let written = File::create(&temp_path).and_then(|mut file| {
+        store::set_file_mode(&file, options.file_mode)?;
+        file.write_all(message)?;
+
+        match options.durable {
+            true => file.sync_all(),
+            false => Ok(())
+        }
+    });
+
+    let claimed = written
+        .with_context(|| format!("Error writing message to MH folder {}", folder_path.display()))
+        .and_then(|_| claim_number(folder_path, &temp_path));
+
+    let _ = std::fs::remove_file(&temp_path);
+    let number = claimed?;
+
+    if options.durable {
+        store::sync_parent_dir(&folder_path.join(number.to_string()))?;
+    }
+
+    add_to_unseen(folder_path, number)?;
+
+    Ok(number)
+}
+
+/// Link `temp_path` to the next free message number in the folder.
+fn claim_number(folder_path: &Path, temp_path: &Path) -> Result<u64> {
+    for _ in 0..MAX_NUMBER_ATTEMPTS {
+        let number = highest_number(folder_path)? + 1;
+
+        match std::fs::hard_link(temp_path, folder_path.join(number.to_string())) {
+            Ok(()) => return Ok(number),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error).with_context(|| format!("Error storing message in MH folder {}", folder_path.display()))
+        }
+    }
+
+    bail!("Couldn't find a free message number in MH folder {}", folder_path.display())
+}
+
+/// Return the highest message number in the folder, or 0 if it's
+/// empty.
+fn highest_number(folder_path: &Path) -> Result<u64> {
+    let entries = std::fs::read_dir(folder_path)
+        .with_context(|| format!("Error reading MH folder {}", folder_path.display()))?;
+
+    Ok(entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0))
+}
+
+/// Add message `number` to the `unseen` sequence, keeping the folder's
+/// other sequences as they are.
+fn add_to_unseen(folder_path: &Path, number: u64) -> Result<()> {
+    let path = folder_path.join(SEQUENCES_FILE_NAME);
+
+    let mut file: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Error opening MH sequences {}", path.display()))?;
+
+    file.lock()
+        .with_context(|| format!("Error locking MH sequences {}", path.display()))?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Error reading MH sequences {}", path.display()))?;
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut found_unseen = false;
+
+    for line in contents.lines() {
+        match line.split_once(':') {
+            Some((name, numbers)) if name.trim() == UNSEEN_SEQUENCE => {
+                let mut unseen = parse_sequence(numbers);
+                unseen.insert(number);
+                lines.push(format!("{UNSEEN_SEQUENCE}: {}", format_sequence(&unseen)));
+                found_unseen = true;
+            },
+            _ => lines.push(line.to_string())
+        }
+    }
+
+    if !found_unseen {
+        lines.push(format!("{UNSEEN_SEQUENCE}: {number}"));
+    }
+
+    let new_contents: String = lines.iter().map(|line| format!("{line}\n")).collect();
+
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(new_contents.as_bytes()))
+        .with_context(|| format!("Error writing MH sequences {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Parse a sequence like `1-3 5 7` into message numbers.
+fn parse_sequence(numbers: &str) -> BTreeSet<u64> {
+    numbers
+        .split_whitespace()
+        .filter_map(|range| {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            Some((first.parse::<u64>().ok()?, last.parse::<u64>().ok()?))
+        })
+        .flat_map(|(first, last)| first..=last)
+        .collect()
+}
+
+/// Format message numbers as a sequence, collapsing runs into ranges.
+fn format_sequence(numbers: &BTreeSet<u64>) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+    for &number in numbers {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == number => *last = number,
+            _ => ranges.push((number, number))
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{first}-{last}")
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,82 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::process::wait_with_timeout;
+
+//
+// notmuch delivery
+//
+// With the global `notmuch`, messages bound for Maildir folders are
+// handed to `notmuch insert` instead of being written directly, so that
+// they're indexed as they arrive. notmuch's database has to be in the
+// root Maildir, since `--folder` is relative to it.
+//
+// Messages get notmuch's usual tags for new mail (`new.tags` in its
+// config), plus the mailbox's `labels`, and tags for any Maildir flags
+// they're stored with, as notmuch's flag synchronization would give
+// them: `Seen` takes off `unread`, `Flagged` adds `flagged`, and so on.
+//
+
+/// The tags notmuch gives each Maildir flag.
+const FLAG_TAGS: [(char, &str); 6] = [
+    ('D', "+draft"),
+    ('F', "+flagged"),
+    ('P', "+passed"),
+    ('R', "+replied"),
+    ('S', "-unread"),
+    ('T', "+deleted")
+];
+
+/// Insert `bytes` into the Maildir at `folder_path` with `notmuch
+/// insert`, creating the folder first if `create_folder`, and tagged
+/// with `labels` and the tags for `flags`. notmuch is killed if it runs
+/// longer than `timeout`.
+pub fn insert(root_maildir: &Path, folder_path: &Path, create_folder: bool, flags: Option<&str>, labels: &[String], bytes: &[u8], timeout: Duration) -> Result<()> {
+    let folder = folder_path.strip_prefix(root_maildir).unwrap_or(folder_path);
+
+    let mut command = Command::new("notmuch");
+    command.arg("insert");
+
+    if !folder.as_os_str().is_empty() {
+        command.arg(format!("--folder={}", folder.display()));
+    }
+
+    if create_folder {
+        command.arg("--create-folder");
+    }
+
+    let flag_tags = FLAG_TAGS
+        .iter()
+        .filter(|(flag, _)| flags.unwrap_or_default().contains(*flag))
+        .map(|(_, tag)| tag.to_string());
+
+    command.args(labels.iter().map(|label| format!("+{label}")).chain(flag_tags));
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Error running notmuch insert")?;
+
+    // Write the message on another thread, so that notmuch failing
+    // before it's read everything can't leave us stuck on a full pipe
+    let mut stdin = child.stdin.take().context("Error writing to notmuch insert")?;
+    let bytes = bytes.to_vec();
+    thread::spawn(move || {
+        let _ = stdin.write_all(&bytes);
+    });
+
+    let status = wait_with_timeout(&mut child, timeout)
+        .context("notmuch insert didn't finish")?;
+
+    if !status.success() {
+        bail!("notmuch insert failed ({status})");
+    }
+
+    Ok(())
+}
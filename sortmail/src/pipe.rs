@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::Envelope;
+use crate::process::wait_with_timeout;
+
+//
+// Pipe-to-command delivery
+//
+// A mailbox's `pipe` command is run by `/bin/sh -c`, as procmail does,
+// with the raw message on its stdin and these environment variables
+// set:
+//
+//     SORTMAIL_RECIPIENT      the recipient address
+//     SORTMAIL_EXTENSION      the recipient's address extension, if any
+//     SORTMAIL_SENDER         the envelope sender, if any
+//     SORTMAIL_MAILBOX        the name of the mailbox that matched
+//
+// The message counts as delivered if the command exits with status 0;
+// what happens otherwise is up to the mailbox's `pipe_failure`.
+//
+
+/// What to do when a `pipe` command fails (exits non-zero, is killed
+/// by a signal, times out or can't be run).
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PipeFailure {
+    /// Consider the message delivered anyway
+    Delivered,
+
+    /// Carry on as though the mailbox hadn't matched, trying later
+    /// mailboxes and the default delivery
+    Continue,
+
+    /// Exit with EX_TEMPFAIL, so that the MTA tries again later
+    #[default]
+    Tempfail
+}
+
+#[derive(Clone, Debug)]
+pub struct PipeCommand {
+    pub command: String,
+    pub on_failure: PipeFailure,
+    pub timeout: Duration
+}
+
+impl PipeCommand {
+    /// Run the command with `bytes` on its stdin, and fail unless it
+    /// exits successfully. The command is killed if it runs longer than
+    /// `timeout`.
+    pub fn run(&self, envelope: &Envelope, mailbox_name: &str, bytes: &[u8]) -> Result<()> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("SORTMAIL_RECIPIENT", &envelope.recipient)
+            .env("SORTMAIL_EXTENSION", envelope.recipient_extension.as_deref().unwrap_or_default())
+            .env("SORTMAIL_SENDER", envelope.sender.as_deref().unwrap_or_default())
+            .env("SORTMAIL_MAILBOX", mailbox_name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Error running pipe command {}", self.command))?;
+
+        // Write the message on another thread, so that a command that
+        // doesn't read all of its input can't block us forever; one
+        // that exits early just gets a broken pipe
+        let mut stdin = child.stdin.take().context("Error writing to pipe command")?;
+        let bytes = bytes.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&bytes);
+        });
+
+        let status = wait_with_timeout(&mut child, self.timeout)
+            .with_context(|| format!("Pipe command {} didn't finish", self.command))?;
+
+        if !status.success() {
+            bail!("Pipe command {} failed ({status})", self.command);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use std::process::{Child, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+//
+// Subprocesses
+//
+// Every command sortmail runs (pipes, hooks, lookups, encryption, spam
+// learners and the rest) is waited for with a timeout, so that one that
+// hangs can't keep the MTA's delivery waiting forever. Callers say what
+// the command was in their own error context.
+//
+
+/// How often to check whether a command has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait for `child` to exit, and return its exit status. It's killed if
+/// it runs longer than `timeout`.
+pub fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait().context("Error waiting for command")? {
+            return Ok(status);
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Timed out after {timeout:?}");
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
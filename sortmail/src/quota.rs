@@ -0,0 +1,214 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+//
+// Maildir++ quota
+//
+// Courier and Dovecot keep track of how much a Maildir holds in a
+// `maildirsize` file in the root Maildir. Its first line is the quota,
+// like `1073741824S,10000C` for 1G in at most 10000 messages, and each
+// line after that is a change in usage, `<bytes> <messages>`; the
+// usage is the sum of those lines. Deliveries append a line, with a
+// single write so that no locking is needed, and once the file grows
+// past a few kilobytes it's rewritten with the usage counted afresh.
+//
+// Before a message is stored, the usage is checked against the quota,
+// and a message that would take the Maildir over it is dealt with as
+// the global `quota_full` says.
+//
+
+/// Name of the quota file, in the root Maildir.
+const MAILDIRSIZE_FILE_NAME: &str = "maildirsize";
+
+/// Recount the usage once the file is bigger than this, as Courier
+/// does.
+const MAX_MAILDIRSIZE_FILE_SIZE: u64 = 5120;
+
+/// A Maildir's quota, from the global `quota` and `quota_messages`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: Option<u64>,
+    pub messages: Option<u64>
+}
+
+/// What to do with a message that would take the root Maildir over its
+/// quota.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaFull {
+    /// Exit with EX_TEMPFAIL, so that the MTA tries again later
+    #[default]
+    Tempfail,
+
+    /// Exit with EX_CANTCREAT, so that the MTA bounces the message
+    Reject,
+
+    /// Store the message in the global `quota_overflow_folder` instead
+    /// of where it was going, even though that takes the Maildir over
+    /// quota
+    Overflow
+}
+
+/// How much a Maildir holds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub messages: u64
+}
+
+impl Quota {
+    /// Return the quota as the first line of `maildirsize`.
+    fn definition(&self) -> String {
+        let mut parts = Vec::new();
+
+        if let Some(bytes) = self.bytes {
+            parts.push(format!("{bytes}S"));
+        }
+
+        if let Some(messages) = self.messages {
+            parts.push(format!("{messages}C"));
+        }
+
+        parts.join(",")
+    }
+
+    /// Return true if adding a message of `size` bytes to a Maildir
+    /// with `usage` would take it over the quota.
+    pub fn is_exceeded(&self, usage: &Usage, size: u64) -> bool {
+        self.bytes.is_some_and(|bytes| usage.bytes + size > bytes)
+            || self.messages.is_some_and(|messages| usage.messages + 1 > messages)
+    }
+}
+
+/// Record a message of `size` bytes just delivered to the Maildir at
+/// `root_maildir`. If the Maildir has no `maildirsize` file, one is
+/// only created if there's a `quota`; if the file's quota isn't
+/// `quota`, or the file has grown too big, it's rewritten with the
+/// usage counted from the Maildir's messages.
+pub fn record_delivery(root_maildir: &Path, quota: Option<&Quota>, size: u64) -> Result<()> {
+    let path = root_maildir.join(MAILDIRSIZE_FILE_NAME);
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(error) if error.kind() == ErrorKind::NotFound => None,
+        Err(error) => return Err(error).with_context(|| format!("Error reading {}", path.display()))
+    };
+
+    let needs_recount = match (&contents, quota) {
+        (None, None) => return Ok(()),
+        (None, Some(_)) => true,
+        (Some(contents), quota) => {
+            let definition_changed = quota.is_some_and(|quota| contents.lines().next() != Some(&quota.definition()));
+            definition_changed || contents.len() as u64 > MAX_MAILDIRSIZE_FILE_SIZE
+        }
+    };
+
+    if !needs_recount {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Error opening {}", path.display()))?;
+
+        return file.write_all(format!("{size} 1\n").as_bytes())
+            .with_context(|| format!("Error writing {}", path.display()));
+    }
+
+    // The message is already in the Maildir, so it's counted
+    let definition = match quota {
+        Some(quota) => quota.definition(),
+        None => contents.as_deref().and_then(|contents| contents.lines().next()).unwrap_or_default().to_string()
+    };
+
+    let usage = count_usage(root_maildir)?;
+    let temp_path = root_maildir.join("tmp").join(format!("{MAILDIRSIZE_FILE_NAME}.{}", std::process::id()));
+
+    std::fs::write(&temp_path, format!("{definition}\n{} {}\n", usage.bytes, usage.messages))
+        .and_then(|_| std::fs::rename(&temp_path, &path))
+        .with_context(|| format!("Error writing {}", path.display()))
+}
+
+/// Return how much the Maildir at `root_maildir` holds, from its
+/// `maildirsize` file if it has one and by counting its messages
+/// otherwise.
+pub fn usage(root_maildir: &Path) -> Result<Usage> {
+    let path = root_maildir.join(MAILDIRSIZE_FILE_NAME);
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return count_usage(root_maildir),
+        Err(error) => return Err(error).with_context(|| format!("Error reading {}", path.display()))
+    };
+
+    let mut usage = Usage::default();
+
+    // Lines can be negative, for messages that have been deleted
+    for line in contents.lines().skip(1) {
+        let mut fields = line.split_whitespace().map(|field| field.parse::<i64>().unwrap_or(0));
+        usage.bytes = usage.bytes.saturating_add_signed(fields.next().unwrap_or(0));
+        usage.messages = usage.messages.saturating_add_signed(fields.next().unwrap_or(0));
+    }
+
+    Ok(usage)
+}
+
+/// Count the messages in every folder's `new` and `cur` directories,
+/// taking sizes from `,S=` in their filenames where it's there.
+fn count_usage(root_maildir: &Path) -> Result<Usage> {
+    let mut usage = Usage::default();
+    let mut dirs = vec![root_maildir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Error reading {}", dir.display()))?;
+
+        for entry in entries.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            if !file_type.is_dir() || name == "tmp" {
+                continue;
+            }
+
+            if name != "new" && name != "cur" {
+                dirs.push(entry.path());
+                continue;
+            }
+
+            for message in std::fs::read_dir(entry.path()).into_iter().flatten().flatten() {
+                let message_name = message.file_name();
+                let message_name = message_name.to_string_lossy();
+
+                if message_name.starts_with('.') {
+                    continue;
+                }
+
+                let size = size_from_name(&message_name)
+                    .or_else(|| message.metadata().ok().map(|metadata| metadata.len()))
+                    .unwrap_or(0);
+
+                usage.bytes += size;
+                usage.messages += 1;
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Return the size in a message's `,S=<size>` filename attribute.
+fn size_from_name(name: &str) -> Option<u64> {
+    let name = name.split_once(':').map_or(name, |(name, _)| name);
+
+    name.split(',')
+        .skip(1)
+        .find_map(|attribute| attribute.strip_prefix("S="))
+        .and_then(|size| size.parse().ok())
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::compress;
+use crate::process::wait_with_timeout;
+use crate::store::{self, StoreOptions};
+
+//
+// Remote Maildir delivery
+//
+// A mailbox's `remote_maildir`, like `ssh://mail@store.example.com/srv/
+// mail/Maildir/.Lists`, names a Maildir on another machine, which
+// messages are uploaded to with `sftp`, so a small relay can sort
+// straight into a mail store elsewhere. As with a local Maildir, each
+// message is uploaded into `tmp` and then renamed into `new` (or `cur`),
+// and SFTP's rename won't replace an existing file, so readers on the
+// other end never see half a message.
+//
+// sftp runs in batch mode, so it has to be able to log in without
+// asking anything: with a key that ssh-agent or `~/.ssh/config`
+// provides, and the host already in `known_hosts`. A path starting
+// with `/~/` is relative to the remote user's home directory.
+//
+
+/// A Maildir on another machine, reached over SFTP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RemoteMaildir {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+
+    /// The Maildir's path on the remote machine
+    path: String
+}
+
+impl RemoteMaildir {
+    /// Parse an `ssh://[user@]host[:port]/path` URL.
+    pub fn parse(url: &str) -> Result<RemoteMaildir> {
+        let Some(rest) = url.strip_prefix("ssh://").or_else(|| url.strip_prefix("sftp://")) else {
+            bail!("Invalid remote Maildir {url:?}: expected ssh://[user@]host[:port]/path");
+        };
+
+        let Some((authority, path)) = rest.split_once('/') else {
+            bail!("Invalid remote Maildir {url:?}: no path");
+        };
+
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, authority)
+        };
+
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().with_context(|| format!("Invalid port in remote Maildir {url:?}"))?)),
+            None => (host_port, None)
+        };
+
+        let path = match path.strip_prefix("~/") {
+            Some(relative) => relative.trim_end_matches('/').to_string(),
+            None => format!("/{}", path.trim_end_matches('/'))
+        };
+
+        if host.is_empty() || path.is_empty() {
+            bail!("Invalid remote Maildir {url:?}: expected ssh://[user@]host[:port]/path");
+        }
+
+        // Paths are quoted in sftp's batch commands
+        if path.contains(['"', '\\', '\n']) {
+            bail!("Invalid remote Maildir {url:?}: the path can't contain quotes, backslashes or newlines");
+        }
+
+        Ok(RemoteMaildir {
+            user,
+            host: host.to_string(),
+            port,
+            path
+        })
+    }
+
+    /// Upload `bytes` into the Maildir, creating it first if
+    /// `create_folder`: into `new`, or into `cur` with info flags
+    /// `flags` if given. sftp is killed if it takes longer than
+    /// `timeout`.
+    pub fn upload(&self, create_folder: bool, flags: Option<&str>, bytes: &[u8], options: &StoreOptions, timeout: Duration) -> Result<()> {
+        let contents = compress::compress(bytes, options.compression.as_ref())?;
+        let name = store::remote_message_name(flags, bytes, options);
+        let file_name = name.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+        let temp_path = format!("{}/tmp/{file_name}", self.path);
+        let path = format!("{}/{}", self.path, name.display());
+
+        let local_temp_path = write_local_copy(&file_name, &contents)?;
+
+        let mut batch = Vec::new();
+        if create_folder {
+            // Ignoring errors (the `-`), since parts may already exist
+            for directory in ["", "/tmp", "/new", "/cur"] {
+                batch.push(format!("-mkdir \"{}{directory}\"", self.path));
+            }
+        }
+        batch.push(format!("put \"{}\" \"{temp_path}\"", local_temp_path.display()));
+        batch.push(format!("rename \"{temp_path}\" \"{path}\""));
+
+        let uploaded = self.run_batch(&batch, timeout);
+        let _ = std::fs::remove_file(&local_temp_path);
+
+        if uploaded.is_err() {
+            // The upload may have got as far as tmp
+            let _ = self.run_batch(&[format!("-rm \"{temp_path}\"")], timeout);
+        }
+
+        uploaded.with_context(|| format!("Error uploading message to {self}"))
+    }
+
+    /// Run sftp with the batch `commands`, and fail unless they all
+    /// succeed (other than those starting with `-`).
+    fn run_batch(&self, commands: &[String], timeout: Duration) -> Result<()> {
+        let mut command = Command::new("sftp");
+        command.args(["-q", "-b", "-", "-o", "BatchMode=yes"]);
+
+        if let Some(port) = self.port {
+            command.arg("-P").arg(port.to_string());
+        }
+
+        command.arg(match self.user {
+            Some(ref user) => format!("{user}@{}", self.host),
+            None => self.host.clone()
+        });
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("Error running sftp")?;
+
+        // The batch is small enough to fit in the pipe, so this can't
+        // block on sftp
+        let mut stdin = child.stdin.take().context("Error writing to sftp")?;
+        stdin.write_all(format!("{}\n", commands.join("\n")).as_bytes())
+            .context("Error writing to sftp")?;
+        drop(stdin);
+
+        let status = wait_with_timeout(&mut child, timeout).context("sftp didn't finish")?;
+
+        if !status.success() {
+            bail!("sftp failed ({status})");
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for RemoteMaildir {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ssh://")?;
+        if let Some(ref user) = self.user {
+            write!(f, "{user}@")?;
+        }
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        match self.path.starts_with('/') {
+            true => write!(f, "{}", self.path),
+            false => write!(f, "/~/{}", self.path)
+        }
+    }
+}
+
+/// Write `contents` to a private file in the temporary directory, for
+/// sftp to upload, and return its path.
+fn write_local_copy(file_name: &str, contents: &[u8]) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!("sortmail-{file_name}"));
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("Error writing {}", path.display()))?;
+
+    Ok(path)
+}
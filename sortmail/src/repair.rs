@@ -0,0 +1,77 @@
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::store;
+
+//
+// Maildir repair
+//
+// A folder that's missing its `cur`, `new` or `tmp` directory (created
+// by hand, half-restored from a backup, or left half made by a crashed
+// tool) can't take deliveries, and neither can one whose directories
+// its owner can't write to. Repairing a folder recreates the missing
+// directories and gives the owner back full access to the folder's own
+// directories, leaving the permissions for anyone else as they are;
+// with the global `directory_mode`, the directories are given exactly
+// that mode instead.
+//
+// `sortmail repair` repairs the root Maildir and every folder in it;
+// with the global `repair_folders`, each existing folder is also
+// repaired just before a message is stored in it.
+//
+
+/// The directories every Maildir folder has.
+const SUBDIRECTORIES: [&str; 3] = ["cur", "new", "tmp"];
+
+/// Repair the Maildir folder at `folder_path`, which should already
+/// exist, giving its directories `directory_mode` if there is one, and
+/// return a description of each fix, without making them if `dry_run`.
+pub fn repair_folder(folder_path: &Path, directory_mode: Option<u32>, dry_run: bool) -> Result<Vec<String>> {
+    let mut fixes = Vec::new();
+
+    fix_permissions(folder_path, directory_mode, dry_run, &mut fixes)?;
+
+    for subdir in SUBDIRECTORIES {
+        let path = folder_path.join(subdir);
+
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => fix_permissions(&path, directory_mode, dry_run, &mut fixes)?,
+            Ok(_) => bail!("Can't repair {}: it isn't a directory", path.display()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                if !dry_run {
+                    store::create_dir_all(&path, directory_mode)?;
+                }
+                fixes.push(format!("Created missing {}", path.display()));
+            },
+            Err(error) => return Err(error).with_context(|| format!("Error reading {}", path.display()))
+        }
+    }
+
+    Ok(fixes)
+}
+
+/// Give the directory at `path` the permissions `directory_mode`, or
+/// without one, give its owner read, write and search permission on it
+/// if they're missing any.
+fn fix_permissions(path: &Path, directory_mode: Option<u32>, dry_run: bool, fixes: &mut Vec<String>) -> Result<()> {
+    let mode = std::fs::metadata(path)
+        .with_context(|| format!("Error reading {}", path.display()))?
+        .permissions()
+        .mode() & 0o7777;
+
+    let fixed_mode = directory_mode.unwrap_or(mode | 0o700);
+    if fixed_mode == mode {
+        return Ok(());
+    }
+
+    if !dry_run {
+        std::fs::set_permissions(path, Permissions::from_mode(fixed_mode))
+            .with_context(|| format!("Error changing permissions of {}", path.display()))?;
+    }
+    fixes.push(format!("Changed permissions of {} from {mode:04o} to {fixed_mode:04o}", path.display()));
+
+    Ok(())
+}
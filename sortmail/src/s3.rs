@@ -0,0 +1,282 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use native_tls::TlsConnector;
+use sha2::{Digest, Sha256};
+
+use crate::dates;
+
+//
+// S3 delivery
+//
+// A mailbox's `s3_folder` stores its messages as objects in a bucket on
+// Amazon S3, or on anything else that speaks its API (MinIO, Garage,
+// Ceph, Backblaze B2, Cloudflare R2 and so on), for archiving mail into
+// cheap object storage. Keys are laid out like a Maildir under the
+// folder, `Archive/new/<unique name>` or `Archive/cur/<unique name>:2,S`
+// with flags, so that a copy of the bucket is a Maildir that any mail
+// reader can open. Objects are `message/rfc822`, with the envelope
+// sender and the message's keywords as `x-amz-meta-` metadata.
+//
+// Each message is a single PUT, signed with AWS Signature Version 4,
+// over its own HTTPS connection (or plain HTTP, for an `http://`
+// endpoint on a trusted network). Buckets are addressed as
+// `bucket.host` unless `s3_path_style` asks for `host/bucket`, which
+// most self-hosted servers want.
+//
+
+/// Where the S3 API is served.
+#[derive(Debug)]
+pub struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16
+}
+
+/// The bucket that `s3_folder` mail is stored in, from the global `s3_`
+/// options.
+#[derive(Debug)]
+pub struct S3Bucket {
+    pub endpoint: Endpoint,
+    pub bucket: String,
+    pub region: String,
+    pub path_style: bool,
+    pub access_key: String,
+    pub secret_key: String,
+    pub timeout: Duration
+}
+
+impl Endpoint {
+    /// Parse an `https://host[:port]` (or `http://`) URL.
+    pub fn parse(url: &str) -> Result<Endpoint> {
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => bail!("Invalid S3 endpoint {url:?}: expected https://host[:port]")
+        };
+
+        let authority = rest.trim_end_matches('/');
+        if authority.is_empty() || authority.contains('/') {
+            bail!("Invalid S3 endpoint {url:?}: expected https://host[:port], without a path");
+        }
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().with_context(|| format!("Invalid port in S3 endpoint {url:?}"))?),
+            None => (authority, if tls { 443 } else { 80 })
+        };
+
+        Ok(Endpoint {
+            tls,
+            host: host.to_string(),
+            port
+        })
+    }
+
+    /// Return the endpoint for AWS's own S3 in `region`.
+    pub fn aws(region: &str) -> Endpoint {
+        Endpoint {
+            tls: true,
+            host: format!("s3.{region}.amazonaws.com"),
+            port: 443
+        }
+    }
+}
+
+impl S3Bucket {
+    /// Store `body` as the object `key`, with the `x-amz-meta-`
+    /// `metadata`, replacing any object already there.
+    pub fn put_object(&self, key: &str, body: &[u8], metadata: &[(&str, String)]) -> Result<()> {
+        let (host, path) = match self.path_style {
+            true => (self.endpoint.host.clone(), format!("/{}/{}", self.bucket, uri_encode(key))),
+            false => (format!("{}.{}", self.bucket, self.endpoint.host), format!("/{}", uri_encode(key)))
+        };
+
+        let host_header = match self.endpoint.port == if self.endpoint.tls { 443 } else { 80 } {
+            true => host.clone(),
+            false => format!("{host}:{}", self.endpoint.port)
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        let amz_date = dates::format_utc(now, "%Y%m%dT%H%M%SZ");
+        let payload_hash = hex(&Sha256::digest(body));
+
+        let mut headers = vec![
+            ("content-length".to_string(), body.len().to_string()),
+            ("content-type".to_string(), "message/rfc822".to_string()),
+            ("host".to_string(), host_header),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone())
+        ];
+        headers.extend(metadata
+            .iter()
+            .map(|(name, value)| (format!("x-amz-meta-{name}"), header_value(value)))
+            .filter(|(_, value)| !value.is_empty()));
+        headers.sort();
+
+        let authorization = self.authorization("PUT", &path, &headers, &payload_hash, &amz_date);
+
+        let mut request = format!("PUT {path} HTTP/1.1\r\n");
+        for (name, value) in &headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str(&format!("authorization: {authorization}\r\nconnection: close\r\n\r\n"));
+
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+
+        let response = self.send(&host, &request)
+            .with_context(|| format!("Error connecting to S3 endpoint {host}:{}", self.endpoint.port))?;
+
+        check_response(&response)
+    }
+
+    /// Return the `Authorization` header value that signs the request
+    /// (AWS Signature Version 4), given its headers sorted by name.
+    fn authorization(&self, method: &str, path: &str, headers: &[(String, String)], payload_hash: &str, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+
+        let signed_headers: Vec<_> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        let signed_headers = signed_headers.join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+
+        let canonical_request = format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}", hex(&Sha256::digest(canonical_request.as_bytes())));
+
+        let signing_key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac_sha256(&key, part.as_bytes()).to_vec());
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}", self.access_key)
+    }
+
+    /// Send `request` to `host` and return the whole response.
+    fn send(&self, host: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let addrs = (host, self.endpoint.port).to_socket_addrs()?;
+
+        let mut last_error = None;
+        let mut tcp_stream = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    tcp_stream = Some(stream);
+                    break;
+                },
+                Err(error) => last_error = Some(error)
+            }
+        }
+
+        let tcp_stream = match (tcp_stream, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(error)) => return Err(error.into()),
+            (None, None) => bail!("No addresses found for {host}")
+        };
+
+        tcp_stream.set_read_timeout(Some(self.timeout))?;
+        tcp_stream.set_write_timeout(Some(self.timeout))?;
+
+        match self.endpoint.tls {
+            true => {
+                let mut tls_stream = TlsConnector::new()
+                    .context("Error setting up TLS")?
+                    .connect(host, tcp_stream)
+                    .map_err(|error| anyhow!("TLS error: {error}"))?;
+                exchange(&mut tls_stream, request)
+            },
+            false => exchange(&mut &tcp_stream, request)
+        }
+    }
+}
+
+/// Write `request` to `stream`, then read the response until the
+/// server closes the connection.
+fn exchange<S: Read + Write>(stream: &mut S, request: &[u8]) -> Result<Vec<u8>> {
+    stream.write_all(request)
+        .and_then(|_| stream.flush())
+        .context("Error sending message to S3")?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).context("Error reading from S3")?;
+
+    Ok(response)
+}
+
+/// Fail unless `response` has a 2xx status, with S3's error code if it
+/// gave one.
+fn check_response(response: &[u8]) -> Result<()> {
+    let response = String::from_utf8_lossy(response);
+    let status_line = response.lines().next().unwrap_or_default();
+
+    let is_success = status_line
+        .split(' ')
+        .nth(1)
+        .is_some_and(|status| status.starts_with('2'));
+
+    if is_success {
+        return Ok(());
+    }
+
+    let error_code = response
+        .split_once("<Code>")
+        .and_then(|(_, rest)| rest.split_once("</Code>"))
+        .map(|(code, _)| code);
+
+    match (status_line.is_empty(), error_code) {
+        (true, _) => bail!("Connection closed by S3 without a response"),
+        (false, Some(code)) => bail!("S3 refused the message: {status_line} ({code})"),
+        (false, None) => bail!("S3 refused the message: {status_line}")
+    }
+}
+
+/// Percent-encode `key` for the request path, as SigV4 wants: everything
+/// but letters, digits, `-._~` and the `/` between path segments.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{b:02X}")
+        })
+        .collect()
+}
+
+/// Return `value` as it can go in a header: printable ASCII, with runs
+/// of spaces collapsed, since that's how S3 signs it.
+fn header_value(value: &str) -> String {
+    let printable: String = value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+        .collect();
+
+    printable.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    match key.len() > block.len() {
+        true => block[..32].copy_from_slice(&Sha256::digest(key)),
+        false => block[..key.len()].copy_from_slice(key)
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize();
+
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(&outer);
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,649 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::Envelope;
+use crate::message::IncomingMessage;
+
+//
+// Sieve scripts
+//
+// The global `sieve_script` is a Sieve (RFC 5228) script that sorts
+// mail like the mailbox rules do, so that a script written for
+// Dovecot's Pigeonhole can be kept when moving to sortmail. Only the
+// filing part of Sieve is supported:
+//
+//     require ["fileinto", "envelope", "copy", "mailbox"];
+//
+//     if header :contains "List-Id" "announce.example.com" {
+//         fileinto "Lists.Announce";
+//         stop;
+//     } elsif anyof (address :domain "from" "example.net",
+//                    size :over 10M) {
+//         fileinto :create "Other";
+//     } else {
+//         keep;
+//     }
+//
+// Commands are `if`/`elsif`/`else`, `fileinto` (with `:copy` and
+// `:create`), `keep`, `discard` and `stop`. Tests are `header`,
+// `address` (with `:all`, `:localpart` and `:domain`), `envelope`
+// (`from` and `to`), `exists`, `size` (`:over` and `:under`, with
+// `K`, `M` and `G` suffixes), `allof`, `anyof`, `not`, `true` and
+// `false`, comparing with `:is` (the default), `:contains` or
+// `:matches` (with `*` and `?` wildcards), case-insensitively unless
+// `:comparator "i;octet"` says otherwise. Anything else, like
+// `vacation` or `redirect`, is an error when the config is loaded,
+// rather than being skipped when mail arrives.
+//
+// As in Sieve, a message is kept unless a `fileinto` (without `:copy`)
+// or `discard` runs. Keeping it means the usual default delivery, so
+// `follow_threads`, `list_folder`, catch-all folders and so on still
+// apply. `fileinto "INBOX"` is the root Maildir, and folder names can
+// start with the `folder_prefix`, like Dovecot's do.
+//
+
+/// When a `sieve_script` runs.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SieveMode {
+    /// In place of the mailbox rules
+    #[default]
+    Instead,
+
+    /// For messages that no mailbox claims, or whose mailboxes all
+    /// have `continue = true`
+    After
+}
+
+/// The Sieve extensions that scripts can `require`.
+const SUPPORTED_EXTENSIONS: [&str; 5] = ["fileinto", "envelope", "copy", "mailbox", "comparator-i;octet"];
+
+#[derive(Debug)]
+pub struct SieveScript {
+    commands: Vec<Command>
+}
+
+/// What a script decided for a message.
+#[derive(Debug, Default)]
+pub struct SieveOutcome {
+    /// Folders to file the message into, and whether each should be
+    /// created if it doesn't exist
+    pub folders: Vec<(String, bool)>,
+
+    /// Whether the message also gets the default delivery
+    pub keep: bool
+}
+
+#[derive(Debug)]
+enum Command {
+    If(Vec<(Test, Vec<Command>)>, Vec<Command>),
+    FileInto { folder: String, copy: bool, create: bool },
+    Keep,
+    Discard,
+    Stop
+}
+
+#[derive(Debug)]
+enum Test {
+    Header(Vec<String>, Comparison),
+    Address(AddressPart, Vec<String>, Comparison),
+    Envelope(AddressPart, Vec<String>, Comparison),
+    Exists(Vec<String>),
+    SizeOver(u64),
+    SizeUnder(u64),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Not(Box<Test>),
+    True,
+    False
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain
+}
+
+#[derive(Debug)]
+enum Comparison {
+    Is(Vec<String>, bool),
+    Contains(Vec<String>, bool),
+    Matches(Vec<Regex>)
+}
+
+impl SieveScript {
+    pub fn from_file(script_file: &Path) -> Result<SieveScript> {
+        let source = std::fs::read_to_string(script_file)
+            .with_context(|| format!("Error opening Sieve script {}", script_file.display()))?;
+
+        SieveScript::parse(&source)
+            .with_context(|| format!("Error parsing Sieve script {}", script_file.display()))
+    }
+
+    fn parse(source: &str) -> Result<SieveScript> {
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+        let commands = parser.parse_commands()?;
+
+        match parser.next() {
+            None => Ok(SieveScript { commands }),
+            Some((token, line)) => bail!("Unexpected {token:?} on line {line}")
+        }
+    }
+
+    /// Run the script for a message.
+    pub fn evaluate(&self, envelope: &Envelope, message: &IncomingMessage) -> SieveOutcome {
+        let mut state = State {
+            folders: Vec::new(),
+            explicit_keep: false,
+            implicit_keep: true
+        };
+
+        run(&self.commands, &mut state, envelope, message);
+
+        SieveOutcome {
+            folders: state.folders,
+            keep: state.explicit_keep || state.implicit_keep
+        }
+    }
+}
+
+//
+// Evaluation
+//
+
+struct State {
+    folders: Vec<(String, bool)>,
+    explicit_keep: bool,
+    implicit_keep: bool
+}
+
+/// Run `commands`, and return true if one of them was `stop`.
+fn run(commands: &[Command], state: &mut State, envelope: &Envelope, message: &IncomingMessage) -> bool {
+    for command in commands {
+        match command {
+            Command::If(branches, otherwise) => {
+                let block = branches
+                    .iter()
+                    .find(|(test, _)| test.evaluate(envelope, message))
+                    .map_or(otherwise, |(_, block)| block);
+
+                if run(block, state, envelope, message) {
+                    return true;
+                }
+            },
+            Command::FileInto { folder, copy, create } => {
+                state.folders.push((folder.clone(), *create));
+                if !copy {
+                    state.implicit_keep = false;
+                }
+            },
+            Command::Keep => state.explicit_keep = true,
+            Command::Discard => state.implicit_keep = false,
+            Command::Stop => return true
+        }
+    }
+
+    false
+}
+
+impl Test {
+    fn evaluate(&self, envelope: &Envelope, message: &IncomingMessage) -> bool {
+        match self {
+            Test::Header(names, comparison) => names
+                .iter()
+                .flat_map(|name| message.header_values(name))
+                .any(|value| comparison.matches(value)),
+            Test::Address(part, names, comparison) => names
+                .iter()
+                .flat_map(|name| message.header_addresses(name))
+                .any(|address| comparison.matches(part.of(&address))),
+            Test::Envelope(part, names, comparison) => names
+                .iter()
+                .filter_map(|name| match name.to_lowercase().as_str() {
+                    "from" => Some(envelope.sender.clone().unwrap_or_default()),
+                    "to" => Some(envelope.recipient.clone()),
+                    _ => None
+                })
+                .any(|address| comparison.matches(part.of(&address))),
+            Test::Exists(names) => names.iter().all(|name| message.header_values(name).next().is_some()),
+            Test::SizeOver(limit) => message.size() > *limit,
+            Test::SizeUnder(limit) => message.size() < *limit,
+            Test::AllOf(tests) => tests.iter().all(|test| test.evaluate(envelope, message)),
+            Test::AnyOf(tests) => tests.iter().any(|test| test.evaluate(envelope, message)),
+            Test::Not(test) => !test.evaluate(envelope, message),
+            Test::True => true,
+            Test::False => false
+        }
+    }
+}
+
+impl AddressPart {
+    fn of<'a>(&self, address: &'a str) -> &'a str {
+        let (local_part, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+
+        match self {
+            AddressPart::All => address,
+            AddressPart::LocalPart => local_part,
+            AddressPart::Domain => domain
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Comparison::Is(keys, true) => keys.iter().any(|key| value == key),
+            Comparison::Is(keys, false) => keys.iter().any(|key| value.to_lowercase() == *key),
+            Comparison::Contains(keys, true) => keys.iter().any(|key| value.contains(key.as_str())),
+            Comparison::Contains(keys, false) => keys.iter().any(|key| value.to_lowercase().contains(key.as_str())),
+            Comparison::Matches(patterns) => patterns.iter().any(|pattern| pattern.is_match(value))
+        }
+    }
+}
+
+/// Turn a Sieve `:matches` pattern into a regular expression.
+fn wildcard_regex(pattern: &str, case_sensitive: bool) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '\\' => regex.push_str(&regex::escape(&chars.next().map(String::from).unwrap_or_default())),
+            other => regex.push_str(&regex::escape(&other.to_string()))
+        }
+    }
+    regex.push('$');
+
+    RegexBuilder::new(&regex)
+        .case_insensitive(!case_sensitive)
+        .dot_matches_new_line(true)
+        .build()
+        .with_context(|| format!("Error parsing :matches pattern {pattern:?}"))
+}
+
+//
+// Tokenizer
+//
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    String(String),
+    Number(u64),
+    Punctuation(char)
+}
+
+/// Return the tokens of `source`, each with its line number.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(&c) = chars.peek() {
+        let start_line = line;
+
+        if c == '\n' {
+            chars.next();
+            line += 1;
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            while chars.next_if(|&c| c != '\n').is_some() {}
+        } else if c == '/' {
+            chars.next();
+            if chars.next() != Some('*') {
+                bail!("Unexpected character '/' on line {line}");
+            }
+
+            let mut previous = '\0';
+            loop {
+                match chars.next() {
+                    Some('/') if previous == '*' => break,
+                    Some(c) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        previous = c;
+                    },
+                    None => bail!("Unterminated comment starting on line {start_line}")
+                }
+            }
+        } else if c == '"' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => value.push(escaped),
+                        None => bail!("Unterminated string starting on line {start_line}")
+                    },
+                    Some('"') => break,
+                    Some(c) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        value.push(c);
+                    },
+                    None => bail!("Unterminated string starting on line {start_line}")
+                }
+            }
+            tokens.push((Token::String(value), start_line));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                number.push(c);
+            }
+
+            let multiplier = match chars.next_if(|c| "KkMmGg".contains(*c)) {
+                Some('K' | 'k') => 1 << 10,
+                Some('M' | 'm') => 1 << 20,
+                Some('G' | 'g') => 1 << 30,
+                _ => 1
+            };
+
+            let value = number
+                .parse::<u64>()
+                .ok()
+                .and_then(|value| value.checked_mul(multiplier))
+                .with_context(|| format!("Invalid number {number:?} on line {line}"))?;
+            tokens.push((Token::Number(value), start_line));
+        } else if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            let is_tag = chars.next_if_eq(&':').is_some();
+            let mut identifier = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                identifier.push(c.to_ascii_lowercase());
+            }
+
+            if identifier == "text" && chars.peek() == Some(&':') {
+                bail!("Multi-line strings (text:) aren't supported, on line {line}");
+            }
+
+            match is_tag {
+                true => tokens.push((Token::Tag(identifier), start_line)),
+                false => tokens.push((Token::Identifier(identifier), start_line))
+            }
+        } else if "[](),;{}".contains(c) {
+            chars.next();
+            tokens.push((Token::Punctuation(c), start_line));
+        } else {
+            bail!("Unexpected character {c:?} on line {line}");
+        }
+    }
+
+    Ok(tokens)
+}
+
+//
+// Parser
+//
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<&(Token, usize)> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    /// Describe where the parser is, for error messages.
+    fn location(&self) -> String {
+        match self.tokens.get(self.position) {
+            Some((token, line)) => format!("{token:?} on line {line}"),
+            None => "end of script".to_string()
+        }
+    }
+
+    /// Return the line the parser is on.
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn next_is(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punctuation(c))
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.next_is(c) {
+            true => {
+                self.position += 1;
+                Ok(())
+            },
+            false => bail!("Expected '{c}', found {}", self.location())
+        }
+    }
+
+    fn parse_commands(&mut self) -> Result<Vec<Command>> {
+        let mut commands = Vec::new();
+
+        while matches!(self.peek(), Some(Token::Identifier(_))) {
+            if let Some(command) = self.parse_command()? {
+                commands.push(command);
+            }
+        }
+
+        Ok(commands)
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Command>> {
+        self.expect('{')?;
+        let commands = self.parse_commands()?;
+        self.expect('}')?;
+        Ok(commands)
+    }
+
+    /// Parse a command, or return None for one, like `require`, that
+    /// does nothing when the script runs.
+    fn parse_command(&mut self) -> Result<Option<Command>> {
+        let (location, line) = (self.location(), self.line());
+        let Some((Token::Identifier(name), _)) = self.next() else {
+            bail!("Expected a command, found {location}");
+        };
+        let name = name.clone();
+
+        let command = match name.as_str() {
+            "require" => {
+                for extension in self.parse_string_list()? {
+                    if !SUPPORTED_EXTENSIONS.contains(&extension.to_lowercase().as_str()) {
+                        bail!("Unsupported Sieve extension {extension:?}, on line {line}");
+                    }
+                }
+                self.expect(';')?;
+                return Ok(None);
+            },
+            "if" => {
+                let mut branches = vec![(self.parse_test()?, self.parse_block()?)];
+                let mut otherwise = Vec::new();
+
+                loop {
+                    match self.peek() {
+                        Some(Token::Identifier(name)) if name == "elsif" => {
+                            self.position += 1;
+                            branches.push((self.parse_test()?, self.parse_block()?));
+                        },
+                        Some(Token::Identifier(name)) if name == "else" => {
+                            self.position += 1;
+                            otherwise = self.parse_block()?;
+                            break;
+                        },
+                        _ => break
+                    }
+                }
+
+                return Ok(Some(Command::If(branches, otherwise)));
+            },
+            "fileinto" => {
+                let (mut copy, mut create) = (false, false);
+                while let Some(Token::Tag(tag)) = self.peek() {
+                    match tag.as_str() {
+                        "copy" => copy = true,
+                        "create" => create = true,
+                        _ => bail!("Unsupported fileinto argument :{tag}, on line {}", self.line())
+                    }
+                    self.position += 1;
+                }
+
+                let folder = self.parse_string()?;
+                Command::FileInto { folder, copy, create }
+            },
+            "keep" => Command::Keep,
+            "discard" => Command::Discard,
+            "stop" => Command::Stop,
+            other => bail!("Unsupported Sieve command {other:?}, on line {line}")
+        };
+
+        self.expect(';')?;
+        Ok(Some(command))
+    }
+
+    fn parse_test(&mut self) -> Result<Test> {
+        let (location, line) = (self.location(), self.line());
+        let Some((Token::Identifier(name), _)) = self.next() else {
+            bail!("Expected a test, found {location}");
+        };
+        let name = name.clone();
+
+        let test = match name.as_str() {
+            "header" => {
+                let (part, case_sensitive, match_type) = self.parse_tags(false)?;
+                if part.is_some() {
+                    bail!("header tests don't take an address part, on line {line}");
+                }
+                let names = self.parse_string_list()?;
+                Test::Header(names, self.parse_comparison(match_type, case_sensitive)?)
+            },
+            "address" | "envelope" => {
+                let (part, case_sensitive, match_type) = self.parse_tags(true)?;
+                let part = part.unwrap_or(AddressPart::All);
+                let names = self.parse_string_list()?;
+                let comparison = self.parse_comparison(match_type, case_sensitive)?;
+                match name.as_str() {
+                    "address" => Test::Address(part, names, comparison),
+                    _ => Test::Envelope(part, names, comparison)
+                }
+            },
+            "exists" => Test::Exists(self.parse_string_list()?),
+            "size" => {
+                let over = match self.next() {
+                    Some((Token::Tag(tag), _)) if tag == "over" => true,
+                    Some((Token::Tag(tag), _)) if tag == "under" => false,
+                    _ => bail!("Expected :over or :under after size, on line {line}")
+                };
+                let limit = match self.next() {
+                    Some((Token::Number(limit), _)) => *limit,
+                    _ => bail!("Expected a number for size, on line {line}")
+                };
+                match over {
+                    true => Test::SizeOver(limit),
+                    false => Test::SizeUnder(limit)
+                }
+            },
+            "allof" | "anyof" => {
+                self.expect('(')?;
+                let mut tests = vec![self.parse_test()?];
+                while self.next_is(',') {
+                    self.position += 1;
+                    tests.push(self.parse_test()?);
+                }
+                self.expect(')')?;
+                match name.as_str() {
+                    "allof" => Test::AllOf(tests),
+                    _ => Test::AnyOf(tests)
+                }
+            },
+            "not" => Test::Not(Box::new(self.parse_test()?)),
+            "true" => Test::True,
+            "false" => Test::False,
+            other => bail!("Unsupported Sieve test {other:?}, on line {line}")
+        };
+
+        Ok(test)
+    }
+
+    /// Parse the tagged arguments of a `header`, `address` or
+    /// `envelope` test: the address part (if `address_part` allows
+    /// one), whether the comparator is case-sensitive, and the match
+    /// type.
+    fn parse_tags(&mut self, address_part: bool) -> Result<(Option<AddressPart>, bool, String)> {
+        let (mut part, mut case_sensitive, mut match_type) = (None, false, "is".to_string());
+
+        while let Some(Token::Tag(tag)) = self.peek() {
+            let tag = tag.clone();
+            self.position += 1;
+
+            match tag.as_str() {
+                "is" | "contains" | "matches" => match_type = tag,
+                "all" if address_part => part = Some(AddressPart::All),
+                "localpart" if address_part => part = Some(AddressPart::LocalPart),
+                "domain" if address_part => part = Some(AddressPart::Domain),
+                "comparator" => case_sensitive = match self.parse_string()?.to_lowercase().as_str() {
+                    "i;octet" => true,
+                    "i;ascii-casemap" => false,
+                    other => bail!("Unsupported comparator {other:?}")
+                },
+                _ => bail!("Unsupported argument :{tag}, on line {}", self.line())
+            }
+        }
+
+        Ok((part, case_sensitive, match_type))
+    }
+
+    fn parse_comparison(&mut self, match_type: String, case_sensitive: bool) -> Result<Comparison> {
+        let keys = self.parse_string_list()?;
+        let fold = |keys: Vec<String>| match case_sensitive {
+            true => keys,
+            false => keys.iter().map(|key| key.to_lowercase()).collect()
+        };
+
+        Ok(match match_type.as_str() {
+            "contains" => Comparison::Contains(fold(keys), case_sensitive),
+            "matches" => Comparison::Matches(keys
+                .iter()
+                .map(|key| wildcard_regex(key, case_sensitive))
+                .collect::<Result<_>>()?),
+            _ => Comparison::Is(fold(keys), case_sensitive)
+        })
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let location = self.location();
+        match self.next() {
+            Some((Token::String(value), _)) => Ok(value.clone()),
+            _ => Err(anyhow!("Expected a string, found {location}"))
+        }
+    }
+
+    /// Parse a string, or a list of strings in brackets.
+    fn parse_string_list(&mut self) -> Result<Vec<String>> {
+        if !self.next_is('[') {
+            return Ok(vec![self.parse_string()?]);
+        }
+
+        self.position += 1;
+        let mut strings = vec![self.parse_string()?];
+        while self.next_is(',') {
+            self.position += 1;
+            strings.push(self.parse_string()?);
+        }
+        self.expect(']')?;
+
+        Ok(strings)
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::tls::{self, Security, Stream};
+
+//
+// SMTP client
+//
+// Just enough SMTP (RFC 5321) to hand a message to a smarthost for
+// forwarding: EHLO, optionally STARTTLS (RFC 3207) and AUTH PLAIN, then
+// one MAIL FROM, one RCPT TO and DATA. The connection is plain text
+// unless `smarthost_security` says `tls` (implicit TLS, usually on port
+// 465) or `starttls` (usually on port 587), which is fine for a relay
+// we trust the network path to, such as the local MTA; credentials are
+// never sent over a plain connection.
+//
+
+/// Where forwarded mail is relayed through, from the global
+/// `smarthost` options.
+#[derive(Debug)]
+pub struct Smarthost {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout: Duration
+}
+
+/// An open connection to the smarthost.
+struct Session {
+    stream: BufReader<Stream>
+}
+
+impl Smarthost {
+    /// Relay `message` to `recipient`, with `sender` as the envelope
+    /// sender (the null sender if None). The message's line endings
+    /// are converted to CRLF and lines starting with `.` are escaped.
+    pub fn send(&self, sender: Option<&str>, recipient: &str, message: &[u8]) -> Result<()> {
+        let mut session = self.connect()
+            .with_context(|| format!("Error connecting to smarthost {}:{}", self.host, self.port))?;
+
+        if let Some(ref username) = self.username {
+            if let Stream::Plain(_) = session.stream.get_ref() {
+                bail!("Not sending smarthost credentials over a connection without TLS");
+            }
+
+            let credentials = format!("\0{username}\0{}", self.password.as_deref().unwrap_or_default());
+            session.command(&format!("AUTH PLAIN {}", base64_encode(credentials.as_bytes())), 235)
+                .context("Smarthost refused authentication")?;
+        }
+
+        session.command(&format!("MAIL FROM:<{}>", sender.unwrap_or_default()), 250)
+            .context("Smarthost refused the sender")?;
+        session.command(&format!("RCPT TO:<{recipient}>"), 250)
+            .context("Smarthost refused the recipient")?;
+        session.command("DATA", 354).context("Smarthost refused DATA")?;
+
+        session.stream.get_mut().write_all(&dot_stuffed(message))
+            .and_then(|_| session.stream.get_mut().write_all(b".\r\n"))
+            .context("Error sending message to smarthost")?;
+        session.expect(250).context("Smarthost refused the message")?;
+
+        // The message is accepted at this point, so a failed QUIT
+        // doesn't matter
+        let _ = session.command("QUIT", 221);
+
+        Ok(())
+    }
+
+    /// Connect and greet the smarthost, starting TLS if it's wanted.
+    fn connect(&self) -> Result<Session> {
+        let tcp_stream = self.connect_tcp()?;
+
+        let mut session = match self.security {
+            Security::Tls => Session::new(tls::start_tls(&self.host, tcp_stream)?),
+            Security::StartTls | Security::None => Session::new(Stream::Plain(tcp_stream))
+        };
+
+        session.expect(220).context("Smarthost refused the connection")?;
+
+        let ehlo = format!("EHLO {}", local_hostname());
+        session.command(&ehlo, 250).context("Smarthost refused EHLO")?;
+
+        if self.security == Security::StartTls {
+            session.command("STARTTLS", 220).context("Smarthost refused STARTTLS")?;
+
+            let Stream::Plain(tcp_stream) = session.stream.into_inner() else {
+                bail!("Connection is already using TLS");
+            };
+            session = Session::new(tls::start_tls(&self.host, tcp_stream)?);
+
+            // What the smarthost said before TLS doesn't count
+            session.command(&ehlo, 250).context("Smarthost refused EHLO")?;
+        }
+
+        Ok(session)
+    }
+
+    fn connect_tcp(&self) -> Result<TcpStream> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?;
+
+        let mut last_error = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                },
+                Err(error) => last_error = Some(error)
+            }
+        }
+
+        match last_error {
+            Some(error) => Err(error.into()),
+            None => bail!("No addresses found for {}", self.host)
+        }
+    }
+}
+
+impl Session {
+    fn new(stream: Stream) -> Session {
+        Session {
+            stream: BufReader::new(stream)
+        }
+    }
+
+    /// Send a command line and check the reply code.
+    fn command(&mut self, command: &str, expected_code: u16) -> Result<()> {
+        self.stream.get_mut().write_all(format!("{command}\r\n").as_bytes())?;
+        self.expect(expected_code)
+    }
+
+    /// Read a (possibly multi-line) reply and fail unless it has
+    /// `expected_code`.
+    fn expect(&mut self, expected_code: u16) -> Result<()> {
+        let mut reply = String::new();
+
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                bail!("Connection closed by smarthost");
+            }
+
+            reply.push_str(line.trim_end());
+
+            // `250-...` continues the reply, `250 ...` ends it
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+
+            reply.push(' ');
+        }
+
+        match reply.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if code == expected_code => Ok(()),
+            _ => bail!("Unexpected reply {reply:?}")
+        }
+    }
+}
+
+/// Return the message with CRLF line endings, a `.` added to lines
+/// that start with one, and a final line ending if it had none.
+fn dot_stuffed(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len() + message.len() / 32);
+
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if line.starts_with(b".") {
+            stuffed.push(b'.');
+        }
+
+        stuffed.extend_from_slice(line);
+        stuffed.extend_from_slice(b"\r\n");
+    }
+
+    stuffed
+}
+
+/// Return this machine's hostname, to greet the smarthost with.
+pub fn local_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Encode `bytes` as base64 (RFC 4648), with padding.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('=')
+            }
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::compress;
+use crate::message::{self, IncomingMessage};
+use crate::process::wait_with_timeout;
+
+//
+// Spam filter training
+//
+// With the global `spam_learner` and `junk_folder`, the spam filter
+// learns from where mail ends up: a message a rule stores in the Junk
+// folder is fed to `sa-learn --spam` or `rspamc learn_spam` as it's
+// delivered, and `sortmail learn`, run from cron, catches up with what
+// the user has sorted by hand since, learning messages moved into Junk
+// as spam, and messages that were learned as spam but have since been
+// moved out of it (rescued) as ham.
+//
+// What's been learned is kept in a file in the root Maildir, with a
+// `spam <key>` or `ham <key>` line per message learned, the key being
+// its Message-ID, or `sha256:<hex>` of its contents if it has none;
+// the last line for a key is the one that counts. Rescued messages are
+// found by their Message-ID, so a message without one is only ever
+// learned as spam.
+//
+
+/// Read this much of a message, at most, to find its Message-ID.
+const HEADER_READ_LIMIT: u64 = 64 * 1024;
+
+/// The spam filter that learns from sorted mail.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpamLearner {
+    /// SpamAssassin's `sa-learn`
+    SaLearn,
+
+    /// Rspamd's `rspamc`
+    Rspamc
+}
+
+/// What a message is learned as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Spam,
+    Ham
+}
+
+impl SpamLearner {
+    /// Feed the message `bytes` to the spam filter as `verdict`, and
+    /// fail unless it succeeds. The command is killed if it runs longer
+    /// than `timeout`.
+    pub fn learn(self, verdict: Verdict, bytes: &[u8], timeout: Duration) -> Result<()> {
+        let (program, args): (&str, &[&str]) = match (self, verdict) {
+            (SpamLearner::SaLearn, Verdict::Spam) => ("sa-learn", &["--spam", "-"]),
+            (SpamLearner::SaLearn, Verdict::Ham) => ("sa-learn", &["--ham", "-"]),
+            (SpamLearner::Rspamc, Verdict::Spam) => ("rspamc", &["learn_spam"]),
+            (SpamLearner::Rspamc, Verdict::Ham) => ("rspamc", &["learn_ham"])
+        };
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Error running {program}"))?;
+
+        // Write the message on another thread, so that a learner that
+        // doesn't read all of its input can't block us past the timeout;
+        // one that exits early just gets a broken pipe
+        let mut stdin = child.stdin.take().with_context(|| format!("Error writing to {program}"))?;
+        let bytes = bytes.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&bytes);
+        });
+
+        let status = wait_with_timeout(&mut child, timeout)
+            .with_context(|| format!("{program} didn't finish"))?;
+
+        if !status.success() {
+            bail!("{program} failed ({status})");
+        }
+
+        Ok(())
+    }
+}
+
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Spam => "spam",
+            Verdict::Ham => "ham"
+        }
+    }
+}
+
+/// The file of messages already learned, and what they were learned as.
+pub struct LearnedLog {
+    path: PathBuf,
+    verdicts: HashMap<String, Verdict>
+}
+
+impl LearnedLog {
+    /// Load the log at `path`, which needn't exist yet.
+    pub fn open(path: &Path) -> Result<LearnedLog> {
+        let mut contents = String::new();
+
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .with_context(|| format!("Error reading {}", path.display()))?;
+            },
+            Err(error) if error.kind() == ErrorKind::NotFound => {},
+            Err(error) => return Err(error).with_context(|| format!("Error opening {}", path.display()))
+        }
+
+        let verdicts = contents
+            .lines()
+            .filter_map(|line| match line.split_once(' ')? {
+                ("spam", key) => Some((key.to_string(), Verdict::Spam)),
+                ("ham", key) => Some((key.to_string(), Verdict::Ham)),
+                _ => None
+            })
+            .collect();
+
+        Ok(LearnedLog {
+            path: path.to_path_buf(),
+            verdicts
+        })
+    }
+
+    /// Return what the message with `key` was last learned as, if it
+    /// has been.
+    pub fn verdict(&self, key: &str) -> Option<Verdict> {
+        self.verdicts.get(key).copied()
+    }
+
+    /// Record that the message with `key` was learned as `verdict`.
+    pub fn record(&mut self, key: &str, verdict: Verdict) -> Result<()> {
+        record(&self.path, key, verdict)?;
+        self.verdicts.insert(key.to_string(), verdict);
+        Ok(())
+    }
+}
+
+/// Add a line to the log at `path` saying the message with `key` was
+/// learned as `verdict`. Each line is a single append, so deliveries
+/// can record messages at the same time.
+pub fn record(path: &Path, key: &str, verdict: Verdict) -> Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{} {key}\n", verdict.as_str()).as_bytes()))
+        .with_context(|| format!("Error updating {}", path.display()))
+}
+
+/// Return the key that `message` is recorded under.
+pub fn message_key(message: &IncomingMessage) -> String {
+    message.message_id().unwrap_or_else(|| format!("sha256:{}", message.content_hash()))
+}
+
+/// Return the paths of the messages in the `cur` and `new` directories
+/// of the Maildir folder at `folder_path`.
+pub fn message_paths(folder_path: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = ["cur", "new"]
+        .into_iter()
+        .filter_map(|subdir| std::fs::read_dir(folder_path.join(subdir)).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .map(|entry| entry.path())
+        .collect();
+
+    paths.sort();
+    paths
+}
+
+/// Return the Message-ID of the message at `path`, reading only its
+/// header (decompressed, if it's stored compressed), or None if it
+/// can't be read or doesn't have one.
+pub fn read_message_id(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    compress::open_message(path)
+        .ok()?
+        .take(HEADER_READ_LIMIT)
+        .read_to_end(&mut bytes)
+        .ok()?;
+
+    let (_, body) = message::split_header_fields(&bytes);
+    let header_len = bytes.len() - body.len();
+    bytes.truncate(header_len);
+    bytes.extend_from_slice(b"\n");
+
+    IncomingMessage::from_bytes(bytes.into_boxed_slice()).ok()?.message_id()
+}
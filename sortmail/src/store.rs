@@ -0,0 +1,308 @@
+use std::fs::{File, Metadata, OpenOptions, Permissions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::compress::{self, Compression};
+use crate::smtp::local_hostname;
+
+//
+// Maildir delivery
+//
+// Messages are written to the folder's `tmp` directory and then renamed
+// into `new` (or `cur`, with info flags), so that mail readers never see
+// half a message. Filenames follow the scheme Dovecot and Courier use,
+//
+//     <secs>.#<counter>M<nanos>P<pid>V<dev>I<ino>.<hostname>,S=<size>
+//
+// where `,S=` is the file's size, which lets Dovecot and quota
+// calculations skip a stat(2) per message. With `rfc822_size`, `,W=` is
+// added too: the message's size with CRLF line endings, which is what
+// IMAP reports, so Dovecot doesn't have to read the file to find it.
+//
+// When the same message goes to several folders, the copies after the
+// first are hardlinks to it where possible, so it's only stored once.
+//
+// With `compression`, the file holds the message compressed (see the
+// `compress` module), but `,S=` and `,W=` are still its uncompressed
+// sizes.
+//
+// The message's data is always flushed to disk before it's renamed into
+// place. With `durable`, the rename itself is flushed too, by syncing
+// the directory, so that the message is on disk before the MTA is told
+// it's been delivered and a crash can't lose it.
+//
+// Message files and the directories created for folders get the
+// process's umask, unless `file_mode` and `directory_mode` give them
+// permissions of their own.
+//
+
+/// Options for how messages are written.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StoreOptions {
+    /// Add `,W=<size>` to filenames
+    pub rfc822_size: bool,
+
+    /// Sync the directory a message is stored in, as well as the
+    /// message, before reporting it stored
+    pub durable: bool,
+
+    /// Compress messages
+    pub compression: Option<Compression>,
+
+    /// Permissions for new message files and folder directories,
+    /// instead of those the umask leaves
+    pub file_mode: Option<u32>,
+    pub directory_mode: Option<u32>
+}
+
+/// Makes filenames unique between messages stored by this process in
+/// the same nanosecond.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Store `bytes` in the Maildir at `folder_path`: in `new`, or in `cur`
+/// with info flags `flags` (which may be empty) if given. Returns the
+/// path of the stored message.
+pub fn store(folder_path: &Path, flags: Option<&str>, bytes: &[u8], options: &StoreOptions) -> Result<PathBuf> {
+    let contents = compress::compress(bytes, options.compression.as_ref())?;
+    write_message(folder_path, flags, &contents, &size_fields(bytes, options), options)
+}
+
+/// Write the message file `contents` into the Maildir at `folder_path`
+/// through its `tmp` directory, naming it with the `,S=` and `,W=`
+/// fields in `sizes`.
+fn write_message(folder_path: &Path, flags: Option<&str>, contents: &[u8], sizes: &str, options: &StoreOptions) -> Result<PathBuf> {
+    let (temp_path, mut file, unique) = loop {
+        let unique = unique_name();
+        let temp_path = folder_path.join("tmp").join(format!("{unique}.{}", maildir_hostname()));
+
+        match OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => break (temp_path, file, unique),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error).with_context(|| format!("Error creating {}", temp_path.display()))
+        }
+    };
+
+    let stored = (|| {
+        set_file_mode(&file, options.file_mode)
+            .with_context(|| format!("Error changing permissions of {}", temp_path.display()))?;
+
+        file.write_all(contents)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Error writing {}", temp_path.display()))?;
+
+        let metadata = file.metadata()
+            .with_context(|| format!("Error reading {}", temp_path.display()))?;
+
+        let path = message_path(folder_path, &unique, &metadata, flags, sizes);
+
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("Error moving message to {}", path.display()))?;
+
+        if options.durable {
+            sync_parent_dir(&path)?;
+        }
+
+        Ok(path)
+    })();
+
+    if stored.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    stored
+}
+
+/// Store `bytes`, which are the same as the message already stored at
+/// `existing_path`, by hardlinking that message into the Maildir at
+/// `folder_path` (with its own name and `flags`), so that the copies
+/// share their disk space. If the link can't be made, as when the
+/// folders are on different filesystems, a full copy is written
+/// instead.
+pub fn store_linked(existing_path: &Path, folder_path: &Path, flags: Option<&str>, bytes: &[u8], options: &StoreOptions) -> Result<PathBuf> {
+    let Ok(path) = hard_link_message(existing_path, folder_path, flags, &size_fields(bytes, options)) else {
+        return store(folder_path, flags, bytes, options);
+    };
+
+    if options.durable {
+        sync_parent_dir(&path)?;
+    }
+
+    Ok(path)
+}
+
+/// Store the message file at `existing_path` in the Maildir at
+/// `folder_path` as it is, compressed or not, by hardlinking it or, if
+/// the link can't be made, copying it. Its new name keeps the `,S=` and
+/// `,W=` fields of its old one, which are the message's own sizes even
+/// if the file is compressed.
+pub fn store_file(existing_path: &Path, folder_path: &Path, flags: Option<&str>, options: &StoreOptions) -> Result<PathBuf> {
+    let existing_name = existing_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let mut sizes = name_size_fields(existing_name);
+
+    // A file named by something else may not have them
+    if !sizes.contains(",S=") {
+        let bytes = compress::read_message(existing_path)?;
+        sizes = size_fields(&bytes, options);
+    }
+
+    let path = match hard_link_message(existing_path, folder_path, flags, &sizes) {
+        Ok(path) => path,
+        Err(_) => {
+            let contents = std::fs::read(existing_path)
+                .with_context(|| format!("Error reading {}", existing_path.display()))?;
+            return write_message(folder_path, flags, &contents, &sizes, options);
+        }
+    };
+
+    if options.durable {
+        sync_parent_dir(&path)?;
+    }
+
+    Ok(path)
+}
+
+fn hard_link_message(existing_path: &Path, folder_path: &Path, flags: Option<&str>, sizes: &str) -> std::io::Result<PathBuf> {
+    let metadata = std::fs::metadata(existing_path)?;
+
+    loop {
+        let path = message_path(folder_path, &unique_name(), &metadata, flags, sizes);
+
+        match std::fs::hard_link(existing_path, &path) {
+            Ok(()) => return Ok(path),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error)
+        }
+    }
+}
+
+/// Return a new `<secs>.#<counter>M<nanos>P<pid>` unique part for a
+/// filename.
+fn unique_name() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let counter = COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    format!("{}.#{counter:x}M{}P{}", now.as_secs(), now.subsec_nanos(), std::process::id())
+}
+
+/// Return the path in `new`, or in `cur` if there are `flags`, for a
+/// message file with `metadata` and the size fields `sizes`.
+fn message_path(folder_path: &Path, unique: &str, metadata: &Metadata, flags: Option<&str>, sizes: &str) -> PathBuf {
+    folder_path.join(message_name(&format!("{unique}V{}I{}", metadata.dev(), metadata.ino()), flags, sizes))
+}
+
+/// Return a new filename, under `new` or (with `flags`) `cur`, for a
+/// message stored on another machine, whose device and inode numbers
+/// we can't know, so the filename leaves them out.
+pub fn remote_message_name(flags: Option<&str>, bytes: &[u8], options: &StoreOptions) -> PathBuf {
+    message_name(&unique_name(), flags, &size_fields(bytes, options))
+}
+
+fn message_name(unique: &str, flags: Option<&str>, sizes: &str) -> PathBuf {
+    let name = format!("{unique}.{}{sizes}", maildir_hostname());
+
+    match flags {
+        Some(flags) => Path::new("cur").join(format!("{name}:2,{flags}")),
+        None => Path::new("new").join(name)
+    }
+}
+
+/// Return the `,S=` (and with `rfc822_size`, `,W=`) fields of a filename
+/// for the message `bytes`.
+fn size_fields(bytes: &[u8], options: &StoreOptions) -> String {
+    let mut sizes = format!(",S={}", bytes.len());
+
+    if options.rfc822_size {
+        sizes.push_str(&format!(",W={}", rfc822_size(bytes)));
+    }
+
+    sizes
+}
+
+/// Return the `,S=` and `,W=` fields of the message filename `name`.
+fn name_size_fields(name: &str) -> String {
+    let base = name.split_once(':').map_or(name, |(base, _)| base);
+
+    base.split(',')
+        .skip(1)
+        .filter(|field| field.starts_with("S=") || field.starts_with("W="))
+        .map(|field| format!(",{field}"))
+        .collect()
+}
+
+/// Create the Maildir folder at `folder_path`, with its `cur`, `new` and
+/// `tmp` directories, and any missing parents.
+pub fn create_folder(folder_path: &Path, options: &StoreOptions) -> Result<()> {
+    for directory in [folder_path.to_path_buf(), folder_path.join("cur"), folder_path.join("new"), folder_path.join("tmp")] {
+        create_dir_all(&directory, options.directory_mode)?;
+    }
+
+    Ok(())
+}
+
+/// Create the directory at `path` and any missing parents, giving each
+/// directory created `mode`, if there is one.
+pub fn create_dir_all(path: &Path, mode: Option<u32>) -> Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        create_dir_all(parent, mode)?;
+    }
+
+    match std::fs::create_dir(path) {
+        Ok(()) => {},
+        // Another delivery created it first
+        Err(error) if error.kind() == ErrorKind::AlreadyExists && path.is_dir() => return Ok(()),
+        Err(error) => return Err(error).with_context(|| format!("Error creating {}", path.display()))
+    }
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))
+            .with_context(|| format!("Error changing permissions of {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Give the newly created `file` the permissions `mode`, if there is
+/// one, whatever the umask.
+pub fn set_file_mode(file: &File, mode: Option<u32>) -> std::io::Result<()> {
+    match mode {
+        Some(mode) => file.set_permissions(Permissions::from_mode(mode)),
+        None => Ok(())
+    }
+}
+
+/// Flush the directory entry for `path` to disk, by syncing the
+/// directory it's in.
+pub fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Error syncing directory {}", dir.display()))
+}
+
+/// Return the size of a message with every line ending as CRLF.
+fn rfc822_size(bytes: &[u8]) -> usize {
+    let bare_line_feeds = bytes
+        .iter()
+        .enumerate()
+        .filter(|&(i, &b)| b == b'\n' && (i == 0 || bytes[i - 1] != b'\r'))
+        .count();
+
+    bytes.len() + bare_line_feeds
+}
+
+/// Return the hostname for filenames, with `/` and `:` (which can't
+/// appear in a Maildir filename's unique part) escaped as Dovecot and
+/// Courier do.
+fn maildir_hostname() -> String {
+    local_hostname().replace('/', "\\057").replace(':', "\\072")
+}
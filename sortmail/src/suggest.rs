@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::folders::{self, Namespace};
+use crate::message::IncomingMessage;
+
+//
+// Rule suggestions
+//
+// Working backwards from mail that's already been sorted by hand: for
+// each folder, the senders, mailing lists and recipients that turn up
+// often in it, and (almost) only in it, are suggested as that folder's
+// `from_addresses`, `list_ids` and `addresses`.
+//
+
+/// Only this many messages are read from each folder.
+const MAX_MESSAGES_PER_FOLDER: usize = 5000;
+
+/// A value is only suggested for a folder if at least this share of
+/// the messages it appears in are in that folder.
+const MIN_SPECIFICITY: f64 = 0.9;
+
+/// At most this many values are suggested for each key.
+const MAX_SUGGESTIONS_PER_KEY: usize = 20;
+
+/// Finds the values of one config key in a message.
+type ValuesInMessage = fn(&IncomingMessage) -> Vec<String>;
+
+/// The config keys that suggestions are made for, and how to find the
+/// values for each in a message.
+const KEYS: [(&str, ValuesInMessage); 3] = [
+    ("from_addresses", |message| message.header_addresses("From")),
+    ("list_ids", |message| message.list_id().into_iter().collect()),
+    ("addresses", envelope_recipients)
+];
+
+/// Return TOML for a mailbox table for each folder of the Maildir at
+/// `root_maildir` that has something worth suggesting. Values must
+/// appear in at least `min_messages` of a folder's messages.
+pub fn suggest(root_maildir: &Path, namespace: &Namespace, ignore_folders: &[String], min_messages: usize) -> Result<String> {
+    // (key, value) -> folder -> number of messages
+    let mut counts: HashMap<(&str, String), HashMap<String, usize>> = HashMap::new();
+    let mut folder_message_counts = Vec::new();
+
+    for (mailbox_name, folder_path) in folders::list(root_maildir, namespace, ignore_folders)? {
+        let mut message_count = 0;
+
+        for message in folders::messages(&folder_path, MAX_MESSAGES_PER_FOLDER) {
+            message_count += 1;
+
+            for (key, values) in KEYS {
+                let mut values = values(&message);
+                values.sort();
+                values.dedup();
+
+                for value in values {
+                    *counts
+                        .entry((key, value))
+                        .or_default()
+                        .entry(mailbox_name.clone())
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        folder_message_counts.push((mailbox_name, message_count));
+    }
+
+    let mut toml = String::new();
+
+    for (mailbox_name, message_count) in folder_message_counts {
+        let mut table = String::new();
+
+        for (key, _) in KEYS {
+            let mut suggestions: Vec<_> = counts
+                .iter()
+                .filter(|((value_key, _), _)| *value_key == key)
+                .filter_map(|((_, value), folder_counts)| {
+                    let count = folder_counts.get(&mailbox_name).copied().unwrap_or_default();
+                    let total: usize = folder_counts.values().sum();
+
+                    match count >= min_messages && count as f64 / total as f64 >= MIN_SPECIFICITY {
+                        true => Some((value, count)),
+                        false => None
+                    }
+                })
+                .collect();
+
+            if suggestions.is_empty() {
+                continue;
+            }
+
+            suggestions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            suggestions.truncate(MAX_SUGGESTIONS_PER_KEY);
+
+            table.push_str(&format!("{key} = [\n"));
+            for (value, count) in suggestions {
+                table.push_str(&format!("    {},  # {count}\n", toml::Value::String(value.clone())));
+            }
+            table.push_str("]\n");
+        }
+
+        if !table.is_empty() {
+            toml.push_str(&format!("[{}]\n", toml_key(&mailbox_name)));
+            toml.push_str(&format!("# from {message_count} messages\n"));
+            toml.push_str(&table);
+            toml.push('\n');
+        }
+    }
+
+    Ok(toml)
+}
+
+/// Return the addresses the message was delivered to, as recorded by
+/// the MTA in `Delivered-To:` or `X-Original-To:`. (`To:` and `Cc:`
+/// aren't used, since they often name a list rather than us.)
+fn envelope_recipients(message: &IncomingMessage) -> Vec<String> {
+    ["Delivered-To", "X-Original-To"]
+        .into_iter()
+        .flat_map(|header_name| message.header_addresses(header_name))
+        .collect()
+}
+
+/// Quote a table name for TOML, unless it's a valid bare key.
+fn toml_key(name: &str) -> String {
+    let is_bare = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match is_bare {
+        true => name.to_string(),
+        false => toml::Value::String(name.to_string()).to_string()
+    }
+}
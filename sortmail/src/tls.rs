@@ -0,0 +1,73 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use anyhow::{anyhow, Context, Result};
+use native_tls::{TlsConnector, TlsStream};
+use serde::Deserialize;
+
+//
+// TLS connections
+//
+// The IMAP and SMTP clients both talk to servers that may want TLS from
+// the start (implicit TLS, as on ports 993 and 465) or an upgrade with
+// STARTTLS partway through, and share the connection type here, which
+// is either. Certificates are checked against the system's trusted
+// roots and the server's hostname, with native-tls.
+//
+
+/// How a connection to a server is secured.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// TLS from the start
+    #[default]
+    Tls,
+
+    /// Plain text, upgraded with STARTTLS
+    StartTls,
+
+    /// Plain text throughout, for a server on a trusted network
+    None
+}
+
+/// A connection to a server, encrypted or not.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>)
+}
+
+/// Start TLS on `tcp_stream`, checking that the server's certificate is
+/// for `host`.
+pub fn start_tls(host: &str, tcp_stream: TcpStream) -> Result<Stream> {
+    let stream = TlsConnector::new()
+        .context("Error setting up TLS")?
+        .connect(host, tcp_stream)
+        .map_err(|error| anyhow!("TLS error: {error}"))?;
+
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush()
+        }
+    }
+}
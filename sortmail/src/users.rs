@@ -0,0 +1,220 @@
+use std::env;
+use std::ffi::{CStr, CString, OsStr};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::store;
+
+//
+// Local users
+//
+// For multi-user delivery, where sortmail runs as root as the system's
+// local delivery agent: recipients are looked up in the password
+// database, and each user's mail is delivered by a child process that
+// has permanently switched to that user, so that their Maildir, config
+// file and `pipe` commands are only ever touched with their own
+// privileges.
+//
+// The one thing done for a user while still root is creating their
+// root Maildir, when it doesn't exist yet and they couldn't create it
+// themselves, as with a `root_maildir` under `/var/mail`. It's then
+// handed over to the user and their group. To make sure that can't be
+// turned against another file, the directory it's made in has to be
+// root's and not writable by anyone else (or sticky, like `/tmp`),
+// and the new directories are only handed over once they're all made.
+//
+
+/// A user from the password database.
+#[derive(Debug)]
+pub struct LocalUser {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: PathBuf
+}
+
+/// Which side of a fork we're on.
+pub enum Forked {
+    Child,
+    Parent(libc::pid_t)
+}
+
+/// Size of the first buffer tried for getpwnam_r's strings, which is
+/// doubled until they fit.
+const INITIAL_BUFFER_SIZE: usize = 1024;
+
+/// Return true if we're running as root.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Look up `name` in the password database, returning None if there's
+/// no such user.
+pub fn lookup(name: &str) -> Result<Option<LocalUser>> {
+    let Ok(c_name) = CString::new(name) else {
+        return Ok(None);
+    };
+
+    let mut buffer: Vec<libc::c_char> = vec![0; INITIAL_BUFFER_SIZE];
+
+    loop {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+        let error = unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+
+        if error != 0 {
+            return Err(std::io::Error::from_raw_os_error(error))
+                .with_context(|| format!("Error looking up user {name}"));
+        }
+
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+
+        return Ok(Some(LocalUser {
+            name: name.to_string(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            home: PathBuf::from(OsStr::from_bytes(home.to_bytes()))
+        }));
+    }
+}
+
+impl LocalUser {
+    /// Permanently switch to this user, with their groups, and set
+    /// HOME, USER and LOGNAME to match.
+    pub fn become_user(&self) -> Result<()> {
+        let c_name = CString::new(self.name.as_str())
+            .with_context(|| format!("Invalid user name {:?}", self.name))?;
+
+        // The groups have to be set while we're still root, so the uid
+        // goes last
+        if unsafe { libc::initgroups(c_name.as_ptr(), self.gid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Error setting groups for user {}", self.name));
+        }
+
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Error switching to group {}", self.gid));
+        }
+
+        if unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Error switching to user {}", self.name));
+        }
+
+        env::set_var("HOME", &self.home);
+        env::set_var("USER", &self.name);
+        env::set_var("LOGNAME", &self.name);
+
+        // Commands run for the user shouldn't start out in whatever
+        // directory the MTA ran us from
+        let _ = env::set_current_dir(&self.home);
+
+        Ok(())
+    }
+
+    /// Create the root Maildir at `path` for this user, if it doesn't
+    /// exist and they can't create it themselves, owned by them and with
+    /// `directory_mode` if there is one. Returns true if it was created.
+    pub fn create_root_maildir(&self, path: &Path, directory_mode: Option<u32>) -> Result<bool> {
+        if std::fs::symlink_metadata(path).is_ok() {
+            return Ok(false);
+        }
+
+        let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) else {
+            return Ok(false);
+        };
+
+        // Anything else is the user's own to sort out
+        let Ok(parent_metadata) = std::fs::symlink_metadata(parent) else {
+            return Ok(false);
+        };
+        if parent_metadata.uid() != 0 {
+            return Ok(false);
+        }
+
+        let parent_mode = parent_metadata.permissions().mode();
+        let is_sticky = parent_mode & 0o1000 != 0;
+        if !parent_metadata.is_dir() || (parent_mode & 0o022 != 0 && !is_sticky) {
+            bail!("Not creating root Maildir {} for user {}: {} isn't a directory only root can write to", path.display(), self.name, parent.display());
+        }
+
+        let directories = [path.to_path_buf(), path.join("cur"), path.join("new"), path.join("tmp")];
+
+        for directory in &directories {
+            store::create_dir_all(directory, directory_mode)?;
+        }
+
+        // The Maildir itself goes last, so the user can't swap anything
+        // into it before we're done
+        for directory in directories.iter().rev() {
+            self.chown(directory)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Give `path` (but not what it links to) to this user and their
+    /// group.
+    fn chown(&self, path: &Path) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("Invalid path {}", path.display()))?;
+
+        if unsafe { libc::lchown(c_path.as_ptr(), self.uid, self.gid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Error giving {} to user {}", path.display(), self.name));
+        }
+
+        Ok(())
+    }
+}
+
+/// Fork the process. Stdout is flushed first, so that output the parent
+/// has buffered isn't printed twice.
+pub fn fork() -> Result<Forked> {
+    let _ = std::io::stdout().flush();
+
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("Error forking delivery process"),
+        0 => Ok(Forked::Child),
+        pid => Ok(Forked::Parent(pid))
+    }
+}
+
+/// Wait for the child process `pid` to finish, and return its exit
+/// code, or None if it was killed by a signal.
+pub fn wait(pid: libc::pid_t) -> Result<Option<i32>> {
+    let mut status = 0;
+
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            break;
+        }
+
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error).context("Error waiting for delivery process");
+        }
+    }
+
+    Ok(match libc::WIFEXITED(status) {
+        true => Some(libc::WEXITSTATUS(status)),
+        false => None
+    })
+}
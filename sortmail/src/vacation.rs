@@ -0,0 +1,231 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::Envelope;
+use crate::address;
+use crate::dates;
+use crate::message::IncomingMessage;
+use crate::smtp::{base64_encode, local_hostname};
+
+//
+// Vacation responder
+//
+// With the global `vacation_file`, each sender of mail that's delivered
+// is sent that file as an automatic reply (RFC 3834), through the
+// smarthost, at most once every `vacation_days` days. The senders
+// replied to are remembered in a `.sortmail-vacation` file in the root
+// Maildir, with one `<unix time> <address>` line each.
+//
+// No reply is sent to mail that a person didn't send: bounces and mail
+// without an envelope sender, machine-generated mail, mailing list
+// mail, and mail from addresses like `MAILER-DAEMON`, `noreply` or
+// `owner-list`. Nor is one sent for mail that the recipient only got as
+// a Bcc or via a list that strips List-Id, so mail has to name the
+// recipient, or one of the `vacation_addresses`, in `To:` or `Cc:`.
+//
+// `{subject}` in `vacation_subject` and the file is replaced with the
+// subject of the message being replied to. Replies have the null
+// envelope sender, so that they can't bounce back to us.
+//
+
+/// Senders whose mail never gets a reply, lowercased, as in vacation(1).
+const NEVER_REPLY_TO: [&str; 8] = [
+    "mailer-daemon",
+    "postmaster",
+    "listserv",
+    "majordomo",
+    "noreply",
+    "no-reply",
+    "donotreply",
+    "do-not-reply"
+];
+
+/// The vacation responder, from the global `vacation_` options.
+#[derive(Debug)]
+pub struct Vacation {
+    pub subject: String,
+    pub body: String,
+    pub from: Option<String>,
+    pub interval: Duration,
+    pub addresses: Vec<String>
+}
+
+impl Vacation {
+    /// Return why `message` shouldn't be replied to, or None if it
+    /// should.
+    pub fn reason_not_to_reply(&self, envelope: &Envelope, message: &IncomingMessage) -> Option<&'static str> {
+        let Some(ref sender) = envelope.sender else {
+            return Some("it has no envelope sender");
+        };
+
+        let local_part = address::split_address(sender)
+            .map(|(local_part, _)| local_part.to_lowercase())
+            .unwrap_or_default();
+
+        let is_system_sender = NEVER_REPLY_TO.contains(&local_part.as_str())
+            || local_part.starts_with("owner-")
+            || local_part.ends_with("-request")
+            || local_part.ends_with("-owner")
+            || local_part.ends_with("-bounces");
+
+        let is_own_address = *sender == envelope.recipient
+            || *sender == envelope.recipient_without_extension
+            || self.addresses.contains(sender);
+
+        let addressed_to_recipient = ["To", "Cc"]
+            .iter()
+            .flat_map(|name| message.header_addresses(name))
+            .any(|address| address == envelope.recipient
+                || address == envelope.recipient_without_extension
+                || self.addresses.contains(&address));
+
+        if message.is_bounce() {
+            Some("it's a bounce")
+        } else if message.is_automated() {
+            Some("it's machine-generated")
+        } else if message.mailing_list_identifier().is_some() {
+            Some("it's from a mailing list")
+        } else if is_system_sender {
+            Some("the sender doesn't take replies")
+        } else if is_own_address {
+            Some("it's from the recipient")
+        } else if !addressed_to_recipient {
+            Some("it isn't addressed to the recipient")
+        } else {
+            None
+        }
+    }
+
+    /// Return the reply to `message`, from the recipient in `envelope`
+    /// (or `vacation_from`) to its envelope sender.
+    pub fn reply(&self, envelope: &Envelope, message: &IncomingMessage) -> Vec<u8> {
+        let original_subject = message.header_values("Subject").next().unwrap_or_default().to_string();
+        let subject = self.subject
+            .replace("{subject}", &original_subject)
+            .replace(['\r', '\n'], " ");
+        let body = self.body.replace("{subject}", &original_subject);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut headers = vec![
+            format!("From: {}", self.from.as_deref().unwrap_or(&envelope.original_case_recipient)),
+            format!("To: {}", envelope.sender.as_deref().unwrap_or_default()),
+            format!("Subject: {}", encode_header_value(&subject)),
+            format!("Date: {}", dates::rfc5322_date(now.as_secs() as i64)),
+            format!("Message-ID: <{}.{}.{}.sortmail@{}>", now.as_secs(), now.subsec_nanos(), std::process::id(), local_hostname()),
+            "Auto-Submitted: auto-replied".to_string()
+        ];
+
+        if let Some(message_id) = message.message_id() {
+            let mut references = message.thread_parent_ids();
+            references.reverse();
+            references.dedup();
+            references.push(message_id.clone());
+
+            headers.push(format!("In-Reply-To: {message_id}"));
+            headers.push(format!("References: {}", references.join(" ")));
+        }
+
+        headers.extend([
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Transfer-Encoding: 8bit".to_string()
+        ]);
+
+        let mut reply = headers.join("\r\n");
+        reply.push_str("\r\n\r\n");
+        reply.push_str(&body.replace("\r\n", "\n").replace('\n', "\r\n"));
+        if !reply.ends_with("\r\n") {
+            reply.push_str("\r\n");
+        }
+
+        reply.into_bytes()
+    }
+}
+
+/// Return `value` as an RFC 2047 encoded-word if it isn't plain ASCII.
+fn encode_header_value(value: &str) -> String {
+    match value.is_ascii() {
+        true => value.to_string(),
+        false => format!("=?UTF-8?B?{}?=", base64_encode(value.as_bytes()))
+    }
+}
+
+/// The senders that have been replied to, held open and exclusively
+/// locked so that concurrent deliveries can't both reply.
+pub struct ReplyLog {
+    file: File,
+
+    /// When each sender was last replied to
+    entries: Vec<(u64, String)>
+}
+
+impl ReplyLog {
+    /// Open (creating it if needed) and lock the log file at `path`.
+    pub fn open(path: &Path) -> Result<ReplyLog> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Error opening vacation log {}", path.display()))?;
+
+        file.lock()
+            .with_context(|| format!("Error locking vacation log {}", path.display()))?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .with_context(|| format!("Error reading vacation log {}", path.display()))?;
+
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let (time, sender) = line.split_once(' ')?;
+                Some((time.parse().ok()?, sender.to_string()))
+            })
+            .collect();
+
+        Ok(ReplyLog {
+            file,
+            entries
+        })
+    }
+
+    /// Return true if `sender` was replied to less than `interval` ago.
+    pub fn replied_within(&self, sender: &str, interval: Duration) -> bool {
+        let now = now();
+        self.entries
+            .iter()
+            .any(|(time, replied_to)| replied_to == sender && now.saturating_sub(*time) < interval.as_secs())
+    }
+
+    /// Record a reply to `sender`, dropping entries older than
+    /// `interval`, and write the log back out.
+    pub fn record(&mut self, sender: &str, interval: Duration) -> Result<()> {
+        let now = now();
+
+        self.entries.retain(|(time, replied_to)| replied_to != sender && now.saturating_sub(*time) < interval.as_secs());
+        self.entries.push((now, sender.to_string()));
+
+        let contents: String = self.entries
+            .iter()
+            .map(|(time, sender)| format!("{time} {sender}\n"))
+            .collect();
+
+        self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.set_len(0))
+            .and_then(|_| self.file.write_all(contents.as_bytes()))
+            .and_then(|_| self.file.sync_all())
+            .context("Error writing vacation log")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}